@@ -0,0 +1,444 @@
+//! Loading of a `Target` from its TOML description.
+
+use crate::target::{Device, Link, MemoryKind, MemorySpace, Target};
+use diagnostics::{Diagnostic, ErrorKind, Span};
+use parser::types::Type;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use toml::Spanned;
+use toml::de::{DeTable, DeValue};
+
+type Value<'i> = Spanned<DeValue<'i>>;
+
+impl Target {
+    /// Target of the TOML description `source`, laid out as in the docs of
+    /// the crate. Fails with every mistake found.
+    pub fn parse(source: &str) -> Result<Target, Vec<Diagnostic>> {
+        let document = DeTable::parse(source).map_err(|e| {
+            let range = e.span().unwrap_or(0..0);
+            let message = e.message().trim_end().to_string();
+            vec![Diagnostic::error(
+                ErrorKind::InvalidTarget,
+                message,
+                span(source, range),
+            )]
+        })?;
+        let mut reader = Reader {
+            source,
+            diags: Vec::new(),
+        };
+        let target = reader.target(document.get_ref());
+        if reader.diags.is_empty() {
+            Ok(target)
+        } else {
+            Err(reader.diags)
+        }
+    }
+}
+
+/// The TOML description of the target, which `Target::parse` reads back.
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let strings = |items: &[String]| {
+            let items: Vec<String> = items.iter().map(|s| quote(s)).collect();
+            format!("[{}]", items.join(", "))
+        };
+        writeln!(f, "host = {}", quote(&self.host))?;
+        for device in self.devices() {
+            writeln!(f, "\n[devices.{}]", quote(&device.name))?;
+            writeln!(f, "vector_width = {}", device.vector_width)?;
+            if let Some(dtypes) = &device.dtypes {
+                let dtypes: Vec<String> = dtypes.iter().map(|t| t.to_string()).collect();
+                writeln!(f, "dtypes = {}", strings(&dtypes))?;
+            }
+            if let Some(ops) = &device.ops {
+                writeln!(f, "ops = {}", strings(ops))?;
+            }
+            for space in &device.memory {
+                // a string, since TOML integers stop at i64::MAX
+                writeln!(
+                    f,
+                    "memory.{} = {{ kind = \"{}\", capacity = \"{}\" }}",
+                    quote(&space.name),
+                    space.kind,
+                    space.capacity
+                )?;
+            }
+        }
+        for link in self.links() {
+            writeln!(f, "\n[[links]]")?;
+            writeln!(f, "from = {}", quote(&link.from))?;
+            writeln!(f, "to = {}", quote(&link.to))?;
+            writeln!(f, "bandwidth = {}", link.bandwidth)?;
+            writeln!(f, "latency = {}", link.latency)?;
+        }
+        Ok(())
+    }
+}
+
+/// `s` as a TOML basic string.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Span of the bytes `range` of `source`.
+fn span(source: &str, range: Range<usize>) -> Span {
+    let before = &source[..range.start];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    Span::new(range.start, range.end, line, col)
+}
+
+/// Bytes of a size such as `1024` or `"256KiB"`.
+fn parse_size(text: &str) -> Option<u64> {
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (n, unit) = text.split_at(digits);
+    let scale: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return None,
+    };
+    n.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+struct Reader<'s> {
+    source: &'s str,
+    diags: Vec<Diagnostic>,
+}
+
+impl Reader<'_> {
+    fn error(&mut self, range: Range<usize>, message: String) {
+        let span = span(self.source, range);
+        self.diags
+            .push(Diagnostic::error(ErrorKind::InvalidTarget, message, span));
+    }
+
+    /// Entries of `table` by key, reporting the keys other than `known`.
+    fn entries<'t, 'i>(
+        &mut self,
+        table: &'t DeTable<'i>,
+        known: &[&str],
+    ) -> HashMap<&'t str, &'t Value<'i>> {
+        let mut entries = HashMap::new();
+        for (key, value) in table {
+            if known.contains(&key.get_ref().as_ref()) {
+                entries.insert(key.get_ref().as_ref(), value);
+            } else {
+                let diag = Diagnostic::error(
+                    ErrorKind::InvalidTarget,
+                    format!("unknown key `{}`", key.get_ref()),
+                    span(self.source, key.span()),
+                )
+                .with_help(format!("expect one of: {}", known.join(", ")));
+                self.diags.push(diag);
+            }
+        }
+        entries
+    }
+
+    fn expected(&mut self, value: &Value, what: &str) {
+        let found = value.get_ref().type_str();
+        self.error(value.span(), format!("expect {}, found {}", what, found));
+    }
+
+    fn table<'t, 'i>(&mut self, value: &'t Value<'i>) -> Option<&'t DeTable<'i>> {
+        let table = value.get_ref().as_table();
+        if table.is_none() {
+            self.expected(value, "a table");
+        }
+        table
+    }
+
+    fn string(&mut self, value: &Value) -> Option<String> {
+        let s = value.get_ref().as_str().map(str::to_string);
+        if s.is_none() {
+            self.expected(value, "a string");
+        }
+        s
+    }
+
+    /// Strings of an array, with their place.
+    fn strings(&mut self, value: &Value) -> Option<Vec<(String, Range<usize>)>> {
+        let Some(items) = value.get_ref().as_array() else {
+            self.expected(value, "an array of strings");
+            return None;
+        };
+        let strings = items
+            .iter()
+            .map(|item| Some((self.string(item)?, item.span())))
+            .collect::<Vec<_>>();
+        strings.into_iter().collect()
+    }
+
+    /// A non-negative integer, at least `min`.
+    fn integer(&mut self, value: &Value, min: u64) -> Option<u64> {
+        let Some(n) = value.get_ref().as_integer() else {
+            self.expected(value, "an integer");
+            return None;
+        };
+        match u64::from_str_radix(n.as_str(), n.radix()) {
+            Ok(n) if n >= min => Some(n),
+            _ => {
+                self.error(
+                    value.span(),
+                    format!("expect an integer of at least {}", min),
+                );
+                None
+            }
+        }
+    }
+
+    fn size(&mut self, value: &Value) -> Option<u64> {
+        if value.get_ref().is_integer() {
+            return self.integer(value, 0);
+        }
+        let size = value.get_ref().as_str().and_then(parse_size);
+        if size.is_none() {
+            self.error(
+                value.span(),
+                "expect a size in bytes, e.g. 1024 or \"256KiB\"".to_string(),
+            );
+        }
+        size
+    }
+
+    fn target(&mut self, document: &DeTable) -> Target {
+        let entries = self.entries(document, &["host", "devices", "links"]);
+        let host = match entries.get("host") {
+            Some(value) => self.string(value).unwrap_or_default(),
+            None => "cpu".to_string(),
+        };
+        let mut target = Target::new(&host);
+        if let Some(devices) = entries.get("devices").and_then(|v| self.table(v)) {
+            for (name, value) in devices {
+                if let Some(device) = self.device(name.get_ref(), value) {
+                    target.register(device);
+                }
+            }
+        }
+        if target.device(&host).is_none() {
+            let range = entries.get("host").map_or(0..0, |v| v.span());
+            self.error(
+                range,
+                format!("the host `{}` is not a declared device", host),
+            );
+        }
+        if let Some(links) = entries.get("links") {
+            let Some(items) = links.get_ref().as_array() else {
+                self.expected(links, "an array of tables");
+                return target;
+            };
+            for item in items.iter() {
+                if let Some(link) = self.link(item, &target) {
+                    if target.link(&link.from, &link.to).is_some() {
+                        self.error(
+                            item.span(),
+                            format!(
+                                "a link from `{}` to `{}` is already declared",
+                                link.from, link.to
+                            ),
+                        );
+                    }
+                    target.connect(link);
+                }
+            }
+        }
+        target
+    }
+
+    fn device(&mut self, name: &str, value: &Value) -> Option<Device> {
+        let table = self.table(value)?;
+        let entries = self.entries(table, &["memory", "dtypes", "vector_width", "ops"]);
+        let mut device = Device::new(name);
+        if let Some(memory) = entries.get("memory").and_then(|v| self.table(v)) {
+            for (name, value) in memory {
+                if let Some(space) = self.memory(name.get_ref(), value) {
+                    device.memory.push(space);
+                }
+            }
+            device.memory.sort_by_key(|m| m.kind);
+        }
+        if let Some(dtypes) = entries.get("dtypes").and_then(|v| self.strings(v)) {
+            let mut scalars = Vec::new();
+            for (name, range) in dtypes {
+                match name.parse::<Type>() {
+                    Ok(
+                        ty @ (Type::F32
+                        | Type::F64
+                        | Type::I32
+                        | Type::U32
+                        | Type::I64
+                        | Type::U64
+                        | Type::Char
+                        | Type::Bool),
+                    ) => scalars.push(ty),
+                    _ => self.error(range, format!("`{}` is not a scalar type", name)),
+                }
+            }
+            device.dtypes = Some(scalars);
+        }
+        if let Some(width) = entries.get("vector_width") {
+            device.vector_width = self.integer(width, 1)?.try_into().unwrap_or(u32::MAX);
+        }
+        if let Some(ops) = entries.get("ops").and_then(|v| self.strings(v)) {
+            device.ops = Some(ops.into_iter().map(|(op, _)| op).collect());
+        }
+        Some(device)
+    }
+
+    fn memory(&mut self, name: &str, value: &Value) -> Option<MemorySpace> {
+        let table = self.table(value)?;
+        let entries = self.entries(table, &["kind", "capacity"]);
+        let (Some(kind), Some(capacity)) = (entries.get("kind"), entries.get("capacity")) else {
+            self.error(
+                value.span(),
+                format!("the memory `{}` needs a `kind` and a `capacity`", name),
+            );
+            return None;
+        };
+        let text = self.string(kind)?;
+        let kind = text
+            .parse::<MemoryKind>()
+            .map_err(|e| self.error(kind.span(), e))
+            .ok()?;
+        Some(MemorySpace {
+            name: name.to_string(),
+            kind,
+            capacity: self.size(capacity)?,
+        })
+    }
+
+    fn link(&mut self, value: &Value, target: &Target) -> Option<Link> {
+        let table = self.table(value)?;
+        let entries = self.entries(table, &["from", "to", "bandwidth", "latency"]);
+        let (Some(from), Some(to), Some(bandwidth)) = (
+            entries.get("from"),
+            entries.get("to"),
+            entries.get("bandwidth"),
+        ) else {
+            self.error(
+                value.span(),
+                "a link needs `from`, `to` and a `bandwidth`".to_string(),
+            );
+            return None;
+        };
+        let mut device = |value: &Value| {
+            let name = self.string(value)?;
+            if target.device(&name).is_none() {
+                self.error(value.span(), format!("unknown device `{}`", name));
+                return None;
+            }
+            Some(name)
+        };
+        let (from, to) = (device(from), device(to));
+        let latency = match entries.get("latency") {
+            Some(latency) => self.integer(latency, 0),
+            None => Some(0),
+        };
+        Some(Link {
+            from: from?,
+            to: to?,
+            bandwidth: self.integer(bandwidth, 1)?,
+            latency: latency?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTION: &str = r#"
+host = "cpu"
+
+[devices.cpu]
+memory.dram = { kind = "dram", capacity = "16GiB" }
+
+[devices.xpu]
+vector_width = 16
+dtypes = ["f32", "i32"]
+ops = ["add", "mul", "map"]
+memory.sram = { kind = "sram", capacity = "256KiB" }
+memory.global = { kind = "dram", capacity = "1GiB" }
+memory.local = { kind = "registers", capacity = 1024 }
+
+[[links]]
+from = "cpu"
+to = "xpu"
+bandwidth = 32
+latency = 1000
+"#;
+
+    #[test]
+    fn reads_devices_and_links() {
+        let target = Target::parse(DESCRIPTION).unwrap();
+        let xpu = target.device("xpu").unwrap();
+        let memory: Vec<(&str, u64)> = xpu
+            .memory
+            .iter()
+            .map(|m| (m.name.as_str(), m.capacity))
+            .collect();
+        assert_eq!(
+            memory,
+            [("global", 1 << 30), ("sram", 256 << 10), ("local", 1024)]
+        );
+        assert_eq!(xpu.main_memory().unwrap().kind, MemoryKind::Dram);
+        assert_eq!(xpu.vector_width, 16);
+        let tensor = Type::Tensor {
+            dtype: Box::new(Type::F32),
+            shape: parser::types::TensorShapeType::Any,
+        };
+        assert!(xpu.supports_type(&tensor));
+        assert!(!xpu.supports_type(&Type::List(Box::new(Type::F64))));
+        assert!(xpu.supports_op("map") && !xpu.supports_op("call"));
+        assert!(target.host_device().unwrap().supports_op("call"));
+        assert_eq!(target.transfer_cycles("cpu", "xpu", 64), Some(1002));
+        assert_eq!(target.transfer_cycles("xpu", "cpu", 64), None);
+
+        assert_eq!(Target::parse(&target.to_string()), Ok(target));
+        let host = Target::default();
+        assert_eq!(Target::parse(&host.to_string()), Ok(host));
+    }
+
+    #[test]
+    fn reports_every_mistake_in_place() {
+        let source = DESCRIPTION
+            .replace("\"i32\"", "\"int\"")
+            .replace("kind = \"sram\"", "kind = \"l2\"")
+            .replace("latency = 1000", "latency = 1000\nspeed = 1")
+            .replace("to = \"xpu\"", "to = \"gpu\"");
+        let diags = Target::parse(&source).unwrap_err();
+        let messages: Vec<String> = diags
+            .iter()
+            .map(|d| format!("{}:{} {}", d.span().line, d.span().col, d.message))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "11:24 unknown memory kind `l2`, expect one of: dram, sram, registers",
+                "9:18 `int` is not a scalar type",
+                "20:1 unknown key `speed`",
+                "17:6 unknown device `gpu`",
+            ]
+        );
+        assert!(Target::parse("host = \"gpu\"").is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! Description of the devices a program runs on.
+//!
+//! A `Target` is the registry of the devices named by `@`, with their
+//! memory hierarchy, the dtypes, ops and vector width they compute with,
+//! and the links copying values between them. Passes and the scheduler
+//! read it to place values and size tiles. Without a description the
+//! target is the host alone, a `cpu` with unbounded memory. `description`
+//! loads one from TOML:
+//!
+//! ```toml
+//! host = "cpu"
+//!
+//! [devices.cpu]
+//! memory.dram = { kind = "dram", capacity = "16GiB" }
+//!
+//! [devices.xpu]
+//! vector_width = 16
+//! dtypes = ["f32", "i32"]
+//! ops = ["add", "mul", "map", "move", "buffer"]
+//! memory.global = { kind = "dram", capacity = "1GiB" }
+//! memory.sram = { kind = "sram", capacity = "256KiB" }
+//! memory.local = { kind = "registers", capacity = 1024 }
+//!
+//! [[links]]
+//! from = "cpu"
+//! to = "xpu"
+//! bandwidth = 32  # bytes per cycle
+//! latency = 1000  # cycles
+//! ```
+
+pub mod description;
+pub mod target;
+
+pub use target::{Device, Link, MemoryKind, MemorySpace, Target, size_of};
@@ -0,0 +1,224 @@
+use parser::types::{TensorShapeType, Type};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Level of a memory in the hierarchy of a device, from the slowest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemoryKind {
+    Dram,
+    Sram,
+    Registers,
+}
+
+impl FromStr for MemoryKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dram" => Ok(MemoryKind::Dram),
+            "sram" => Ok(MemoryKind::Sram),
+            "registers" => Ok(MemoryKind::Registers),
+            _ => Err(format!(
+                "unknown memory kind `{}`, expect one of: dram, sram, registers",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MemoryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryKind::Dram => write!(f, "dram"),
+            MemoryKind::Sram => write!(f, "sram"),
+            MemoryKind::Registers => write!(f, "registers"),
+        }
+    }
+}
+
+/// Bytes taken by a value of type `ty`, `None` when its shape is not
+/// known.
+pub fn size_of(ty: &Type) -> Option<u64> {
+    match ty {
+        Type::Bool => Some(1),
+        Type::F32 | Type::I32 | Type::U32 | Type::Char => Some(4),
+        Type::F64 | Type::I64 | Type::U64 => Some(8),
+        Type::Unit => Some(0),
+        Type::Tuple(items) => items.iter().map(size_of).sum(),
+        Type::Tensor {
+            dtype,
+            shape: TensorShapeType::Shape(dims),
+        } => dims
+            .iter()
+            .try_fold(size_of(dtype)?, |n, dim| n.checked_mul(dim.as_const()?)),
+        _ => None,
+    }
+}
+
+/// A memory of a device, named by the buffers `$(size, name)` placed in
+/// it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemorySpace {
+    pub name: String,
+    pub kind: MemoryKind,
+    /// Bytes
+    pub capacity: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub name: String,
+    /// Memory spaces from the slowest to the fastest
+    pub memory: Vec<MemorySpace>,
+    /// Scalar types it computes on, `None` for all
+    pub dtypes: Option<Vec<Type>>,
+    /// Elements one vector instruction computes, 1 without vectors
+    pub vector_width: u32,
+    /// Names of the IR ops it runs, e.g. `add` or `map`, `None` for all
+    pub ops: Option<Vec<String>>,
+}
+
+impl Device {
+    /// A scalar device without memory which runs everything.
+    pub fn new(name: &str) -> Self {
+        Device {
+            name: name.to_string(),
+            memory: Vec::new(),
+            dtypes: None,
+            vector_width: 1,
+            ops: None,
+        }
+    }
+
+    pub fn memory(&self, name: &str) -> Option<&MemorySpace> {
+        self.memory.iter().find(|m| m.name == name)
+    }
+
+    /// The slowest memory, where values live unless placed elsewhere.
+    pub fn main_memory(&self) -> Option<&MemorySpace> {
+        self.memory.first()
+    }
+
+    /// Whether the device computes on the scalars of `ty`. Types without
+    /// scalars, such as functions, are always supported.
+    pub fn supports_type(&self, ty: &Type) -> bool {
+        let Some(dtypes) = &self.dtypes else {
+            return true;
+        };
+        match ty {
+            Type::List(elem) | Type::Tensor { dtype: elem, .. } => self.supports_type(elem),
+            Type::Tuple(items) => items.iter().all(|t| self.supports_type(t)),
+            Type::F32
+            | Type::F64
+            | Type::I32
+            | Type::U32
+            | Type::I64
+            | Type::U64
+            | Type::Char
+            | Type::Bool => dtypes.contains(ty),
+            _ => true,
+        }
+    }
+
+    pub fn supports_op(&self, op: &str) -> bool {
+        self.ops
+            .as_ref()
+            .is_none_or(|ops| ops.iter().any(|o| o == op))
+    }
+}
+
+/// A channel copying values from a device to another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub from: String,
+    pub to: String,
+    /// Bytes per cycle
+    pub bandwidth: u64,
+    /// Cycles before the first byte arrives
+    pub latency: u64,
+}
+
+impl Link {
+    /// Cycles taken to copy `bytes`.
+    pub fn cycles(&self, bytes: u64) -> u64 {
+        self.latency + bytes.div_ceil(self.bandwidth)
+    }
+}
+
+/// The devices of a machine, by name, and the links between them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    devices: BTreeMap<String, Device>,
+    links: Vec<Link>,
+    /// Device running what is not placed anywhere else
+    pub host: String,
+}
+
+impl Default for Target {
+    /// The host alone, a `cpu` with unbounded memory.
+    fn default() -> Self {
+        let mut cpu = Device::new("cpu");
+        cpu.memory.push(MemorySpace {
+            name: "dram".to_string(),
+            kind: MemoryKind::Dram,
+            capacity: u64::MAX,
+        });
+        let mut target = Target::new("cpu");
+        target.register(cpu);
+        target
+    }
+}
+
+impl Target {
+    /// A target without devices yet, whose host will be `host`.
+    pub fn new(host: &str) -> Self {
+        Target {
+            devices: BTreeMap::new(),
+            links: Vec::new(),
+            host: host.to_string(),
+        }
+    }
+
+    /// Add `device`, replacing any device of the same name.
+    pub fn register(&mut self, device: Device) {
+        self.devices.insert(device.name.clone(), device);
+    }
+
+    pub fn device(&self, name: &str) -> Option<&Device> {
+        self.devices.get(name)
+    }
+
+    /// Devices sorted by name.
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.devices.values()
+    }
+
+    pub fn host_device(&self) -> Option<&Device> {
+        self.device(&self.host)
+    }
+
+    /// Add a link, replacing any link between the same devices.
+    pub fn connect(&mut self, link: Link) {
+        self.links
+            .retain(|l| l.from != link.from || l.to != link.to);
+        self.links.push(link);
+    }
+
+    pub fn link(&self, from: &str, to: &str) -> Option<&Link> {
+        self.links.iter().find(|l| l.from == from && l.to == to)
+    }
+
+    pub fn links(&self) -> &[Link] {
+        &self.links
+    }
+
+    /// Cycles taken to copy `bytes` from a device to another, nothing on
+    /// the same device and `None` when no link connects them.
+    pub fn transfer_cycles(&self, from: &str, to: &str, bytes: u64) -> Option<u64> {
+        if from == to {
+            return Some(0);
+        }
+        self.link(from, to).map(|l| l.cycles(bytes))
+    }
+}
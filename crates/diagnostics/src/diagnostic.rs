@@ -0,0 +1,247 @@
+use crate::span::Span;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// What went wrong, independent of the wording of the message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// A closing delimiter does not match the innermost open one
+    UnmatchedDelimiter { expected: char, found: char },
+    /// An open delimiter is never closed
+    UnclosedDelimiter { delimiter: char },
+    /// A closing delimiter without any open one
+    UnexpectedClosingDelimiter { found: char },
+    /// A token which can not appear at this position
+    UnexpectedToken { found: String },
+    /// The input ends in the middle of an expression
+    UnexpectedEof,
+    /// Tokens left after a complete expression
+    TrailingTokens,
+    /// Malformed `:<type>`
+    BadTypeAnnotation,
+    /// Malformed `$(size, anno)`
+    BadBufferArgs,
+    /// Left side of `=` is not an identifier
+    InvalidAssignTarget,
+    /// Left side of `=>` is not an identifier or a tuple
+    InvalidLambdaParam,
+    /// Calling something that is not a function
+    InvalidCallee,
+    /// Right side of `@` is not a device identifier
+    InvalidMoveDevice,
+    /// Left side of `#` is not an identifier
+    InvalidMetaDefine,
+    /// `[...]` whose content is not a list of items
+    InvalidListLiteral,
+    /// `:` after something which can not carry a type
+    InvalidAnnotationTarget,
+    /// Right side of `.` is not a method name
+    InvalidMethodCall,
+
+    /// A name which is neither bound nor a builtin
+    UnboundName { name: String },
+    /// Calling a value which is not a function
+    NotCallable,
+    /// Calling a function with the wrong number of arguments
+    ArityMismatch { expected: usize, found: usize },
+    /// An operator or builtin applied to values it does not support
+    InvalidOperand,
+    /// Integer division or remainder by zero
+    DivisionByZero,
+
+    /// Two types which must be equal differ
+    TypeMismatch { expected: String, found: String },
+    /// A type which would have to contain itself, e.g. `f(f)`
+    InfiniteType,
+    /// Tensors whose static shapes differ
+    ShapeMismatch { expected: String, found: String },
+    /// Element-wise operands whose shapes can not be broadcast
+    NotBroadcastable,
+
+    /// Malformed textual IR
+    InvalidIr,
+    /// A pass could not transform the IR
+    PassFailed,
+    /// Malformed `rule <name>: <pattern> => <replacement>`
+    InvalidRule,
+    /// Malformed or inconsistent description of the target devices
+    InvalidTarget,
+    /// A value placed on a device the target lacks or which can not
+    /// compute it
+    InvalidPlacement,
+    /// Values live at the same time which do not fit in a memory space
+    OutOfMemory,
+}
+
+impl ErrorKind {
+    /// Stable code of the error kind, e.g. `E0001`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::UnmatchedDelimiter { .. } => "E0001",
+            ErrorKind::UnclosedDelimiter { .. } => "E0002",
+            ErrorKind::UnexpectedClosingDelimiter { .. } => "E0003",
+            ErrorKind::UnexpectedToken { .. } => "E0004",
+            ErrorKind::UnexpectedEof => "E0005",
+            ErrorKind::TrailingTokens => "E0006",
+            ErrorKind::BadTypeAnnotation => "E0007",
+            ErrorKind::BadBufferArgs => "E0008",
+            ErrorKind::InvalidAssignTarget => "E0009",
+            ErrorKind::InvalidLambdaParam => "E0010",
+            ErrorKind::InvalidCallee => "E0011",
+            ErrorKind::InvalidMoveDevice => "E0012",
+            ErrorKind::InvalidMetaDefine => "E0013",
+            ErrorKind::InvalidListLiteral => "E0014",
+            ErrorKind::InvalidAnnotationTarget => "E0015",
+            ErrorKind::InvalidMethodCall => "E0016",
+            ErrorKind::UnboundName { .. } => "E0017",
+            ErrorKind::NotCallable => "E0018",
+            ErrorKind::ArityMismatch { .. } => "E0019",
+            ErrorKind::InvalidOperand => "E0020",
+            ErrorKind::DivisionByZero => "E0021",
+            ErrorKind::TypeMismatch { .. } => "E0022",
+            ErrorKind::InfiniteType => "E0023",
+            ErrorKind::ShapeMismatch { .. } => "E0024",
+            ErrorKind::NotBroadcastable => "E0025",
+            ErrorKind::InvalidIr => "E0026",
+            ErrorKind::PassFailed => "E0027",
+            ErrorKind::InvalidRule => "E0028",
+            ErrorKind::InvalidTarget => "E0029",
+            ErrorKind::InvalidPlacement => "E0030",
+            ErrorKind::OutOfMemory => "E0031",
+        }
+    }
+}
+
+/// A span with an optional message printed under it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Label {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        Label {
+            span,
+            message: message.into(),
+        }
+    }
+}
+
+/// A diagnostic, boxed so that `Result<_, Diagnostic>` stays a pointer
+/// wide on the error side. Its fields are reached through `Deref`.
+#[derive(Clone, PartialEq)]
+pub struct Diagnostic(Box<DiagnosticData>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagnosticData {
+    pub kind: ErrorKind,
+    pub severity: Severity,
+    pub message: String,
+    pub primary: Label,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(
+        severity: Severity,
+        kind: ErrorKind,
+        message: impl Into<String>,
+        span: Span,
+    ) -> Self {
+        Diagnostic(Box::new(DiagnosticData {
+            kind,
+            severity,
+            message: message.into(),
+            primary: Label::new(span, ""),
+            secondary: Vec::new(),
+            notes: Vec::new(),
+            help: Vec::new(),
+        }))
+    }
+
+    pub fn error(kind: ErrorKind, message: impl Into<String>, span: Span) -> Self {
+        Diagnostic::new(Severity::Error, kind, message, span)
+    }
+
+    pub fn warning(kind: ErrorKind, message: impl Into<String>, span: Span) -> Self {
+        Diagnostic::new(Severity::Warning, kind, message, span)
+    }
+
+    /// Text printed under the primary span.
+    pub fn with_label(mut self, message: impl Into<String>) -> Self {
+        self.primary.message = message.into();
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.secondary.push(Label::new(span, message));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help.push(help.into());
+        self
+    }
+
+    pub fn span(&self) -> Span {
+        self.primary.span
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Deref for Diagnostic {
+    type Target = DiagnosticData;
+
+    fn deref(&self) -> &DiagnosticData {
+        &self.0
+    }
+}
+
+impl DerefMut for Diagnostic {
+    fn deref_mut(&mut self) -> &mut DiagnosticData {
+        &mut self.0
+    }
+}
+
+impl fmt::Debug for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Single line form, `line:col: message`, for places without the source text.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.primary.span, self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
@@ -0,0 +1,7 @@
+pub mod diagnostic;
+pub mod render;
+pub mod span;
+
+pub use diagnostic::{Diagnostic, DiagnosticData, ErrorKind, Label, Severity};
+pub use render::render;
+pub use span::{Span, Spanned};
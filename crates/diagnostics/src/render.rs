@@ -0,0 +1,169 @@
+use crate::diagnostic::{Diagnostic, Label};
+use crate::span::Span;
+
+/// Render a diagnostic with annotated source snippets, in the style of rustc:
+///
+/// ```text
+/// error[E0001]: mismatched closing delimiter `]`
+///  --> demo.lasmiao:1:6
+///   |
+/// 1 | (1, 2]
+///   |      ^ expected `)`
+///   | - unclosed delimiter
+///   |
+///   = help: ...
+/// ```
+///
+/// A diagnostic with the default span, which points nowhere in the
+/// source, is rendered without a location nor a snippet.
+pub fn render(diag: &Diagnostic, file_name: &str, source: &str) -> String {
+    let lines: Vec<&str> = source
+        .split('\n')
+        .map(|l| l.trim_end_matches('\r'))
+        .collect();
+
+    let mut out = format!(
+        "{}[{}]: {}\n",
+        diag.severity,
+        diag.kind.code(),
+        diag.message
+    );
+    if diag.primary.span == Span::default() {
+        for note in &diag.notes {
+            out.push_str(&format!(" = note: {}\n", note));
+        }
+        for help in &diag.help {
+            out.push_str(&format!(" = help: {}\n", help));
+        }
+        return out;
+    }
+
+    let mut labels: Vec<(&Label, bool)> = std::iter::once((&diag.primary, true))
+        .chain(diag.secondary.iter().map(|l| (l, false)))
+        .collect();
+    labels.sort_by_key(|(l, primary)| (l.span.line, !primary, l.span.col));
+
+    let max_line = labels.iter().map(|(l, _)| l.span.line).max().unwrap_or(1);
+    let pad = " ".repeat(max_line.to_string().len());
+
+    out.push_str(&format!(
+        "{}--> {}:{}:{}\n",
+        pad, file_name, diag.primary.span.line, diag.primary.span.col
+    ));
+    out.push_str(&format!("{} |\n", pad));
+
+    let mut prev_line: Option<usize> = None;
+    for (i, (label, _)) in labels.iter().enumerate() {
+        let line_no = label.span.line;
+        if prev_line == Some(line_no) {
+            continue;
+        }
+        if let Some(prev) = prev_line
+            && line_no > prev + 1
+        {
+            out.push_str("...\n");
+        }
+        prev_line = Some(line_no);
+
+        let text = lines.get(line_no.wrapping_sub(1)).copied().unwrap_or("");
+        out.push_str(&format!(
+            "{:>width$} | {}\n",
+            line_no,
+            text,
+            width = pad.len()
+        ));
+        for (label, primary) in labels[i..]
+            .iter()
+            .take_while(|(l, _)| l.span.line == line_no)
+        {
+            out.push_str(&format!(
+                "{} | {}\n",
+                pad,
+                underline(label, *primary, text, source).trim_end()
+            ));
+        }
+    }
+
+    if !diag.notes.is_empty() || !diag.help.is_empty() {
+        out.push_str(&format!("{} |\n", pad));
+    }
+    for note in &diag.notes {
+        out.push_str(&format!("{} = note: {}\n", pad, note));
+    }
+    for help in &diag.help {
+        out.push_str(&format!("{} = help: {}\n", pad, help));
+    }
+    out
+}
+
+/// Markers under the part of `text` covered by `label`, followed by its message.
+fn underline(label: &Label, primary: bool, text: &str, source: &str) -> String {
+    let span = label.span;
+    // keep tabs so that the markers line up with the source line
+    let indent: String = text
+        .chars()
+        .take(span.col.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let line_rest = text
+        .chars()
+        .count()
+        .saturating_sub(span.col.saturating_sub(1));
+    let covered = source
+        .get(span.start..span.end.min(source.len()))
+        .map_or(0, |s| s.split('\n').next().unwrap_or("").chars().count());
+    let width = covered.min(line_rest).max(1);
+
+    let mark = if primary { "^" } else { "-" };
+    format!("{}{} {}", indent, mark.repeat(width), label.message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+
+    #[test]
+    fn renders_primary_and_secondary_labels() {
+        let src = "a = 1\nb = (1, 2]\n";
+        let diag = Diagnostic::error(
+            ErrorKind::UnmatchedDelimiter {
+                expected: ')',
+                found: ']',
+            },
+            "mismatched closing delimiter `]`",
+            Span::new(15, 16, 2, 10),
+        )
+        .with_label("expected `)`")
+        .with_secondary(Span::new(10, 11, 2, 5), "unclosed delimiter")
+        .with_help("close the `(` with `)`");
+
+        assert_eq!(
+            render(&diag, "demo.lasmiao", src),
+            "error[E0001]: mismatched closing delimiter `]`\n \
+             --> demo.lasmiao:2:10\n  \
+             |\n\
+             2 | b = (1, 2]\n  \
+             |          ^ expected `)`\n  \
+             |     - unclosed delimiter\n  \
+             |\n  \
+             = help: close the `(` with `)`\n"
+        );
+    }
+
+    #[test]
+    fn renders_no_location_for_the_default_span() {
+        let diag = Diagnostic::warning(
+            ErrorKind::PassFailed,
+            "the rewrites of `rules` still apply after 10 rounds",
+            Span::default(),
+        )
+        .with_help("check that no rule undoes another one");
+        assert_eq!(
+            render(&diag, "demo.lasmiao", "a = 1\n"),
+            "warning[E0027]: the rewrites of `rules` still apply after 10 rounds\n \
+             = help: check that no rule undoes another one\n"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use crate::eval::Interpreter;
+use crate::value::Value;
+use diagnostics::{Diagnostic, ErrorKind, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Sin,
+    Cos,
+    Tan,
+    Exp,
+    Log,
+    Sqrt,
+    Abs,
+    Min,
+    Max,
+    Len,
+    Sum,
+    Map,
+}
+
+const FUNCTIONS: [Builtin; 12] = [
+    Builtin::Sin,
+    Builtin::Cos,
+    Builtin::Tan,
+    Builtin::Exp,
+    Builtin::Log,
+    Builtin::Sqrt,
+    Builtin::Abs,
+    Builtin::Min,
+    Builtin::Max,
+    Builtin::Len,
+    Builtin::Sum,
+    Builtin::Map,
+];
+
+impl Builtin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Sin => "sin",
+            Builtin::Cos => "cos",
+            Builtin::Tan => "tan",
+            Builtin::Exp => "exp",
+            Builtin::Log => "log",
+            Builtin::Sqrt => "sqrt",
+            Builtin::Abs => "abs",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+            Builtin::Len => "len",
+            Builtin::Sum => "sum",
+            Builtin::Map => "map",
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        match self {
+            Builtin::Min | Builtin::Max | Builtin::Map => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Value of a builtin name, either a constant or a function.
+pub fn lookup(name: &str) -> Option<Value> {
+    match name {
+        "pi" => Some(Value::Float(std::f64::consts::PI)),
+        "e" => Some(Value::Float(std::f64::consts::E)),
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => FUNCTIONS
+            .iter()
+            .find(|b| b.name() == name)
+            .map(|b| Value::Builtin(*b)),
+    }
+}
+
+fn invalid(builtin: Builtin, arg: &Value, span: Span) -> Diagnostic {
+    Diagnostic::error(
+        ErrorKind::InvalidOperand,
+        format!("`{}` does not accept a {}", builtin.name(), arg.kind()),
+        span,
+    )
+}
+
+/// Apply a float function to a scalar, or to each element of a list.
+fn elementwise(
+    builtin: Builtin,
+    arg: &Value,
+    f: fn(f64) -> f64,
+    span: Span,
+) -> Result<Value, Diagnostic> {
+    match arg {
+        Value::List(items) => Ok(Value::List(
+            items
+                .iter()
+                .map(|v| elementwise(builtin, v, f, span))
+                .collect::<Result<_, _>>()?,
+        )),
+        _ => arg
+            .as_f64()
+            .map(|v| Value::Float(f(v)))
+            .ok_or_else(|| invalid(builtin, arg, span)),
+    }
+}
+
+pub fn call(
+    interp: &Interpreter,
+    builtin: Builtin,
+    args: Vec<Value>,
+    span: Span,
+) -> Result<Value, Diagnostic> {
+    if args.len() != builtin.arity() {
+        return Err(Diagnostic::error(
+            ErrorKind::ArityMismatch {
+                expected: builtin.arity(),
+                found: args.len(),
+            },
+            format!(
+                "`{}` takes {} arguments, but {} were given",
+                builtin.name(),
+                builtin.arity(),
+                args.len()
+            ),
+            span,
+        ));
+    }
+    let arg = &args[0];
+    match builtin {
+        Builtin::Sin => elementwise(builtin, arg, f64::sin, span),
+        Builtin::Cos => elementwise(builtin, arg, f64::cos, span),
+        Builtin::Tan => elementwise(builtin, arg, f64::tan, span),
+        Builtin::Exp => elementwise(builtin, arg, f64::exp, span),
+        Builtin::Log => elementwise(builtin, arg, f64::ln, span),
+        Builtin::Sqrt => elementwise(builtin, arg, f64::sqrt, span),
+        Builtin::Abs => match arg {
+            Value::Int(v) => Ok(Value::Int(v.abs())),
+            _ => elementwise(builtin, arg, f64::abs, span),
+        },
+        Builtin::Min | Builtin::Max => {
+            let pick_left = match (&args[0], &args[1]) {
+                (Value::Int(a), Value::Int(b)) => (a <= b) == (builtin == Builtin::Min),
+                (a, b) => match (a.as_f64(), b.as_f64()) {
+                    (Some(a), Some(b)) => (a <= b) == (builtin == Builtin::Min),
+                    _ => {
+                        return Err(invalid(
+                            builtin,
+                            if a.as_f64().is_none() { a } else { b },
+                            span,
+                        ));
+                    }
+                },
+            };
+            Ok(if pick_left {
+                args[0].clone()
+            } else {
+                args[1].clone()
+            })
+        }
+        Builtin::Len => match arg {
+            Value::List(items) | Value::Tuple(items) => Ok(Value::Int(items.len() as i64)),
+            _ => Err(invalid(builtin, arg, span)),
+        },
+        Builtin::Sum => match arg {
+            Value::List(items) => items.iter().try_fold(Value::Int(0), |acc, v| {
+                interp.binary(&lexer::Token::Plus, acc, v.clone(), span)
+            }),
+            _ => Err(invalid(builtin, arg, span)),
+        },
+        Builtin::Map => match arg {
+            Value::List(items) => Ok(Value::List(
+                items
+                    .iter()
+                    .map(|v| interp.apply(&args[1], vec![v.clone()], span))
+                    .collect::<Result<_, _>>()?,
+            )),
+            _ => Err(invalid(builtin, arg, span)),
+        },
+    }
+}
@@ -0,0 +1,471 @@
+use crate::builtins;
+use crate::value::{Closure, Value};
+use diagnostics::{Diagnostic, ErrorKind, Span};
+use lexer::Token;
+use parser::expr::{Expr, ExprKind};
+use parser::types::Type;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Values bound to names.
+#[derive(Debug, Clone, Default)]
+pub struct Env {
+    vars: HashMap<String, Value>,
+}
+
+impl Env {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)
+    }
+
+    pub fn bind(&mut self, name: impl Into<String>, val: Value) {
+        self.vars.insert(name.into(), val);
+    }
+}
+
+/// Tree-walking interpreter, the globals persist across `run`s.
+#[derive(Default)]
+pub struct Interpreter {
+    globals: Env,
+}
+
+fn invalid_operand(message: String, span: Span) -> Diagnostic {
+    Diagnostic::error(ErrorKind::InvalidOperand, message, span)
+}
+
+/// Convert `val` to the representation of an annotated type, e.g. round
+/// to f32 precision for `:f32` or wrap to 32 bits for `:i32`.
+fn cast(val: Value, typ: &Type) -> Value {
+    match (val, typ) {
+        (Value::List(items), Type::List(elem)) => {
+            Value::List(items.into_iter().map(|v| cast(v, elem)).collect())
+        }
+        (Value::List(items), Type::Tensor { .. }) => {
+            Value::List(items.into_iter().map(|v| cast(v, typ)).collect())
+        }
+        (val, Type::Tensor { dtype, .. }) => cast(val, dtype),
+        (Value::Int(v), Type::F32) => Value::Float(v as f32 as f64),
+        (Value::Float(v), Type::F32) => Value::Float(v as f32 as f64),
+        (Value::Int(v), Type::F64) => Value::Float(v as f64),
+        (Value::Int(v), Type::I32) => Value::Int(v as i32 as i64),
+        (Value::Float(v), Type::I32) => Value::Int(v as i32 as i64),
+        (Value::Int(v), Type::U32) => Value::Int(v as u32 as i64),
+        (Value::Float(v), Type::U32) => Value::Int(v as u32 as i64),
+        (Value::Float(v), Type::I64 | Type::U64) => Value::Int(v as i64),
+        (val, _) => val,
+    }
+}
+
+fn annotation(expr: &Expr) -> Option<&Type> {
+    match &expr.kind {
+        ExprKind::Identifier { typ, .. } if *typ != Type::Unknown => Some(typ),
+        _ => None,
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter::default()
+    }
+
+    pub fn globals(&self) -> &Env {
+        &self.globals
+    }
+
+    /// Evaluate a program, whose bindings stay visible to later programs.
+    /// Returns the value of the last statement.
+    pub fn run(&mut self, program: &Expr) -> Result<Value, Diagnostic> {
+        let mut env = std::mem::take(&mut self.globals);
+        let res = match &program.kind {
+            ExprKind::Block(stmts) => self.eval_statements(stmts, &mut env),
+            _ => self.eval(program, &mut env),
+        };
+        self.globals = env;
+        res
+    }
+
+    fn eval_statements(&self, stmts: &[Expr], env: &mut Env) -> Result<Value, Diagnostic> {
+        let mut last = Value::Unit;
+        for stmt in stmts {
+            last = self.eval(stmt, env)?;
+        }
+        Ok(last)
+    }
+
+    /// Values of the arguments of a call, `f()`, `f(x)` or `f(x, y)`.
+    fn eval_args(&self, args: &Expr, env: &mut Env) -> Result<Vec<Value>, Diagnostic> {
+        match &args.kind {
+            ExprKind::Unit => Ok(Vec::new()),
+            ExprKind::Tuple(items) => items.iter().map(|i| self.eval(i, env)).collect(),
+            _ => Ok(vec![self.eval(args, env)?]),
+        }
+    }
+
+    pub fn eval(&self, expr: &Expr, env: &mut Env) -> Result<Value, Diagnostic> {
+        match &expr.kind {
+            ExprKind::Unit => Ok(Value::Unit),
+            ExprKind::Float { val, typ } => Ok(cast(Value::Float(*val), typ)),
+            ExprKind::Integer { val, typ } => Ok(cast(Value::Int(*val as i64), typ)),
+            ExprKind::List(items) => Ok(Value::List(
+                items
+                    .iter()
+                    .map(|i| self.eval(i, env))
+                    .collect::<Result<_, _>>()?,
+            )),
+            ExprKind::Tuple(items) => Ok(Value::Tuple(
+                items
+                    .iter()
+                    .map(|i| self.eval(i, env))
+                    .collect::<Result<_, _>>()?,
+            )),
+            ExprKind::Buffer { size, anno } => Ok(Value::Buffer {
+                size: *size,
+                anno: anno.clone(),
+            }),
+            ExprKind::Identifier { name, typ } => {
+                let val = env
+                    .get(name)
+                    .cloned()
+                    .or_else(|| builtins::lookup(name))
+                    .ok_or_else(|| {
+                        Diagnostic::error(
+                            ErrorKind::UnboundName { name: name.clone() },
+                            format!("`{}` is not bound", name),
+                            expr.span,
+                        )
+                        .with_label("not found in this scope")
+                    })?;
+                Ok(cast(val, typ))
+            }
+            ExprKind::Assign { name, val } => {
+                let ExprKind::Identifier { name: id, .. } = &name.kind else {
+                    unreachable!("the parser only assigns to identifiers")
+                };
+                let mut v = self.eval(val, env)?;
+                if let Some(typ) = annotation(name) {
+                    v = cast(v, typ);
+                }
+                env.bind(id.clone(), v.clone());
+                Ok(v)
+            }
+            ExprKind::MetaDefine { name, val } => {
+                let v = self.eval(val, env)?;
+                env.bind(name.clone(), v.clone());
+                Ok(v)
+            }
+            ExprKind::Unary { op, arg } => {
+                let v = self.eval(arg, env)?;
+                Ok(cast(self.unary(op, v, expr.span)?, &expr.ty))
+            }
+            ExprKind::Binary { left, op, right } => {
+                let l = self.eval(left, env)?;
+                let r = self.eval(right, env)?;
+                // wrap to the inferred type, as the compiled program does
+                Ok(cast(self.binary(op, l, r, expr.span)?, &expr.ty))
+            }
+            ExprKind::Call { callee, args } => {
+                let f = self.eval(callee, env)?;
+                let args = self.eval_args(args, env)?;
+                self.apply(&f, args, expr.span)
+            }
+            ExprKind::Lambda { param, body } => {
+                let params = match &param.kind {
+                    ExprKind::Identifier { name, .. } => vec![name.clone()],
+                    ExprKind::Tuple(items) => items
+                        .iter()
+                        .map(|item| match &item.kind {
+                            ExprKind::Identifier { name, .. } => Ok(name.clone()),
+                            _ => Err(Diagnostic::error(
+                                ErrorKind::InvalidLambdaParam,
+                                "expect an identifier as lambda parameter",
+                                item.span,
+                            )),
+                        })
+                        .collect::<Result<_, _>>()?,
+                    _ => unreachable!("the parser only accepts identifiers or tuples as params"),
+                };
+                Ok(Value::Closure(Rc::new(Closure {
+                    params,
+                    body: (**body).clone(),
+                    env: env.clone(),
+                })))
+            }
+            // the value is the same wherever it lives
+            ExprKind::Move { val, .. } => self.eval(val, env),
+            ExprKind::Block(stmts) => self.eval_statements(stmts, &mut env.clone()),
+            // a rule only rewrites the compiled program
+            ExprKind::Rule { .. } => Ok(Value::Unit),
+            ExprKind::Error => Err(invalid_operand(
+                "can not evaluate an expression which failed to parse".to_string(),
+                expr.span,
+            )),
+        }
+    }
+
+    /// Call a function value.
+    pub fn apply(&self, f: &Value, mut args: Vec<Value>, span: Span) -> Result<Value, Diagnostic> {
+        match f {
+            Value::Builtin(builtin) => builtins::call(self, *builtin, args, span),
+            Value::Closure(closure) => {
+                // `(a, b) => ...` applied to one tuple, e.g. by `map`
+                if closure.params.len() > 1
+                    && args.len() == 1
+                    && matches!(&args[0], Value::Tuple(items) if items.len() == closure.params.len())
+                {
+                    let Some(Value::Tuple(items)) = args.pop() else {
+                        unreachable!()
+                    };
+                    args = items;
+                }
+                if args.len() != closure.params.len() {
+                    return Err(Diagnostic::error(
+                        ErrorKind::ArityMismatch {
+                            expected: closure.params.len(),
+                            found: args.len(),
+                        },
+                        format!(
+                            "the lambda takes {} arguments, but {} were given",
+                            closure.params.len(),
+                            args.len()
+                        ),
+                        span,
+                    ));
+                }
+                let mut env = closure.env.clone();
+                for (name, arg) in closure.params.iter().zip(args) {
+                    env.bind(name.clone(), arg);
+                }
+                self.eval(&closure.body, &mut env)
+            }
+            _ => Err(Diagnostic::error(
+                ErrorKind::NotCallable,
+                format!("a {} is not callable", f.kind()),
+                span,
+            )),
+        }
+    }
+
+    fn unary(&self, op: &Token, v: Value, span: Span) -> Result<Value, Diagnostic> {
+        match (op, v) {
+            (_, Value::List(items)) => Ok(Value::List(
+                items
+                    .into_iter()
+                    .map(|v| self.unary(op, v, span))
+                    .collect::<Result<_, _>>()?,
+            )),
+            (Token::Minus, Value::Int(v)) => Ok(Value::Int(v.wrapping_neg())),
+            (Token::Minus, Value::Float(v)) => Ok(Value::Float(-v)),
+            (Token::Not, Value::Bool(v)) => Ok(Value::Bool(!v)),
+            (op, v) => Err(invalid_operand(
+                format!("unary `{}` can not be applied to a {}", op, v.kind()),
+                span,
+            )),
+        }
+    }
+
+    /// Apply a binary operator, element by element when one side is a list.
+    /// Lists broadcast like NumPy arrays, aligned at their innermost dims.
+    pub fn binary(&self, op: &Token, l: Value, r: Value, span: Span) -> Result<Value, Diagnostic> {
+        let (left_rank, right_rank) = (l.rank(), r.rank());
+        match (l, r) {
+            // the lower rank side is repeated for each element of the other
+            (Value::List(a), b) if left_rank > right_rank => Ok(Value::List(
+                a.into_iter()
+                    .map(|a| self.binary(op, a, b.clone(), span))
+                    .collect::<Result<_, _>>()?,
+            )),
+            (a, Value::List(b)) if left_rank < right_rank => Ok(Value::List(
+                b.into_iter()
+                    .map(|b| self.binary(op, a.clone(), b, span))
+                    .collect::<Result<_, _>>()?,
+            )),
+            (Value::List(a), Value::List(b)) => {
+                // as in NumPy, a dim of 1 stretches to the other one
+                let len = match (a.len(), b.len()) {
+                    (x, y) if x == y => x,
+                    (1, y) => y,
+                    (x, 1) => x,
+                    (x, y) => {
+                        return Err(invalid_operand(
+                            format!(
+                                "`{}` of lists with lengths {} and {} which can not be broadcast",
+                                op, x, y
+                            ),
+                            span,
+                        ));
+                    }
+                };
+                let pick =
+                    |items: &[Value], i: usize| items[if items.len() == 1 { 0 } else { i }].clone();
+                Ok(Value::List(
+                    (0..len)
+                        .map(|i| self.binary(op, pick(&a, i), pick(&b, i), span))
+                        .collect::<Result<_, _>>()?,
+                ))
+            }
+            (Value::Int(a), Value::Int(b)) => int_binary(op, a, b, span),
+            (Value::Bool(a), Value::Bool(b)) => match op {
+                Token::LogicAnd | Token::And => Ok(Value::Bool(a && b)),
+                Token::LogicOr | Token::Or => Ok(Value::Bool(a || b)),
+                Token::Xor | Token::NotEqual => Ok(Value::Bool(a != b)),
+                Token::DoubleEqual => Ok(Value::Bool(a == b)),
+                _ => Err(invalid_operand(
+                    format!("`{}` can not be applied to bools", op),
+                    span,
+                )),
+            },
+            (a, b) => match (a.as_f64(), b.as_f64()) {
+                (Some(a), Some(b)) => float_binary(op, a, b, span),
+                _ => Err(invalid_operand(
+                    format!(
+                        "`{}` can not be applied to a {} and a {}",
+                        op,
+                        a.kind(),
+                        b.kind()
+                    ),
+                    span,
+                )),
+            },
+        }
+    }
+}
+
+fn int_binary(op: &Token, a: i64, b: i64, span: Span) -> Result<Value, Diagnostic> {
+    let division_by_zero = || {
+        Diagnostic::error(
+            ErrorKind::DivisionByZero,
+            "attempt to divide an integer by zero",
+            span,
+        )
+    };
+    Ok(match op {
+        Token::Plus => Value::Int(a.wrapping_add(b)),
+        Token::Minus => Value::Int(a.wrapping_sub(b)),
+        Token::Star => Value::Int(a.wrapping_mul(b)),
+        Token::Slash => Value::Int(a.checked_div(b).ok_or_else(division_by_zero)?),
+        Token::Mod => Value::Int(a.checked_rem(b).ok_or_else(division_by_zero)?),
+        Token::And => Value::Int(a & b),
+        Token::Or => Value::Int(a | b),
+        Token::Xor => Value::Int(a ^ b),
+        _ => return compare(op, a.cmp(&b), span),
+    })
+}
+
+fn float_binary(op: &Token, a: f64, b: f64, span: Span) -> Result<Value, Diagnostic> {
+    Ok(match op {
+        Token::Plus => Value::Float(a + b),
+        Token::Minus => Value::Float(a - b),
+        Token::Star => Value::Float(a * b),
+        Token::Slash => Value::Float(a / b),
+        Token::Mod => Value::Float(a % b),
+        _ => match a.partial_cmp(&b) {
+            Some(ord) => return compare(op, ord, span),
+            // NaN is only unequal
+            None => Value::Bool(*op == Token::NotEqual),
+        },
+    })
+}
+
+fn compare(op: &Token, ord: std::cmp::Ordering, span: Span) -> Result<Value, Diagnostic> {
+    use std::cmp::Ordering::*;
+    Ok(Value::Bool(match op {
+        Token::DoubleEqual => ord == Equal,
+        Token::NotEqual => ord != Equal,
+        Token::LessThan => ord == Less,
+        Token::LessThanEq => ord != Greater,
+        Token::GreatThan => ord == Greater,
+        Token::GreatThanEq => ord != Less,
+        _ => {
+            return Err(invalid_operand(
+                format!("`{}` can not be applied to numbers", op),
+                span,
+            ));
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::{LasmiaoLexer, Lexer};
+    use parser::TokenParser;
+    use parser::traits::Parser;
+
+    fn run(interp: &mut Interpreter, src: &str) -> Result<Value, Diagnostic> {
+        let tokens = LasmiaoLexer::make_tokens(src).unwrap();
+        let program = TokenParser::new(tokens).parse_exprs().unwrap();
+        interp.run(&program)
+    }
+
+    #[test]
+    fn evaluates_the_readme_functions() {
+        let mut interp = Interpreter::new();
+        assert_eq!(
+            run(&mut interp, "a:f32 = 1.+sin(pi)"),
+            Ok(Value::Float(
+                (1. + std::f64::consts::PI.sin()) as f32 as f64
+            ))
+        );
+        run(&mut interp, "f = (x => sin(x)+cos(x))\nb = [0., pi]").unwrap();
+        assert_eq!(
+            run(&mut interp, "c = b.map(x => f(x)+1.)"),
+            Ok(Value::List(vec![
+                Value::Float(2.),
+                Value::Float(std::f64::consts::PI.sin() + std::f64::consts::PI.cos() + 1.),
+            ]))
+        );
+    }
+
+    #[test]
+    fn tuple_params_and_elementwise_ops() {
+        let mut interp = Interpreter::new();
+        assert_eq!(
+            run(&mut interp, "add = ((a, b) => a + b)\nadd(1, 2)"),
+            Ok(Value::Int(3))
+        );
+        assert_eq!(
+            run(&mut interp, "[[1, 2], [3, 4]].map(sum)"),
+            Ok(Value::List(vec![Value::Int(3), Value::Int(7)]))
+        );
+        assert_eq!(
+            run(&mut interp, "[[1, 2], [3, 4]] * 2 - 1"),
+            Ok(Value::List(vec![
+                Value::List(vec![Value::Int(1), Value::Int(3)]),
+                Value::List(vec![Value::Int(5), Value::Int(7)]),
+            ]))
+        );
+        assert_eq!(
+            run(&mut interp, "[[1, 2], [3, 4]] + [10, 20]"),
+            Ok(Value::List(vec![
+                Value::List(vec![Value::Int(11), Value::Int(22)]),
+                Value::List(vec![Value::Int(13), Value::Int(24)]),
+            ]))
+        );
+        assert_eq!(run(&mut interp, "x:i32 = 4294967297"), Ok(Value::Int(1)));
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let mut interp = Interpreter::new();
+        let err = run(&mut interp, "1 + y").unwrap_err();
+        assert_eq!(
+            err.kind,
+            ErrorKind::UnboundName {
+                name: "y".to_string()
+            }
+        );
+        assert_eq!(err.span().col, 5);
+        let err = run(&mut interp, "(x => x)(1, 2)").unwrap_err();
+        assert_eq!(
+            err.kind,
+            ErrorKind::ArityMismatch {
+                expected: 1,
+                found: 2
+            }
+        );
+        assert_eq!(
+            run(&mut interp, "1 / 0").unwrap_err().kind,
+            ErrorKind::DivisionByZero
+        );
+    }
+}
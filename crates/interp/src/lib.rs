@@ -0,0 +1,11 @@
+//! Reference interpreter of LasMiao, walking the `Expr` tree directly.
+//!
+//! It defines what a program means and serves as the golden model which
+//! compiled code is checked against.
+
+pub mod builtins;
+pub mod eval;
+pub mod value;
+
+pub use eval::{Env, Interpreter};
+pub use value::Value;
@@ -0,0 +1,107 @@
+use crate::builtins::Builtin;
+use crate::eval::Env;
+use parser::expr::Expr;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Unit,
+    Float(f64),
+    Int(i64),
+    Bool(bool),
+
+    List(Vec<Value>),
+    Tuple(Vec<Value>),
+    Buffer { size: u64, anno: String },
+
+    Closure(Rc<Closure>),
+    Builtin(Builtin),
+}
+
+/// A lambda together with the bindings it captured.
+#[derive(Debug)]
+pub struct Closure {
+    /// Names of the parameters, a single name for `x => ...`
+    pub params: Vec<String>,
+    pub body: Expr,
+    pub env: Env,
+}
+
+impl Value {
+    /// Name of the kind of value, for error messages.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::Unit => "unit",
+            Value::Float(_) => "float",
+            Value::Int(_) => "integer",
+            Value::Bool(_) => "bool",
+            Value::List(_) => "list",
+            Value::Tuple(_) => "tuple",
+            Value::Buffer { .. } => "buffer",
+            Value::Closure(_) | Value::Builtin(_) => "function",
+        }
+    }
+
+    /// Number of nested list levels, 0 for a scalar.
+    pub fn rank(&self) -> usize {
+        match self {
+            Value::List(items) => 1 + items.first().map_or(0, Value::rank),
+            _ => 0,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Float(v) => Some(*v),
+            Value::Int(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Unit, Value::Unit) => true,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::List(a), Value::List(b)) | (Value::Tuple(a), Value::Tuple(b)) => a == b,
+            (
+                Value::Buffer { size, anno },
+                Value::Buffer {
+                    size: size2,
+                    anno: anno2,
+                },
+            ) => size == size2 && anno == anno2,
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Builtin(a), Value::Builtin(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+fn join(items: &[Value]) -> String {
+    items
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Unit => write!(f, "()"),
+            Value::Float(v) => write!(f, "{:?}", v),
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::List(items) => write!(f, "[{}]", join(items)),
+            Value::Tuple(items) => write!(f, "({})", join(items)),
+            Value::Buffer { size, anno } => write!(f, "$({}, {})", size, anno),
+            Value::Closure(closure) => write!(f, "<lambda ({})>", closure.params.join(", ")),
+            Value::Builtin(builtin) => write!(f, "<builtin {}>", builtin.name()),
+        }
+    }
+}
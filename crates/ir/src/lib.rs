@@ -1,14 +1,20 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+//! Dataflow graph IR of LasMiao.
+//!
+//! A program is a `Module` of ops in SSA form: every op takes typed values
+//! as operands and defines new ones, lambdas are ops owning a region, and
+//! moves and buffers carry the device they are placed on. `lower` builds
+//! a module from a checked `Expr`. Modules print to a textual form which
+//! `parse` reads back, and `verify` checks the invariants passes rely on.
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+pub mod lower;
+pub mod module;
+pub mod op;
+pub mod parse;
+mod print;
+pub mod verify;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub use lower::lower;
+pub use module::{Module, Op, OpId, Region, RegionId, Value, ValueDef, ValueId};
+pub use op::{BinOp, Literal, OpKind, UnOp};
+pub use parse::parse;
+pub use verify::verify;
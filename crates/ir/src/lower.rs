@@ -0,0 +1,348 @@
+use crate::module::{Module, RegionId, ValueId};
+use crate::op::{BinOp, Literal, OpKind, UnOp};
+use parser::expr::{Expr, ExprKind};
+use parser::types::Type;
+use std::collections::HashMap;
+
+type Scope = HashMap<String, ValueId>;
+
+/// Lower a parsed program into a module. Types of values are taken from
+/// the `ty` of the expressions, so the program should have been checked.
+pub fn lower(program: &Expr) -> Module {
+    let mut lowering = Lowering {
+        module: Module::new(),
+        symbols: HashMap::new(),
+    };
+    let body = lowering.module.body;
+    let mut scope = Scope::new();
+    let stmts = match &program.kind {
+        ExprKind::Block(stmts) => stmts.as_slice(),
+        _ => std::slice::from_ref(program),
+    };
+    let mut last = None;
+    // rules rewrite the module rather than compute a value
+    for stmt in stmts
+        .iter()
+        .filter(|s| !matches!(s.kind, ExprKind::Rule { .. }))
+    {
+        let value = lowering.lower(stmt, body, &mut scope);
+        if let Some(name) = bound_name(stmt) {
+            lowering.module.names.push((name.to_string(), value));
+        }
+        last = Some(value);
+    }
+    let mut module = lowering.module;
+    let result =
+        last.unwrap_or_else(|| module.push(body, OpKind::Const(Literal::Unit), vec![], Type::Unit));
+    module.region_mut(body).results.push(result);
+    module
+}
+
+fn bound_name(stmt: &Expr) -> Option<&str> {
+    match &stmt.kind {
+        ExprKind::Assign { name, .. } => match &name.kind {
+            ExprKind::Identifier { name, .. } => Some(name),
+            _ => None,
+        },
+        ExprKind::MetaDefine { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+struct Lowering {
+    module: Module,
+    /// The `symbol` of each input or builtin in the body, reused by every
+    /// mention of the name at the same type
+    symbols: HashMap<String, ValueId>,
+}
+
+impl Lowering {
+    /// Append an op computing `expr` from `operands` to `region`.
+    fn emit(
+        &mut self,
+        region: RegionId,
+        kind: OpKind,
+        operands: Vec<ValueId>,
+        expr: &Expr,
+    ) -> ValueId {
+        let value = self.module.push(region, kind, operands, expr.ty.clone());
+        let op = self.module.def_op(value).unwrap();
+        self.module.op_mut(op).span = Some(expr.span);
+        value
+    }
+
+    fn lower_all(&mut self, exprs: &[Expr], region: RegionId, scope: &mut Scope) -> Vec<ValueId> {
+        exprs.iter().map(|e| self.lower(e, region, scope)).collect()
+    }
+
+    fn lower(&mut self, expr: &Expr, region: RegionId, scope: &mut Scope) -> ValueId {
+        match &expr.kind {
+            ExprKind::Unit => self.emit(region, OpKind::Const(Literal::Unit), vec![], expr),
+            ExprKind::Float { val, .. } => {
+                self.emit(region, OpKind::Const(Literal::Float(*val)), vec![], expr)
+            }
+            ExprKind::Integer { val, .. } => {
+                // an integer literal may have been inferred as a float
+                let literal = match expr.ty {
+                    Type::F32 | Type::F64 => Literal::Float(*val as f64),
+                    _ => Literal::Int(*val as i64),
+                };
+                self.emit(region, OpKind::Const(literal), vec![], expr)
+            }
+            ExprKind::List(items) => {
+                let items = self.lower_all(items, region, scope);
+                self.emit(region, OpKind::List, items, expr)
+            }
+            ExprKind::Tuple(items) => {
+                let items = self.lower_all(items, region, scope);
+                self.emit(region, OpKind::Tuple, items, expr)
+            }
+            ExprKind::Buffer { size, anno } => {
+                let kind = OpKind::Buffer {
+                    size: *size,
+                    space: anno.clone(),
+                };
+                self.emit(region, kind, vec![], expr)
+            }
+            ExprKind::Identifier { name, .. } => {
+                if let Some(value) = scope.get(name) {
+                    return *value;
+                }
+                if let Some(value) = self.symbols.get(name)
+                    && self.module.value(*value).ty == expr.ty
+                {
+                    return *value;
+                }
+                let kind = match name.as_str() {
+                    "pi" => OpKind::Const(Literal::Float(std::f64::consts::PI)),
+                    "e" => OpKind::Const(Literal::Float(std::f64::consts::E)),
+                    "true" => OpKind::Const(Literal::Bool(true)),
+                    "false" => OpKind::Const(Literal::Bool(false)),
+                    _ => OpKind::Symbol(name.clone()),
+                };
+                let is_symbol = matches!(kind, OpKind::Symbol(_));
+                let value = self.emit(region, kind, vec![], expr);
+                if is_symbol && region == self.module.body {
+                    self.symbols.insert(name.clone(), value);
+                }
+                value
+            }
+            ExprKind::Assign { name, val } => {
+                let ExprKind::Identifier { name, .. } = &name.kind else {
+                    unreachable!("the parser only assigns to identifiers")
+                };
+                let value = self.lower(val, region, scope);
+                scope.insert(name.clone(), value);
+                value
+            }
+            ExprKind::MetaDefine { name, val } => {
+                let attrs = &self.module.attrs;
+                let constant = val
+                    .to_dim()
+                    .and_then(|d| {
+                        d.specialize(&|n| attrs.get(n).and_then(|v| u64::try_from(*v).ok()))
+                            .as_const()
+                    })
+                    .and_then(|v| i64::try_from(v).ok());
+                if let Some(v) = constant {
+                    self.module.attrs.insert(name.clone(), v);
+                }
+                let value = self.lower(val, region, scope);
+                scope.insert(name.clone(), value);
+                value
+            }
+            ExprKind::Unary { op, arg } => {
+                let arg = self.lower(arg, region, scope);
+                let op = UnOp::from_token(op).expect("the parser only makes known unary ops");
+                self.emit(region, OpKind::Unary(op), vec![arg], expr)
+            }
+            ExprKind::Binary { left, op, right } => {
+                let left = self.lower(left, region, scope);
+                let right = self.lower(right, region, scope);
+                let op = BinOp::from_token(op).expect("the parser only makes known binary ops");
+                self.emit(region, OpKind::Binary(op), vec![left, right], expr)
+            }
+            ExprKind::Call { callee, args } => {
+                // `map` of the builtin is an op of its own, so that passes
+                // can see through it
+                let is_map = matches!(&callee.kind, ExprKind::Identifier { name, .. }
+                    if name == "map" && !scope.contains_key(name));
+                let f = (!is_map).then(|| self.lower(callee, region, scope));
+                let args = match &args.kind {
+                    ExprKind::Unit => Vec::new(),
+                    ExprKind::Tuple(items) => self.lower_all(items, region, scope),
+                    _ => vec![self.lower(args, region, scope)],
+                };
+                if f.is_none() && args.len() == 2 {
+                    return self.emit(region, OpKind::Map, args, expr);
+                }
+                let f = f.unwrap_or_else(|| self.lower(callee, region, scope));
+                let operands = std::iter::once(f).chain(args).collect();
+                self.emit(region, OpKind::Call, operands, expr)
+            }
+            ExprKind::Lambda { param, body } => self.lower_lambda(expr, param, body, region, scope),
+            ExprKind::Move { val, device } => {
+                let ExprKind::Identifier { name, .. } = &device.kind else {
+                    unreachable!("the parser only moves to device names")
+                };
+                let val = self.lower(val, region, scope);
+                let value = self.emit(region, OpKind::Move, vec![val], expr);
+                let op = self.module.def_op(value).unwrap();
+                self.module.op_mut(op).device = Some(name.clone());
+                value
+            }
+            ExprKind::Block(stmts) => {
+                let mut inner = scope.clone();
+                match self.lower_all(stmts, region, &mut inner).last() {
+                    Some(last) => *last,
+                    None => self.emit(region, OpKind::Const(Literal::Unit), vec![], expr),
+                }
+            }
+            ExprKind::Rule { .. } => unreachable!("the parser only accepts rules at the top level"),
+            ExprKind::Error => unreachable!("can not lower a program which failed to parse"),
+        }
+    }
+
+    fn lower_lambda(
+        &mut self,
+        expr: &Expr,
+        param: &Expr,
+        body: &Expr,
+        region: RegionId,
+        scope: &Scope,
+    ) -> ValueId {
+        let params = match &param.kind {
+            ExprKind::Tuple(items) => items.iter().collect(),
+            _ => vec![param],
+        };
+        let value = self.emit(region, OpKind::Lambda, vec![], expr);
+        let op = self.module.def_op(value).unwrap();
+        let inner = self
+            .module
+            .add_region(op, params.iter().map(|p| p.ty.clone()).collect());
+
+        let mut scope = scope.clone();
+        for (param, value) in params.iter().zip(self.module.region(inner).params.clone()) {
+            if let ExprKind::Identifier { name, .. } = &param.kind {
+                scope.insert(name.clone(), value);
+            }
+        }
+        let result = self.lower(body, inner, &mut scope);
+        self.module.region_mut(inner).results.push(result);
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::{LasmiaoLexer, Lexer};
+    use parser::TokenParser;
+    use parser::traits::Parser;
+    use typeck::TypeChecker;
+
+    fn lower_source(src: &str) -> Module {
+        let tokens = LasmiaoLexer::make_tokens(src).unwrap();
+        let mut program = TokenParser::new(tokens).parse_exprs().unwrap();
+        TypeChecker::new().check_program(&mut program);
+        lower(&program)
+    }
+
+    fn kinds(module: &Module, region: RegionId) -> Vec<&'static str> {
+        module
+            .region(region)
+            .ops
+            .iter()
+            .map(|op| module.op(*op).kind.name())
+            .collect()
+    }
+
+    #[test]
+    fn lowers_the_readme_example() {
+        let src = std::fs::read_to_string("../../examples/readme.lasmiao").unwrap();
+        let module = lower_source(&src);
+        assert_eq!(module.attrs.get("xpuN"), Some(&1024));
+
+        let names: Vec<_> = module.names.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "a",
+                "f",
+                "c",
+                "list_on_cpu",
+                "xpuN",
+                "tensor_on_xpu",
+                "buffer_on_cpu",
+                "buffer_on_xpu",
+                "m3"
+            ]
+        );
+
+        let value = |name: &str| module.names.iter().find(|(n, _)| n == name).unwrap().1;
+        let a = module.value(value("a"));
+        assert_eq!(a.ty, Type::F32);
+
+        let moved = module.def_op(value("tensor_on_xpu")).unwrap();
+        let moved = module.op(moved);
+        assert_eq!(moved.kind, OpKind::Move);
+        assert_eq!(moved.device.as_deref(), Some("xpu"));
+        assert_eq!(moved.operands, vec![value("list_on_cpu")]);
+
+        let buffer = module.def_op(value("buffer_on_xpu")).unwrap();
+        let alloc = module.def_op(module.op(buffer).operands[0]).unwrap();
+        assert_eq!(
+            module.op(alloc).kind,
+            OpKind::Buffer {
+                size: 1024,
+                space: "sram".to_string()
+            }
+        );
+        assert_eq!(module.output(), Some(value("m3")));
+    }
+
+    #[test]
+    fn lambdas_become_regions() {
+        let module = lower_source("xs = [1.,2.]\nxs.map(x => sin(x)+1.)");
+        assert_eq!(
+            kinds(&module, module.body),
+            ["const", "const", "list", "lambda", "map"]
+        );
+        let map = module.op(module.def_op(module.output().unwrap()).unwrap());
+        assert_eq!(map.operands[0], module.names[0].1);
+
+        let lambda = module.op(module.def_op(map.operands[1]).unwrap());
+        assert_eq!(lambda.kind, OpKind::Lambda);
+        let body = lambda.regions[0];
+        assert_eq!(kinds(&module, body), ["symbol", "call", "const", "add"]);
+        let param = module.region(body).params[0];
+        assert_eq!(module.value(param).ty, Type::F64);
+        let call = module.region(body).ops[1];
+        assert_eq!(module.op(call).operands[1], param);
+    }
+
+    #[test]
+    fn blocks_scope_their_bindings() {
+        let module = lower_source("x = 1\ny = { x = 2; x + 1 }\nx");
+        let x = module.names[0].1;
+        assert_eq!(module.output(), Some(x));
+        let sum = module.def_op(module.names[1].1).unwrap();
+        assert_ne!(module.op(sum).operands[0], x);
+    }
+
+    #[test]
+    fn inputs_are_lowered_once() {
+        let module =
+            lower_source("xs: tensor(f32, 4)\nys = xs + xs\nys.map(x => x * sin(1.:f32))\nsin(2.)");
+        let symbols = |name: &str| {
+            module
+                .walk(module.body)
+                .into_iter()
+                .filter(|op| module.op(*op).kind == OpKind::Symbol(name.to_string()))
+                .count()
+        };
+        assert_eq!(symbols("xs"), 1);
+        // sin is a symbol of the lambda and one of another type in the body
+        assert_eq!(symbols("sin"), 2);
+    }
+}
@@ -0,0 +1,416 @@
+use crate::op::OpKind;
+use diagnostics::Span;
+use parser::types::Type;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+macro_rules! id {
+    ($name:ident, $prefix:literal) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(pub u32);
+
+        impl $name {
+            pub fn index(self) -> usize {
+                self.0 as usize
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, concat!($prefix, "{}"), self.0)
+            }
+        }
+    };
+}
+
+id!(ValueId, "%");
+id!(OpId, "op");
+id!(RegionId, "region");
+
+/// Where a value comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueDef {
+    /// Result `index` of an op
+    Result(OpId, usize),
+    /// Parameter `index` of a region
+    Param(RegionId, usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Value {
+    pub ty: Type,
+    pub def: ValueDef,
+}
+
+#[derive(Debug, Clone)]
+pub struct Op {
+    pub kind: OpKind,
+    pub operands: Vec<ValueId>,
+    pub results: Vec<ValueId>,
+    pub regions: Vec<RegionId>,
+    /// Device the op runs on, or for a `Move` the device it copies to.
+    /// `None` until placed
+    pub device: Option<String>,
+    pub span: Option<Span>,
+    /// Region the op is in, `None` once it is erased or before it is inserted
+    pub parent: Option<RegionId>,
+}
+
+/// A list of ops computing `results` from `params`. Every value an op uses
+/// is defined earlier in the same region or in an enclosing one.
+#[derive(Debug, Clone, Default)]
+pub struct Region {
+    pub params: Vec<ValueId>,
+    pub ops: Vec<OpId>,
+    pub results: Vec<ValueId>,
+    /// Op the region belongs to, `None` for the body of the module
+    pub parent: Option<OpId>,
+}
+
+/// A program as a dataflow graph. Ops, values and regions live in arenas
+/// and refer to each other by id; erased ops stay in the arena but are no
+/// longer in any region.
+#[derive(Debug, Clone)]
+pub struct Module {
+    ops: Vec<Op>,
+    values: Vec<Value>,
+    regions: Vec<Region>,
+    /// Top level region, its results are the value of the program
+    pub body: RegionId,
+    /// Constants of `MetaDefine`s such as `xpuN#1024`
+    pub attrs: BTreeMap<String, i64>,
+    /// Top level values bound to a name
+    pub names: Vec<(String, ValueId)>,
+}
+
+impl Default for Module {
+    fn default() -> Self {
+        Module {
+            ops: Vec::new(),
+            values: Vec::new(),
+            regions: vec![Region::default()],
+            body: RegionId(0),
+            attrs: BTreeMap::new(),
+            names: Vec::new(),
+        }
+    }
+}
+
+impl Module {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn op(&self, id: OpId) -> &Op {
+        &self.ops[id.index()]
+    }
+
+    pub fn op_mut(&mut self, id: OpId) -> &mut Op {
+        &mut self.ops[id.index()]
+    }
+
+    pub fn value(&self, id: ValueId) -> &Value {
+        &self.values[id.index()]
+    }
+
+    pub fn value_mut(&mut self, id: ValueId) -> &mut Value {
+        &mut self.values[id.index()]
+    }
+
+    pub fn region(&self, id: RegionId) -> &Region {
+        &self.regions[id.index()]
+    }
+
+    pub fn region_mut(&mut self, id: RegionId) -> &mut Region {
+        &mut self.regions[id.index()]
+    }
+
+    /// Op defining `value`, `None` for region params.
+    pub fn def_op(&self, value: ValueId) -> Option<OpId> {
+        match self.value(value).def {
+            ValueDef::Result(op, _) => Some(op),
+            ValueDef::Param(..) => None,
+        }
+    }
+
+    /// Device computing `value`, that of the lambda for a param.
+    pub fn device_of(&self, value: ValueId) -> Option<&str> {
+        let op = match self.value(value).def {
+            ValueDef::Result(op, _) => op,
+            ValueDef::Param(region, _) => self.region(region).parent?,
+        };
+        self.op(op).device.as_deref()
+    }
+
+    /// Whether `op` does nothing but compute its results from its operands,
+    /// so that it can be removed when they are unused. Moves and buffers
+    /// are effects, and a call has effects unless its callee is a function
+    /// `symbol` or a lambda whose body has none.
+    pub fn is_pure(&self, op: OpId) -> bool {
+        let data = self.op(op);
+        if data.kind != OpKind::Call {
+            return data.kind.is_pure();
+        }
+        self.def_op(data.operands[0]).is_some_and(|f| {
+            let f = self.op(f);
+            match &f.kind {
+                OpKind::Symbol(_) => matches!(self.value(f.results[0]).ty, Type::Function { .. }),
+                OpKind::Lambda => self
+                    .walk(f.regions[0])
+                    .into_iter()
+                    .all(|op| self.is_pure(op)),
+                _ => false,
+            }
+        })
+    }
+
+    /// Create an op which is not in any region yet.
+    pub fn create_op(&mut self, kind: OpKind, operands: Vec<ValueId>, results: Vec<Type>) -> OpId {
+        let id = OpId(self.ops.len() as u32);
+        let results = results
+            .into_iter()
+            .enumerate()
+            .map(|(i, ty)| self.new_value(ty, ValueDef::Result(id, i)))
+            .collect();
+        self.ops.push(Op {
+            kind,
+            operands,
+            results,
+            regions: Vec::new(),
+            device: None,
+            span: None,
+            parent: None,
+        });
+        id
+    }
+
+    fn new_value(&mut self, ty: Type, def: ValueDef) -> ValueId {
+        self.values.push(Value { ty, def });
+        ValueId(self.values.len() as u32 - 1)
+    }
+
+    /// Add a region with params of the given types to `op`.
+    pub fn add_region(&mut self, op: OpId, params: Vec<Type>) -> RegionId {
+        let id = RegionId(self.regions.len() as u32);
+        let params = params
+            .into_iter()
+            .enumerate()
+            .map(|(i, ty)| self.new_value(ty, ValueDef::Param(id, i)))
+            .collect();
+        self.regions.push(Region {
+            params,
+            ops: Vec::new(),
+            results: Vec::new(),
+            parent: Some(op),
+        });
+        self.op_mut(op).regions.push(id);
+        id
+    }
+
+    /// Copy `op` and the ops of its regions into an op which is not in any
+    /// region yet. Operands found in `values` are replaced, and the values
+    /// defined by `op` are mapped to their copies.
+    pub fn copy_op(&mut self, op: OpId, values: &mut HashMap<ValueId, ValueId>) -> OpId {
+        let data = self.op(op).clone();
+        let operands = data
+            .operands
+            .iter()
+            .map(|v| *values.get(v).unwrap_or(v))
+            .collect();
+        let types = data
+            .results
+            .iter()
+            .map(|v| self.value(*v).ty.clone())
+            .collect();
+        let copy = self.create_op(data.kind, operands, types);
+        self.op_mut(copy).device = data.device;
+        self.op_mut(copy).span = data.span;
+        let results = self.op(copy).results.clone();
+        values.extend(data.results.into_iter().zip(results));
+        for region in data.regions {
+            let data = self.region(region).clone();
+            let params = data
+                .params
+                .iter()
+                .map(|v| self.value(*v).ty.clone())
+                .collect();
+            let inner = self.add_region(copy, params);
+            let params = self.region(inner).params.clone();
+            values.extend(data.params.into_iter().zip(params));
+            for op in data.ops {
+                let op = self.copy_op(op, values);
+                self.append_op(inner, op);
+            }
+            self.region_mut(inner).results = data
+                .results
+                .iter()
+                .map(|v| *values.get(v).unwrap_or(v))
+                .collect();
+        }
+        copy
+    }
+
+    pub fn append_op(&mut self, region: RegionId, op: OpId) {
+        self.region_mut(region).ops.push(op);
+        self.op_mut(op).parent = Some(region);
+    }
+
+    /// Insert `op` right before `anchor`, in the region of `anchor`.
+    pub fn insert_op_before(&mut self, anchor: OpId, op: OpId) {
+        let region = self.op(anchor).parent.expect("anchor is not in a region");
+        let index = self.position(anchor);
+        self.region_mut(region).ops.insert(index, op);
+        self.op_mut(op).parent = Some(region);
+    }
+
+    /// Insert `op` right after `anchor`, in the region of `anchor`.
+    pub fn insert_op_after(&mut self, anchor: OpId, op: OpId) {
+        let region = self.op(anchor).parent.expect("anchor is not in a region");
+        let index = self.position(anchor) + 1;
+        self.region_mut(region).ops.insert(index, op);
+        self.op_mut(op).parent = Some(region);
+    }
+
+    /// Create an op with a single result at the end of `region`.
+    pub fn push(
+        &mut self,
+        region: RegionId,
+        kind: OpKind,
+        operands: Vec<ValueId>,
+        ty: Type,
+    ) -> ValueId {
+        let op = self.create_op(kind, operands, vec![ty]);
+        self.append_op(region, op);
+        self.op(op).results[0]
+    }
+
+    /// Index of `op` in its region.
+    pub fn position(&self, op: OpId) -> usize {
+        let region = self.op(op).parent.expect("op is not in a region");
+        self.region(region)
+            .ops
+            .iter()
+            .position(|o| *o == op)
+            .expect("op is missing from its parent region")
+    }
+
+    /// Remove `op` from its region. Its results must be unused.
+    pub fn erase_op(&mut self, op: OpId) {
+        if let Some(region) = self.op(op).parent {
+            let index = self.position(op);
+            self.region_mut(region).ops.remove(index);
+            self.op_mut(op).parent = None;
+        }
+    }
+
+    /// Ops of `region` and of the regions nested in it, each op before
+    /// the ops of its regions.
+    pub fn walk(&self, region: RegionId) -> Vec<OpId> {
+        let mut out = Vec::new();
+        self.walk_into(region, &mut out);
+        out
+    }
+
+    fn walk_into(&self, region: RegionId, out: &mut Vec<OpId>) {
+        for op in &self.region(region).ops {
+            out.push(*op);
+            for inner in &self.op(*op).regions {
+                self.walk_into(*inner, out);
+            }
+        }
+    }
+
+    /// Every op in the module.
+    pub fn ops(&self) -> Vec<OpId> {
+        self.walk(self.body)
+    }
+
+    /// The body of the module and the body of every lambda, outermost
+    /// first.
+    pub fn functions(&self) -> Vec<RegionId> {
+        std::iter::once(self.body)
+            .chain(self.ops().into_iter().flat_map(|op| {
+                let op = self.op(op);
+                match op.kind {
+                    OpKind::Lambda => op.regions.clone(),
+                    _ => Vec::new(),
+                }
+            }))
+            .collect()
+    }
+
+    /// Ops using `value` as an operand.
+    pub fn users(&self, value: ValueId) -> Vec<OpId> {
+        self.ops()
+            .into_iter()
+            .filter(|op| self.op(*op).operands.contains(&value))
+            .collect()
+    }
+
+    /// Whether `value` is used by an op, a region result or a name.
+    pub fn is_used(&self, value: ValueId) -> bool {
+        !self.users(value).is_empty()
+            || self.regions.iter().any(|r| r.results.contains(&value))
+            || self.names.iter().any(|(_, v)| *v == value)
+    }
+
+    /// Replace every use of `from` by `to`.
+    pub fn replace_all_uses(&mut self, from: ValueId, to: ValueId) {
+        let uses = self
+            .ops
+            .iter_mut()
+            .flat_map(|op| op.operands.iter_mut())
+            .chain(self.regions.iter_mut().flat_map(|r| r.results.iter_mut()))
+            .chain(self.names.iter_mut().map(|(_, v)| v));
+        for v in uses {
+            if *v == from {
+                *v = to;
+            }
+        }
+    }
+
+    /// Whether `region` is `ancestor` or nested in it.
+    pub fn is_nested_in(&self, mut region: RegionId, ancestor: RegionId) -> bool {
+        loop {
+            if region == ancestor {
+                return true;
+            }
+            match self.region(region).parent.and_then(|op| self.op(op).parent) {
+                Some(parent) => region = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Value of the program, the last result of the body.
+    pub fn output(&self) -> Option<ValueId> {
+        self.region(self.body).results.last().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::op::{BinOp, Literal};
+
+    #[test]
+    fn builds_and_rewrites_the_graph() {
+        let mut m = Module::new();
+        let body = m.body;
+        let a = m.push(body, OpKind::Const(Literal::Int(1)), vec![], Type::I64);
+        let b = m.push(body, OpKind::Const(Literal::Int(2)), vec![], Type::I64);
+        let sum = m.push(body, OpKind::Binary(BinOp::Add), vec![a, b], Type::I64);
+        m.region_mut(body).results.push(sum);
+        assert_eq!(m.users(a), vec![m.def_op(sum).unwrap()]);
+
+        m.replace_all_uses(a, b);
+        assert!(!m.is_used(a));
+        assert_eq!(m.op(m.def_op(sum).unwrap()).operands, vec![b, b]);
+
+        let dead = m.def_op(a).unwrap();
+        m.erase_op(dead);
+        assert_eq!(m.ops().len(), 2);
+        assert_eq!(m.op(dead).parent, None);
+        assert_eq!(m.output(), Some(sum));
+    }
+}
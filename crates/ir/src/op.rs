@@ -0,0 +1,210 @@
+use lexer::Token;
+use std::fmt;
+
+/// A constant scalar.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Unit,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Literal::Unit => write!(f, "()"),
+            Literal::Int(v) => write!(f, "{}", v),
+            // always with a `.` or an exponent to tell it from an integer
+            Literal::Float(v) => write!(f, "{:?}", v),
+            Literal::Bool(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Element-wise binary operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Xor,
+    LogicAnd,
+    LogicOr,
+}
+
+pub const BIN_OPS: [BinOp; 16] = [
+    BinOp::Add,
+    BinOp::Sub,
+    BinOp::Mul,
+    BinOp::Div,
+    BinOp::Rem,
+    BinOp::Eq,
+    BinOp::Ne,
+    BinOp::Lt,
+    BinOp::Le,
+    BinOp::Gt,
+    BinOp::Ge,
+    BinOp::And,
+    BinOp::Or,
+    BinOp::Xor,
+    BinOp::LogicAnd,
+    BinOp::LogicOr,
+];
+
+impl BinOp {
+    pub fn from_token(token: &Token) -> Option<BinOp> {
+        Some(match token {
+            Token::Plus => BinOp::Add,
+            Token::Minus => BinOp::Sub,
+            Token::Star => BinOp::Mul,
+            Token::Slash => BinOp::Div,
+            Token::Mod => BinOp::Rem,
+            Token::DoubleEqual => BinOp::Eq,
+            Token::NotEqual => BinOp::Ne,
+            Token::LessThan => BinOp::Lt,
+            Token::LessThanEq => BinOp::Le,
+            Token::GreatThan => BinOp::Gt,
+            Token::GreatThanEq => BinOp::Ge,
+            Token::And => BinOp::And,
+            Token::Or => BinOp::Or,
+            Token::Xor => BinOp::Xor,
+            Token::LogicAnd => BinOp::LogicAnd,
+            Token::LogicOr => BinOp::LogicOr,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Rem => "rem",
+            BinOp::Eq => "eq",
+            BinOp::Ne => "ne",
+            BinOp::Lt => "lt",
+            BinOp::Le => "le",
+            BinOp::Gt => "gt",
+            BinOp::Ge => "ge",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::Xor => "xor",
+            BinOp::LogicAnd => "land",
+            BinOp::LogicOr => "lor",
+        }
+    }
+
+    /// Whether `a op b == b op a`.
+    pub fn is_commutative(&self) -> bool {
+        matches!(
+            self,
+            BinOp::Add
+                | BinOp::Mul
+                | BinOp::Eq
+                | BinOp::Ne
+                | BinOp::And
+                | BinOp::Or
+                | BinOp::Xor
+                | BinOp::LogicAnd
+                | BinOp::LogicOr
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+pub const UN_OPS: [UnOp; 2] = [UnOp::Neg, UnOp::Not];
+
+impl UnOp {
+    pub fn from_token(token: &Token) -> Option<UnOp> {
+        match token {
+            Token::Minus => Some(UnOp::Neg),
+            Token::Not => Some(UnOp::Not),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnOp::Neg => "neg",
+            UnOp::Not => "not",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpKind {
+    /// A constant scalar, no operands
+    Const(Literal),
+    /// A value from outside of the program, a builtin such as `sin` or an
+    /// unbound input, no operands
+    Symbol(String),
+    /// Element-wise with broadcasting, two operands
+    Binary(BinOp),
+    /// Element-wise, one operand
+    Unary(UnOp),
+    /// A list or tensor of the operands
+    List,
+    Tuple,
+    /// The element `index` of a tuple operand
+    Extract(usize),
+    /// A function value. Region 0 is the body, its params are the params
+    /// of the function and it may use values of the enclosing regions
+    Lambda,
+    /// Operand 0 applied to the other operands
+    Call,
+    /// The last operand, a function, applied to the elements at each index
+    /// of the other operands, which have the same outer dim
+    Map,
+    /// Operand 0 copied to the device of the op, or tile by tile through
+    /// two buffers of the device, operands 1 and 2, which it alternates
+    /// between so that a tile is copied while the previous one is used
+    Move,
+    /// Memory of `size` bytes in the memory space `space`, no operands
+    Buffer {
+        size: u64,
+        space: String,
+    },
+}
+
+impl OpKind {
+    /// Name of the op in the textual IR.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpKind::Const(_) => "const",
+            OpKind::Symbol(_) => "symbol",
+            OpKind::Binary(op) => op.name(),
+            OpKind::Unary(op) => op.name(),
+            OpKind::List => "list",
+            OpKind::Tuple => "tuple",
+            OpKind::Extract(_) => "extract",
+            OpKind::Lambda => "lambda",
+            OpKind::Call => "call",
+            OpKind::Map => "map",
+            OpKind::Move => "move",
+            OpKind::Buffer { .. } => "buffer",
+        }
+    }
+
+    /// Whether ops of this kind have no effects of their own. A call has
+    /// those of its callee, see `Module::is_pure`.
+    pub fn is_pure(&self) -> bool {
+        !matches!(self, OpKind::Move | OpKind::Buffer { .. })
+    }
+}
@@ -0,0 +1,675 @@
+use crate::module::{Module, OpId, RegionId, ValueId};
+use crate::op::{BIN_OPS, Literal, OpKind, UN_OPS};
+use diagnostics::{Diagnostic, ErrorKind, Span};
+use parser::types::{Dim, DimOp, TensorShapeType, Type};
+use std::collections::HashMap;
+
+/// Parse the textual form printed by `Module`'s `Display`. Values must be
+/// defined before they are used, in the same region or an enclosing one.
+/// `//` starts a comment running to the end of the line.
+pub fn parse(source: &str) -> Result<Module, Diagnostic> {
+    let mut parser = IrParser {
+        tokens: tokenize(source)?,
+        pos: 0,
+        module: Module::new(),
+        scopes: vec![HashMap::new()],
+    };
+    parser.module()?;
+    Ok(parser.module)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    /// `%name`
+    Value(String),
+    Ident(String),
+    /// Digits with an optional fraction and exponent
+    Number(String),
+    /// `'a`, a type variable
+    TypeVar(String),
+    Arrow,
+    Punct(char),
+    Eof,
+}
+
+impl Tok {
+    fn describe(&self) -> String {
+        match self {
+            Tok::Value(name) => format!("`%{}`", name),
+            Tok::Ident(s) | Tok::Number(s) => format!("`{}`", s),
+            Tok::TypeVar(s) => format!("`'{}`", s),
+            Tok::Arrow => "`=>`".to_string(),
+            Tok::Punct(c) => format!("`{}`", c),
+            Tok::Eof => "end of input".to_string(),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Tok, Span)>, Diagnostic> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut tokens = Vec::new();
+    let (mut i, mut line, mut col) = (0, 1, 1);
+    let word = |i: &mut usize| {
+        let start = *i;
+        while *i < chars.len() && (chars[*i].1.is_alphanumeric() || chars[*i].1 == '_') {
+            *i += 1;
+        }
+        chars[start..*i].iter().map(|(_, c)| c).collect::<String>()
+    };
+    while i < chars.len() {
+        let (offset, c) = chars[i];
+        let start = (i, col);
+        let tok = match c {
+            '\n' => {
+                i += 1;
+                line += 1;
+                col = 1;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                i += 1;
+                col += 1;
+                continue;
+            }
+            '/' if chars.get(i + 1).map(|(_, c)| *c) == Some('/') => {
+                while i < chars.len() && chars[i].1 != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '%' | '\'' => {
+                i += 1;
+                let name = word(&mut i);
+                if name.is_empty() {
+                    let span = Span::new(offset, offset + 1, line, col);
+                    return Err(invalid(format!("expect a name after `{}`", c), span));
+                }
+                if c == '%' {
+                    Tok::Value(name)
+                } else {
+                    Tok::TypeVar(name)
+                }
+            }
+            '=' if chars.get(i + 1).map(|(_, c)| *c) == Some('>') => {
+                i += 2;
+                Tok::Arrow
+            }
+            c if c.is_ascii_digit() => {
+                let mut end = i;
+                while end < chars.len() {
+                    let c = chars[end].1;
+                    let exponent_sign = (c == '-' || c == '+')
+                        && matches!(chars[end - 1].1, 'e' | 'E')
+                        && chars[end + 1..]
+                            .first()
+                            .is_some_and(|(_, c)| c.is_ascii_digit());
+                    if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+                        end += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let text = chars[i..end].iter().map(|(_, c)| c).collect();
+                i = end;
+                Tok::Number(text)
+            }
+            c if c.is_alphabetic() || c == '_' => Tok::Ident(word(&mut i)),
+            c if "=:,(){}[]<>@$?-+*/".contains(c) => {
+                i += 1;
+                Tok::Punct(c)
+            }
+            c => {
+                let span = Span::new(offset, offset + c.len_utf8(), line, col);
+                return Err(invalid(format!("unexpected character `{}`", c), span));
+            }
+        };
+        let end = chars.get(i).map_or(source.len(), |(o, _)| *o);
+        col += i - start.0;
+        tokens.push((tok, Span::new(offset, end, line, start.1)));
+    }
+    tokens.push((Tok::Eof, Span::new(source.len(), source.len(), line, col)));
+    Ok(tokens)
+}
+
+fn invalid(message: String, span: Span) -> Diagnostic {
+    Diagnostic::error(ErrorKind::InvalidIr, message, span)
+}
+
+struct IrParser {
+    tokens: Vec<(Tok, Span)>,
+    pos: usize,
+    module: Module,
+    /// Values visible in each enclosing region, innermost last
+    scopes: Vec<HashMap<String, ValueId>>,
+}
+
+impl IrParser {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.pos].0
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.pos].1
+    }
+
+    fn next(&mut self) -> Tok {
+        let tok = self.tokens[self.pos].0.clone();
+        if tok != Tok::Eof {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn error(&self, expected: &str) -> Diagnostic {
+        invalid(
+            format!("expect {}, found {}", expected, self.peek().describe()),
+            self.span(),
+        )
+        .with_label(format!("expect {}", expected))
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = *self.peek() == Tok::Punct(c);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Diagnostic> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("`{}`", c)))
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Tok::Ident(s) if s == keyword)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), Diagnostic> {
+        if self.is_keyword(keyword) {
+            self.next();
+            Ok(())
+        } else {
+            Err(self.error(&format!("`{}`", keyword)))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Diagnostic> {
+        match self.peek().clone() {
+            Tok::Ident(name) => {
+                self.next();
+                Ok(name)
+            }
+            _ => Err(self.error("a name")),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, Diagnostic> {
+        match self.peek().clone() {
+            Tok::Number(text) => {
+                let value = text.parse().map_err(|_| self.error(what))?;
+                self.next();
+                Ok(value)
+            }
+            _ => Err(self.error(what)),
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64, Diagnostic> {
+        let negative = self.eat('-');
+        let value: i64 = self.number("an integer")?;
+        Ok(if negative { -value } else { value })
+    }
+
+    /// Name of a value defined by an op or a region param.
+    fn value_name(&mut self) -> Result<String, Diagnostic> {
+        match self.peek().clone() {
+            Tok::Value(name) => {
+                self.next();
+                Ok(name)
+            }
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn define(&mut self, name: String, value: ValueId, span: Span) -> Result<(), Diagnostic> {
+        let scope = self.scopes.last_mut().unwrap();
+        if scope.insert(name.clone(), value).is_some() {
+            return Err(invalid(format!("`%{}` is defined twice", name), span));
+        }
+        Ok(())
+    }
+
+    /// A use of a value defined earlier.
+    fn value(&mut self) -> Result<ValueId, Diagnostic> {
+        let span = self.span();
+        let name = self.value_name()?;
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name).copied())
+            .ok_or_else(|| {
+                invalid(format!("`%{}` is not defined", name), span)
+                    .with_label("not defined before this use")
+            })
+    }
+
+    fn values(&mut self) -> Result<Vec<ValueId>, Diagnostic> {
+        let mut values = Vec::new();
+        if matches!(self.peek(), Tok::Value(_)) {
+            values.push(self.value()?);
+            while self.eat(',') {
+                values.push(self.value()?);
+            }
+        }
+        Ok(values)
+    }
+
+    fn module(&mut self) -> Result<(), Diagnostic> {
+        self.keyword("module")?;
+        if self.is_keyword("attributes") {
+            self.next();
+            self.expect('{')?;
+            if !self.eat('}') {
+                loop {
+                    let name = self.ident()?;
+                    self.expect('=')?;
+                    let value = self.integer()?;
+                    self.module.attrs.insert(name, value);
+                    if self.eat('}') {
+                        break;
+                    }
+                    self.expect(',')?;
+                }
+            }
+        }
+        self.expect('{')?;
+        let body = self.module.body;
+        self.body(body)?;
+        if *self.peek() != Tok::Eof {
+            return Err(self.error("end of input"));
+        }
+        Ok(())
+    }
+
+    /// Ops of `region` up to and including its `yield` and the closing `}`.
+    fn body(&mut self, region: RegionId) -> Result<(), Diagnostic> {
+        loop {
+            if self.is_keyword("yield") {
+                self.next();
+                let results = self.values()?;
+                self.module.region_mut(region).results = results;
+                return self.expect('}');
+            }
+            if region == self.module.body && self.is_keyword("name") {
+                self.next();
+                let name = self.ident()?;
+                self.expect('=')?;
+                let value = self.value()?;
+                self.module.names.push((name, value));
+                continue;
+            }
+            let op = self.op()?;
+            self.module.append_op(region, op);
+        }
+    }
+
+    fn op(&mut self) -> Result<OpId, Diagnostic> {
+        let mut results = Vec::new();
+        if matches!(self.peek(), Tok::Value(_)) {
+            results.push((self.span(), self.value_name()?));
+            while self.eat(',') {
+                results.push((self.span(), self.value_name()?));
+            }
+            self.expect('=')?;
+        }
+
+        let span = self.span();
+        let name = self.ident()?;
+        let (kind, operands) = self.payload(&name, span)?;
+
+        let mut regions = Vec::new();
+        while self.eat('(') {
+            let mut params = Vec::new();
+            if !self.eat(')') {
+                loop {
+                    let span = self.span();
+                    let name = self.value_name()?;
+                    self.expect(':')?;
+                    params.push((span, name, self.ty()?));
+                    if self.eat(')') {
+                        break;
+                    }
+                    self.expect(',')?;
+                }
+            }
+            regions.push(params);
+        }
+
+        self.expect(':')?;
+        let mut types = vec![self.ty()?];
+        while self.eat(',') {
+            types.push(self.ty()?);
+        }
+        if types.len() != results.len() {
+            return Err(invalid(
+                format!(
+                    "`{}` has {} results but {} types",
+                    name,
+                    results.len(),
+                    types.len()
+                ),
+                span,
+            ));
+        }
+
+        let op = self.module.create_op(kind, operands, types);
+        self.module.op_mut(op).device = if self.eat('@') {
+            Some(self.ident()?)
+        } else {
+            None
+        };
+        for params in regions {
+            self.expect('{')?;
+            let types = params.iter().map(|(_, _, ty)| ty.clone()).collect();
+            let region = self.module.add_region(op, types);
+            self.scopes.push(HashMap::new());
+            let values = self.module.region(region).params.clone();
+            for ((span, name, _), value) in params.into_iter().zip(values) {
+                self.define(name, value, span)?;
+            }
+            self.body(region)?;
+            self.scopes.pop();
+        }
+        for ((span, name), value) in results.into_iter().zip(self.module.op(op).results.clone()) {
+            self.define(name, value, span)?;
+        }
+        Ok(op)
+    }
+
+    /// Kind and operands of the op called `name`.
+    fn payload(&mut self, name: &str, span: Span) -> Result<(OpKind, Vec<ValueId>), Diagnostic> {
+        let kind = match name {
+            "const" => OpKind::Const(self.literal()?),
+            "symbol" => OpKind::Symbol(self.ident()?),
+            "buffer" => {
+                self.expect('$')?;
+                self.expect('(')?;
+                let size = self.number("a size")?;
+                self.expect(',')?;
+                let space = self.ident()?;
+                self.expect(')')?;
+                OpKind::Buffer { size, space }
+            }
+            "extract" => {
+                let tuple = self.value()?;
+                self.expect('[')?;
+                let index = self.number("an index")?;
+                self.expect(']')?;
+                return Ok((OpKind::Extract(index), vec![tuple]));
+            }
+            "list" => OpKind::List,
+            "tuple" => OpKind::Tuple,
+            "lambda" => OpKind::Lambda,
+            "call" => OpKind::Call,
+            "map" => OpKind::Map,
+            "move" => OpKind::Move,
+            _ => {
+                if let Some(op) = BIN_OPS.iter().find(|op| op.name() == name) {
+                    OpKind::Binary(*op)
+                } else if let Some(op) = UN_OPS.iter().find(|op| op.name() == name) {
+                    OpKind::Unary(*op)
+                } else {
+                    return Err(
+                        invalid(format!("unknown op `{}`", name), span).with_label("not an op")
+                    );
+                }
+            }
+        };
+        let operands = match kind {
+            OpKind::Const(_) | OpKind::Symbol(_) | OpKind::Buffer { .. } => Vec::new(),
+            _ => self.values()?,
+        };
+        Ok((kind, operands))
+    }
+
+    fn literal(&mut self) -> Result<Literal, Diagnostic> {
+        if self.eat('(') {
+            self.expect(')')?;
+            return Ok(Literal::Unit);
+        }
+        let negative = self.eat('-');
+        let sign = if negative { -1.0 } else { 1.0 };
+        let literal = match self.peek().clone() {
+            Tok::Ident(s) if s == "true" && !negative => Literal::Bool(true),
+            Tok::Ident(s) if s == "false" && !negative => Literal::Bool(false),
+            Tok::Ident(s) if s == "inf" => Literal::Float(sign * f64::INFINITY),
+            Tok::Ident(s) if s == "NaN" => Literal::Float(f64::NAN),
+            Tok::Number(text) if text.contains(['.', 'e', 'E']) => match text.parse::<f64>() {
+                Ok(v) => Literal::Float(sign * v),
+                Err(_) => return Err(self.error("a number")),
+            },
+            Tok::Number(text) => {
+                match format!("{}{}", if negative { "-" } else { "" }, text).parse::<i64>() {
+                    Ok(v) => Literal::Int(v),
+                    Err(_) => return Err(self.error("an integer")),
+                }
+            }
+            _ => return Err(self.error("a constant")),
+        };
+        self.next();
+        Ok(literal)
+    }
+
+    fn ty(&mut self) -> Result<Type, Diagnostic> {
+        let span = self.span();
+        let ty = match self.next() {
+            Tok::Punct('?') => Type::Unknown,
+            Tok::Punct('(') => {
+                let mut items = Vec::new();
+                if !self.eat(')') {
+                    loop {
+                        items.push(self.ty()?);
+                        if self.eat(')') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                if *self.peek() == Tok::Arrow {
+                    self.next();
+                    let ret = Box::new(self.ty()?);
+                    return Ok(Type::Function { params: items, ret });
+                }
+                Type::Tuple(items)
+            }
+            Tok::TypeVar(name) => {
+                let var = match name.as_bytes() {
+                    [c] if c.is_ascii_lowercase() => Some((c - b'a') as u32),
+                    [b't', digits @ ..] => std::str::from_utf8(digits)
+                        .ok()
+                        .and_then(|d| d.parse().ok()),
+                    _ => None,
+                };
+                let var =
+                    var.ok_or_else(|| invalid(format!("invalid type variable `'{}`", name), span))?;
+                Type::Var(var)
+            }
+            Tok::Ident(name) if name == "List" => {
+                self.expect('<')?;
+                let elem = self.ty()?;
+                self.expect('>')?;
+                Type::List(Box::new(elem))
+            }
+            Tok::Ident(name) if name == "tensor" => {
+                self.expect('(')?;
+                let dtype = Box::new(self.ty()?);
+                self.expect(',')?;
+                let shape = if self.is_keyword("any") {
+                    self.next();
+                    TensorShapeType::Any
+                } else {
+                    let mut dims = Vec::new();
+                    if *self.peek() != Tok::Punct(')') {
+                        dims.push(self.dim()?);
+                        while self.eat(',') {
+                            dims.push(self.dim()?);
+                        }
+                    }
+                    TensorShapeType::Shape(dims)
+                };
+                self.expect(')')?;
+                Type::Tensor { dtype, shape }
+            }
+            Tok::Ident(name) => name.parse().map_err(|e| invalid(e, span))?,
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("a type"));
+            }
+        };
+        Ok(ty)
+    }
+
+    /// Sums of products of dims, as printed by `Dim`'s `Display`.
+    fn dim(&mut self) -> Result<Dim, Diagnostic> {
+        let mut dim = self.dim_term()?;
+        loop {
+            let op = match self.peek() {
+                Tok::Punct('+') => DimOp::Add,
+                Tok::Punct('-') => DimOp::Sub,
+                _ => return Ok(dim),
+            };
+            self.next();
+            dim = Dim::Op(op, Box::new(dim), Box::new(self.dim_term()?));
+        }
+    }
+
+    fn dim_term(&mut self) -> Result<Dim, Diagnostic> {
+        let mut dim = self.dim_atom()?;
+        loop {
+            let op = match self.peek() {
+                Tok::Punct('*') => DimOp::Mul,
+                Tok::Punct('/') => DimOp::Div,
+                _ => return Ok(dim),
+            };
+            self.next();
+            dim = Dim::Op(op, Box::new(dim), Box::new(self.dim_atom()?));
+        }
+    }
+
+    fn dim_atom(&mut self) -> Result<Dim, Diagnostic> {
+        match self.peek().clone() {
+            Tok::Number(_) => Ok(Dim::Const(self.number("a dim")?)),
+            Tok::Ident(name) => {
+                self.next();
+                Ok(Dim::Sym(name))
+            }
+            Tok::Punct('(') => {
+                self.next();
+                let dim = self.dim()?;
+                self.expect(')')?;
+                Ok(dim)
+            }
+            _ => Err(self.error("a dim")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::{LasmiaoLexer, Lexer};
+    use parser::TokenParser;
+    use parser::traits::Parser;
+    use typeck::TypeChecker;
+
+    fn lower_source(src: &str) -> Module {
+        let tokens = LasmiaoLexer::make_tokens(src).unwrap();
+        let mut program = TokenParser::new(tokens).parse_exprs().unwrap();
+        TypeChecker::new().check_program(&mut program);
+        crate::lower(&program)
+    }
+
+    #[test]
+    fn round_trips_the_readme_example() {
+        let src = std::fs::read_to_string("../../examples/readme.lasmiao").unwrap();
+        let text = lower_source(&src).to_string();
+        let module = parse(&text).unwrap_or_else(|d| panic!("{:?}\n{}", d, text));
+        assert_eq!(module.to_string(), text);
+        assert_eq!(module.attrs.get("xpuN"), Some(&1024));
+        assert_eq!(module.names.len(), 9);
+    }
+
+    #[test]
+    fn prints_regions_and_attributes() {
+        let module = lower_source("xs = [1.,2.]\nys = xs.map(x => -x * 2.)@xpu");
+        let expected = "\
+module {
+  %0 = const 1.0 : f64
+  %1 = const 2.0 : f64
+  %2 = list %0, %1 : tensor(f64, 2)
+  %3 = lambda (%4: f64) : (f64) => f64 {
+    %5 = neg %4 : f64
+    %6 = const 2.0 : f64
+    %7 = mul %5, %6 : f64
+    yield %7
+  }
+  %8 = map %2, %3 : tensor(f64, 2)
+  %9 = move %8 : tensor(f64, 2) @xpu
+  name xs = %2
+  name ys = %9
+  yield %9
+}
+";
+        assert_eq!(module.to_string(), expected);
+    }
+
+    #[test]
+    fn parses_handwritten_ir() {
+        let src = "
+            // comments and any value names are accepted
+            module attributes {N = 4} {
+              %a = symbol input : tensor('a, N*2, (N+1)*2)
+              %f = lambda (%x: 't30, %y: ()) : ('t30, ()) => ? {
+                yield %x
+              }
+              %t = tuple %a, %a : (tensor('a, N*2, (N+1)*2), tensor('a, any))
+              %e = extract %t[1] : tensor('a, )
+              %b = buffer $(64, sram) : buffer @cpu
+              %c = const -1.5e-3 : f32
+              yield %e, %b
+            }";
+        let module = parse(src).unwrap();
+        let text = module.to_string();
+        assert!(text.contains("%0 = symbol input : tensor('a, N*2, (N+1)*2)"));
+        assert!(text.contains("%1 = lambda (%2: 't30, %3: ()) : ('t30, ()) => ? {"));
+        assert!(text.contains("%5 = extract %4[1] : tensor('a, )"));
+        assert!(text.contains("%6 = buffer $(64, sram) : buffer @cpu"));
+        assert!(text.contains("%7 = const -0.0015 : f32"));
+        assert_eq!(parse(&text).unwrap().to_string(), text);
+    }
+
+    #[test]
+    fn reports_malformed_ir() {
+        let error = |src: &str| parse(src).unwrap_err().message.clone();
+        assert_eq!(
+            error("module {\n  %0 = add %1, %1 : f64\n  yield %0\n}"),
+            "`%1` is not defined"
+        );
+        assert_eq!(
+            error("module {\n  %0 = frob : f64\n  yield\n}"),
+            "unknown op `frob`"
+        );
+        assert_eq!(
+            error("module {\n  %0 = lambda (%1: f64) : f64 {\n    yield %1\n  }\n  yield %1\n}"),
+            "`%1` is not defined"
+        );
+        assert_eq!(
+            error("module {\n  %0 = const 1 f64\n}"),
+            "expect `:`, found `f64`"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use crate::module::{Module, OpId, RegionId, ValueId};
+use crate::op::OpKind;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+/// Textual form of a module, e.g.
+///
+/// ```text
+/// module attributes {xpuN = 1024} {
+///   %0 = const 1.0 : f64
+///   %1 = lambda (%2: f64) : (f64) => f64 {
+///     %3 = add %2, %0 : f64
+///     yield %3
+///   }
+///   %4 = move %0 : f64 @xpu
+///   name x = %4
+///   yield %4
+/// }
+/// ```
+///
+/// Values are numbered in the order they are printed, so the text of a
+/// module does not depend on how its arenas are laid out. Spans are not
+/// printed.
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer {
+            module: self,
+            numbers: HashMap::new(),
+            out: String::new(),
+        };
+        printer.print()?;
+        f.write_str(&printer.out)
+    }
+}
+
+impl Module {
+    /// Numbers of the values in the text of the module, 3 for `%3`.
+    pub fn value_numbers(&self) -> HashMap<ValueId, usize> {
+        let mut printer = Printer {
+            module: self,
+            numbers: HashMap::new(),
+            out: String::new(),
+        };
+        // writing to a string does not fail
+        let _ = printer.print();
+        printer.numbers
+    }
+}
+
+struct Printer<'a> {
+    module: &'a Module,
+    numbers: HashMap<ValueId, usize>,
+    out: String,
+}
+
+impl Printer<'_> {
+    fn print(&mut self) -> fmt::Result {
+        let module = self.module;
+        write!(self.out, "module")?;
+        if !module.attrs.is_empty() {
+            let attrs: Vec<_> = module
+                .attrs
+                .iter()
+                .map(|(k, v)| format!("{} = {}", k, v))
+                .collect();
+            write!(self.out, " attributes {{{}}}", attrs.join(", "))?;
+        }
+        writeln!(self.out, " {{")?;
+        self.body(module.body, 1)?;
+        writeln!(self.out, "}}")
+    }
+
+    /// Define the name of a new value.
+    fn define(&mut self, value: ValueId) -> String {
+        let n = self.numbers.len();
+        self.numbers.insert(value, n);
+        format!("%{}", n)
+    }
+
+    fn name(&self, value: ValueId) -> String {
+        match self.numbers.get(&value) {
+            Some(n) => format!("%{}", n),
+            // used before its definition, only in a malformed module
+            None => format!("%?{}", value.0),
+        }
+    }
+
+    fn names(&self, values: &[ValueId]) -> String {
+        values
+            .iter()
+            .map(|v| self.name(*v))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn body(&mut self, region: RegionId, depth: usize) -> fmt::Result {
+        let module = self.module;
+        let indent = "  ".repeat(depth);
+        for op in &module.region(region).ops {
+            self.op(*op, depth)?;
+        }
+        if region == module.body {
+            for (name, value) in &module.names {
+                writeln!(self.out, "{}name {} = {}", indent, name, self.name(*value))?;
+            }
+        }
+        let results = self.names(&module.region(region).results);
+        if results.is_empty() {
+            writeln!(self.out, "{}yield", indent)
+        } else {
+            writeln!(self.out, "{}yield {}", indent, results)
+        }
+    }
+
+    fn op(&mut self, id: OpId, depth: usize) -> fmt::Result {
+        let module = self.module;
+        let op = module.op(id);
+        let indent = "  ".repeat(depth);
+        write!(self.out, "{}", indent)?;
+        if !op.results.is_empty() {
+            let results: Vec<_> = op.results.iter().map(|r| self.define(*r)).collect();
+            write!(self.out, "{} = ", results.join(", "))?;
+        }
+        write!(self.out, "{}", op.kind.name())?;
+        match &op.kind {
+            OpKind::Const(literal) => write!(self.out, " {}", literal)?,
+            OpKind::Symbol(name) => write!(self.out, " {}", name)?,
+            OpKind::Buffer { size, space } => write!(self.out, " $({}, {})", size, space)?,
+            OpKind::Extract(index) => write!(self.out, " {}[{}]", self.names(&op.operands), index)?,
+            _ if !op.operands.is_empty() => write!(self.out, " {}", self.names(&op.operands))?,
+            _ => {}
+        }
+        for region in &op.regions {
+            let params: Vec<_> = module
+                .region(*region)
+                .params
+                .iter()
+                .map(|p| format!("{}: {}", self.define(*p), module.value(*p).ty))
+                .collect();
+            write!(self.out, " ({})", params.join(", "))?;
+        }
+        let types: Vec<_> = op
+            .results
+            .iter()
+            .map(|r| module.value(*r).ty.to_string())
+            .collect();
+        write!(self.out, " : {}", types.join(", "))?;
+        if let Some(device) = &op.device {
+            write!(self.out, " @{}", device)?;
+        }
+        for region in &op.regions {
+            writeln!(self.out, " {{")?;
+            self.body(*region, depth + 1)?;
+            write!(self.out, "{}}}", indent)?;
+        }
+        writeln!(self.out)
+    }
+}
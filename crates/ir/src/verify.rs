@@ -0,0 +1,198 @@
+use crate::module::{Module, OpId, RegionId, ValueId};
+use crate::op::OpKind;
+use diagnostics::{Diagnostic, ErrorKind};
+use parser::types::Type;
+use std::collections::HashSet;
+
+/// Check that `module` is well formed: every op is in exactly one region,
+/// values are defined before they are used, in the same region or an
+/// enclosing one, and ops have the operands and regions their kind needs.
+pub fn verify(module: &Module) -> Result<(), Diagnostic> {
+    let mut verifier = Verifier {
+        module,
+        seen: HashSet::new(),
+    };
+    let visible = verifier.region(module.body, None, HashSet::new())?;
+    for (name, value) in &module.names {
+        if !visible.contains(value) {
+            return Err(invalid(
+                format!("`{}` names {} which is not defined", name, value),
+                None,
+                module,
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn invalid(message: String, op: Option<OpId>, module: &Module) -> Diagnostic {
+    let span = op.and_then(|op| module.op(op).span).unwrap_or_default();
+    Diagnostic::error(ErrorKind::InvalidIr, message, span)
+}
+
+struct Verifier<'a> {
+    module: &'a Module,
+    seen: HashSet<OpId>,
+}
+
+impl Verifier<'_> {
+    /// Verify `region` given the values of the enclosing regions, and
+    /// return the values visible at its end.
+    fn region(
+        &mut self,
+        region: RegionId,
+        parent: Option<OpId>,
+        mut visible: HashSet<ValueId>,
+    ) -> Result<HashSet<ValueId>, Diagnostic> {
+        let module = self.module;
+        let data = module.region(region);
+        if data.parent != parent {
+            return Err(invalid(
+                format!("{} is attached to the wrong op", region),
+                parent,
+                module,
+            ));
+        }
+        visible.extend(data.params.iter().copied());
+        for op in &data.ops {
+            let op = *op;
+            if !self.seen.insert(op) {
+                return Err(invalid(
+                    format!("{} is in two places", op),
+                    Some(op),
+                    module,
+                ));
+            }
+            if module.op(op).parent != Some(region) {
+                return Err(invalid(
+                    format!("{} does not know it is in {}", op, region),
+                    Some(op),
+                    module,
+                ));
+            }
+            self.op(op)?;
+            for operand in &module.op(op).operands {
+                if !visible.contains(operand) {
+                    return Err(invalid(
+                        format!(
+                            "`{}` uses {} which is not defined before it",
+                            module.op(op).kind.name(),
+                            operand
+                        ),
+                        Some(op),
+                        module,
+                    ));
+                }
+            }
+            for inner in &module.op(op).regions {
+                self.region(*inner, Some(op), visible.clone())?;
+            }
+            visible.extend(module.op(op).results.iter().copied());
+        }
+        for result in &data.results {
+            if !visible.contains(result) {
+                return Err(invalid(
+                    format!("{} yields {} which is not defined", region, result),
+                    parent,
+                    module,
+                ));
+            }
+        }
+        Ok(visible)
+    }
+
+    /// Check the shape of a single op.
+    fn op(&self, id: OpId) -> Result<(), Diagnostic> {
+        let module = self.module;
+        let op = module.op(id);
+        let name = op.kind.name();
+        let operands = match op.kind {
+            OpKind::Const(_) | OpKind::Symbol(_) | OpKind::Buffer { .. } | OpKind::Lambda => {
+                Some(0)
+            }
+            OpKind::Unary(_) | OpKind::Extract(_) => Some(1),
+            OpKind::Binary(_) => Some(2),
+            OpKind::List | OpKind::Tuple | OpKind::Call | OpKind::Map | OpKind::Move => None,
+        };
+        let error = |message: String| Err(invalid(message, Some(id), module));
+        if let Some(n) = operands
+            && op.operands.len() != n
+        {
+            return error(format!(
+                "`{}` takes {} operands, found {}",
+                name,
+                n,
+                op.operands.len()
+            ));
+        }
+        if op.kind == OpKind::Call && op.operands.is_empty() {
+            return error("`call` needs a callee".to_string());
+        }
+        if op.kind == OpKind::Map && op.operands.len() < 2 {
+            return error("`map` needs an input and a function".to_string());
+        }
+        let buffers = &op.operands[1.min(op.operands.len())..];
+        if op.kind == OpKind::Move
+            && (!matches!(op.operands.len(), 1 | 3)
+                || buffers.iter().any(|v| module.value(*v).ty != Type::Buffer))
+        {
+            return error("`move` takes a value and optionally two buffers".to_string());
+        }
+        let regions = usize::from(op.kind == OpKind::Lambda);
+        if op.regions.len() != regions {
+            return error(format!(
+                "`{}` has {} regions, found {}",
+                name,
+                regions,
+                op.regions.len()
+            ));
+        }
+        if op.kind == OpKind::Lambda && module.region(op.regions[0]).results.len() != 1 {
+            return error("the body of `lambda` must yield one value".to_string());
+        }
+        if op.results.len() != 1 {
+            return error(format!(
+                "`{}` has 1 result, found {}",
+                name,
+                op.results.len()
+            ));
+        }
+        if op.kind == OpKind::Move && op.device.is_none() {
+            return error("`move` has no destination device".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn rejects_uses_before_definitions() {
+        let mut module = parse(
+            "module {
+               %0 = const 1 : i64
+               %1 = neg %0 : i64
+               yield %1
+             }",
+        )
+        .unwrap();
+        assert!(verify(&module).is_ok());
+
+        let body = module.body;
+        module.region_mut(body).ops.swap(0, 1);
+        let error = verify(&module).unwrap_err();
+        assert_eq!(
+            error.message,
+            "`neg` uses %0 which is not defined before it"
+        );
+
+        module.region_mut(body).ops.swap(0, 1);
+        let neg = module.region(body).ops[1];
+        module.op_mut(neg).operands.clear();
+        let error = verify(&module).unwrap_err();
+        assert_eq!(error.message, "`neg` takes 1 operands, found 0");
+    }
+}
@@ -1,51 +1,142 @@
 use crate::token::Token;
 use crate::traits::Lexer;
+use diagnostics::{Diagnostic, ErrorKind, Span, Spanned};
 use std::collections::VecDeque;
+use std::iter::Peekable;
+use std::str::CharIndices;
 
 /// Lexer for PSH (Pre-Established Harmony)
 pub struct LasmiaoLexer;
 
-fn try_match_pair(pair_queue: &mut VecDeque<char>, got: char) -> Result<(), String> {
+/// Char iterator which keeps track of the byte offset and line/column.
+struct Cursor<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    len: usize,
+    line: usize,
+    col: usize,
+}
+
+/// Position of the next char, used as the start of a token.
+#[derive(Clone, Copy)]
+struct Mark {
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(input: &'a str) -> Self {
+        Cursor {
+            chars: input.char_indices().peekable(),
+            len: input.len(),
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek().map(|(_, c)| c)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn pos(&mut self) -> usize {
+        self.chars.peek().map_or(self.len, |(i, _)| *i)
+    }
+
+    fn mark(&mut self) -> Mark {
+        Mark {
+            pos: self.pos(),
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    /// Span from `mark` to the current position.
+    fn span_from(&mut self, mark: Mark) -> Span {
+        Span::new(mark.pos, self.pos(), mark.line, mark.col)
+    }
+}
+
+fn try_match_pair(
+    pair_queue: &mut VecDeque<(char, Span)>,
+    got: char,
+    span: Span,
+) -> Result<(), Diagnostic> {
     match pair_queue.pop_back() {
-        Some(expect) if expect == got => Ok(()),
-        Some(expect) => Err(format!(
-            "Expect {} to match a pair, but got {}",
-            expect, got
-        )),
-        None => Err(format!("Unexpected {}, no matching pair found", got)),
+        Some((expect, _)) if expect == got => Ok(()),
+        Some((expect, open)) => Err(Diagnostic::error(
+            ErrorKind::UnmatchedDelimiter {
+                expected: expect,
+                found: got,
+            },
+            format!("mismatched closing delimiter `{}`", got),
+            span,
+        )
+        .with_label(format!("expected `{}`", expect))
+        .with_secondary(open, "unclosed delimiter")),
+        None => Err(Diagnostic::error(
+            ErrorKind::UnexpectedClosingDelimiter { found: got },
+            format!("unexpected closing delimiter `{}`", got),
+            span,
+        )
+        .with_label("no matching pair found")),
     }
 }
 
 impl Lexer for LasmiaoLexer {
-    fn make_tokens(input: &str) -> Result<Vec<Token>, String> {
-        let mut tokens = Vec::new();
-        let mut chars = input.chars().peekable();
+    fn make_tokens(input: &str) -> Result<Vec<Spanned<Token>>, Diagnostic> {
+        let mut tokens: Vec<Spanned<Token>> = Vec::new();
+        let mut chars = Cursor::new(input);
 
-        let mut pair_queue: VecDeque<char> = VecDeque::new();
+        let mut pair_queue: VecDeque<(char, Span)> = VecDeque::new();
 
         while let Some(&c) = chars.peek() {
-            match c {
-                ' ' | '\t' => {
+            let start = chars.mark();
+            let token = match c {
+                ' ' | '\t' | '\r' => {
                     chars.next();
+                    continue;
                 }
                 '\n' => {
                     chars.next();
 
-                    if pair_queue.is_empty() && tokens.last() != Some(&Token::Semicolon) {
-                        tokens.push(Token::Semicolon);
+                    // a newline ends a statement at top level or directly inside `{}`
+                    let in_statements = match pair_queue.back() {
+                        None => true,
+                        Some((close, _)) => *close == '}',
+                    };
+                    if in_statements
+                        && !matches!(
+                            tokens.last().map(|t| &t.node),
+                            Some(Token::Semicolon) | Some(Token::LBrace)
+                        )
+                    {
+                        Token::Semicolon
+                    } else {
+                        continue;
                     }
                 }
                 '+' => {
                     chars.next();
-                    tokens.push(Token::Plus);
+                    Token::Plus
                 }
                 '-' => {
                     chars.next();
-                    tokens.push(Token::Minus);
+                    Token::Minus
                 }
                 '*' => {
                     chars.next();
-                    tokens.push(Token::Star);
+                    Token::Star
                 }
                 '/' => {
                     chars.next();
@@ -53,63 +144,62 @@ impl Lexer for LasmiaoLexer {
                         Some(&'/') => {
                             // skip comment which start with `//` and end with `\n`
                             chars.next();
-                            loop {
-                                if let Some(&c) = chars.peek() {
-                                    if c == '\n' {
-                                        break;
-                                    }
-                                    chars.next();
-                                } else {
+                            while let Some(&c) = chars.peek() {
+                                if c == '\n' {
                                     break;
                                 }
+                                chars.next();
                             }
+                            continue;
                         }
-                        _ => {
-                            tokens.push(Token::Slash);
-                        }
+                        _ => Token::Slash,
                     }
                 }
+                ';' => {
+                    chars.next();
+                    Token::Semicolon
+                }
                 '%' => {
                     chars.next();
-                    tokens.push(Token::Mod);
+                    Token::Mod
                 }
                 '(' => {
                     chars.next();
-                    tokens.push(Token::LParen);
-                    pair_queue.push_back(')');
+                    pair_queue.push_back((')', chars.span_from(start)));
+                    Token::LParen
                 }
                 ')' => {
                     chars.next();
-                    tokens.push(Token::RParen);
-                    try_match_pair(&mut pair_queue, c)?;
+                    try_match_pair(&mut pair_queue, c, chars.span_from(start))?;
+                    Token::RParen
                 }
                 '[' => {
                     chars.next();
-                    tokens.push(Token::LBracket);
-                    pair_queue.push_back(']');
+                    pair_queue.push_back((']', chars.span_from(start)));
+                    Token::LBracket
                 }
                 ']' => {
                     chars.next();
-                    tokens.push(Token::RBracket);
-                    try_match_pair(&mut pair_queue, c)?;
+                    try_match_pair(&mut pair_queue, c, chars.span_from(start))?;
+                    Token::RBracket
                 }
                 '{' => {
                     chars.next();
-                    tokens.push(Token::LBrace);
-                    pair_queue.push_back('}');
+                    pair_queue.push_back(('}', chars.span_from(start)));
+                    Token::LBrace
                 }
                 '}' => {
                     chars.next();
-                    tokens.push(Token::RBrace);
-                    try_match_pair(&mut pair_queue, c)?;
+                    try_match_pair(&mut pair_queue, c, chars.span_from(start))?;
+                    Token::RBrace
                 }
                 ',' => {
                     chars.next();
-                    tokens.push(Token::Comma);
+                    Token::Comma
                 }
                 '.' => {
                     chars.next();
-                    tokens.push(Token::Dot);
+                    Token::Dot
                 }
                 '=' => {
                     chars.next();
@@ -117,21 +207,19 @@ impl Lexer for LasmiaoLexer {
                         Some(&'=') => {
                             // ==
                             chars.next();
-                            tokens.push(Token::DoubleEqual);
+                            Token::DoubleEqual
                         }
                         Some(&'>') => {
                             // =>
                             chars.next();
-                            tokens.push(Token::FatArrow);
-                        }
-                        _ => {
-                            tokens.push(Token::Equal);
+                            Token::FatArrow
                         }
+                        _ => Token::Equal,
                     }
                 }
                 ':' => {
                     chars.next();
-                    tokens.push(Token::Colon);
+                    Token::Colon
                 }
                 '!' => {
                     chars.next();
@@ -139,11 +227,9 @@ impl Lexer for LasmiaoLexer {
                         Some(&'=') => {
                             // !=
                             chars.next();
-                            tokens.push(Token::NotEqual);
-                        }
-                        _ => {
-                            tokens.push(Token::Not);
+                            Token::NotEqual
                         }
+                        _ => Token::Not,
                     }
                 }
                 '<' => {
@@ -152,11 +238,9 @@ impl Lexer for LasmiaoLexer {
                         Some(&'=') => {
                             // <=
                             chars.next();
-                            tokens.push(Token::LessThanEq);
-                        }
-                        _ => {
-                            tokens.push(Token::LessThan);
+                            Token::LessThanEq
                         }
+                        _ => Token::LessThan,
                     }
                 }
                 '>' => {
@@ -165,11 +249,9 @@ impl Lexer for LasmiaoLexer {
                         Some(&'=') => {
                             // >=
                             chars.next();
-                            tokens.push(Token::GreatThanEq);
-                        }
-                        _ => {
-                            tokens.push(Token::GreatThan);
+                            Token::GreatThanEq
                         }
+                        _ => Token::GreatThan,
                     }
                 }
                 '&' => {
@@ -178,11 +260,9 @@ impl Lexer for LasmiaoLexer {
                         Some(&'&') => {
                             // &&
                             chars.next();
-                            tokens.push(Token::LogicAnd);
-                        }
-                        _ => {
-                            tokens.push(Token::And);
+                            Token::LogicAnd
                         }
+                        _ => Token::And,
                     }
                 }
                 '|' => {
@@ -191,97 +271,171 @@ impl Lexer for LasmiaoLexer {
                         Some(&'|') => {
                             // ||
                             chars.next();
-                            tokens.push(Token::LogicOr);
-                        }
-                        _ => {
-                            tokens.push(Token::Or);
+                            Token::LogicOr
                         }
+                        _ => Token::Or,
                     }
                 }
                 '^' => {
                     chars.next();
-                    tokens.push(Token::Xor);
+                    Token::Xor
                 }
                 '@' => {
                     chars.next();
-                    tokens.push(Token::At);
+                    Token::At
                 }
                 '$' => {
                     chars.next();
-                    tokens.push(Token::Cache);
+                    Token::Cache
                 }
                 '#' => {
                     chars.next();
-                    tokens.push(Token::Hash);
+                    Token::Hash
                 }
                 '0'..='9' => {
                     chars.next();
                     let mut num: u64 = c.to_digit(10).unwrap() as u64;
                     let mut num_f64 = 0_f64;
                     let mut after_dot = 0;
-                    loop {
-                        if let Some(&c2) = chars.peek() {
-                            if c2.is_ascii_digit() {
-                                chars.next();
-                                if after_dot == 0 {
-                                    num = num * 10 + c2.to_digit(10).unwrap() as u64;
+                    while let Some(&c2) = chars.peek() {
+                        if c2.is_ascii_digit() {
+                            chars.next();
+                            if after_dot == 0 {
+                                num = num * 10 + c2.to_digit(10).unwrap() as u64;
+                            } else {
+                                num_f64 +=
+                                    c2.to_digit(10).unwrap() as f64 * 10_f64.powi(-after_dot);
+                                after_dot += 1;
+                            }
+                        } else if c2 == '.' {
+                            chars.next();
+                            after_dot = 1;
+                            num_f64 = num as f64;
+                        } else if c == '0' && c2 == 'x' {
+                            // hex
+                            chars.next();
+                            while let Some(&c3) = chars.peek() {
+                                if c3.is_ascii_hexdigit() {
+                                    chars.next();
+                                    num = num * 16 + u64::from(c3.to_digit(16).unwrap());
                                 } else {
-                                    num_f64 +=
-                                        c2.to_digit(10).unwrap() as f64 * 10_f64.powi(-after_dot);
-                                    after_dot += 1;
-                                }
-                            } else if c2 == '.' {
-                                chars.next();
-                                after_dot = 1;
-                                num_f64 = num as f64;
-                            } else if c == '0' && c2 == 'x' {
-                                // hex
-                                chars.next();
-                                while let Some(&c3) = chars.peek() {
-                                    if c3.is_ascii_hexdigit() {
-                                        chars.next();
-                                        num = num * 16
-                                            + u64::try_from(c3.to_digit(16).unwrap()).unwrap();
-                                    } else {
-                                        break;
-                                    }
+                                    break;
                                 }
-                            } else {
-                                break;
                             }
+                        } else {
+                            break;
                         }
                     }
                     if after_dot == 0 {
-                        tokens.push(Token::U64(num))
+                        Token::U64(num)
                     } else {
-                        tokens.push(Token::F64(num_f64))
+                        Token::F64(num_f64)
                     }
                 }
                 _ => {
                     let mut symbol = String::new();
                     chars.next();
                     symbol.push(c);
-                    loop {
-                        if let Some(&c2) = chars.peek() {
-                            match c2 {
-                                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => {
-                                    chars.next();
-                                    symbol.push(c2);
-                                }
-                                _ => break,
+                    while let Some(&c2) = chars.peek() {
+                        match c2 {
+                            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => {
+                                chars.next();
+                                symbol.push(c2);
                             }
+                            _ => break,
                         }
                     }
-                    tokens.push(Token::Symbol(symbol))
+                    Token::Symbol(symbol)
                 }
-            }
+            };
+            tokens.push(Spanned::new(token, chars.span_from(start)));
         }
-        if !pair_queue.is_empty() {
-            return Err(format!("{:?} remain to unmatch!", pair_queue));
+        if let Some((expect, open)) = pair_queue.back() {
+            let end = chars.mark();
+            return Err(Diagnostic::error(
+                ErrorKind::UnclosedDelimiter { delimiter: *expect },
+                "this file contains an unclosed delimiter",
+                chars.span_from(end),
+            )
+            .with_label(format!("expected `{}`", expect))
+            .with_secondary(*open, "unclosed delimiter"));
         }
-        if tokens.last() == Some(&Token::Semicolon) {
+        if tokens.last().map(|t| &t.node) == Some(&Token::Semicolon) {
             tokens.pop();
         }
         Ok(tokens)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_carry_spans() {
+        let tokens = LasmiaoLexer::make_tokens("a = 1\nbc+2.5").unwrap();
+        let spans: Vec<(usize, usize, usize, usize)> = tokens
+            .iter()
+            .map(|t| (t.span.start, t.span.end, t.span.line, t.span.col))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (0, 1, 1, 1),
+                (2, 3, 1, 3),
+                (4, 5, 1, 5),
+                (5, 6, 1, 6),
+                (6, 8, 2, 1),
+                (8, 9, 2, 3),
+                (9, 12, 2, 4),
+            ]
+        );
+        assert_eq!(tokens[3].node, Token::Semicolon);
+        assert_eq!(tokens[6].node, Token::F64(2.5));
+    }
+
+    #[test]
+    fn unmatched_pair_reports_position() {
+        let err = LasmiaoLexer::make_tokens("f(\n  [1, 2)").unwrap_err();
+        assert_eq!(
+            err.kind,
+            ErrorKind::UnmatchedDelimiter {
+                expected: ']',
+                found: ')'
+            }
+        );
+        assert_eq!((err.span().line, err.span().col), (2, 8));
+        assert_eq!(err.secondary[0].span.col, 3);
+    }
+
+    #[test]
+    fn input_without_trailing_newline() {
+        let tokens = LasmiaoLexer::make_tokens("abc").unwrap();
+        assert_eq!(tokens[0].node, Token::Symbol("abc".to_string()));
+    }
+
+    #[test]
+    fn newlines_end_statements_inside_braces_only() {
+        let tokens = LasmiaoLexer::make_tokens("{\n  a = (1,\n 2)\n  a; b\n}\n").unwrap();
+        let nodes: Vec<Token> = tokens.into_iter().map(|t| t.node).collect();
+        assert_eq!(
+            nodes,
+            vec![
+                Token::LBrace,
+                Token::Symbol("a".to_string()),
+                Token::Equal,
+                Token::LParen,
+                Token::U64(1),
+                Token::Comma,
+                Token::U64(2),
+                Token::RParen,
+                Token::Semicolon,
+                Token::Symbol("a".to_string()),
+                Token::Semicolon,
+                Token::Symbol("b".to_string()),
+                Token::Semicolon,
+                Token::RBrace,
+            ]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 pub mod lan;
 pub mod token;
 pub mod traits;
+pub use diagnostics::{Span, Spanned};
 pub use token::Token;
 pub use traits::Lexer;
 
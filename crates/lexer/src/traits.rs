@@ -1,5 +1,6 @@
 use crate::token::Token;
+use diagnostics::{Diagnostic, Spanned};
 
 pub trait Lexer {
-    fn make_tokens(input: &str) -> Result<Vec<Token>, String>;
+    fn make_tokens(input: &str) -> Result<Vec<Spanned<Token>>, Diagnostic>;
 }
@@ -1,9 +1,18 @@
-use crate::types::Type;
-use lexer::Token;
+use crate::types::{Dim, DimOp, Type};
+use lexer::{Span, Token};
 use std::fmt;
 
-#[derive(Debug)]
-pub enum Expr {
+/// A node of the AST together with the source region it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    /// Inferred type, `Unknown` until the type checker has run
+    pub ty: Type,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
     // Type
     Unit,
     Float {
@@ -69,6 +78,17 @@ pub enum Expr {
     },
 
     Block(Vec<Expr>),
+
+    /// `rule <name>: <lhs> => <rhs>`, a rewrite of the IR declared at the
+    /// top level of a program
+    Rule {
+        name: String,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+
+    /// Placeholder for a part of the source which failed to parse
+    Error,
 }
 
 impl fmt::Display for Expr {
@@ -78,6 +98,73 @@ impl fmt::Display for Expr {
 }
 
 impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr {
+            kind,
+            span,
+            ty: Type::Unknown,
+        }
+    }
+
+    /// The tensor dim written by this expression, e.g. `3`, `N` or `N*2`.
+    pub fn to_dim(&self) -> Option<Dim> {
+        match &self.kind {
+            ExprKind::Integer { val, .. } => Some(Dim::Const(*val)),
+            ExprKind::Identifier { name, typ } if *typ == Type::Unknown => {
+                Some(Dim::Sym(name.clone()))
+            }
+            ExprKind::Binary { left, op, right } => {
+                let op = match op {
+                    Token::Plus => DimOp::Add,
+                    Token::Minus => DimOp::Sub,
+                    Token::Star => DimOp::Mul,
+                    Token::Slash => DimOp::Div,
+                    _ => return None,
+                };
+                Some(Dim::Op(
+                    op,
+                    Box::new(left.to_dim()?),
+                    Box::new(right.to_dim()?),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Sub-expressions in source order. The device of a `Move` is a name
+    /// rather than a value, so it is not among them.
+    pub fn children(&self) -> Vec<&Expr> {
+        match &self.kind {
+            ExprKind::List(items) | ExprKind::Tuple(items) | ExprKind::Block(items) => {
+                items.iter().collect()
+            }
+            ExprKind::Assign { name, val } => vec![name, val],
+            ExprKind::MetaDefine { val, .. } | ExprKind::Move { val, .. } => vec![val],
+            ExprKind::Unary { arg, .. } => vec![arg],
+            ExprKind::Binary { left, right, .. } => vec![left, right],
+            ExprKind::Call { callee, args } => vec![callee, args],
+            ExprKind::Lambda { param, body } => vec![param, body],
+            ExprKind::Rule { lhs, rhs, .. } => vec![lhs, rhs],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match &mut self.kind {
+            ExprKind::List(items) | ExprKind::Tuple(items) | ExprKind::Block(items) => {
+                items.iter_mut().collect()
+            }
+            ExprKind::Assign { name, val } => vec![name, val],
+            ExprKind::MetaDefine { val, .. } | ExprKind::Move { val, .. } => vec![val],
+            ExprKind::Unary { arg, .. } => vec![arg],
+            ExprKind::Binary { left, right, .. } => vec![left, right],
+            ExprKind::Call { callee, args } => vec![callee, args],
+            ExprKind::Lambda { param, body } => vec![param, body],
+            ExprKind::Rule { lhs, rhs, .. } => vec![lhs, rhs],
+            _ => Vec::new(),
+        }
+    }
+
     fn format_as_tree(
         &self,
         f: &mut fmt::Formatter<'_>,
@@ -89,90 +176,106 @@ impl Expr {
 
         let new_prefix = format!("{}{}", prefix, if is_last { "    " } else { "│   " });
 
-        match self {
-            Expr::Float { val, typ } => writeln!(f, "Num({}:{})", val, typ)?,
-            Expr::Integer { val, typ } => writeln!(f, "Num({}:{})", val, typ)?,
-            Expr::Unit => writeln!(f, "Unit")?,
-            Expr::Identifier { name, typ } => writeln!(f, "{}:{}", name, typ)?,
-            Expr::Unary { op, .. } => writeln!(f, "Unary({})", op)?,
-            Expr::Binary { op, .. } => writeln!(f, "Binary({})", op)?,
-            Expr::Call { callee, .. } => {
-                if let Expr::Identifier { name, typ } = &**callee {
-                    writeln!(f, "Call({}:{})", name, typ)?;
+        match &self.kind {
+            ExprKind::Float { val, typ } => write!(f, "Num({}:{})", val, typ)?,
+            ExprKind::Integer { val, typ } => write!(f, "Num({}:{})", val, typ)?,
+            ExprKind::Unit => write!(f, "Unit")?,
+            ExprKind::Error => write!(f, "Error")?,
+            ExprKind::Block(_) => write!(f, "Block")?,
+            ExprKind::Identifier { name, typ } => write!(f, "{}:{}", name, typ)?,
+            ExprKind::Unary { op, .. } => write!(f, "Unary({})", op)?,
+            ExprKind::Binary { op, .. } => write!(f, "Binary({})", op)?,
+            ExprKind::Call { callee, .. } => {
+                if let ExprKind::Identifier { name, typ } = &callee.kind {
+                    write!(f, "Call({}:{})", name, typ)?;
                 } else {
-                    writeln!(f, "Call")?;
-                    callee.format_as_tree(f, &new_prefix, false)?;
+                    write!(f, "Call")?;
                 }
             }
-            Expr::List(_) => writeln!(f, "List")?,
-            Expr::Tuple(_) => writeln!(f, "Tuple")?,
-            Expr::Buffer { size, anno } => writeln!(f, "Buffer({}):{}", size, anno)?,
-            Expr::Assign { name, .. } => {
-                if let Expr::Identifier { name, typ } = &**name {
-                    writeln!(f, "Assign({}:{})", name, typ)?
+            ExprKind::List(_) => write!(f, "List")?,
+            ExprKind::Tuple(_) => write!(f, "Tuple")?,
+            ExprKind::Buffer { size, anno } => write!(f, "Buffer({}):{}", size, anno)?,
+            ExprKind::Assign { name, .. } => {
+                if let ExprKind::Identifier { name, typ } = &name.kind {
+                    write!(f, "Assign({}:{})", name, typ)?
                 } else {
-                    panic!("name in Expr::Let must be an Expr::Identifier")
+                    panic!("name in ExprKind::Let must be an ExprKind::Identifier")
                 }
             }
-            Expr::MetaDefine { name, .. } => writeln!(f, "MetaDefine({})", name)?,
-            Expr::Lambda { param, .. } => {
-                if let Expr::Identifier { name, typ } = &**param {
-                    writeln!(f, "Lambda({}:{})", name, typ)?
-                } else if let Expr::Tuple(items) = &**param {
+            ExprKind::MetaDefine { name, .. } => write!(f, "MetaDefine({})", name)?,
+            ExprKind::Rule { name, .. } => write!(f, "Rule({})", name)?,
+            ExprKind::Lambda { param, .. } => {
+                if let ExprKind::Identifier { name, typ } = &param.kind {
+                    write!(f, "Lambda({}:{})", name, typ)?
+                } else if let ExprKind::Tuple(items) = &param.kind {
                     let param_names: Vec<String> = items
                         .iter()
                         .map(|item| {
-                            if let Expr::Identifier { name, typ } = item {
+                            if let ExprKind::Identifier { name, typ } = &item.kind {
                                 format!("{}:{}", name, typ)
                             } else {
                                 "???".to_string()
                             }
                         })
                         .collect();
-                    writeln!(f, "Lambda({})", param_names.join(", "))?
+                    write!(f, "Lambda({})", param_names.join(", "))?
                 } else {
-                    panic!("param in Expr::Lambda must be an Expr::Identifier or Expr::Tuple")
+                    panic!(
+                        "param in ExprKind::Lambda must be an ExprKind::Identifier or ExprKind::Tuple"
+                    )
                 }
             }
-            Expr::Move { device, .. } => {
-                if let Expr::Identifier { name, .. } = &**device {
-                    writeln!(f, "Move@{}", name)?
+            ExprKind::Move { device, .. } => {
+                if let ExprKind::Identifier { name, .. } = &device.kind {
+                    write!(f, "Move@{}", name)?
                 } else {
-                    panic!("device in Expr::Move must be an Expr::Identifier")
+                    panic!("device in ExprKind::Move must be an ExprKind::Identifier")
                 }
             }
-            _ => panic!("Do not known how to print {}", self),
+        }
+        if self.ty != Type::Unknown {
+            write!(f, " : {}", self.ty)?;
+        }
+        writeln!(f)?;
+        if let ExprKind::Call { callee, .. } = &self.kind
+            && !matches!(callee.kind, ExprKind::Identifier { .. })
+        {
+            callee.format_as_tree(f, &new_prefix, false)?;
         }
 
-        match self {
-            Expr::Unary { arg, .. } => {
+        match &self.kind {
+            ExprKind::Unary { arg, .. } => {
                 arg.format_as_tree(f, &new_prefix, true)?;
             }
-            Expr::Binary { left, right, .. } => {
+            ExprKind::Binary { left, right, .. } => {
                 left.format_as_tree(f, &new_prefix, false)?;
                 right.format_as_tree(f, &new_prefix, true)?;
             }
-            Expr::Call { args, .. } => {
+            ExprKind::Call { args, .. } => {
                 args.format_as_tree(f, &new_prefix, true)?;
             }
-            Expr::Tuple(items) | Expr::List(items) => {
+            ExprKind::Tuple(items) | ExprKind::List(items) | ExprKind::Block(items) => {
                 for (i, item) in items.iter().enumerate() {
                     let last_child = i == items.len() - 1;
                     item.format_as_tree(f, &new_prefix, last_child)?;
                 }
             }
-            Expr::Assign { val, .. } => {
+            ExprKind::Assign { val, .. } => {
                 val.format_as_tree(f, &new_prefix, true)?;
             }
-            Expr::MetaDefine { val, .. } => {
+            ExprKind::MetaDefine { val, .. } => {
                 val.format_as_tree(f, &new_prefix, true)?;
             }
-            Expr::Lambda { body, .. } => {
+            ExprKind::Lambda { body, .. } => {
                 body.format_as_tree(f, &new_prefix, true)?;
             }
-            Expr::Move { val, .. } => {
+            ExprKind::Move { val, .. } => {
                 val.format_as_tree(f, &new_prefix, true)?;
             }
+            ExprKind::Rule { lhs, rhs, .. } => {
+                lhs.format_as_tree(f, &new_prefix, false)?;
+                rhs.format_as_tree(f, &new_prefix, true)?;
+            }
             _ => {}
         }
         Ok(())
@@ -1,26 +1,229 @@
-use crate::expr::Expr;
+use crate::expr::{Expr, ExprKind};
 use crate::traits::Parser;
-use crate::types::{TensorShapeType, Type};
-use lexer::Token;
+use crate::types::{Dim, TensorShapeType, Type};
+use diagnostics::{Diagnostic, ErrorKind};
+use lexer::{Span, Spanned, Token};
 
 pub struct TokenParser {
-    tokens: Vec<Token>,
+    tokens: Vec<Spanned<Token>>,
     pos: usize,
+    /// Span of the most recently consumed token
+    prev_span: Span,
+    /// Errors which have been recovered from
+    errors: Vec<Diagnostic>,
 }
 
 impl TokenParser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        TokenParser { tokens, pos: 0 }
+    pub fn new(tokens: Vec<Spanned<Token>>) -> Self {
+        TokenParser {
+            tokens,
+            pos: 0,
+            prev_span: Span::default(),
+            errors: Vec::new(),
+        }
     }
 
     fn current(&self) -> Option<&Token> {
-        self.tokens.get(self.pos)
+        self.tokens.get(self.pos).map(|t| &t.node)
     }
 
-    fn advance(&mut self) -> Token {
-        let t = self.tokens[self.pos].clone();
+    /// Span of the current token, or an empty span right after the last
+    /// token when the input is exhausted.
+    fn current_span(&self) -> Span {
+        match self.tokens.get(self.pos) {
+            Some(t) => t.span,
+            None => match self.tokens.last() {
+                Some(t) => Span::new(
+                    t.span.end,
+                    t.span.end,
+                    t.span.line,
+                    t.span.col + t.span.len(),
+                ),
+                None => Span::new(0, 0, 1, 1),
+            },
+        }
+    }
+
+    fn advance(&mut self) -> Result<Token, Diagnostic> {
+        let Some(t) = self.tokens.get(self.pos) else {
+            return Err(Diagnostic::error(
+                ErrorKind::UnexpectedEof,
+                "unexpected end of input",
+                self.current_span(),
+            )
+            .with_label("expected more tokens"));
+        };
+        let t = t.clone();
         self.pos += 1;
-        t
+        self.prev_span = t.span;
+        Ok(t.node)
+    }
+
+    /// Span from `start` to the end of the most recently consumed token.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.prev_span)
+    }
+
+    /// Skip tokens up to and including the closing delimiter of the pair
+    /// we are currently inside.
+    fn recover_to_close(&mut self) {
+        let mut depth = 0;
+        while let Some(token) = self.current() {
+            let token = token.clone();
+            self.pos += 1;
+            self.prev_span = self.tokens[self.pos - 1].span;
+            match token {
+                Token::LParen | Token::LBracket | Token::LBrace => depth += 1,
+                Token::RParen | Token::RBracket | Token::RBrace => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Skip tokens up to the next `;` outside of any pair, or up to `close`
+    /// which ends the enclosing block. The `;` or `close` is left for the
+    /// caller.
+    fn recover_to_statement_end(&mut self, close: Option<&Token>) {
+        let mut depth: usize = 0;
+        while let Some(token) = self.current() {
+            if depth == 0 && (*token == Token::Semicolon || Some(token) == close) {
+                return;
+            }
+            match token {
+                Token::LParen | Token::LBracket | Token::LBrace => depth += 1,
+                Token::RParen | Token::RBracket | Token::RBrace => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            self.prev_span = self.tokens[self.pos].span;
+            self.pos += 1;
+        }
+    }
+
+    /// Parse one statement, on error the rest of the statement is skipped
+    /// and an `Expr::Error` is returned in its place.
+    fn parse_statement(&mut self, close: Option<&Token>) -> Expr {
+        let start = self.current_span();
+        let result = if self.at_rule() {
+            self.parse_rule(close)
+        } else {
+            self.parse_expression(0)
+        };
+        match result {
+            Ok(expr) => {
+                if let Some(token) = self.current()
+                    && *token != Token::Semicolon
+                    && Some(token) != close
+                {
+                    self.errors.push(
+                        Diagnostic::error(
+                            ErrorKind::TrailingTokens,
+                            format!("expect the end of statement, but got `{}`", token),
+                            self.current_span(),
+                        )
+                        .with_label("expected a newline or `;`")
+                        .with_secondary(expr.span, "statement"),
+                    );
+                    self.recover_to_statement_end(close);
+                }
+                expr
+            }
+            Err(e) => {
+                self.errors.push(e);
+                self.recover_to_statement_end(close);
+                Expr::new(ExprKind::Error, start.to(self.prev_span))
+            }
+        }
+    }
+
+    /// Whether the next tokens start a rule, `rule <name>:`. `rule` is
+    /// otherwise an ordinary name.
+    fn at_rule(&self) -> bool {
+        matches!(
+            self.tokens.get(self.pos..self.pos + 3),
+            Some([keyword, name, colon])
+                if keyword.node == Token::Symbol("rule".to_string())
+                    && matches!(name.node, Token::Symbol(_))
+                    && colon.node == Token::Colon
+        )
+    }
+
+    /// Parse `rule <name>: <lhs> => <rhs>`. The `=>` of the rule binds
+    /// looser than any in `lhs`, and `rhs` may itself be a lambda.
+    fn parse_rule(&mut self, close: Option<&Token>) -> Result<Expr, Diagnostic> {
+        let start = self.current_span();
+        self.advance()?;
+        let Token::Symbol(name) = self.advance()? else {
+            unreachable!("`at_rule` checked the name")
+        };
+        let name_span = self.prev_span;
+        self.advance()?;
+        if close.is_some() {
+            return Err(Diagnostic::error(
+                ErrorKind::InvalidRule,
+                format!("rule `{}` is not at the top level", name),
+                name_span,
+            )
+            .with_label("declared inside a block")
+            .with_help("move the rule out of the block, rules apply to the whole program"));
+        }
+        let lhs = self.parse_expression(self.get_binding_power(&Token::FatArrow))?;
+        if self.current() != Some(&Token::FatArrow) {
+            return Err(Diagnostic::error(
+                ErrorKind::InvalidRule,
+                format!("expect `=>` after the pattern of rule `{}`", name),
+                self.current_span(),
+            )
+            .with_label("expected `=>`")
+            .with_secondary(lhs.span, "pattern"));
+        }
+        self.advance()?;
+        let rhs = self.parse_expression(self.get_binding_power(&Token::Comma))?;
+        Ok(Expr::new(
+            ExprKind::Rule {
+                name,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// Parse `;` separated statements up to `close` (not consumed) or the
+    /// end of input.
+    fn parse_statements(&mut self, close: Option<&Token>) -> Vec<Expr> {
+        let mut stmts = Vec::new();
+        while let Some(token) = self.current() {
+            if Some(token) == close {
+                break;
+            }
+            if *token == Token::Semicolon {
+                self.pos += 1;
+                continue;
+            }
+            let pos = self.pos;
+            stmts.push(self.parse_statement(close));
+            if self.pos == pos {
+                // a stray token which no statement can start with
+                self.pos += 1;
+            }
+        }
+        stmts
+    }
+
+    /// Parse a whole program into an `Expr::Block`.
+    fn parse_program(&mut self) -> Expr {
+        let start = self.current_span();
+        let stmts = self.parse_statements(None);
+        let span = match stmts.last() {
+            Some(last) => start.to(last.span),
+            None => start,
+        };
+        Expr::new(ExprKind::Block(stmts), span)
     }
 
     fn get_binding_power(&self, token: &Token) -> u8 {
@@ -44,48 +247,76 @@ impl TokenParser {
         }
     }
 
-    fn parse_type_annotation(&mut self) -> Result<Type, String> {
-        let token_after_colon = self.advance();
+    fn parse_type_annotation(&mut self) -> Result<Type, Diagnostic> {
+        let token_after_colon = self.advance()?;
+        let start = self.prev_span;
         if let Token::Symbol(annotation) = token_after_colon {
             match annotation.as_str() {
                 "tensor" => {
-                    if self.advance() == Token::LParen {
+                    if self.advance()? == Token::LParen {
                         let right_expr = self.parse_sub_and_check_pair(Token::RParen)?;
-                        let Expr::Tuple(args) = right_expr else {
-                            return Err(format!(
-                                "Expect an Expr::Tuple between `(` and `)` for a tensor type annotation, but got {}",
-                                right_expr
-                            ));
+                        if let ExprKind::Error = right_expr.kind {
+                            return Ok(Type::Unknown);
+                        }
+                        let ExprKind::Tuple(args) = right_expr.kind else {
+                            return Err(Diagnostic::error(
+                                ErrorKind::BadTypeAnnotation,
+                                "expect the dtype and dims of a tensor type annotation",
+                                right_expr.span,
+                            )
+                            .with_label("expect a tuple here")
+                            .with_help("write a tensor type as `tensor(<dtype>, <dim>, ...)`"));
                         };
 
-                        let mut shape: Vec<u64> = Vec::new();
+                        let mut shape: Vec<Dim> = Vec::new();
                         let mut typ = Type::Unknown;
 
                         for arg in args {
-                            match arg {
-                                Expr::Integer { val, .. } => shape.push(val),
-                                Expr::Identifier { name, .. } => {
-                                    let t = Type::from_str(name.as_str());
-                                    if typ != Type::Unknown {
-                                        if t == Type::Any {
-                                            break;
+                            match &arg.kind {
+                                // the first name is the dtype
+                                ExprKind::Identifier { name, .. } if typ == Type::Unknown => {
+                                    typ = name.parse().map_err(|e| {
+                                        Diagnostic::error(ErrorKind::BadTypeAnnotation, e, arg.span)
+                                    })?;
+                                }
+                                ExprKind::Identifier { name, .. } => {
+                                    let t: Type = name.parse().map_err(|e| {
+                                        Diagnostic::error(ErrorKind::BadTypeAnnotation, e, arg.span)
+                                    })?;
+                                    match t {
+                                        Type::Any => break,
+                                        // any other name is a symbolic dim
+                                        Type::Ext(name) => shape.push(Dim::Sym(name)),
+                                        t => {
+                                            return Err(Diagnostic::error(
+                                                ErrorKind::BadTypeAnnotation,
+                                                format!(
+                                                    "expect single type for a tensor type annotation, but got two types: {} and {}",
+                                                    typ, t
+                                                ),
+                                                arg.span,
+                                            )
+                                            .with_label("second dtype"));
                                         }
-                                        return Err(format!(
-                                            "Expect single type for a tensor type annotation, but got two types: {} and {}",
-                                            typ, t
-                                        ));
                                     }
-                                    typ = t;
-                                }
-                                _ => {
-                                    return Err(format!(
-                                        "Expect an Expr::Integer or Type for a tensor type annotation, but got {}",
-                                        arg
-                                    ));
                                 }
+                                _ => match arg.to_dim() {
+                                    Some(dim) => shape.push(dim),
+                                    None => {
+                                        return Err(Diagnostic::error(
+                                            ErrorKind::BadTypeAnnotation,
+                                            "expect a dim or a dtype for a tensor type annotation",
+                                            arg.span,
+                                        )
+                                        .with_label("neither a dim nor a dtype")
+                                        .with_help(
+                                            "a dim is an integer, a name or `+ - * /` of them, e.g. `N*2`",
+                                        ));
+                                    }
+                                },
                             }
                         }
-                        if shape.len() != 0 {
+                        if !shape.is_empty() {
                             Ok(Type::Tensor {
                                 dtype: Box::new(typ),
                                 shape: TensorShapeType::Shape(shape),
@@ -97,100 +328,166 @@ impl TokenParser {
                             })
                         }
                     } else {
-                        return Err(format!(
-                            "Expect a Token::LParen `(` after `tensor` for type annotation"
-                        ));
+                        Err(Diagnostic::error(
+                            ErrorKind::BadTypeAnnotation,
+                            "expect `(` after `tensor` for type annotation",
+                            self.prev_span,
+                        )
+                        .with_secondary(start, "tensor type"))
                     }
                 }
                 "list" => {
-                    if self.advance() == Token::LParen {
+                    if self.advance()? == Token::LParen {
                         let typ = self.parse_type_annotation()?;
-                        if self.advance() == Token::RParen {
+                        if self.advance()? == Token::RParen {
                             Ok(Type::List(Box::new(typ)))
                         } else {
-                            return Err(format!("Expect a `)` to match `(`"));
+                            Err(Diagnostic::error(
+                                ErrorKind::BadTypeAnnotation,
+                                "expect a `)` to close the `list(` type annotation",
+                                self.prev_span,
+                            )
+                            .with_secondary(start, "list type"))
                         }
                     } else {
-                        return Err(format!(
-                            "Expect a Token::LParen `(` after `list` for type annotation"
-                        ));
+                        Err(Diagnostic::error(
+                            ErrorKind::BadTypeAnnotation,
+                            "expect `(` after `list` for type annotation",
+                            self.prev_span,
+                        )
+                        .with_secondary(start, "list type"))
                     }
                 }
-                _ => Ok(Type::from_str(annotation.as_str())),
+                _ => annotation
+                    .parse()
+                    .map_err(|e| Diagnostic::error(ErrorKind::BadTypeAnnotation, e, start)),
             }
         } else {
-            return Err(format!(
-                "Expect a Token::Symbol after Token::Colon `:` for type annotation, but got {}",
-                token_after_colon
-            ));
+            Err(Diagnostic::error(
+                ErrorKind::BadTypeAnnotation,
+                format!(
+                    "expect a type name after `:` for type annotation, but got `{}`",
+                    token_after_colon
+                ),
+                start,
+            ))
         }
     }
 
-    fn parse_sub_and_check_pair(&mut self, expect: Token) -> Result<Expr, String> {
+    /// Parse the inside of a pair whose opening token has just been
+    /// consumed, the returned span covers both delimiters.
+    fn parse_sub_and_check_pair(&mut self, expect: Token) -> Result<Expr, Diagnostic> {
+        let open = self.prev_span;
         if self.current() == Some(&expect) {
-            self.advance();
-            return Ok(Expr::Unit);
-        }
-        let sub_expr = self.parse_expression(0)?;
-        if self.advance() != expect {
-            Err(format!(
-                "Expect {} to match a pair, but got {:?}",
-                expect,
-                self.current()
-            ))
-        } else {
-            Ok(sub_expr)
+            self.advance()?;
+            return Ok(Expr::new(ExprKind::Unit, self.span_from(open)));
         }
+        let error = match self.parse_expression(0) {
+            Ok(sub_expr) => {
+                let got = self.advance()?;
+                if got == expect {
+                    return Ok(Expr::new(sub_expr.kind, self.span_from(open)));
+                }
+                self.pos -= 1;
+                Diagnostic::error(
+                    ErrorKind::UnexpectedToken {
+                        found: got.to_string(),
+                    },
+                    format!("expect `{}` to match a pair, but got `{}`", expect, got),
+                    self.current_span(),
+                )
+                .with_label(format!("expected `{}`", expect))
+                .with_secondary(open, "opened here")
+            }
+            Err(e) => e,
+        };
+        // the pair is known to be balanced by the lexer, so resync at its end
+        self.errors.push(error);
+        self.recover_to_close();
+        Ok(Expr::new(ExprKind::Error, self.span_from(open)))
     }
 
-    fn parse_expression(&mut self, rbp: u8) -> Result<Expr, String> {
-        let token = self.advance();
-        let mut left: Expr = match token {
-            Token::F64(n) => Expr::Float {
+    fn parse_expression(&mut self, rbp: u8) -> Result<Expr, Diagnostic> {
+        let token = self.advance()?;
+        let start = self.prev_span;
+        let kind = match token {
+            Token::F64(n) => ExprKind::Float {
                 val: n,
                 typ: Type::Unknown,
             },
-            Token::U64(n) => Expr::Integer {
+            Token::U64(n) => ExprKind::Integer {
                 val: n,
                 typ: Type::Unknown,
             },
             Token::Minus | Token::Star => {
                 let sub_expr = self.parse_expression(128)?;
-                Expr::Unary {
+                ExprKind::Unary {
                     op: token,
                     arg: Box::new(sub_expr),
                 }
             }
-            Token::Symbol(identifier) => Expr::Identifier {
+            Token::Symbol(identifier) => ExprKind::Identifier {
                 name: identifier,
                 typ: Type::Unknown,
             },
-            Token::LParen => self.parse_sub_and_check_pair(Token::RParen)?,
+            Token::LParen => self.parse_sub_and_check_pair(Token::RParen)?.kind,
             Token::LBracket => {
                 // List
                 let sub_expr = self.parse_sub_and_check_pair(Token::RBracket)?;
-                if let Expr::Tuple(args) = sub_expr {
-                    Expr::List(args)
-                } else {
-                    return Err(format!(
-                        "Expect a Expr::Tuple between `[` and `]`, but got {}",
-                        sub_expr
-                    ));
+                match sub_expr.kind {
+                    ExprKind::Tuple(args) => ExprKind::List(args),
+                    ExprKind::Error => ExprKind::Error,
+                    ExprKind::Unit => {
+                        return Err(Diagnostic::error(
+                            ErrorKind::InvalidListLiteral,
+                            "expect comma separated items between `[` and `]`",
+                            sub_expr.span,
+                        )
+                        .with_label("empty list"));
+                    }
+                    // e.g. `[1]`, a dim of 1 to broadcast
+                    _ => ExprKind::List(vec![sub_expr]),
                 }
             }
-            Token::Cache => Expr::Buffer {
+            Token::LBrace => {
+                // Block
+                let stmts = self.parse_statements(Some(&Token::RBrace));
+                if self.advance()? != Token::RBrace {
+                    unreachable!("statements in a block only stop at `}}`")
+                }
+                ExprKind::Block(stmts)
+            }
+            Token::Cache => ExprKind::Buffer {
                 size: 0,
                 anno: String::new(),
             },
-            _ => return Err(format!("Unexpected start token: {:?}", token)),
+            _ => {
+                if matches!(
+                    token,
+                    Token::RParen | Token::RBracket | Token::RBrace | Token::Semicolon
+                ) {
+                    // leave the token for the recovery to resync at
+                    self.pos -= 1;
+                }
+                return Err(Diagnostic::error(
+                    ErrorKind::UnexpectedToken {
+                        found: token.to_string(),
+                    },
+                    format!("unexpected token `{}` at the start of an expression", token),
+                    start,
+                )
+                .with_label("expected an expression"));
+            }
         };
+        let mut left = Expr::new(kind, self.span_from(start));
 
         loop {
-            if self.current() == None || self.get_binding_power(self.current().unwrap()) <= rbp {
+            if self.current().is_none() || self.get_binding_power(self.current().unwrap()) <= rbp {
                 break;
             }
-            let op = self.advance();
-            left = match op {
+            let op = self.advance()?;
+            let op_span = self.prev_span;
+            let kind = match op {
                 Token::Plus
                 | Token::Minus
                 | Token::Star
@@ -208,7 +505,7 @@ impl TokenParser {
                 | Token::GreatThan
                 | Token::GreatThanEq => {
                     let right_expr = self.parse_expression(self.get_binding_power(&op))?;
-                    Expr::Binary {
+                    ExprKind::Binary {
                         left: Box::new(left),
                         op,
                         right: Box::new(right_expr),
@@ -216,120 +513,157 @@ impl TokenParser {
                 }
                 // <id> = <body>
                 Token::Equal => {
-                    if let Expr::Identifier { .. } = left {
+                    if let ExprKind::Identifier { .. } = left.kind {
                         let right_expr = self.parse_expression(self.get_binding_power(&op))?;
-                        Expr::Assign {
+                        ExprKind::Assign {
                             name: Box::new(left),
                             val: Box::new(right_expr),
                         }
                     } else {
-                        return Err(format!(
-                            "Expect a string on the left of `=`, but got {:?}",
-                            op
-                        ));
+                        return Err(Diagnostic::error(
+                            ErrorKind::InvalidAssignTarget,
+                            "expect an identifier on the left of `=`",
+                            left.span,
+                        )
+                        .with_label("can not assign to this")
+                        .with_secondary(op_span, "assignment"));
                     }
                 }
                 // (args)=><body>
-                Token::FatArrow => match left {
-                    Expr::Identifier { .. } | Expr::Tuple(_) => {
+                Token::FatArrow => match left.kind {
+                    ExprKind::Identifier { .. } | ExprKind::Tuple(_) => {
                         let right_expr = self.parse_expression(self.get_binding_power(&op))?;
-                        Expr::Lambda {
+                        ExprKind::Lambda {
                             param: Box::new(left),
                             body: Box::new(right_expr),
                         }
                     }
                     _ => {
-                        return Err(format!(
-                            "Expect an Expr::Identifier or an Expr::Tuple on the left of `=>`, but got {:?}",
-                            op
-                        ));
+                        return Err(Diagnostic::error(
+                            ErrorKind::InvalidLambdaParam,
+                            "expect an identifier or a tuple of identifiers on the left of `=>`",
+                            left.span,
+                        )
+                        .with_label("invalid lambda parameter")
+                        .with_secondary(op_span, "lambda"));
                     }
                 },
                 // <var>.<func>(<args>)
                 Token::Dot => {
-                    if let Token::Symbol(callee) = self.advance() {
+                    let callee_token = self.advance()?;
+                    let callee_span = self.prev_span;
+                    if let Token::Symbol(callee) = callee_token {
                         let mut args: Vec<Expr> = Vec::new();
                         args.push(left);
-                        if op == Token::LParen {
-                            self.advance();
-                            args.extend(self.parse_sub_and_check_pair(Token::RParen))
+                        if self.current() == Some(&Token::LParen) {
+                            self.advance()?;
+                            let sub_expr = self.parse_sub_and_check_pair(Token::RParen)?;
+                            match sub_expr.kind {
+                                ExprKind::Unit => {}
+                                ExprKind::Tuple(items) => args.extend(items),
+                                _ => args.push(sub_expr),
+                            }
                         }
-                        Expr::Call {
-                            callee: Box::new(Expr::Identifier {
-                                name: callee,
-                                typ: Type::Unknown,
-                            }),
-                            args: Box::new(Expr::Tuple(args)),
+                        ExprKind::Call {
+                            callee: Box::new(Expr::new(
+                                ExprKind::Identifier {
+                                    name: callee,
+                                    typ: Type::Unknown,
+                                },
+                                callee_span,
+                            )),
+                            args: Box::new(Expr::new(ExprKind::Tuple(args), self.span_from(start))),
                         }
                     } else {
-                        return Err(format!(
-                            "Expect a Token::Symbol on the left of `.`, but got {:?}",
-                            op
-                        ));
+                        return Err(Diagnostic::error(
+                            ErrorKind::InvalidMethodCall,
+                            format!(
+                                "expect a function name on the right of `.`, but got `{}`",
+                                callee_token
+                            ),
+                            callee_span,
+                        )
+                        .with_secondary(op_span, "method call"));
                     }
                 }
                 Token::Comma => {
                     let right_expr = self.parse_expression(self.get_binding_power(&op))?;
-                    if let Expr::Tuple(mut a) = left {
-                        if let Expr::Tuple(t) = right_expr {
+                    if let ExprKind::Tuple(mut a) = left.kind {
+                        if let ExprKind::Tuple(t) = right_expr.kind {
                             a.extend(t);
                         } else {
                             a.push(right_expr);
                         }
-                        Expr::Tuple(a)
+                        ExprKind::Tuple(a)
                     } else {
-                        Expr::Tuple(vec![left, right_expr])
+                        ExprKind::Tuple(vec![left, right_expr])
                     }
                 }
                 Token::LParen => {
                     let right_expr = self.parse_sub_and_check_pair(Token::RParen)?;
-                    match left {
+                    match left.kind {
                         // e.g. sin(pi), (x=>x+1)(1)
-                        Expr::Identifier { .. } | Expr::Lambda { .. } => Expr::Call {
+                        ExprKind::Identifier { .. } | ExprKind::Lambda { .. } => ExprKind::Call {
                             callee: Box::new(left),
                             args: Box::new(right_expr),
                         },
                         // e.g. $(1024, local)
-                        Expr::Buffer { .. } => {
-                            if let Expr::Tuple(args) = right_expr {
+                        ExprKind::Buffer { .. } => {
+                            if let ExprKind::Error = right_expr.kind {
+                                ExprKind::Error
+                            } else if let ExprKind::Tuple(args) = &right_expr.kind {
                                 let size: u64;
                                 let anno: String;
                                 if args.len() == 2 {
-                                    if let Expr::Integer { val, .. } = &args[0] {
-                                        size = val.clone();
+                                    if let ExprKind::Integer { val, .. } = &args[0].kind {
+                                        size = *val;
                                     } else {
-                                        return Err(format!(
-                                            "Expect an Expr::Integer at arg0 for Buffer size, but got {}",
-                                            args[0]
-                                        ));
+                                        return Err(Diagnostic::error(
+                                            ErrorKind::BadBufferArgs,
+                                            "expect an integer as the size of a buffer",
+                                            args[0].span,
+                                        )
+                                        .with_label("buffer size"));
                                     }
-                                    if let Expr::Identifier { name, .. } = &args[1] {
+                                    if let ExprKind::Identifier { name, .. } = &args[1].kind {
                                         anno = name.clone();
                                     } else {
-                                        return Err(format!(
-                                            "Expect an Expr::Identifier at arg1 for Buffer anno, but got {}",
-                                            args[1]
-                                        ));
+                                        return Err(Diagnostic::error(
+                                            ErrorKind::BadBufferArgs,
+                                            "expect an identifier as the annotation of a buffer",
+                                            args[1].span,
+                                        )
+                                        .with_label("buffer annotation")
+                                        .with_note("e.g. `local`, `global`, `sram`"));
                                     }
                                 } else {
-                                    return Err(format!(
-                                        "Expect 2 args for Buffer: $(size:int, anno:str), but got {} args",
-                                        args.len()
-                                    ));
+                                    return Err(Diagnostic::error(
+                                        ErrorKind::BadBufferArgs,
+                                        format!(
+                                            "expect 2 args for a buffer, but got {} args",
+                                            args.len()
+                                        ),
+                                        right_expr.span,
+                                    )
+                                    .with_help("write a buffer as `$(<size>, <anno>)`"));
                                 }
-                                Expr::Buffer { size, anno }
+                                ExprKind::Buffer { size, anno }
                             } else {
-                                return Err(format!(
-                                    "Expect an Expr::Tuple for Buffer args, but got {}",
-                                    right_expr
-                                ));
+                                return Err(Diagnostic::error(
+                                    ErrorKind::BadBufferArgs,
+                                    "expect 2 args for a buffer, but got 1 arg",
+                                    right_expr.span,
+                                )
+                                .with_help("write a buffer as `$(<size>, <anno>)`"));
                             }
                         }
                         _ => {
-                            return Err(format!(
-                                "Expect an Expr::Identifier or an Expr::Lambda on the left of an infix `(`, but got {}",
-                                left
-                            ));
+                            return Err(Diagnostic::error(
+                                ErrorKind::InvalidCallee,
+                                "expect a function name or a lambda before `(`",
+                                left.span,
+                            )
+                            .with_label("this is not callable"));
                         }
                     }
                 }
@@ -338,31 +672,37 @@ impl TokenParser {
                     // Parse the type annotation
                     let new_typ = self.parse_type_annotation()?;
 
-                    match &mut left {
-                        Expr::Identifier { typ, .. }
-                        | Expr::Float { typ, .. }
-                        | Expr::Integer { typ, .. } => {
+                    match &mut left.kind {
+                        ExprKind::Identifier { typ, .. }
+                        | ExprKind::Float { typ, .. }
+                        | ExprKind::Integer { typ, .. } => {
                             *typ = new_typ;
-                            left
+                            left.kind
                         }
                         _ => {
-                            return Err(format!(
-                                "Expect an Identifier, Float, or Integer on the left of `:`, but got {}",
-                                left
-                            ));
+                            return Err(Diagnostic::error(
+                                ErrorKind::InvalidAnnotationTarget,
+                                "expect an identifier, a float or an integer on the left of `:`",
+                                left.span,
+                            )
+                            .with_label("can not carry a type annotation")
+                            .with_secondary(op_span, "type annotation"));
                         }
                     }
                 }
                 // <expr>@<device>
                 Token::At => {
                     let right_expr = self.parse_expression(self.get_binding_power(&op))?;
-                    if !matches!(right_expr, Expr::Identifier { .. }) {
-                        return Err(format!(
-                            "Expect an Expr::Identifier after Token::At `@`, but got {}",
-                            right_expr
-                        ));
+                    if !matches!(right_expr.kind, ExprKind::Identifier { .. }) {
+                        return Err(Diagnostic::error(
+                            ErrorKind::InvalidMoveDevice,
+                            "expect a device name after `@`",
+                            right_expr.span,
+                        )
+                        .with_label("not a device name")
+                        .with_secondary(op_span, "move"));
                     }
-                    Expr::Move {
+                    ExprKind::Move {
                         val: Box::new(left),
                         device: Box::new(right_expr),
                     }
@@ -370,39 +710,181 @@ impl TokenParser {
                 // <name>#<param>
                 Token::Hash => {
                     let right_expr = self.parse_expression(self.get_binding_power(&op))?;
-                    if let Expr::Identifier { name, .. } = left {
-                        Expr::MetaDefine {
-                            name: name,
+                    if let ExprKind::Identifier { name, .. } = left.kind {
+                        ExprKind::MetaDefine {
+                            name,
                             val: Box::new(right_expr),
                         }
                     } else {
-                        return Err(format!(
-                            "Expect an Expr::Identifier before `#` for MetaDefine name, but got {}",
-                            right_expr
-                        ));
+                        return Err(Diagnostic::error(
+                            ErrorKind::InvalidMetaDefine,
+                            "expect an identifier before `#` for the name of a meta define",
+                            left.span,
+                        )
+                        .with_secondary(op_span, "meta define"));
                     }
                 }
                 _ => {
-                    return Err(format!(
-                        "Unexpected infix operator: {:?}, left now is :\nAST:\n{}",
-                        op, left
-                    ));
+                    return Err(Diagnostic::error(
+                        ErrorKind::UnexpectedToken {
+                            found: op.to_string(),
+                        },
+                        format!("unexpected infix operator `{}`", op),
+                        op_span,
+                    )
+                    .with_secondary(left.span, "left operand"));
                 }
-            }
+            };
+            left = Expr::new(kind, self.span_from(start));
         }
         Ok(left)
     }
 }
 
 impl Parser for TokenParser {
-    fn parse_exprs(&mut self) -> Result<Expr, String> {
-        let res = self.parse_expression(0);
-        if self.pos != self.tokens.len() {
-            return Err(format!(
-                "Unhandled tokens remain: {:?}",
-                &self.tokens[self.pos..]
-            ));
+    fn parse_exprs(&mut self) -> Result<Expr, Diagnostic> {
+        let program = self.parse_program();
+        if !self.errors.is_empty() {
+            return Err(self.errors.remove(0));
         }
-        res
+        Ok(program)
+    }
+
+    fn parse_recovering(&mut self) -> (Expr, Vec<Diagnostic>) {
+        let program = self.parse_program();
+        (program, std::mem::take(&mut self.errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::{LasmiaoLexer, Lexer};
+
+    fn parse(src: &str) -> Result<Expr, Diagnostic> {
+        TokenParser::new(LasmiaoLexer::make_tokens(src).unwrap()).parse_exprs()
+    }
+
+    #[test]
+    fn bad_buffer_args() {
+        let err = parse("a = $(1024, 2)").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadBufferArgs);
+        assert_eq!(err.span().col, 13);
+    }
+
+    #[test]
+    fn invalid_assign_target() {
+        let err = parse("1 = 2").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidAssignTarget);
+        assert_eq!(err.secondary[0].span.col, 3);
+    }
+
+    #[test]
+    fn unexpected_eof() {
+        let err = parse("a = 1 +").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(err.span().col, 8);
+    }
+
+    #[test]
+    fn recovers_at_statement_and_pair_boundaries() {
+        let src = "a = (1 2)\nb = 1 +\nc = [1, $(1, 2)]\nd = x.map(y => y)";
+        let mut parser = TokenParser::new(LasmiaoLexer::make_tokens(src).unwrap());
+        let (program, errors) = parser.parse_recovering();
+        let ExprKind::Block(stmts) = program.kind else {
+            panic!("expect a block, got {}", program);
+        };
+
+        assert_eq!(stmts.len(), 4);
+        assert!(matches!(stmts[1].kind, ExprKind::Error));
+        assert!(matches!(stmts[3].kind, ExprKind::Assign { .. }));
+        let ExprKind::Assign { val, .. } = &stmts[0].kind else {
+            panic!("expect an assign, got {}", stmts[0]);
+        };
+        assert!(matches!(val.kind, ExprKind::Error));
+
+        let kinds: Vec<(ErrorKind, usize)> = errors
+            .into_iter()
+            .map(|e| (e.kind.clone(), e.span().line))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (
+                    ErrorKind::UnexpectedToken {
+                        found: "2".to_string()
+                    },
+                    1
+                ),
+                (
+                    ErrorKind::UnexpectedToken {
+                        found: ";".to_string()
+                    },
+                    2
+                ),
+                (ErrorKind::BadBufferArgs, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn programs_and_braces_parse_into_blocks() {
+        let program = parse("f = (x => {\n  y = x + 1\n  y * 2\n})\n\nf(1); g = {}\n").unwrap();
+        let ExprKind::Block(stmts) = &program.kind else {
+            panic!("expect a block, got {}", program);
+        };
+        assert_eq!(stmts.len(), 3);
+
+        let ExprKind::Assign { val, .. } = &stmts[0].kind else {
+            panic!("expect an assign, got {}", stmts[0]);
+        };
+        let ExprKind::Lambda { body, .. } = &val.kind else {
+            panic!("expect a lambda, got {}", val);
+        };
+        assert!(matches!(&body.kind, ExprKind::Block(inner) if inner.len() == 2));
+        assert_eq!((body.span.line, body.span.col), (1, 11));
+
+        let ExprKind::Assign { val, .. } = &stmts[2].kind else {
+            panic!("expect an assign, got {}", stmts[2]);
+        };
+        assert!(matches!(&val.kind, ExprKind::Block(inner) if inner.is_empty()));
+    }
+
+    #[test]
+    fn rules_split_at_their_own_arrow() {
+        let program = parse("rule fuse_map: x.map(f).map(g) => x.map(y => g(f(y)))").unwrap();
+        let ExprKind::Block(stmts) = &program.kind else {
+            panic!("expect a block, got {}", program);
+        };
+        let ExprKind::Rule { name, lhs, rhs } = &stmts[0].kind else {
+            panic!("expect a rule, got {}", stmts[0]);
+        };
+        assert_eq!(name, "fuse_map");
+        assert!(matches!(lhs.kind, ExprKind::Call { .. }));
+        assert_eq!((rhs.span.col, rhs.span.end), (35, 53));
+
+        assert!(parse("rule = 1; rule + 1").is_ok());
+        let err = parse("rule r: x + 0").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidRule);
+        let err = parse("f = { rule r: x => x }").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidRule);
+    }
+
+    #[test]
+    fn symbolic_tensor_dims() {
+        let program = parse("a:tensor(f32, N, N*2+1, 3) = b").unwrap();
+        let ExprKind::Block(stmts) = &program.kind else {
+            panic!("expect a block, got {}", program);
+        };
+        let ExprKind::Assign { name, .. } = &stmts[0].kind else {
+            panic!("expect an assign, got {}", stmts[0]);
+        };
+        let ExprKind::Identifier { typ, .. } = &name.kind else {
+            panic!("expect an identifier, got {}", name);
+        };
+        assert_eq!(typ.to_string(), "tensor(f32, N, N*2+1, 3)");
+
+        let err = parse("a:tensor(f32, i32) = b").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadTypeAnnotation);
     }
 }
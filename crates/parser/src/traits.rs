@@ -1,5 +1,12 @@
 use crate::expr::Expr;
+use diagnostics::Diagnostic;
 
 pub trait Parser {
-    fn parse_exprs(&mut self) -> Result<Expr, String>;
+    /// Parse a whole program into an `Expr::Block` of its statements.
+    fn parse_exprs(&mut self) -> Result<Expr, Diagnostic>;
+
+    /// Like `parse_exprs`, but without stopping at the first syntax error.
+    /// A statement which fails to parse is kept in the block as an
+    /// `Expr::Error`, all the errors are returned alongside.
+    fn parse_recovering(&mut self) -> (Expr, Vec<Diagnostic>);
 }
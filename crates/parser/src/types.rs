@@ -1,9 +1,111 @@
 use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DimOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// A dim of a tensor shape, known or named by a `MetaDefine` constant,
+/// e.g. `N` and `N*2` in `tensor(f32, N, N*2)`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Dim {
+    Const(u64),
+    Sym(String),
+    Op(DimOp, Box<Dim>, Box<Dim>),
+}
+
+impl Dim {
+    pub fn as_const(&self) -> Option<u64> {
+        match self {
+            Dim::Const(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn is_symbolic(&self) -> bool {
+        self.as_const().is_none()
+    }
+
+    /// Replace the symbols which have a value and fold the arithmetic on
+    /// constants. Subtractions below zero and inexact divisions are kept.
+    pub fn specialize(&self, value: &dyn Fn(&str) -> Option<u64>) -> Dim {
+        match self {
+            Dim::Const(_) => self.clone(),
+            Dim::Sym(name) => value(name).map_or_else(|| self.clone(), Dim::Const),
+            Dim::Op(op, l, r) => {
+                let (l, r) = (l.specialize(value), r.specialize(value));
+                let folded = match (op, l.as_const(), r.as_const()) {
+                    (DimOp::Add, Some(a), Some(b)) => a.checked_add(b),
+                    (DimOp::Sub, Some(a), Some(b)) => a.checked_sub(b),
+                    (DimOp::Mul, Some(a), Some(b)) => a.checked_mul(b),
+                    (DimOp::Div, Some(a), Some(b)) if b != 0 && a % b == 0 => Some(a / b),
+                    _ => None,
+                };
+                folded.map_or_else(|| Dim::Op(*op, Box::new(l), Box::new(r)), Dim::Const)
+            }
+        }
+    }
+}
+
+impl From<u64> for Dim {
+    fn from(n: u64) -> Self {
+        Dim::Const(n)
+    }
+}
+
+impl fmt::Display for Dim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dim::Const(n) => write!(f, "{}", n),
+            Dim::Sym(name) => write!(f, "{}", name),
+            Dim::Op(op, l, r) => {
+                let (sym, tight) = match op {
+                    DimOp::Add => ('+', false),
+                    DimOp::Sub => ('-', false),
+                    DimOp::Mul => ('*', true),
+                    DimOp::Div => ('/', true),
+                };
+                // parenthesize sums inside products, and any right operand
+                // which is an operation, e.g. `N-(M-1)`
+                let paren = |d: &Dim, right: bool| match d {
+                    Dim::Op(DimOp::Add | DimOp::Sub, ..) => tight || right,
+                    Dim::Op(..) => right,
+                    _ => false,
+                };
+                for (d, right) in [(l, false), (r, true)] {
+                    if right {
+                        write!(f, "{}", sym)?;
+                    }
+                    if paren(d, right) {
+                        write!(f, "({})", d)?;
+                    } else {
+                        write!(f, "{}", d)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TensorShapeType {
     Any,
-    Shape(Vec<u64>),
+    Shape(Vec<Dim>),
+}
+
+impl fmt::Display for TensorShapeType {
+    /// Dims as written in an annotation, e.g. `3, 2`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TensorShapeType::Any => write!(f, "any"),
+            TensorShapeType::Shape(dims) => write!(f, "{}", join(dims)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -22,6 +124,8 @@ pub enum Type {
 
     List(Box<Type>),
     Tuple(Vec<Type>),
+    /// Memory reserved by `$(size, anno)`
+    Buffer,
 
     Tensor {
         dtype: Box<Type>,
@@ -34,12 +138,16 @@ pub enum Type {
     },
 
     Ext(String),
+    /// Type variable, solved by inference
+    Var(u32),
     Unknown,
 }
 
-impl Type {
-    pub fn from_str(s: &str) -> Self {
-        match s {
+impl FromStr for Type {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
             "any" => Type::Any,
             "unit" => Type::Unit,
 
@@ -51,41 +159,51 @@ impl Type {
             "u64" => Type::U64,
             "char" => Type::Char,
             "bool" => Type::Bool,
+            "buffer" => Type::Buffer,
 
-            "tensor" | "list" | "tuple" => panic!("single {} str cannot convert to a type", s),
+            "tensor" | "list" | "tuple" => {
+                return Err(format!("single {} str cannot convert to a type", s));
+            }
 
             _ => Type::Ext(s.to_string()),
-        }
+        })
     }
+}
 
-    fn to_string(&self) -> String {
-        match self {
-            Type::Any => "any".to_string(),
-            Type::Unit => "unit".to_string(),
-            Type::F32 => "f32".to_string(),
-            Type::F64 => "f64".to_string(),
-            Type::I32 => "i32".to_string(),
-            Type::U32 => "u32".to_string(),
-            Type::I64 => "i64".to_string(),
-            Type::U64 => "u64".to_string(),
-            Type::Char => "char".to_string(),
-            Type::Bool => "bool".to_string(),
-
-            Type::List(typ) => format!("List<{}>", typ.to_string()),
-
-            Type::Tensor { dtype, shape } => {
-                format!("tensor<{}, {:?}>", dtype.to_string(), shape)
-            }
-
-            Type::Ext(name) => format!("{}", name),
-            Type::Unknown => "?".to_string(),
-            _ => panic!("unknown type {}", self),
-        }
-    }
+fn join<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.to_string())
+        match self {
+            Type::Any => write!(f, "any"),
+            Type::Unit => write!(f, "unit"),
+            Type::F32 => write!(f, "f32"),
+            Type::F64 => write!(f, "f64"),
+            Type::I32 => write!(f, "i32"),
+            Type::U32 => write!(f, "u32"),
+            Type::I64 => write!(f, "i64"),
+            Type::U64 => write!(f, "u64"),
+            Type::Char => write!(f, "char"),
+            Type::Bool => write!(f, "bool"),
+
+            Type::List(typ) => write!(f, "List<{}>", typ),
+            Type::Tuple(items) => write!(f, "({})", join(items)),
+            Type::Buffer => write!(f, "buffer"),
+
+            Type::Tensor { dtype, shape } => write!(f, "tensor({}, {})", dtype, shape),
+
+            Type::Function { params, ret } => write!(f, "({}) => {}", join(params), ret),
+
+            Type::Ext(name) => write!(f, "{}", name),
+            Type::Var(n) if *n < 26 => write!(f, "'{}", (b'a' + *n as u8) as char),
+            Type::Var(n) => write!(f, "'t{}", n),
+            Type::Unknown => write!(f, "?"),
+        }
     }
 }
@@ -0,0 +1,130 @@
+//! Common subexpression elimination.
+
+use crate::traits::{Pass, PassContext};
+use ir::{Module, RegionId, ValueId};
+use std::collections::HashMap;
+
+/// What two ops must share to compute the same value. The kind and the
+/// type are compared through their text, which tells floats apart by
+/// value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    kind: String,
+    operands: Vec<ValueId>,
+    ty: String,
+    device: Option<String>,
+}
+
+/// Replaces a pure op by an identical one defined before it, in the same
+/// region or an enclosing one. Ops placed on different devices are kept
+/// apart, and ops with effects (`move`, `buffer` and calls of functions
+/// using them) or regions are never merged.
+pub struct Cse;
+
+impl Cse {
+    /// Merge the ops of `region` given those seen in the enclosing ones,
+    /// returning whether any was merged.
+    fn region(module: &mut Module, region: RegionId, mut seen: HashMap<Key, ValueId>) -> bool {
+        let mut changed = false;
+        for op in module.region(region).ops.clone() {
+            let data = module.op(op);
+            for inner in data.regions.clone() {
+                changed |= Self::region(module, inner, seen.clone());
+            }
+            let data = module.op(op);
+            if !module.is_pure(op) || !data.regions.is_empty() || data.results.len() != 1 {
+                continue;
+            }
+            let result = data.results[0];
+            let key = Key {
+                kind: format!("{:?}", data.kind),
+                operands: data.operands.clone(),
+                ty: module.value(result).ty.to_string(),
+                device: data.device.clone(),
+            };
+            match seen.get(&key) {
+                Some(first) => {
+                    module.replace_all_uses(result, *first);
+                    module.erase_op(op);
+                    changed = true;
+                }
+                None => {
+                    seen.insert(key, result);
+                }
+            }
+        }
+        changed
+    }
+}
+
+impl Pass for Cse {
+    fn name(&self) -> &str {
+        "cse"
+    }
+
+    fn description(&self) -> &str {
+        "Merge identical pure ops"
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, _: &mut PassContext) -> bool {
+        Self::region(module, module.body, HashMap::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_pure_ops_on_the_same_device() {
+        let mut module = ir::parse(
+            "module {
+               %x = symbol x : f64
+               %y = symbol x : f64
+               %a = add %x, %y : f64
+               %b = add %y, %x : f64
+               %c = add %x, %x : f64 @xpu
+               %f = lambda (%p: f64) : (f64) => f64 {
+                 %z = symbol x : f64
+                 %d = add %z, %z : f64
+                 yield %d
+               }
+               %m = move %a : f64 @xpu
+               %n = move %b : f64 @xpu
+               %t = tuple %a, %b, %c, %m, %n : (f64, f64, f64, f64, f64)
+               yield %t, %f
+             }",
+        )
+        .unwrap();
+        assert!(Cse.run_on_module(&mut module, &mut PassContext::default()));
+        ir::verify(&module).unwrap();
+        let text = module.to_string();
+        assert_eq!(text.matches("symbol").count(), 1, "{}", text);
+        // the adds are the same once the symbols are, even in the lambda
+        assert_eq!(text.matches("add").count(), 2, "{}", text);
+        assert_eq!(text.matches("move").count(), 2, "{}", text);
+        assert!(text.contains("tuple %1, %1, %2, %5, %6"), "{}", text);
+
+        // calls of builtins are merged, those of a lambda moving values not
+        let mut module = ir::parse(
+            "module {
+               %x = symbol x : f64
+               %s = symbol sin : (f64) => f64
+               %a = call %s, %x : f64
+               %b = call %s, %x : f64
+               %f = lambda (%p: f64) : (f64) => f64 {
+                 %m = move %p : f64 @xpu
+                 yield %m
+               }
+               %c = call %f, %x : f64
+               %d = call %f, %x : f64
+               %t = tuple %a, %b, %c, %d : (f64, f64, f64, f64)
+               yield %t
+             }",
+        )
+        .unwrap();
+        assert!(Cse.run_on_module(&mut module, &mut PassContext::default()));
+        let text = module.to_string();
+        assert_eq!(text.matches("call").count(), 3, "{}", text);
+    }
+}
@@ -0,0 +1,102 @@
+//! Dead code elimination.
+
+use crate::traits::{Pass, PassContext};
+use ir::{Module, OpId, RegionId};
+use std::collections::HashSet;
+
+/// Removes the pure ops whose results are never used, and the names bound
+/// to them. Ops with effects (`move`, `buffer` and calls of functions
+/// using them) stay, along with what they use, unless they are in the body
+/// of a lambda which is itself unused.
+pub struct Dce;
+
+impl Dce {
+    /// Mark the ops `region` needs, the region being live.
+    fn mark(module: &Module, region: RegionId, live: &mut HashSet<OpId>) {
+        let data = module.region(region);
+        let mut worklist: Vec<OpId> = data
+            .results
+            .iter()
+            .filter_map(|v| module.def_op(*v))
+            .chain(data.ops.iter().copied().filter(|op| !module.is_pure(*op)))
+            .collect();
+        while let Some(op) = worklist.pop() {
+            if !live.insert(op) {
+                continue;
+            }
+            let data = module.op(op);
+            worklist.extend(data.operands.iter().filter_map(|v| module.def_op(*v)));
+            for inner in &data.regions {
+                Self::mark(module, *inner, live);
+            }
+        }
+    }
+}
+
+impl Pass for Dce {
+    fn name(&self) -> &str {
+        "dce"
+    }
+
+    fn description(&self) -> &str {
+        "Remove unused pure ops and bindings"
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, _: &mut PassContext) -> bool {
+        let mut live = HashSet::new();
+        Self::mark(module, module.body, &mut live);
+        let dead: Vec<OpId> = module
+            .ops()
+            .into_iter()
+            .filter(|op| !live.contains(op))
+            .collect();
+        for op in &dead {
+            module.erase_op(*op);
+        }
+        let names = std::mem::take(&mut module.names);
+        let bound = names.len();
+        module.names = names
+            .into_iter()
+            .filter(|(_, v)| {
+                module
+                    .def_op(*v)
+                    .is_none_or(|op| module.op(op).parent.is_some())
+            })
+            .collect();
+        !dead.is_empty() || module.names.len() != bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::lower;
+
+    #[test]
+    fn keeps_effects_and_what_the_output_needs() {
+        let src = "a = 1 + 2\n\
+                   b = $(1024, sram)@xpu\n\
+                   f = (x => [x, x@cpu])\n\
+                   g = (x => x@xpu)\n\
+                   c = g(3)\n\
+                   e = sin(1.)\n\
+                   d = 4\n\
+                   d";
+        let mut module = lower(src);
+        assert!(Dce.run_on_module(&mut module, &mut PassContext::default()));
+        ir::verify(&module).unwrap();
+
+        let names: Vec<&str> = module.names.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["b", "g", "c", "d"]);
+        let kinds: Vec<&str> = module
+            .ops()
+            .iter()
+            .map(|op| module.op(*op).kind.name())
+            .collect();
+        assert_eq!(
+            kinds,
+            ["buffer", "move", "lambda", "move", "const", "call", "const"]
+        );
+        assert!(!Dce.run_on_module(&mut module, &mut PassContext::default()));
+    }
+}
@@ -0,0 +1,334 @@
+//! Constant folding and algebraic simplification.
+//!
+//! Operators and builtin calls whose operands are all constants are
+//! computed in the dtype of their operands: f32 values are rounded to f32,
+//! integers wrap at the width of i32, u32, i64 or u64. Divisions by zero,
+//! or which overflow, are left for the program to fail on.
+//!
+//! Identities only apply when they hold for every value of the dtype:
+//! `x*1`, `x/1` and `x-0` for any number, `x+0` and `x-x` only for
+//! integers, since `-0.+0.` is `0.` and `inf-inf` is NaN.
+
+use crate::rewrite::{
+    DagRewrite, Pattern, RewritePass, RewritePattern, Rewriter, constant, is_number,
+};
+use ir::{BinOp, Literal, Module, Op, OpKind, UnOp, ValueId};
+use parser::types::Type;
+use std::cmp::Ordering;
+
+/// The pass `fold`.
+pub fn fold_pass() -> RewritePass {
+    RewritePass::new("fold", patterns())
+}
+
+/// Patterns of the `fold` pass.
+pub fn patterns() -> Vec<Box<dyn RewritePattern>> {
+    let same_type =
+        |module: &Module, x: ValueId, root: ValueId| module.value(x).ty == module.value(root).ty;
+    // `n` is tried on both sides of a commutative operator, a value
+    // pattern matching the constant as well
+    let identity = |op: BinOp, n: f64, ints_only: bool| {
+        let orders: &[[&str; 2]] = if op.is_commutative() {
+            &[["x", "n"], ["n", "x"]]
+        } else {
+            &[["x", "n"]]
+        };
+        orders
+            .iter()
+            .map(move |order| {
+                let operands = order.iter().map(|name| Pattern::value(name)).collect();
+                let name = format!("{}-{}", op.name(), n);
+                let rewrite = DagRewrite::new(&name, Pattern::op(op.name(), operands), |_, m| {
+                    Some(m.value("x"))
+                })
+                .when(move |module, m| {
+                    let root = module.op(m.root()).results[0];
+                    is_number(module, m.value("n"), n)
+                        && same_type(module, m.value("x"), root)
+                        && (!ints_only || is_int(&module.value(root).ty))
+                });
+                Box::new(rewrite) as Box<dyn RewritePattern>
+            })
+            .collect::<Vec<_>>()
+    };
+    let sub_self = DagRewrite::new(
+        "sub-self",
+        Pattern::op("sub", vec![Pattern::value("x"), Pattern::value("x")]),
+        |rewriter, m| {
+            let root = rewriter.module.op(m.root()).results[0];
+            let ty = rewriter.module.value(root).ty.clone();
+            Some(rewriter.create(OpKind::Const(Literal::Int(0)), vec![], ty))
+        },
+    )
+    .when(|module, m| {
+        let root = module.op(m.root()).results[0];
+        is_int(&module.value(root).ty)
+    });
+    let mut patterns: Vec<Box<dyn RewritePattern>> =
+        vec![Box::new(FoldConstants), Box::new(sub_self)];
+    patterns.extend(identity(BinOp::Mul, 1.0, false));
+    patterns.extend(identity(BinOp::Div, 1.0, false));
+    patterns.extend(identity(BinOp::Sub, 0.0, false));
+    patterns.extend(identity(BinOp::Add, 0.0, true));
+    patterns
+}
+
+fn is_int(ty: &Type) -> bool {
+    matches!(ty, Type::I32 | Type::U32 | Type::I64 | Type::U64)
+}
+
+/// Value of the integer `v` of type `ty`, u64 values being stored as the
+/// bits of an i64.
+fn int_in(ty: &Type, v: i64) -> i128 {
+    match ty {
+        Type::I32 => v as i32 as i128,
+        Type::U32 => v as u32 as i128,
+        Type::U64 => v as u64 as i128,
+        _ => v as i128,
+    }
+}
+
+/// `v` wrapped to the width of `ty`.
+fn wrap(ty: &Type, v: i128) -> i64 {
+    match ty {
+        Type::I32 => v as i32 as i64,
+        Type::U32 => v as u32 as i64,
+        Type::U64 => v as u64 as i64,
+        _ => v as i64,
+    }
+}
+
+fn fits(ty: &Type, v: i128) -> bool {
+    match ty {
+        Type::I32 => i32::try_from(v).is_ok(),
+        Type::U32 => u32::try_from(v).is_ok(),
+        Type::U64 => u64::try_from(v).is_ok(),
+        _ => i64::try_from(v).is_ok(),
+    }
+}
+
+/// `v` rounded to the precision of `ty`.
+fn round(ty: &Type, v: f64) -> f64 {
+    match ty {
+        Type::F32 => v as f32 as f64,
+        _ => v,
+    }
+}
+
+fn float(literal: &Literal) -> Option<f64> {
+    match literal {
+        Literal::Int(v) => Some(*v as f64),
+        Literal::Float(v) => Some(*v),
+        _ => None,
+    }
+}
+
+fn compare(op: BinOp, ord: Ordering) -> Option<bool> {
+    Some(match op {
+        BinOp::Eq => ord == Ordering::Equal,
+        BinOp::Ne => ord != Ordering::Equal,
+        BinOp::Lt => ord == Ordering::Less,
+        BinOp::Le => ord != Ordering::Greater,
+        BinOp::Gt => ord == Ordering::Greater,
+        BinOp::Ge => ord != Ordering::Less,
+        _ => return None,
+    })
+}
+
+/// `l op r` for operands of type `ty`.
+fn binary(op: BinOp, l: &Literal, r: &Literal, ty: &Type) -> Option<Literal> {
+    match (l, r) {
+        (Literal::Int(a), Literal::Int(b)) if !matches!(ty, Type::F32 | Type::F64) => {
+            let (a, b) = (int_in(ty, *a), int_in(ty, *b));
+            let v = match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a.wrapping_mul(b),
+                BinOp::Div | BinOp::Rem => {
+                    let v = if op == BinOp::Div {
+                        a.checked_div(b)?
+                    } else {
+                        a.checked_rem(b)?
+                    };
+                    if !fits(ty, v) {
+                        return None;
+                    }
+                    v
+                }
+                BinOp::And => a & b,
+                BinOp::Or => a | b,
+                BinOp::Xor => a ^ b,
+                _ => return compare(op, a.cmp(&b)).map(Literal::Bool),
+            };
+            Some(Literal::Int(wrap(ty, v)))
+        }
+        (Literal::Bool(a), Literal::Bool(b)) => Some(Literal::Bool(match op {
+            BinOp::And | BinOp::LogicAnd => *a && *b,
+            BinOp::Or | BinOp::LogicOr => *a || *b,
+            BinOp::Xor | BinOp::Ne => a != b,
+            BinOp::Eq => a == b,
+            _ => return None,
+        })),
+        _ => {
+            let (a, b) = (round(ty, float(l)?), round(ty, float(r)?));
+            let v = match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => a / b,
+                BinOp::Rem => a % b,
+                _ => {
+                    return match a.partial_cmp(&b) {
+                        Some(ord) => compare(op, ord).map(Literal::Bool),
+                        // NaN is only unequal
+                        None => Some(Literal::Bool(op == BinOp::Ne)),
+                    };
+                }
+            };
+            Some(Literal::Float(round(ty, v)))
+        }
+    }
+}
+
+fn unary(op: UnOp, v: &Literal, ty: &Type) -> Option<Literal> {
+    match (op, v) {
+        (UnOp::Neg, Literal::Int(v)) => Some(Literal::Int(wrap(ty, -int_in(ty, *v)))),
+        (UnOp::Neg, Literal::Float(v)) => Some(Literal::Float(round(ty, -v))),
+        (UnOp::Not, Literal::Bool(v)) => Some(Literal::Bool(!v)),
+        _ => None,
+    }
+}
+
+/// Builtin `name` applied to `args`, returning a value of type `ty`.
+fn builtin(name: &str, args: &[&Literal], ty: &Type) -> Option<Literal> {
+    let f: fn(f64) -> f64 = match (name, args) {
+        ("abs", [Literal::Int(v)]) => return Some(Literal::Int(wrap(ty, int_in(ty, *v).abs()))),
+        ("min" | "max", [Literal::Int(a), Literal::Int(b)]) => {
+            let pick_left = (int_in(ty, *a) <= int_in(ty, *b)) == (name == "min");
+            return Some(Literal::Int(if pick_left { *a } else { *b }));
+        }
+        ("min" | "max", [a, b]) => {
+            let (a, b) = (round(ty, float(a)?), round(ty, float(b)?));
+            let pick_left = (a <= b) == (name == "min");
+            return Some(Literal::Float(if pick_left { a } else { b }));
+        }
+        (_, [_]) => match name {
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "exp" => f64::exp,
+            "log" => f64::ln,
+            "sqrt" => f64::sqrt,
+            "abs" => f64::abs,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(Literal::Float(round(ty, f(round(ty, float(args[0])?)))))
+}
+
+/// Replaces an operator or a builtin call on constants by its value.
+struct FoldConstants;
+
+impl FoldConstants {
+    fn fold(module: &Module, op: &Op) -> Option<Literal> {
+        let literal = |i: usize| constant(module, op.operands[i]);
+        let operand_ty = |i: usize| &module.value(op.operands[i]).ty;
+        match &op.kind {
+            OpKind::Binary(bin) => binary(*bin, literal(0)?, literal(1)?, operand_ty(0)),
+            OpKind::Unary(un) => unary(*un, literal(0)?, operand_ty(0)),
+            OpKind::Call => {
+                let callee = module.def_op(op.operands[0])?;
+                let OpKind::Symbol(name) = &module.op(callee).kind else {
+                    return None;
+                };
+                let args: Vec<&Literal> =
+                    (1..op.operands.len()).map(literal).collect::<Option<_>>()?;
+                builtin(name, &args, &module.value(op.results[0]).ty)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl RewritePattern for FoldConstants {
+    fn name(&self) -> &str {
+        "fold-constants"
+    }
+
+    fn benefit(&self) -> u32 {
+        2
+    }
+
+    fn match_and_rewrite(&self, rewriter: &mut Rewriter) -> bool {
+        let root = rewriter.root();
+        let op = rewriter.module.op(root);
+        let Some(literal) = Self::fold(rewriter.module, op) else {
+            return false;
+        };
+        let ty = rewriter.module.value(op.results[0]).ty.clone();
+        let value = rewriter.create(OpKind::Const(literal), vec![], ty);
+        rewriter.replace(root, value);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rewrite::apply_greedily;
+    use crate::testing::lower;
+
+    fn fold(src: &str) -> Module {
+        let mut module = lower(src);
+        assert!(apply_greedily(&mut module, &patterns(), 10).converged);
+        ir::verify(&module).unwrap();
+        module
+    }
+
+    /// Literal bound to `name`, if it was folded to one.
+    fn literal(module: &Module, name: &str) -> Option<Literal> {
+        let value = module.names.iter().find(|(n, _)| n == name)?.1;
+        constant(module, value).cloned()
+    }
+
+    #[test]
+    fn folds_the_readme_expression_in_f32() {
+        let module = fold("a:f32 = 1.+sin(pi)\nb = 1.+sin(pi)");
+        let pi = std::f64::consts::PI;
+        let sin = (pi as f32 as f64).sin() as f32 as f64;
+        assert_eq!(
+            literal(&module, "a"),
+            Some(Literal::Float((1. + sin) as f32 as f64))
+        );
+        assert_eq!(literal(&module, "b"), Some(Literal::Float(1. + pi.sin())));
+        assert_eq!(module.ops().len(), 2, "{}", module);
+    }
+
+    #[test]
+    fn integers_wrap_at_their_width() {
+        let module = fold(
+            "a:i32 = 2147483647\nb = a + 1\n\
+             c:u32 = 0\nd = c - 1\n\
+             e = 7 / 0\nf = -(2 * 3) % 4 < 0",
+        );
+        assert_eq!(literal(&module, "b"), Some(Literal::Int(i32::MIN as i64)));
+        assert_eq!(literal(&module, "d"), Some(Literal::Int(u32::MAX as i64)));
+        assert_eq!(literal(&module, "e"), None);
+        assert_eq!(literal(&module, "f"), Some(Literal::Bool(true)));
+    }
+
+    #[test]
+    fn simplifies_identities_of_the_dtype() {
+        let module = fold(
+            "f = (n:i64 => (1 * n + 0) - (n - n))\n\
+             g = (x:f64 => x * 1. + 0.)",
+        );
+        let names: Vec<&str> = module
+            .ops()
+            .iter()
+            .map(|op| module.op(*op).kind.name())
+            .collect();
+        assert_eq!(names, ["lambda", "lambda", "const", "add"], "{}", module);
+    }
+}
@@ -0,0 +1,359 @@
+//! Fusion of maps and element-wise ops into a single loop.
+//!
+//! `b.map(f).map(g) * 2.` runs three loops and materializes two
+//! intermediate tensors. Fused, it is a single `map %b, %h` whose lambda
+//! calls `f` and `g` and multiplies. A map over several inputs zips them,
+//! so that `a.map(f) + b` fuses as well.
+
+use crate::rewrite::{Rewriter, element};
+use crate::traits::{Pass, PassContext};
+use device::size_of;
+use ir::{Module, OpId, OpKind, RegionId, ValueId};
+use parser::types::{Dim, TensorShapeType, Type};
+use std::collections::HashMap;
+
+/// Decides which producers are worth fusing into their consumers.
+#[derive(Debug, Clone)]
+pub struct CostModel {
+    /// Most inputs a fused loop may read, beyond which a device runs out
+    /// of registers or DMA channels
+    pub max_inputs: usize,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel { max_inputs: 8 }
+    }
+}
+
+impl CostModel {
+    /// Whether to fuse `producer` into `consumer`, the fused loop reading
+    /// `inputs`. Ops on different devices are never fused, the loop would
+    /// run on one of them and move its elements one by one. A producer
+    /// used elsewhere is materialized anyway, it is only computed again in
+    /// the loop when reading its inputs costs fewer bytes than reading it.
+    fn fuse(
+        &self,
+        module: &Module,
+        producer: &Kernel,
+        consumer: &Kernel,
+        inputs: &[ValueId],
+    ) -> bool {
+        let (p, c) = (module.op(producer.op), module.op(consumer.op));
+        if p.device != c.device || inputs.len() > self.max_inputs {
+            return false;
+        }
+        let result = p.results[0];
+        if single_use(module, result, consumer.op) {
+            return true;
+        }
+        let read: Option<u64> = producer
+            .inputs
+            .iter()
+            .filter(|v| !consumer.inputs.contains(v))
+            .map(|v| size_of(&module.value(*v).ty))
+            .sum();
+        matches!((size_of(&module.value(result).ty), read), (Some(saved), Some(read)) if saved > read)
+    }
+}
+
+/// An op computing the element at each index of its result from the
+/// elements at the same index of its inputs, e.g. a map, or `+` on tensors
+/// of the same shape. Its other operands are the same for every element.
+struct Kernel {
+    op: OpId,
+    inputs: Vec<ValueId>,
+}
+
+impl Kernel {
+    fn of(module: &Module, op: OpId) -> Option<Kernel> {
+        let data = module.op(op);
+        let inputs = match data.kind {
+            OpKind::Map => data.operands[..data.operands.len() - 1].to_vec(),
+            OpKind::Binary(_) | OpKind::Unary(_) => {
+                let dims = shape(&module.value(data.results[0]).ty)?;
+                let mut inputs = Vec::new();
+                for operand in &data.operands {
+                    let ty = &module.value(*operand).ty;
+                    match shape(ty) {
+                        Some(operand_dims) if operand_dims == dims => inputs.push(*operand),
+                        // broadcast
+                        Some(_) => return None,
+                        None if is_scalar(ty) => {}
+                        None => return None,
+                    }
+                }
+                inputs
+            }
+            _ => return None,
+        };
+        Some(Kernel { op, inputs })
+    }
+
+    /// Emit into `region` the element of the result given those of the
+    /// inputs in `elements`.
+    fn emit(
+        &self,
+        module: &mut Module,
+        region: RegionId,
+        elements: &HashMap<ValueId, ValueId>,
+    ) -> ValueId {
+        let data = module.op(self.op);
+        let ty = element(&module.value(data.results[0]).ty);
+        let element = |v: &ValueId| elements.get(v).copied().unwrap_or(*v);
+        let (kind, operands) = match &data.kind {
+            OpKind::Map => {
+                let (f, inputs) = data.operands.split_last().unwrap();
+                let operands = std::iter::once(*f).chain(inputs.iter().map(element));
+                (OpKind::Call, operands.collect())
+            }
+            kind => (kind.clone(), data.operands.iter().map(element).collect()),
+        };
+        let (span, device) = (data.span, data.device.clone());
+        let value = module.push(region, kind, operands, ty);
+        let op = module.def_op(value).unwrap();
+        module.op_mut(op).span = span;
+        module.op_mut(op).device = device;
+        value
+    }
+}
+
+/// Dims of a tensor of known rank.
+fn shape(ty: &Type) -> Option<&[Dim]> {
+    match ty {
+        Type::Tensor {
+            shape: TensorShapeType::Shape(dims),
+            ..
+        } => Some(dims),
+        _ => None,
+    }
+}
+
+fn is_scalar(ty: &Type) -> bool {
+    matches!(
+        ty,
+        Type::F32
+            | Type::F64
+            | Type::I32
+            | Type::U32
+            | Type::I64
+            | Type::U64
+            | Type::Char
+            | Type::Bool
+    )
+}
+
+/// Whether `value` is only used by `op`, names aside.
+fn single_use(module: &Module, value: ValueId, op: OpId) -> bool {
+    module.users(value) == [op] && !is_result(module, value)
+}
+
+fn is_result(module: &Module, value: ValueId) -> bool {
+    module
+        .functions()
+        .iter()
+        .any(|r| module.region(*r).results.contains(&value))
+}
+
+/// Fuses chains of maps and element-wise ops on tensors into single maps,
+/// as long as the cost model agrees. Moves are never fused, so the loops
+/// stop at the `@` of the program. Names bound to a fused intermediate
+/// are dropped along with it.
+#[derive(Default)]
+pub struct Fuse {
+    pub cost: CostModel,
+}
+
+impl Fuse {
+    pub fn new(cost: CostModel) -> Self {
+        Fuse { cost }
+    }
+
+    /// Fuse into `op` the first of its producers worth it, returning the
+    /// fused op.
+    fn step(&self, module: &mut Module, op: OpId) -> Option<OpId> {
+        let consumer = Kernel::of(module, op)?;
+        for input in &consumer.inputs {
+            let Some(def) = module.def_op(*input) else {
+                continue;
+            };
+            if module.op(def).parent != module.op(op).parent {
+                continue;
+            }
+            let Some(producer) = Kernel::of(module, def) else {
+                continue;
+            };
+            let mut inputs = Vec::new();
+            for v in &consumer.inputs {
+                let replaced = if v == input {
+                    &producer.inputs[..]
+                } else {
+                    std::slice::from_ref(v)
+                };
+                for v in replaced {
+                    if !inputs.contains(v) {
+                        inputs.push(*v);
+                    }
+                }
+            }
+            if self.cost.fuse(module, &producer, &consumer, &inputs) {
+                return Some(fuse(module, &producer, &consumer, inputs));
+            }
+        }
+        None
+    }
+}
+
+/// Replace `consumer` by a map over `inputs` computing `producer` and
+/// `consumer` element by element.
+fn fuse(module: &mut Module, producer: &Kernel, consumer: &Kernel, inputs: Vec<ValueId>) -> OpId {
+    let intermediate = module.op(producer.op).results[0];
+    let ty = module.value(module.op(consumer.op).results[0]).ty.clone();
+    let params: Vec<Type> = inputs
+        .iter()
+        .map(|v| element(&module.value(*v).ty))
+        .collect();
+    let fn_ty = Type::Function {
+        params: params.clone(),
+        ret: Box::new(element(&ty)),
+    };
+    let device = module.op(consumer.op).device.clone();
+    let mut rewriter = Rewriter::new(module, consumer.op);
+    let f = rewriter.lambda(params, fn_ty, |module, region, params| {
+        let mut elements: HashMap<ValueId, ValueId> =
+            inputs.iter().copied().zip(params.iter().copied()).collect();
+        let e = producer.emit(module, region, &elements);
+        elements.insert(intermediate, e);
+        consumer.emit(module, region, &elements)
+    });
+    let operands = inputs.iter().copied().chain([f]).collect();
+    let map = rewriter.create(OpKind::Map, operands, ty);
+    for value in [f, map] {
+        let op = rewriter.module.def_op(value).unwrap();
+        rewriter.module.op_mut(op).device = device.clone();
+    }
+    rewriter.replace(consumer.op, map);
+    let module = &mut *rewriter.module;
+    if module.op(producer.op).parent.is_some()
+        && module.users(intermediate).is_empty()
+        && !is_result(module, intermediate)
+    {
+        // only names are left
+        module.names.retain(|(_, v)| *v != intermediate);
+        rewriter.erase(producer.op);
+    }
+    rewriter.module.def_op(map).unwrap()
+}
+
+impl Pass for Fuse {
+    fn name(&self) -> &str {
+        "fuse"
+    }
+
+    fn description(&self) -> &str {
+        "Fuse maps and element-wise ops into single loops"
+    }
+
+    fn run_on_function(
+        &mut self,
+        module: &mut Module,
+        function: RegionId,
+        _: &mut PassContext,
+    ) -> bool {
+        let mut changed = false;
+        for op in module.region(function).ops.clone() {
+            // skip the producers fused into earlier ops
+            if module.op(op).parent != Some(function) {
+                continue;
+            }
+            let mut op = op;
+            while let Some(fused) = self.step(module, op) {
+                op = fused;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::lower;
+
+    fn kinds(module: &Module) -> Vec<&'static str> {
+        module
+            .region(module.body)
+            .ops
+            .iter()
+            .map(|op| module.op(*op).kind.name())
+            .collect()
+    }
+
+    #[test]
+    fn fuses_maps_and_elementwise_ops() {
+        let src = "b = [1., 2., 3.]\n\
+                   c = b.map(x => x * 2.).map(x => x + 1.) + b\n\
+                   -c * 3.";
+        let mut module = lower(src);
+        assert!(Fuse::default().run_on_module(&mut module, &mut PassContext::default()));
+        ir::verify(&module).unwrap();
+
+        let body = kinds(&module);
+        assert_eq!(
+            body.iter().filter(|k| **k == "map").count(),
+            1,
+            "{}",
+            module
+        );
+        assert!(
+            !body.contains(&"add") && !body.contains(&"mul"),
+            "{}",
+            module
+        );
+        let map = module.def_op(module.output().unwrap()).unwrap();
+        assert_eq!(module.op(map).kind, OpKind::Map);
+        // `b` is read once, by the fused loop
+        assert_eq!(module.op(map).operands.len(), 2);
+        let names: Vec<&str> = module.names.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["b"]);
+        assert!(!Fuse::default().run_on_module(&mut module, &mut PassContext::default()));
+    }
+
+    #[test]
+    fn stops_at_devices_and_at_costly_producers() {
+        let mut module = ir::parse(
+            "module {
+               %x = symbol xs : tensor(f32, 4)
+               %f = symbol sin : (f32) => f32
+               %g = symbol wide : (f32) => f64
+               %0 = map %x, %f : tensor(f32, 4)
+               %1 = move %0 : tensor(f32, 4) @xpu
+               %2 = neg %1 : tensor(f32, 4) @xpu
+               %3 = map %x, %f : tensor(f32, 4) @xpu
+               %4 = add %2, %3 : tensor(f32, 4)
+               %5 = neg %0 : tensor(f32, 4)
+               %6 = map %x, %g : tensor(f64, 4)
+               %7 = neg %6 : tensor(f64, 4)
+               %8 = neg %6 : tensor(f64, 4)
+               %t = tuple %4, %5, %7, %8 : (tensor(f32, 4), tensor(f32, 4), tensor(f64, 4), tensor(f64, 4))
+               yield %t
+             }",
+        )
+        .unwrap();
+        assert!(Fuse::default().run_on_module(&mut module, &mut PassContext::default()));
+        ir::verify(&module).unwrap();
+        let text = module.to_string();
+        // the move stops %2, %2 and %3 run on another device than %4, and
+        // %5 would read %x as much as %0. %6 is wider than %x, so %7 and
+        // %8 compute it again rather than read it
+        let body = kinds(&module);
+        let count = |kind: &str| body.iter().filter(|k| **k == kind).count();
+        assert_eq!(
+            (count("map"), count("neg"), count("add"), count("lambda")),
+            (4, 2, 1, 2),
+            "{}",
+            text
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Inlining of lambdas at their calls.
+//!
+//! Every application lowers to a `call`, so `(x => x + 1)(2)` and `f(2)`
+//! for a named `f` are inlined alike: the ops of the body are copied
+//! before the call, the params standing for the arguments. The copies
+//! define fresh values, which no value of the call site can capture.
+//! Lambdas left unused are removed by `dce`.
+
+use crate::traits::{Pass, PassContext};
+use ir::{Module, OpId, OpKind, RegionId, ValueId};
+use parser::types::Type;
+use std::collections::HashMap;
+use typeck::unify::substitute;
+
+/// Inlines the calls of lambdas defined in the program, the calls of
+/// `symbol`s being left to the backends. A lambda applied only once is
+/// beta reduced whatever its size, others when their body has at most
+/// `max_size` ops. Tuple params `(a, b) => ...` take the items of a tuple
+/// passed as a single argument.
+pub struct Inline {
+    pub max_size: usize,
+}
+
+impl Default for Inline {
+    fn default() -> Self {
+        Inline { max_size: 16 }
+    }
+}
+
+impl Inline {
+    pub fn new(max_size: usize) -> Self {
+        Inline { max_size }
+    }
+
+    /// Body of the lambda called by `call` if it is worth inlining there.
+    fn callee(&self, module: &Module, call: OpId) -> Option<RegionId> {
+        let callee = module.op(call).operands[0];
+        let lambda = module.def_op(callee)?;
+        if module.op(lambda).kind != OpKind::Lambda {
+            return None;
+        }
+        let body = module.op(lambda).regions[0];
+        if module.is_nested_in(module.op(call).parent?, body) {
+            return None;
+        }
+        let applied_once = module.users(callee) == [call]
+            && module
+                .functions()
+                .iter()
+                .all(|r| !module.region(*r).results.contains(&callee));
+        (applied_once || size(module, body) <= self.max_size).then_some(body)
+    }
+}
+
+/// Ops of `region` and of the regions nested in it.
+fn size(module: &Module, region: RegionId) -> usize {
+    module.region(region).ops.len()
+        + module
+            .region(region)
+            .ops
+            .iter()
+            .flat_map(|op| &module.op(*op).regions)
+            .map(|r| size(module, *r))
+            .sum::<usize>()
+}
+
+/// Bind the type variables of `generic` to the types at the same place in
+/// `ty`.
+fn bind(generic: &Type, ty: &Type, types: &mut HashMap<u32, Type>) {
+    match (generic, ty) {
+        (Type::Var(_), Type::Var(_)) => {}
+        (Type::Var(v), ty) => {
+            types.entry(*v).or_insert_with(|| ty.clone());
+        }
+        (Type::List(a), Type::List(b))
+        | (Type::Tensor { dtype: a, .. }, Type::Tensor { dtype: b, .. }) => bind(a, b, types),
+        (Type::Tuple(a), Type::Tuple(b)) => {
+            a.iter().zip(b).for_each(|(a, b)| bind(a, b, types));
+        }
+        (Type::Function { params: a, ret: r }, Type::Function { params: b, ret: s }) => {
+            a.iter().zip(b).for_each(|(a, b)| bind(a, b, types));
+            bind(r, s, types);
+        }
+        _ => {}
+    }
+}
+
+/// Copy `body` in place of `call`, returning whether the arguments fit
+/// the params.
+fn inline(module: &mut Module, call: OpId, body: RegionId) -> bool {
+    let data = module.op(call);
+    let (result, device, span) = (data.results[0], data.device.clone(), data.span);
+    let mut args = data.operands[1..].to_vec();
+    let params = module.region(body).params.clone();
+    if args.len() != params.len() {
+        let tuple = match args[..] {
+            [tuple] => tuple,
+            _ => return false,
+        };
+        let items = match &module.value(tuple).ty {
+            Type::Tuple(items) if items.len() == params.len() => items.clone(),
+            _ => return false,
+        };
+        args = items
+            .into_iter()
+            .enumerate()
+            .map(|(i, ty)| {
+                let op = module.create_op(OpKind::Extract(i), vec![tuple], vec![ty]);
+                module.op_mut(op).device = device.clone();
+                module.op_mut(op).span = span;
+                module.insert_op_before(call, op);
+                module.op(op).results[0]
+            })
+            .collect();
+    }
+
+    // the type variables of a generic lambda take the types of the call
+    let mut types = HashMap::new();
+    for (param, arg) in params.iter().zip(&args) {
+        bind(&module.value(*param).ty, &module.value(*arg).ty, &mut types);
+    }
+    let yielded = module.region(body).results[0];
+    bind(
+        &module.value(yielded).ty,
+        &module.value(result).ty,
+        &mut types,
+    );
+
+    let mut values: HashMap<ValueId, ValueId> = params.iter().copied().zip(args).collect();
+    for op in module.region(body).ops.clone() {
+        let copy = module.copy_op(op, &mut values);
+        if module.op(copy).device.is_none() {
+            module.op_mut(copy).device = device.clone();
+        }
+        module.insert_op_before(call, copy);
+    }
+    for (old, new) in &values {
+        if !params.contains(old) {
+            let ty = substitute(&module.value(*new).ty, &types);
+            module.value_mut(*new).ty = ty;
+        }
+    }
+    let value = *values.get(&yielded).unwrap_or(&yielded);
+    module.replace_all_uses(result, value);
+    module.erase_op(call);
+    true
+}
+
+impl Pass for Inline {
+    fn name(&self) -> &str {
+        "inline"
+    }
+
+    fn description(&self) -> &str {
+        "Inline lambdas applied once and small ones"
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, _: &mut PassContext) -> bool {
+        let mut changed = false;
+        loop {
+            let mut inlined = false;
+            for op in module.ops() {
+                if module.op(op).kind != OpKind::Call {
+                    continue;
+                }
+                if let Some(body) = self.callee(module, op) {
+                    inlined |= inline(module, op, body);
+                }
+            }
+            if !inlined {
+                return changed;
+            }
+            changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dce::Dce;
+    use crate::testing::lower;
+
+    #[test]
+    fn inlines_named_functions_into_lambdas() {
+        let src = "f = (x => sin(x) + cos(x))\n\
+                   b = [1., 2.]\n\
+                   c = b.map(x => f(x) + 1.)\n\
+                   c";
+        let mut module = lower(src);
+        let cx = &mut PassContext::default();
+        assert!(Inline::default().run_on_module(&mut module, cx));
+        Dce.run_on_module(&mut module, cx);
+        ir::verify(&module).unwrap();
+
+        let lambdas: Vec<OpId> = module
+            .ops()
+            .into_iter()
+            .filter(|op| module.op(*op).kind == OpKind::Lambda)
+            .collect();
+        assert_eq!(lambdas.len(), 1, "{}", module);
+        let body = module.op(lambdas[0]).regions[0];
+        let kinds: Vec<&str> = module
+            .region(body)
+            .ops
+            .iter()
+            .map(|op| module.op(*op).kind.name())
+            .collect();
+        assert_eq!(
+            kinds,
+            ["symbol", "call", "symbol", "call", "add", "const", "add"]
+        );
+        assert!(!Inline::default().run_on_module(&mut module, cx));
+    }
+
+    #[test]
+    fn spreads_tuples_and_specializes_generic_lambdas() {
+        let mut module = ir::parse(
+            "module {
+               %g = lambda (%a: 'a, %b: 'a) : ('a, 'a) => 'a {
+                 %m = mul %a, %b : 'a
+                 %s = add %m, %m : 'a
+                 yield %s
+               }
+               %p = symbol p : (f32, f32)
+               %0 = call %g, %p : f32 @xpu
+               %1 = const 2 : i64
+               %2 = call %g, %1, %1 : i64
+               %t = tuple %0, %2 : (f32, i64)
+               yield %t
+             }",
+        )
+        .unwrap();
+        // `g` is applied twice and its body has more than one op
+        assert!(!Inline::new(1).run_on_module(&mut module, &mut PassContext::default()));
+        assert!(Inline::new(2).run_on_module(&mut module, &mut PassContext::default()));
+        ir::verify(&module).unwrap();
+        let text = module.to_string();
+        assert!(!text.contains("call"), "{}", text);
+        assert!(text.contains("%8 = mul %6, %7 : f32 @xpu"), "{}", text);
+        assert!(text.contains("%7 = extract %5[1] : f32 @xpu"), "{}", text);
+        assert!(text.contains("%11 = mul %10, %10 : i64"), "{}", text);
+    }
+
+    #[test]
+    fn spreads_a_tuple_passed_to_a_lambda_of_the_source() {
+        let mut module = lower("x = (1, 2)\ny = ((a, b) => a + b)(x)\ny");
+        let cx = &mut PassContext::default();
+        assert!(Inline::default().run_on_module(&mut module, cx));
+        Dce.run_on_module(&mut module, cx);
+        ir::verify(&module).unwrap();
+        let text = module.to_string();
+        assert!(!text.contains("call"), "{}", text);
+        assert!(text.contains("%4 = extract %2[1] : i64"), "{}", text);
+        assert!(text.contains("%5 = add %3, %4 : i64"), "{}", text);
+    }
+}
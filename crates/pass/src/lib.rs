@@ -1,14 +1,31 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+//! Passes over the IR and the manager running them.
+//!
+//! A `Pass` transforms a `Module` one function at a time or as a whole.
+//! A `PassManager` runs a pipeline of passes, built directly or parsed
+//! from a spec such as `"inline,fuse,place-devices"` whose names are
+//! looked up in a `PassRegistry`. It times each pass and verifies the IR
+//! in between. Passes written outside of the compiler are loaded from
+//! shared libraries by the `plugin` module. Rewrites of op patterns are
+//! declared with the `rewrite` module, or written in LasMiao as rules
+//! compiled by the `rule` module.
+
+pub mod cse;
+pub mod dce;
+pub mod fold;
+pub mod fuse;
+pub mod inline;
+pub mod manager;
+pub mod place;
+pub mod plugin;
+pub mod registry;
+pub mod rewrite;
+pub mod rule;
+pub mod traits;
 
 #[cfg(test)]
-mod tests {
-    use super::*;
+mod testing;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub use manager::{PassManager, PassRecord, Report};
+pub use registry::PassRegistry;
+pub use rewrite::{DagRewrite, Pattern, RewritePass, RewritePattern, Rewriter};
+pub use traits::{Pass, PassContext};
@@ -0,0 +1,263 @@
+use crate::registry::PassRegistry;
+use crate::traits::{Pass, PassContext};
+use device::Target;
+use diagnostics::Diagnostic;
+use ir::Module;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// What one pass of a pipeline did.
+#[derive(Debug, Clone)]
+pub struct PassRecord {
+    pub name: String,
+    pub elapsed: Duration,
+    pub changed: bool,
+    /// Text of the IR after the pass, when dumping
+    pub ir: Option<String>,
+}
+
+/// Outcome of a successful pipeline.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub passes: Vec<PassRecord>,
+    /// Warnings reported by the passes
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    pub fn total(&self) -> Duration {
+        self.passes.iter().map(|p| p.elapsed).sum()
+    }
+}
+
+impl fmt::Display for Report {
+    /// Time taken by each pass, e.g. `  0.012ms  changed  fuse`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| format!("{:.3}ms", d.as_secs_f64() * 1000.0);
+        for pass in &self.passes {
+            let changed = if pass.changed { "changed" } else { "" };
+            writeln!(f, "{:>10}  {:<7}  {}", ms(pass.elapsed), changed, pass.name)?;
+        }
+        writeln!(f, "{:>10}           total", ms(self.total()))
+    }
+}
+
+/// Runs passes in order on a module.
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    verify: bool,
+    dump: bool,
+    target: Target,
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        PassManager {
+            passes: Vec::new(),
+            verify: true,
+            dump: false,
+            target: Target::default(),
+        }
+    }
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pipeline of the comma separated pass names in `spec`, e.g.
+    /// `"inline,fuse,place-devices"`.
+    pub fn parse(spec: &str, registry: &PassRegistry) -> Result<Self, String> {
+        let mut manager = Self::new();
+        if spec.trim().is_empty() {
+            return Ok(manager);
+        }
+        for name in spec.split(',').map(str::trim) {
+            if name.is_empty() {
+                return Err(format!("empty pass name in pipeline `{}`", spec));
+            }
+            let pass = registry.create(name).ok_or_else(|| {
+                let known: Vec<_> = registry.passes().map(|(name, _)| name).collect();
+                format!(
+                    "unknown pass `{}`, expect one of: {}",
+                    name,
+                    known.join(", ")
+                )
+            })?;
+            manager.passes.push(pass);
+        }
+        Ok(manager)
+    }
+
+    pub fn add(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    /// Verify the IR before the first pass and after each one (on by
+    /// default).
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Keep the text of the IR after each pass in the report.
+    pub fn with_dump(mut self, dump: bool) -> Self {
+        self.dump = dump;
+        self
+    }
+
+    /// Compile for `target` rather than the host alone.
+    pub fn with_target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.passes.iter().map(|p| p.name()).collect()
+    }
+
+    /// Run every pass in order. Stops at the first pass which reports an
+    /// error or leaves invalid IR, returning all diagnostics so far.
+    pub fn run(&mut self, module: &mut Module) -> Result<Report, Vec<Diagnostic>> {
+        if self.verify {
+            ir::verify(module).map_err(|d| vec![d.with_note("in the input of the pipeline")])?;
+        }
+        let mut cx = PassContext {
+            target: self.target.clone(),
+            ..PassContext::default()
+        };
+        let mut report = Report::default();
+        for pass in &mut self.passes {
+            let start = Instant::now();
+            let changed = pass.run_on_module(module, &mut cx);
+            let elapsed = start.elapsed();
+            if cx.has_errors() {
+                return Err(cx.diagnostics);
+            }
+            if self.verify
+                && let Err(diag) = ir::verify(module)
+            {
+                let diag = diag.with_note(format!("after the pass `{}`", pass.name()));
+                cx.diagnostics.push(diag);
+                return Err(cx.diagnostics);
+            }
+            report.passes.push(PassRecord {
+                name: pass.name().to_string(),
+                elapsed,
+                changed,
+                ir: self.dump.then(|| module.to_string()),
+            });
+        }
+        report.diagnostics = cx.diagnostics;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ir::{Literal, OpKind, RegionId};
+
+    /// Turns every integer constant into `1`.
+    struct Ones;
+
+    impl Pass for Ones {
+        fn name(&self) -> &str {
+            "ones"
+        }
+
+        fn run_on_function(
+            &mut self,
+            module: &mut Module,
+            function: RegionId,
+            _: &mut PassContext,
+        ) -> bool {
+            let mut changed = false;
+            for op in module.region(function).ops.clone() {
+                if let OpKind::Const(Literal::Int(v)) = &mut module.op_mut(op).kind
+                    && *v != 1
+                {
+                    *v = 1;
+                    changed = true;
+                }
+            }
+            changed
+        }
+    }
+
+    /// Leaves an op using a value which is not defined.
+    struct Breaks;
+
+    impl Pass for Breaks {
+        fn name(&self) -> &str {
+            "breaks"
+        }
+
+        fn run_on_module(&mut self, module: &mut Module, _: &mut PassContext) -> bool {
+            let body = module.body;
+            module.region_mut(body).ops.reverse();
+            true
+        }
+    }
+
+    fn registry() -> PassRegistry {
+        let mut registry = PassRegistry::with_builtins();
+        registry.register("ones", "", || Box::new(Ones));
+        registry.register("breaks", "", || Box::new(Breaks));
+        registry
+    }
+
+    fn module() -> Module {
+        ir::parse(
+            "module {
+               %0 = const 2 : i64
+               %1 = lambda (%2: i64) : (i64) => i64 {
+                 %3 = const 3 : i64
+                 %4 = add %2, %3 : i64
+                 yield %4
+               }
+               %5 = call %1, %0 : i64
+               yield %5
+             }",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn runs_a_pipeline_spec_in_order() {
+        let mut manager = PassManager::parse(" ones , verify,ones", &registry())
+            .unwrap()
+            .with_dump(true);
+        assert_eq!(manager.names(), ["ones", "verify", "ones"]);
+
+        let mut module = module();
+        let report = manager.run(&mut module).unwrap();
+        let changed: Vec<_> = report.passes.iter().map(|p| p.changed).collect();
+        assert_eq!(changed, [true, false, false]);
+        let ir = report.passes[0].ir.as_ref().unwrap();
+        assert!(ir.contains("%0 = const 1 : i64"), "{}", ir);
+        assert!(ir.contains("%3 = const 1 : i64"), "{}", ir);
+        assert!(report.to_string().ends_with("total\n"));
+    }
+
+    #[test]
+    fn reports_bad_specs_and_invalid_ir() {
+        let error = PassManager::parse("ones,frob", &registry()).err().unwrap();
+        assert_eq!(
+            error,
+            "unknown pass `frob`, expect one of: breaks, cse, dce, fold, fuse, inline, ones, place-devices, verify"
+        );
+        assert!(PassManager::parse("ones,,ones", &registry()).is_err());
+
+        let mut manager = PassManager::parse("ones,breaks,ones", &registry()).unwrap();
+        let diags = manager.run(&mut module()).unwrap_err();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].notes, ["after the pass `breaks`"]);
+
+        let mut manager = PassManager::parse("breaks", &registry())
+            .unwrap()
+            .with_verify(false);
+        assert!(manager.run(&mut module()).is_ok());
+    }
+}
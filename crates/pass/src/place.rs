@@ -0,0 +1,470 @@
+//! Placement of ops on the devices of the target.
+
+use crate::traits::{Pass, PassContext};
+use device::{Device, Target, size_of};
+use diagnostics::{Diagnostic, ErrorKind};
+use ir::{Module, OpId, OpKind, ValueDef, ValueId};
+use parser::types::Type;
+use std::collections::HashMap;
+
+/// Places every op on a device of the target, then inserts a `move`
+/// wherever an op uses a value from another device.
+///
+/// Ops placed by `@` keep their device, and the inputs of the program
+/// start on the host. Other ops go where copying their operands costs the
+/// fewest cycles, among the devices which can run them, the host winning
+/// ties. Constants, builtins and lambdas are copied to every device using
+/// them, and the body of a lambda runs where the lambda is. Moves to the
+/// device a value is already on are removed, and moves between devices no
+/// link connects are errors.
+pub struct PlaceDevices;
+
+/// Whether `op` is cheap enough to be copied to its users rather than
+/// moved: constants, builtin functions and lambdas.
+fn is_free(module: &Module, op: OpId) -> bool {
+    let data = module.op(op);
+    match &data.kind {
+        OpKind::Const(_) | OpKind::Lambda => true,
+        OpKind::Symbol(_) => matches!(module.value(data.results[0]).ty, Type::Function { .. }),
+        _ => false,
+    }
+}
+
+/// Whether `device` can run `op`, along with the lambdas it applies.
+fn runs(module: &Module, op: OpId, device: &Device) -> bool {
+    let data = module.op(op);
+    if is_free(module, op) {
+        return true;
+    }
+    if !device.supports_op(data.kind.name()) {
+        return false;
+    }
+    if let OpKind::Buffer { space, .. } = &data.kind
+        && device.memory(space).is_none()
+    {
+        return false;
+    }
+    let types = data.operands.iter().chain(&data.results);
+    if !types
+        .into_iter()
+        .all(|v| device.supports_type(&module.value(*v).ty))
+    {
+        return false;
+    }
+    if matches!(data.kind, OpKind::Map | OpKind::Call) {
+        for operand in &data.operands {
+            if let Some(def) = module.def_op(*operand)
+                && module.op(def).kind == OpKind::Lambda
+                && !module
+                    .walk(module.op(def).regions[0])
+                    .into_iter()
+                    .all(|op| runs(module, op, device))
+            {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Put the ops of the regions of `op` on `device`, unless placed already.
+fn place_regions(module: &mut Module, op: OpId, device: &str) {
+    for region in module.op(op).regions.clone() {
+        for inner in module.walk(region) {
+            module
+                .op_mut(inner)
+                .device
+                .get_or_insert_with(|| device.to_string());
+        }
+    }
+}
+
+impl PlaceDevices {
+    /// Device of the target where `op` costs the least.
+    fn choose(&self, module: &Module, op: OpId, target: &Target, cx: &mut PassContext) -> String {
+        let data = module.op(op);
+        if matches!(data.kind, OpKind::Symbol(_)) {
+            return target.host.clone();
+        }
+        let cost = |device: &Device| -> Option<u64> {
+            let mut cycles = 0u64;
+            for operand in &data.operands {
+                let Some(from) = module.device_of(*operand) else {
+                    continue;
+                };
+                let bytes = size_of(&module.value(*operand).ty).unwrap_or(0);
+                cycles =
+                    cycles.saturating_add(target.transfer_cycles(from, &device.name, bytes)?);
+            }
+            Some(cycles)
+        };
+        let runners: Vec<&Device> = target
+            .devices()
+            .filter(|device| runs(module, op, device))
+            .collect();
+        let best = runners
+            .iter()
+            .filter_map(|device| Some((cost(device)?, device.name != target.host, *device)))
+            .min_by_key(|(cycles, not_host, _)| (*cycles, *not_host));
+        match best {
+            Some((_, _, device)) => device.name.clone(),
+            None if !runners.is_empty() => {
+                let names: Vec<&str> = runners.iter().map(|d| d.name.as_str()).collect();
+                cx.report(
+                    Diagnostic::error(
+                        ErrorKind::InvalidPlacement,
+                        format!(
+                            "no link of the target copies the operands of `{}` to a device running it",
+                            data.kind.name()
+                        ),
+                        data.span.unwrap_or_default(),
+                    )
+                    .with_note(format!("it runs on: {}", names.join(", "))),
+                );
+                target.host.clone()
+            }
+            None => {
+                let message = match &data.kind {
+                    OpKind::Buffer { space, .. } => format!(
+                        "no device of the target has a memory `{}`, the buffer stays on the host",
+                        space
+                    ),
+                    kind => format!(
+                        "no device of the target runs `{}` on {}, it stays on the host",
+                        kind.name(),
+                        module.value(data.results[0]).ty
+                    ),
+                };
+                cx.report(Diagnostic::warning(
+                    ErrorKind::InvalidPlacement,
+                    message,
+                    data.span.unwrap_or_default(),
+                ));
+                target.host.clone()
+            }
+        }
+    }
+
+    /// Place the free `op` on the devices of its users, with a copy for
+    /// each device after the first.
+    fn follow_users(&self, module: &mut Module, op: OpId, host: &str) {
+        let value = module.op(op).results[0];
+        let users = module.users(value);
+        let mut devices: Vec<String> = Vec::new();
+        for user in &users {
+            let device = module.op(*user).device.as_deref().unwrap_or(host);
+            if !devices.iter().any(|d| d == device) {
+                devices.push(device.to_string());
+            }
+        }
+        if devices.is_empty() {
+            devices.push(host.to_string());
+        }
+        for device in &devices[1..] {
+            let copy = module.copy_op(op, &mut HashMap::new());
+            module.insert_op_after(op, copy);
+            let copied = module.op(copy).results[0];
+            for user in &users {
+                if module.op(*user).device.as_deref().unwrap_or(host) == device {
+                    for operand in &mut module.op_mut(*user).operands {
+                        if *operand == value {
+                            *operand = copied;
+                        }
+                    }
+                }
+            }
+            module.op_mut(copy).device = Some(device.clone());
+            place_regions(module, copy, device);
+        }
+        module.op_mut(op).device = Some(devices[0].clone());
+        place_regions(module, op, &devices[0]);
+    }
+}
+
+/// Copy `value` to `device` right after it is computed.
+fn insert_move(module: &mut Module, value: ValueId, device: &str) -> ValueId {
+    let ty = module.value(value).ty.clone();
+    let op = module.create_op(OpKind::Move, vec![value], vec![ty]);
+    module.op_mut(op).device = Some(device.to_string());
+    match module.value(value).def {
+        ValueDef::Result(def, _) => {
+            module.op_mut(op).span = module.op(def).span;
+            module.insert_op_after(def, op);
+        }
+        ValueDef::Param(region, _) => {
+            module.region_mut(region).ops.insert(0, op);
+            module.op_mut(op).parent = Some(region);
+        }
+    }
+    module.op(op).results[0]
+}
+
+impl Pass for PlaceDevices {
+    fn name(&self) -> &str {
+        "place-devices"
+    }
+
+    fn description(&self) -> &str {
+        "Place ops on the devices of the target and insert transfers"
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, cx: &mut PassContext) -> bool {
+        let target = cx.target.clone();
+        for op in module.ops() {
+            let data = module.op(op);
+            if let Some(device) = &data.device
+                && target.device(device).is_none()
+            {
+                let known: Vec<&str> = target.devices().map(|d| d.name.as_str()).collect();
+                cx.report(
+                    Diagnostic::error(
+                        ErrorKind::InvalidPlacement,
+                        format!("the target has no device `{}`", device),
+                        data.span.unwrap_or_default(),
+                    )
+                    .with_help(format!(
+                        "the devices of the target are: {}",
+                        known.join(", ")
+                    )),
+                );
+            }
+        }
+        if cx.has_errors() {
+            return false;
+        }
+
+        let body = module.body;
+        let before: Vec<Option<String>> = module
+            .ops()
+            .iter()
+            .map(|op| module.op(*op).device.clone())
+            .collect();
+        for op in module.region(body).ops.clone() {
+            if module.op(op).device.is_none() && !is_free(module, op) {
+                let device = self.choose(module, op, &target, cx);
+                module.op_mut(op).device = Some(device.clone());
+                place_regions(module, op, &device);
+            }
+        }
+        // last first, so that lambdas are placed before the values they
+        // capture
+        for op in module.region(body).ops.clone().into_iter().rev() {
+            if module.op(op).device.is_none() {
+                self.follow_users(module, op, &target.host);
+            }
+        }
+        let mut changed = before.len() != module.ops().len()
+            || module
+                .ops()
+                .iter()
+                .zip(&before)
+                .any(|(op, device)| module.op(*op).device != *device);
+
+        for op in module.ops() {
+            let data = module.op(op);
+            if data.kind == OpKind::Move
+                && module.device_of(data.operands[0]) == data.device.as_deref()
+            {
+                module.replace_all_uses(data.results[0], data.operands[0]);
+                module.erase_op(op);
+                changed = true;
+            }
+        }
+        let mut moves: HashMap<(ValueId, String), ValueId> = HashMap::new();
+        for op in module.ops() {
+            let data = module.op(op);
+            let Some(to) = data.device.clone() else {
+                continue;
+            };
+            if data.kind == OpKind::Move {
+                continue;
+            }
+            for (i, operand) in data.operands.clone().into_iter().enumerate() {
+                if matches!(module.value(operand).ty, Type::Function { .. }) {
+                    continue;
+                }
+                match module.device_of(operand) {
+                    Some(from) if from != to => {}
+                    _ => continue,
+                }
+                let moved = *moves
+                    .entry((operand, to.clone()))
+                    .or_insert_with(|| insert_move(module, operand, &to));
+                module.op_mut(op).operands[i] = moved;
+                changed = true;
+            }
+        }
+
+        for op in module.ops() {
+            let data = module.op(op);
+            if data.kind != OpKind::Move || module.value(data.results[0]).ty == Type::Buffer {
+                continue;
+            }
+            let to = data.device.as_deref().unwrap_or(&target.host);
+            let from = module.device_of(data.operands[0]).unwrap_or(&target.host);
+            if target.transfer_cycles(from, to, 0).is_none() {
+                cx.report(
+                    Diagnostic::error(
+                        ErrorKind::InvalidPlacement,
+                        format!(
+                            "no link of the target copies values from `{}` to `{}`",
+                            from, to
+                        ),
+                        data.span.unwrap_or_default(),
+                    )
+                    .with_help(format!(
+                        "declare a `[[links]]` from `{}` to `{}` in the target",
+                        from, to
+                    )),
+                );
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: &str = r#"
+[devices.cpu]
+memory.dram = { kind = "dram", capacity = "16GiB" }
+
+[devices.xpu]
+dtypes = ["f32"]
+ops = ["add", "mul", "map", "move", "buffer"]
+memory.sram = { kind = "sram", capacity = "256KiB" }
+
+[[links]]
+from = "cpu"
+to = "xpu"
+bandwidth = 4
+latency = 10
+
+[[links]]
+from = "xpu"
+to = "cpu"
+bandwidth = 4
+latency = 10
+"#;
+
+    fn place(src: &str) -> (Module, PassContext) {
+        let mut module = ir::parse(src).unwrap();
+        let mut cx = PassContext {
+            target: Target::parse(TARGET).unwrap(),
+            ..PassContext::default()
+        };
+        PlaceDevices.run_on_module(&mut module, &mut cx);
+        (module, cx)
+    }
+
+    #[test]
+    fn follows_annotations_and_inserts_transfers() {
+        let (module, cx) = place(
+            "module {
+               %x = symbol xs : tensor(f32, 256)
+               %m = move %x : tensor(f32, 256) @xpu
+               %c = const 2.0 : f32
+               %a = mul %m, %c : tensor(f32, 256)
+               %f = lambda (%p: f32) : (f32) => f32 {
+                 %b = add %p, %c : f32
+                 yield %b
+               }
+               %y = map %a, %f : tensor(f32, 256)
+               %n = neg %y : tensor(f32, 256)
+               %s = add %n, %x : tensor(f32, 256)
+               %q = mul %s, %c : tensor(f32, 256)
+               %z = buffer $(1024, sram) : buffer
+               %w = move %z : buffer @xpu
+               %t = tuple %q, %w : (tensor(f32, 256), buffer)
+               yield %t
+             }",
+        );
+        assert!(cx.diagnostics.is_empty(), "{:?}", cx.diagnostics);
+        ir::verify(&module).unwrap();
+        let placed: Vec<String> = module
+            .ops()
+            .iter()
+            .map(|op| {
+                let op = module.op(*op);
+                format!("{}@{}", op.kind.name(), op.device.as_deref().unwrap_or("?"))
+            })
+            .collect();
+        // the mul and the map follow %m to the xpu, which can not negate,
+        // the constant is copied to both devices using it and the buffer
+        // is allocated where its memory is
+        assert_eq!(
+            placed,
+            [
+                "symbol@cpu",
+                "move@xpu",
+                "const@xpu",
+                "const@cpu",
+                "mul@xpu",
+                "lambda@xpu",
+                "add@xpu",
+                "map@xpu",
+                "move@cpu",
+                "neg@cpu",
+                "add@cpu",
+                "mul@cpu",
+                "buffer@xpu",
+                "move@cpu",
+                "tuple@cpu",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_devices_and_warns_of_unplaceable_ops() {
+        let (_, cx) = place(
+            "module {
+               %x = symbol xs : f64
+               %m = move %x : f64 @gpu
+               yield %m
+             }",
+        );
+        assert_eq!(cx.diagnostics[0].message, "the target has no device `gpu`");
+
+        let (module, cx) = place(
+            "module {
+               %z = buffer $(64, l2) : buffer
+               yield %z
+             }",
+        );
+        assert!(!cx.has_errors());
+        assert_eq!(
+            cx.diagnostics[0].message,
+            "no device of the target has a memory `l2`, the buffer stays on the host"
+        );
+        assert_eq!(module.op(module.ops()[0]).device.as_deref(), Some("cpu"));
+
+        // the cpu negates, but nothing goes back to it from the xpu
+        let mut module = ir::parse(
+            "module {
+               %x = symbol x : f32
+               %m = move %x : f32 @xpu
+               %n = neg %m : f32
+               %r = move %m : f32 @cpu
+               %t = tuple %n, %r : (f32, f32)
+               yield %t
+             }",
+        )
+        .unwrap();
+        let mut cx = PassContext {
+            target: Target::parse(&TARGET[..TARGET.rfind("[[links]]").unwrap()]).unwrap(),
+            ..PassContext::default()
+        };
+        PlaceDevices.run_on_module(&mut module, &mut cx);
+        let messages: Vec<&str> = cx.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "no link of the target copies the operands of `neg` to a device running it",
+                "no link of the target copies values from `xpu` to `cpu`",
+                "no link of the target copies values from `xpu` to `cpu`",
+            ]
+        );
+    }
+}
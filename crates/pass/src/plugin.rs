@@ -0,0 +1,513 @@
+//! Passes loaded from shared libraries.
+//!
+//! A plugin exchanges modules with the compiler in their textual form
+//! through four C functions, so that it does not depend on the layout of
+//! any Rust type and keeps working across compiler versions:
+//!
+//! ```c
+//! uint32_t lasmiao_plugin_abi_version(void);
+//! // passes of the plugin, ended by an entry whose name is NULL
+//! const PassInfo *lasmiao_plugin_passes(void);
+//! // run the pass `name` on `ir` for the devices of `target`, described
+//! // in TOML as by `--target`; 0 when `*out` is the new IR, 1 when `*out`
+//! // is an error message, 2 when nothing changed and `*out` is NULL
+//! int32_t lasmiao_plugin_run(const char *name, const char *ir,
+//!                            const char *target, char **out);
+//! void lasmiao_plugin_free(char *text);
+//! ```
+//!
+//! A Rust plugin is a `cdylib` depending on `pass` and `ir` which
+//! implements `Pass` and exports its passes with `export_plugin!`:
+//!
+//! ```text
+//! pass::export_plugin!(MyFusion::default, MyTiling::new);
+//! ```
+//!
+//! Spans do not survive the trip through text, so diagnostics reported
+//! after a plugin pass point to the start of the file.
+
+use crate::registry::PassRegistry;
+use crate::traits::{Pass, PassContext};
+use device::Target;
+use diagnostics::{Diagnostic, ErrorKind, Span};
+use ir::Module;
+use libloading::Library;
+use std::ffi::{CStr, CString, c_char};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
+
+/// Version of the functions above, bumped when they or the textual IR
+/// change incompatibly.
+pub const ABI_VERSION: u32 = 2;
+
+pub const RUN_CHANGED: i32 = 0;
+pub const RUN_FAILED: i32 = 1;
+pub const RUN_UNCHANGED: i32 = 2;
+
+#[repr(C)]
+pub struct PassInfo {
+    pub name: *const c_char,
+    pub description: *const c_char,
+}
+
+pub type AbiVersionFn = extern "C" fn() -> u32;
+pub type PassesFn = extern "C" fn() -> *const PassInfo;
+pub type RunFn =
+    unsafe extern "C" fn(*const c_char, *const c_char, *const c_char, *mut *mut c_char) -> i32;
+pub type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+/// Entry points of a plugin.
+#[derive(Clone, Copy)]
+pub struct PluginApi {
+    pub abi_version: AbiVersionFn,
+    pub passes: PassesFn,
+    pub run: RunFn,
+    pub free: FreeFn,
+}
+
+struct Plugin {
+    api: PluginApi,
+    /// Keeps the functions of `api` loaded
+    _library: Option<Library>,
+}
+
+impl PassRegistry {
+    /// Register the passes of the shared library at `path` and return
+    /// their names.
+    pub fn load_plugin(&mut self, path: &Path) -> Result<Vec<String>, String> {
+        let error = |e: libloading::Error| format!("can not load {}: {}", path.display(), e);
+        // SAFETY: loading runs the initializers of the library, which is
+        // trusted like the compiler itself. The symbols are copied out as
+        // function pointers, valid as long as `library` is kept alive.
+        unsafe {
+            let library = Library::new(path).map_err(error)?;
+            let api = PluginApi {
+                abi_version: *library
+                    .get::<AbiVersionFn>(b"lasmiao_plugin_abi_version\0")
+                    .map_err(error)?,
+                passes: *library
+                    .get::<PassesFn>(b"lasmiao_plugin_passes\0")
+                    .map_err(error)?,
+                run: *library
+                    .get::<RunFn>(b"lasmiao_plugin_run\0")
+                    .map_err(error)?,
+                free: *library
+                    .get::<FreeFn>(b"lasmiao_plugin_free\0")
+                    .map_err(error)?,
+            };
+            self.register_plugin(api, Some(library))
+                .map_err(|e| format!("can not load {}: {}", path.display(), e))
+        }
+    }
+
+    /// Register the passes of a plugin given by its entry points.
+    ///
+    /// # Safety
+    ///
+    /// The functions of `api` must follow the plugin ABI, and stay valid
+    /// while `library` is alive.
+    pub unsafe fn register_plugin(
+        &mut self,
+        api: PluginApi,
+        library: Option<Library>,
+    ) -> Result<Vec<String>, String> {
+        let version = (api.abi_version)();
+        if version != ABI_VERSION {
+            return Err(format!(
+                "plugin ABI version {} is not supported, expect {}",
+                version, ABI_VERSION
+            ));
+        }
+        let plugin = Rc::new(Plugin {
+            api,
+            _library: library,
+        });
+        let mut names = Vec::new();
+        let mut info = (api.passes)();
+        // SAFETY: the plugin returns an array ended by a NULL name, of
+        // strings which live as long as the library
+        unsafe {
+            while !info.is_null() && !(*info).name.is_null() {
+                let name = CStr::from_ptr((*info).name).to_string_lossy().into_owned();
+                let description = match (*info).description {
+                    d if d.is_null() => String::new(),
+                    d => CStr::from_ptr(d).to_string_lossy().into_owned(),
+                };
+                let pass_name = name.clone();
+                let plugin = plugin.clone();
+                self.register(&name, &description, move || {
+                    Box::new(PluginPass {
+                        name: pass_name.clone(),
+                        plugin: plugin.clone(),
+                    })
+                });
+                names.push(name);
+                info = info.add(1);
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// A pass of a plugin, run on the text of the module.
+struct PluginPass {
+    name: String,
+    plugin: Rc<Plugin>,
+}
+
+impl Pass for PluginPass {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, cx: &mut PassContext) -> bool {
+        let failed = |message: String| {
+            Diagnostic::error(ErrorKind::PassFailed, message, Span::default())
+                .with_note(format!("in the plugin pass `{}`", self.name))
+        };
+        let name = CString::new(self.name.as_str()).expect("pass names have no NUL");
+        let Ok(text) = CString::new(module.to_string()) else {
+            cx.report(failed("the IR contains a NUL character".to_string()));
+            return false;
+        };
+        let Ok(target) = CString::new(cx.target.to_string()) else {
+            cx.report(failed("the target contains a NUL character".to_string()));
+            return false;
+        };
+        let api = self.plugin.api;
+        let mut out = ptr::null_mut();
+        // SAFETY: the strings are NUL terminated and outlive the call, and
+        // `out` is released by the plugin which allocated it
+        let (status, out) = unsafe {
+            let status = (api.run)(name.as_ptr(), text.as_ptr(), target.as_ptr(), &mut out);
+            let text = (!out.is_null()).then(|| CStr::from_ptr(out).to_string_lossy().into_owned());
+            if !out.is_null() {
+                (api.free)(out);
+            }
+            (status, text.unwrap_or_default())
+        };
+        match status {
+            RUN_CHANGED => match ir::parse(&out) {
+                Ok(new) => {
+                    *module = new;
+                    true
+                }
+                Err(diag) => {
+                    cx.report(failed(format!("returned malformed IR: {}", diag)));
+                    false
+                }
+            },
+            RUN_UNCHANGED => false,
+            _ => {
+                cx.report(failed(out));
+                false
+            }
+        }
+    }
+}
+
+/// The `PassInfo` array of a plugin, built by `export_plugin!`.
+pub struct PassTable {
+    _strings: Vec<CString>,
+    infos: Vec<PassInfo>,
+}
+
+// SAFETY: the table is never changed once built, its pointers point into
+// the strings it owns
+unsafe impl Send for PassTable {}
+unsafe impl Sync for PassTable {}
+
+impl PassTable {
+    pub fn new(passes: &[Box<dyn Pass>]) -> Self {
+        let strings: Vec<CString> = passes
+            .iter()
+            .flat_map(|p| [p.name(), p.description()])
+            .map(|s| CString::new(s).unwrap_or_default())
+            .collect();
+        let infos = strings
+            .chunks(2)
+            .map(|pair| PassInfo {
+                name: pair[0].as_ptr(),
+                description: pair[1].as_ptr(),
+            })
+            .chain(std::iter::once(PassInfo {
+                name: ptr::null(),
+                description: ptr::null(),
+            }))
+            .collect();
+        PassTable {
+            _strings: strings,
+            infos,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const PassInfo {
+        self.infos.as_ptr()
+    }
+}
+
+/// Body of `lasmiao_plugin_run` for the plugins made by `export_plugin!`.
+///
+/// # Safety
+///
+/// `name`, `ir` and `target` must be NUL terminated strings and `out`
+/// writable.
+pub unsafe fn run_exported(
+    passes: Vec<Box<dyn Pass>>,
+    name: *const c_char,
+    ir: *const c_char,
+    target: *const c_char,
+    out: *mut *mut c_char,
+) -> i32 {
+    let reply = |text: String| {
+        let text = CString::new(text.replace('\0', " ")).unwrap_or_default();
+        // SAFETY: `out` is writable by the contract of this function
+        unsafe { *out = text.into_raw() };
+    };
+    // SAFETY: they are NUL terminated by the contract of this function
+    let (name, text, target) = unsafe {
+        (
+            CStr::from_ptr(name).to_string_lossy(),
+            CStr::from_ptr(ir).to_string_lossy(),
+            CStr::from_ptr(target).to_string_lossy(),
+        )
+    };
+    let Some(mut pass) = passes.into_iter().find(|p| p.name() == name) else {
+        reply(format!("the plugin has no pass `{}`", name));
+        return RUN_FAILED;
+    };
+    let mut module = match ir::parse(&text) {
+        Ok(module) => module,
+        Err(diag) => {
+            reply(format!("can not parse the IR: {}", diag));
+            return RUN_FAILED;
+        }
+    };
+    let target = match Target::parse(&target) {
+        Ok(target) => target,
+        Err(diags) => {
+            let errors: Vec<_> = diags.iter().map(|d| d.to_string()).collect();
+            reply(format!("can not parse the target: {}", errors.join("\n")));
+            return RUN_FAILED;
+        }
+    };
+    let mut cx = PassContext {
+        target,
+        ..PassContext::default()
+    };
+    let changed = panic::catch_unwind(AssertUnwindSafe(|| {
+        pass.run_on_module(&mut module, &mut cx)
+    }));
+    match changed {
+        Err(_) => {
+            reply(format!("the pass `{}` panicked", name));
+            RUN_FAILED
+        }
+        Ok(_) if cx.has_errors() => {
+            let errors: Vec<_> = cx.diagnostics.iter().map(|d| d.to_string()).collect();
+            reply(errors.join("\n"));
+            RUN_FAILED
+        }
+        Ok(true) => {
+            reply(module.to_string());
+            RUN_CHANGED
+        }
+        Ok(false) => {
+            // SAFETY: `out` is writable by the contract of this function
+            unsafe { *out = ptr::null_mut() };
+            RUN_UNCHANGED
+        }
+    }
+}
+
+/// Body of `lasmiao_plugin_free`.
+///
+/// # Safety
+///
+/// `text` must come from `run_exported` and not have been freed.
+pub unsafe fn free_exported(text: *mut c_char) {
+    if !text.is_null() {
+        // SAFETY: `text` was made by `CString::into_raw`
+        drop(unsafe { CString::from_raw(text) });
+    }
+}
+
+/// Export the plugin ABI for passes made by the given constructors, each
+/// a `fn() -> P` where `P: Pass`.
+#[macro_export]
+macro_rules! export_plugin {
+    ($($create:expr),+ $(,)?) => {
+        fn __lasmiao_plugin_passes() -> Vec<Box<dyn $crate::Pass>> {
+            vec![$(Box::new(($create)())),+]
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn lasmiao_plugin_abi_version() -> u32 {
+            $crate::plugin::ABI_VERSION
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn lasmiao_plugin_passes() -> *const $crate::plugin::PassInfo {
+            static TABLE: std::sync::OnceLock<$crate::plugin::PassTable> =
+                std::sync::OnceLock::new();
+            TABLE
+                .get_or_init(|| $crate::plugin::PassTable::new(&__lasmiao_plugin_passes()))
+                .as_ptr()
+        }
+
+        /// # Safety
+        ///
+        /// See the plugin ABI in the `pass` crate.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn lasmiao_plugin_run(
+            name: *const std::ffi::c_char,
+            ir: *const std::ffi::c_char,
+            target: *const std::ffi::c_char,
+            out: *mut *mut std::ffi::c_char,
+        ) -> i32 {
+            unsafe {
+                $crate::plugin::run_exported(__lasmiao_plugin_passes(), name, ir, target, out)
+            }
+        }
+
+        /// # Safety
+        ///
+        /// See the plugin ABI in the `pass` crate.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn lasmiao_plugin_free(text: *mut std::ffi::c_char) {
+            unsafe { $crate::plugin::free_exported(text) }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PassManager;
+    use ir::{Literal, OpKind};
+
+    /// Doubles every integer constant.
+    #[derive(Default)]
+    struct Double;
+
+    impl Pass for Double {
+        fn name(&self) -> &str {
+            "double"
+        }
+
+        fn description(&self) -> &str {
+            "Double the integer constants"
+        }
+
+        fn run_on_module(&mut self, module: &mut Module, _: &mut PassContext) -> bool {
+            for op in module.ops() {
+                if let OpKind::Const(Literal::Int(v)) = &mut module.op_mut(op).kind {
+                    *v *= 2;
+                }
+            }
+            true
+        }
+    }
+
+    /// Records the number of devices of the target in an attribute.
+    #[derive(Default)]
+    struct CountDevices;
+
+    impl Pass for CountDevices {
+        fn name(&self) -> &str {
+            "count-devices"
+        }
+
+        fn description(&self) -> &str {
+            "Count the devices of the target"
+        }
+
+        fn run_on_module(&mut self, module: &mut Module, cx: &mut PassContext) -> bool {
+            let devices = cx.target.devices().count() as i64;
+            module.attrs.insert("devices".to_string(), devices);
+            true
+        }
+    }
+
+    export_plugin!(Double::default, CountDevices::default);
+
+    fn api() -> PluginApi {
+        PluginApi {
+            abi_version: lasmiao_plugin_abi_version,
+            passes: lasmiao_plugin_passes,
+            run: lasmiao_plugin_run,
+            free: lasmiao_plugin_free,
+        }
+    }
+
+    #[test]
+    fn runs_plugin_passes_through_the_abi() {
+        let mut registry = PassRegistry::new();
+        let names = unsafe { registry.register_plugin(api(), None) }.unwrap();
+        assert_eq!(names, ["double", "count-devices"]);
+        assert_eq!(
+            registry.passes().collect::<Vec<_>>(),
+            [
+                ("count-devices", "Count the devices of the target"),
+                ("double", "Double the integer constants")
+            ]
+        );
+
+        let mut module = ir::parse(
+            "module attributes {N = 2} {\n  %0 = const 21 : i64\n  name x = %0\n  yield %0\n}",
+        )
+        .unwrap();
+        let mut manager = PassManager::parse("double,double", &registry).unwrap();
+        manager.run(&mut module).unwrap();
+        assert_eq!(
+            module.to_string(),
+            "module attributes {N = 2} {\n  %0 = const 84 : i64\n  name x = %0\n  yield %0\n}\n"
+        );
+
+        // the plugin sees the target of the pipeline
+        let target = Target::parse("[devices.cpu]\n[devices.xpu]\n").unwrap();
+        let mut manager = PassManager::parse("count-devices", &registry)
+            .unwrap()
+            .with_target(target);
+        manager.run(&mut module).unwrap();
+        assert_eq!(module.attrs.get("devices"), Some(&2));
+    }
+
+    #[test]
+    fn reports_plugin_failures() {
+        let mut registry = PassRegistry::new();
+        let error = registry
+            .load_plugin(Path::new("/nonexistent/libplugin.so"))
+            .unwrap_err();
+        assert!(
+            error.starts_with("can not load /nonexistent/libplugin.so"),
+            "{}",
+            error
+        );
+
+        extern "C" fn future() -> u32 {
+            ABI_VERSION + 1
+        }
+        let api = PluginApi {
+            abi_version: future,
+            ..api()
+        };
+        let error = unsafe { registry.register_plugin(api, None) }.unwrap_err();
+        assert_eq!(error, "plugin ABI version 3 is not supported, expect 2");
+
+        let mut out = ptr::null_mut();
+        let name = CString::new("double").unwrap();
+        let text = CString::new("module {").unwrap();
+        let target = CString::new(Target::default().to_string()).unwrap();
+        let status =
+            unsafe { lasmiao_plugin_run(name.as_ptr(), text.as_ptr(), target.as_ptr(), &mut out) };
+        assert_eq!(status, RUN_FAILED);
+        let message = unsafe { CStr::from_ptr(out) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { lasmiao_plugin_free(out) };
+        assert!(message.starts_with("can not parse the IR"), "{}", message);
+    }
+}
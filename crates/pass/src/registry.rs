@@ -0,0 +1,91 @@
+use crate::traits::{Pass, PassContext};
+use ir::Module;
+use std::collections::BTreeMap;
+
+type Constructor = Box<dyn Fn() -> Box<dyn Pass>>;
+
+/// Passes which can be named in a pipeline spec.
+#[derive(Default)]
+pub struct PassRegistry {
+    passes: BTreeMap<String, (String, Constructor)>,
+}
+
+impl PassRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry of the passes shipped with the compiler.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        registry.register("verify", "Check that the IR is well formed", || {
+            Box::new(Verify)
+        });
+        registry.register("cse", "Merge identical pure ops", || {
+            Box::new(crate::cse::Cse)
+        });
+        registry.register("dce", "Remove unused pure ops and bindings", || {
+            Box::new(crate::dce::Dce)
+        });
+        registry.register(
+            "fold",
+            "Fold constants and simplify algebraic identities",
+            || Box::new(crate::fold::fold_pass()),
+        );
+        registry.register(
+            "fuse",
+            "Fuse maps and element-wise ops into single loops",
+            || Box::new(crate::fuse::Fuse::default()),
+        );
+        registry.register(
+            "inline",
+            "Inline lambdas applied once and small ones",
+            || Box::new(crate::inline::Inline::default()),
+        );
+        registry.register(
+            "place-devices",
+            "Place ops on the devices of the target and insert transfers",
+            || Box::new(crate::place::PlaceDevices),
+        );
+        registry
+    }
+
+    /// Make `create` available as `name`, replacing any pass of that name.
+    pub fn register<F>(&mut self, name: &str, description: &str, create: F)
+    where
+        F: Fn() -> Box<dyn Pass> + 'static,
+    {
+        self.passes.insert(
+            name.to_string(),
+            (description.to_string(), Box::new(create)),
+        );
+    }
+
+    pub fn create(&self, name: &str) -> Option<Box<dyn Pass>> {
+        self.passes.get(name).map(|(_, create)| create())
+    }
+
+    /// Names and descriptions, sorted by name.
+    pub fn passes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.passes
+            .iter()
+            .map(|(name, (description, _))| (name.as_str(), description.as_str()))
+    }
+}
+
+/// Verifies the IR at a given point of a pipeline, even when the manager
+/// does not verify between passes.
+struct Verify;
+
+impl Pass for Verify {
+    fn name(&self) -> &str {
+        "verify"
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, cx: &mut PassContext) -> bool {
+        if let Err(diag) = ir::verify(module) {
+            cx.report(diag);
+        }
+        false
+    }
+}
@@ -0,0 +1,622 @@
+//! Declarative rewrites of the IR.
+//!
+//! A `RewritePattern` matches an op and replaces it. `DagRewrite` builds
+//! one from a `Pattern` over the ops defining the operands, e.g.
+//! `map(map(x, g), f)`, constraints on what matched, and a function
+//! building the replacement. `apply_greedily` runs patterns over every op
+//! until none applies.
+
+use crate::traits::{Pass, PassContext};
+use diagnostics::{Diagnostic, ErrorKind, Span};
+use ir::{Literal, Module, OpId, OpKind, RegionId, ValueId};
+use parser::types::{TensorShapeType, Type};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Shape of the DAG of ops defining a value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// Any value, or the value bound earlier to the same name
+    Value(String),
+    /// A constant op of this literal
+    Const(Literal),
+    /// A `symbol` op of this name, e.g. the builtin `sin`
+    Symbol(String),
+    /// An op whose kind has the name `op`, e.g. `map` or `add`, with
+    /// operands matching in order, or swapped for commutative operators.
+    /// Its result is bound to `bind` if given
+    Op {
+        op: String,
+        operands: Vec<Pattern>,
+        bind: Option<String>,
+    },
+}
+
+impl Pattern {
+    pub fn value(name: &str) -> Pattern {
+        Pattern::Value(name.to_string())
+    }
+
+    pub fn op(op: &str, operands: Vec<Pattern>) -> Pattern {
+        Pattern::Op {
+            op: op.to_string(),
+            operands,
+            bind: None,
+        }
+    }
+
+    /// Bind the result of an op pattern to `name`.
+    pub fn bind(self, name: &str) -> Pattern {
+        match self {
+            Pattern::Op { op, operands, .. } => Pattern::Op {
+                op,
+                operands,
+                bind: Some(name.to_string()),
+            },
+            pattern => pattern,
+        }
+    }
+
+    /// Name of the root op, `None` when any op may match.
+    pub fn root(&self) -> Option<&str> {
+        match self {
+            Pattern::Op { op, .. } => Some(op),
+            Pattern::Const(_) => Some("const"),
+            Pattern::Symbol(_) => Some("symbol"),
+            Pattern::Value(_) => None,
+        }
+    }
+
+    /// Match `value` against the pattern, adding to the bindings of `m`.
+    pub fn matches(&self, module: &Module, value: ValueId, m: &mut Match) -> bool {
+        match self {
+            Pattern::Value(name) => m.bind(name, value),
+            Pattern::Const(literal) => module
+                .def_op(value)
+                .is_some_and(|op| matches!(&module.op(op).kind, OpKind::Const(l) if l == literal)),
+            Pattern::Symbol(name) => module
+                .def_op(value)
+                .is_some_and(|op| matches!(&module.op(op).kind, OpKind::Symbol(s) if s == name)),
+            Pattern::Op { op, operands, bind } => {
+                let Some(def) = module.def_op(value) else {
+                    return false;
+                };
+                let data = module.op(def);
+                if data.kind.name() != op || data.operands.len() != operands.len() {
+                    return false;
+                }
+                if let Some(name) = bind
+                    && !m.bind(name, value)
+                {
+                    return false;
+                }
+                m.ops.push(def);
+                let saved = m.clone();
+                let in_order = operands
+                    .iter()
+                    .zip(&data.operands)
+                    .all(|(p, v)| p.matches(module, *v, m));
+                if in_order {
+                    return true;
+                }
+                let commutative = matches!(data.kind, OpKind::Binary(op) if op.is_commutative());
+                if !commutative {
+                    return false;
+                }
+                *m = saved;
+                operands
+                    .iter()
+                    .zip(data.operands.iter().rev())
+                    .all(|(p, v)| p.matches(module, *v, m))
+            }
+        }
+    }
+}
+
+/// What a pattern bound, and the ops it went through, root first.
+#[derive(Debug, Clone, Default)]
+pub struct Match {
+    pub values: HashMap<String, ValueId>,
+    pub ops: Vec<OpId>,
+}
+
+impl Match {
+    /// Bind `name`, or check that it is bound to `value` already.
+    fn bind(&mut self, name: &str, value: ValueId) -> bool {
+        *self.values.entry(name.to_string()).or_insert(value) == value
+    }
+
+    pub fn value(&self, name: &str) -> ValueId {
+        self.values[name]
+    }
+
+    pub fn root(&self) -> OpId {
+        self.ops[0]
+    }
+}
+
+/// Changes the module on behalf of a pattern. New ops go right before the
+/// matched root.
+pub struct Rewriter<'a> {
+    pub module: &'a mut Module,
+    root: OpId,
+}
+
+impl<'a> Rewriter<'a> {
+    pub fn new(module: &'a mut Module, root: OpId) -> Self {
+        Rewriter { module, root }
+    }
+
+    pub fn root(&self) -> OpId {
+        self.root
+    }
+
+    /// Create an op with a single result before the root, located at the
+    /// root in the source.
+    pub fn create(&mut self, kind: OpKind, operands: Vec<ValueId>, ty: Type) -> ValueId {
+        let op = self.module.create_op(kind, operands, vec![ty]);
+        self.module.op_mut(op).span = self.module.op(self.root).span;
+        self.module.insert_op_before(self.root, op);
+        self.module.op(op).results[0]
+    }
+
+    /// Create a lambda before the root whose body is built by `body` from
+    /// the region and its params, and yields what `body` returns.
+    pub fn lambda(
+        &mut self,
+        params: Vec<Type>,
+        ty: Type,
+        body: impl FnOnce(&mut Module, RegionId, &[ValueId]) -> ValueId,
+    ) -> ValueId {
+        let value = self.create(OpKind::Lambda, vec![], ty);
+        let op = self.module.def_op(value).unwrap();
+        let region = self.module.add_region(op, params);
+        let params = self.module.region(region).params.clone();
+        let result = body(self.module, region, &params);
+        self.module.region_mut(region).results.push(result);
+        value
+    }
+
+    /// Replace the uses of the result of `op` by `value` and erase `op`,
+    /// along with the ops computing its operands if nothing else uses them.
+    pub fn replace(&mut self, op: OpId, value: ValueId) {
+        let result = self.module.op(op).results[0];
+        if result != value {
+            self.module.replace_all_uses(result, value);
+        }
+        self.erase(op);
+    }
+
+    /// Erase `op`, which must be unused, and the pure ops it leaves dead.
+    pub fn erase(&mut self, op: OpId) {
+        let operands = self.module.op(op).operands.clone();
+        self.module.erase_op(op);
+        for operand in operands {
+            if let Some(def) = self.module.def_op(operand)
+                && self.module.op(def).parent.is_some()
+                && self.module.is_pure(def)
+                && !self.module.is_used(operand)
+            {
+                self.erase(def);
+            }
+        }
+    }
+}
+
+/// Matches ops and rewrites them.
+pub trait RewritePattern {
+    fn name(&self) -> &str;
+
+    /// Name of the ops the pattern can apply to, `None` for any op.
+    fn root(&self) -> Option<&str> {
+        None
+    }
+
+    /// Patterns with a higher benefit are tried first.
+    fn benefit(&self) -> u32 {
+        1
+    }
+
+    /// Rewrite `rewriter.root()` if it matches, and return whether it did.
+    fn match_and_rewrite(&self, rewriter: &mut Rewriter) -> bool;
+}
+
+type Constraint = Box<dyn Fn(&Module, &Match) -> bool>;
+type Build = Box<dyn Fn(&mut Rewriter, &Match) -> Option<ValueId>>;
+
+/// A rewrite of the values matching a `Pattern` into the value returned by
+/// a build function. When it returns `None` nothing is replaced, it must
+/// then not have changed the module.
+pub struct DagRewrite {
+    name: String,
+    pattern: Pattern,
+    benefit: u32,
+    constraints: Vec<Constraint>,
+    build: Build,
+}
+
+impl DagRewrite {
+    pub fn new<F>(name: &str, pattern: Pattern, build: F) -> Self
+    where
+        F: Fn(&mut Rewriter, &Match) -> Option<ValueId> + 'static,
+    {
+        DagRewrite {
+            name: name.to_string(),
+            pattern,
+            benefit: 1,
+            constraints: Vec::new(),
+            build: Box::new(build),
+        }
+    }
+
+    pub fn with_benefit(mut self, benefit: u32) -> Self {
+        self.benefit = benefit;
+        self
+    }
+
+    /// Only rewrite matches for which `constraint` holds.
+    pub fn when<F>(mut self, constraint: F) -> Self
+    where
+        F: Fn(&Module, &Match) -> bool + 'static,
+    {
+        self.constraints.push(Box::new(constraint));
+        self
+    }
+
+    /// Only rewrite when the type of the value bound to `name` satisfies
+    /// `predicate`.
+    pub fn with_type<F>(self, name: &str, predicate: F) -> Self
+    where
+        F: Fn(&Type) -> bool + 'static,
+    {
+        let name = name.to_string();
+        self.when(move |module, m| predicate(&module.value(m.value(&name)).ty))
+    }
+
+    /// Only rewrite when the ops defining the values bound to `a` and `b`
+    /// are placed on the same device, or both unplaced.
+    pub fn same_device(self, a: &str, b: &str) -> Self {
+        let (a, b) = (a.to_string(), b.to_string());
+        self.when(move |module, m| device(module, m.value(&a)) == device(module, m.value(&b)))
+    }
+
+    /// Only rewrite when the value bound to `name` has no other use than
+    /// the matched ops, so that the op defining it goes away.
+    pub fn single_use(self, name: &str) -> Self {
+        let name = name.to_string();
+        self.when(move |module, m| {
+            let value = m.value(&name);
+            module.users(value).len() == 1
+                && !module.names.iter().any(|(_, v)| *v == value)
+                && module
+                    .functions()
+                    .iter()
+                    .all(|r| !module.region(*r).results.contains(&value))
+        })
+    }
+}
+
+/// Device of the op defining `value`, `None` for params and unplaced ops.
+pub fn device(module: &Module, value: ValueId) -> Option<&str> {
+    module
+        .def_op(value)
+        .and_then(|op| module.op(op).device.as_deref())
+}
+
+/// Literal of the const op defining `value`.
+pub fn constant(module: &Module, value: ValueId) -> Option<&Literal> {
+    match &module.op(module.def_op(value)?).kind {
+        OpKind::Const(literal) => Some(literal),
+        _ => None,
+    }
+}
+
+/// Whether `value` is a constant equal to `n`, integer or float.
+pub fn is_number(module: &Module, value: ValueId, n: f64) -> bool {
+    match constant(module, value) {
+        Some(Literal::Int(v)) => *v as f64 == n,
+        Some(Literal::Float(v)) => *v == n,
+        _ => false,
+    }
+}
+
+/// Element of a list or of the outer dim of a tensor.
+pub fn element(ty: &Type) -> Type {
+    match ty {
+        Type::List(elem) => (**elem).clone(),
+        Type::Tensor {
+            dtype,
+            shape: TensorShapeType::Shape(dims),
+        } if dims.len() > 1 => Type::Tensor {
+            dtype: dtype.clone(),
+            shape: TensorShapeType::Shape(dims[1..].to_vec()),
+        },
+        Type::Tensor { dtype, .. } => (**dtype).clone(),
+        _ => Type::Unknown,
+    }
+}
+
+impl RewritePattern for DagRewrite {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn root(&self) -> Option<&str> {
+        self.pattern.root()
+    }
+
+    fn benefit(&self) -> u32 {
+        self.benefit
+    }
+
+    fn match_and_rewrite(&self, rewriter: &mut Rewriter) -> bool {
+        let root = rewriter.root();
+        let module = &*rewriter.module;
+        let Some(result) = module.op(root).results.first().copied() else {
+            return false;
+        };
+        let mut m = Match::default();
+        if !self.pattern.matches(module, result, &mut m)
+            || !self.constraints.iter().all(|c| c(module, &m))
+        {
+            return false;
+        }
+        match (self.build)(rewriter, &m) {
+            Some(value) => {
+                rewriter.replace(root, value);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Outcome of `apply_greedily`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RewriteStats {
+    pub rewrites: usize,
+    /// Whether the last round found nothing to rewrite
+    pub converged: bool,
+}
+
+/// Apply `patterns` to the ops of the module until none applies, or for at
+/// most `max_rounds` rounds over all ops. Within a round the users of a
+/// rewritten op are visited again, the ops a rewrite creates are visited
+/// in the next round.
+pub fn apply_greedily(
+    module: &mut Module,
+    patterns: &[Box<dyn RewritePattern>],
+    max_rounds: usize,
+) -> RewriteStats {
+    let mut order: Vec<&dyn RewritePattern> = patterns.iter().map(|p| p.as_ref()).collect();
+    order.sort_by_key(|p| std::cmp::Reverse(p.benefit()));
+
+    let mut stats = RewriteStats::default();
+    for _ in 0..max_rounds {
+        let mut changed = false;
+        let mut worklist: VecDeque<OpId> = module.ops().into();
+        let mut queued: HashSet<OpId> = worklist.iter().copied().collect();
+        while let Some(op) = worklist.pop_front() {
+            queued.remove(&op);
+            if module.op(op).parent.is_none() {
+                continue;
+            }
+            let name = module.op(op).kind.name();
+            let result = module.op(op).results.first().copied();
+            let users = result.map(|r| module.users(r)).unwrap_or_default();
+            let applied = order
+                .iter()
+                .filter(|p| p.root().is_none_or(|root| root == name))
+                .any(|p| p.match_and_rewrite(&mut Rewriter::new(module, op)));
+            if !applied {
+                continue;
+            }
+            stats.rewrites += 1;
+            changed = true;
+            for user in users {
+                if module.op(user).parent.is_some() && queued.insert(user) {
+                    worklist.push_back(user);
+                }
+            }
+        }
+        if !changed {
+            stats.converged = true;
+            break;
+        }
+    }
+    stats
+}
+
+/// A pass applying a set of patterns greedily.
+pub struct RewritePass {
+    name: String,
+    patterns: Vec<Box<dyn RewritePattern>>,
+    max_rounds: usize,
+}
+
+impl RewritePass {
+    pub fn new(name: &str, patterns: Vec<Box<dyn RewritePattern>>) -> Self {
+        RewritePass {
+            name: name.to_string(),
+            patterns,
+            max_rounds: 10,
+        }
+    }
+
+    pub fn with_max_rounds(mut self, max_rounds: usize) -> Self {
+        self.max_rounds = max_rounds;
+        self
+    }
+}
+
+impl Pass for RewritePass {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, cx: &mut PassContext) -> bool {
+        let stats = apply_greedily(module, &self.patterns, self.max_rounds);
+        if !stats.converged {
+            cx.report(Diagnostic::warning(
+                ErrorKind::PassFailed,
+                format!(
+                    "the rewrites of `{}` still apply after {} rounds",
+                    self.name, self.max_rounds
+                ),
+                Span::default(),
+            ));
+        }
+        stats.rewrites > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(src: &str) -> Module {
+        ir::parse(src).unwrap()
+    }
+
+    /// `map(map(x, g), f)` into `map(x, y => f(g(y)))`.
+    fn fuse_maps() -> Box<dyn RewritePattern> {
+        let pattern = Pattern::op(
+            "map",
+            vec![
+                Pattern::op("map", vec![Pattern::value("x"), Pattern::value("g")]).bind("inner"),
+                Pattern::value("f"),
+            ],
+        );
+        let rewrite = DagRewrite::new("fuse-maps", pattern, |rewriter, m| {
+            let (x, f, g) = (m.value("x"), m.value("f"), m.value("g"));
+            let signature = |v: ValueId| match &rewriter.module.value(v).ty {
+                Type::Function { params, ret } => (params[0].clone(), (**ret).clone()),
+                _ => (Type::Unknown, Type::Unknown),
+            };
+            let ((a, b), (_, c)) = (signature(g), signature(f));
+            let result = rewriter.module.op(m.root()).results[0];
+            let ty = rewriter.module.value(result).ty.clone();
+            let fn_ty = Type::Function {
+                params: vec![a.clone()],
+                ret: Box::new(c.clone()),
+            };
+            let h = rewriter.lambda(vec![a], fn_ty, |module, region, params| {
+                let y = module.push(region, OpKind::Call, vec![g, params[0]], b);
+                module.push(region, OpKind::Call, vec![f, y], c)
+            });
+            Some(rewriter.create(OpKind::Map, vec![x, h], ty))
+        })
+        .single_use("inner")
+        .same_device("inner", "x");
+        Box::new(rewrite)
+    }
+
+    #[test]
+    fn rewrites_a_dag_pattern() {
+        let mut module = parse(
+            "module {
+               %x = symbol xs : List<f64>
+               %g = symbol sin : (f64) => f64
+               %f = symbol cos : (f64) => f64
+               %0 = map %x, %g : List<f64>
+               %1 = map %0, %f : List<f64>
+               %2 = map %1, %g : List<f64>
+               yield %2
+             }",
+        );
+        let stats = apply_greedily(&mut module, &[fuse_maps()], 10);
+        assert_eq!(
+            stats,
+            RewriteStats {
+                rewrites: 2,
+                converged: true
+            }
+        );
+        ir::verify(&module).unwrap();
+        let maps: Vec<_> = module
+            .ops()
+            .into_iter()
+            .filter(|op| module.op(*op).kind == OpKind::Map)
+            .collect();
+        assert_eq!(maps.len(), 1);
+        let input = module.def_op(module.op(maps[0]).operands[0]).unwrap();
+        assert_eq!(module.op(input).kind, OpKind::Symbol("xs".to_string()));
+    }
+
+    #[test]
+    fn respects_constraints() {
+        // the inner map is used twice, fusing would compute it twice
+        let src = "module {
+               %x = symbol xs : List<f64>
+               %g = symbol sin : (f64) => f64
+               %0 = map %x, %g : List<f64>
+               %1 = map %0, %g : List<f64>
+               %2 = tuple %0, %1 : (List<f64>, List<f64>)
+               yield %2
+             }";
+        let mut module = parse(src);
+        let stats = apply_greedily(&mut module, &[fuse_maps()], 10);
+        assert_eq!(stats.rewrites, 0);
+        assert_eq!(module.to_string(), parse(src).to_string());
+
+        // the inner map runs on another device than its input
+        let mut module = parse(
+            "module {
+               %x = symbol xs : List<f64> @cpu
+               %g = symbol sin : (f64) => f64
+               %0 = map %x, %g : List<f64> @xpu
+               %1 = map %0, %g : List<f64> @xpu
+               yield %1
+             }",
+        );
+        assert_eq!(apply_greedily(&mut module, &[fuse_maps()], 10).rewrites, 0);
+    }
+
+    #[test]
+    fn matches_commutative_operands_and_detects_cycles() {
+        let mut module = parse(
+            "module {
+               %x = symbol x : i64
+               %0 = const 0 : i64
+               %1 = add %0, %x : i64
+               yield %1
+             }",
+        );
+        let add_zero = DagRewrite::new(
+            "add-zero",
+            Pattern::op(
+                "add",
+                vec![Pattern::value("x"), Pattern::Const(Literal::Int(0))],
+            ),
+            |_, m| Some(m.value("x")),
+        );
+        let stats = apply_greedily(&mut module, &[Box::new(add_zero)], 10);
+        assert_eq!(
+            stats,
+            RewriteStats {
+                rewrites: 1,
+                converged: true
+            }
+        );
+        assert_eq!(module.ops().len(), 1);
+
+        // negating twice, back and forth, never settles
+        let mut module =
+            parse("module {\n  %x = symbol x : i64\n  %0 = neg %x : i64\n  yield %0\n}");
+        let flip = DagRewrite::new(
+            "flip",
+            Pattern::op("neg", vec![Pattern::value("x")]),
+            |r, m| {
+                let x = m.value("x");
+                let ty = r.module.value(x).ty.clone();
+                let neg = r.create(OpKind::Unary(ir::UnOp::Neg), vec![x], ty.clone());
+                let neg = r.create(OpKind::Unary(ir::UnOp::Neg), vec![neg], ty.clone());
+                Some(r.create(OpKind::Unary(ir::UnOp::Neg), vec![neg], ty))
+            },
+        );
+        let mut pass = RewritePass::new("flip", vec![Box::new(flip)]).with_max_rounds(3);
+        let mut cx = PassContext::default();
+        assert!(pass.run_on_module(&mut module, &mut cx));
+        assert_eq!(cx.diagnostics.len(), 1);
+        assert!(!cx.has_errors());
+    }
+}
@@ -0,0 +1,635 @@
+//! Rewrites declared in LasMiao, e.g.
+//! `rule fuse_map: x.map(f).map(g) => x.map(y => g(f(y)))`.
+//!
+//! The names on the left of a rule are variables matching any value, a
+//! name used twice matches the same value. `x:f32` only matches values of
+//! that type, a number matches a constant equal to it whatever its dtype,
+//! `x@xpu` a move to `xpu` and a called name, e.g. `sin(x)`, the function
+//! of that name only. On the right the variables stand for what
+//! they matched, other names are looked up as in a program. The ops of
+//! the replacement run on the device of the op they replace.
+
+use crate::rewrite::{
+    DagRewrite, Match, Pattern, RewritePass, RewritePattern, Rewriter, device, element, is_number,
+};
+use diagnostics::{Diagnostic, ErrorKind, Span};
+use ir::{BinOp, Literal, Module, OpId, OpKind, RegionId, UnOp, ValueId};
+use parser::expr::{Expr, ExprKind};
+use parser::types::{TensorShapeType, Type};
+use std::collections::HashMap;
+
+/// A rule compiled from its declaration.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub span: Span,
+    pattern: Pattern,
+    checks: Vec<Check>,
+    rhs: Expr,
+}
+
+/// What a matched value must be besides having the shape of the pattern.
+#[derive(Debug, Clone)]
+enum Check {
+    /// A constant equal to the number
+    Number(String, f64),
+    Type(String, Type),
+    /// A move to the device
+    Device(String, String),
+}
+
+impl Check {
+    fn holds(&self, module: &Module, m: &Match) -> bool {
+        match self {
+            Check::Number(name, n) => is_number(module, m.value(name), *n),
+            Check::Type(name, ty) => conforms(&module.value(m.value(name)).ty, ty),
+            Check::Device(name, target) => device(module, m.value(name)) == Some(target.as_str()),
+        }
+    }
+}
+
+/// Whether `ty` is an instance of the annotation `expected`, where `any`
+/// stands for every type or shape.
+fn conforms(ty: &Type, expected: &Type) -> bool {
+    match (ty, expected) {
+        (_, Type::Any) => true,
+        (
+            Type::Tensor { dtype, shape },
+            Type::Tensor {
+                dtype: expected_dtype,
+                shape: expected_shape,
+            },
+        ) => {
+            conforms(dtype, expected_dtype)
+                && (*expected_shape == TensorShapeType::Any || shape == expected_shape)
+        }
+        (Type::List(a), Type::List(b)) => conforms(a, b),
+        _ => ty == expected,
+    }
+}
+
+/// Compile the rules declared at the top level of `program`.
+pub fn rules(program: &Expr) -> Result<Vec<Rule>, Vec<Diagnostic>> {
+    let stmts = match &program.kind {
+        ExprKind::Block(stmts) => stmts.as_slice(),
+        _ => std::slice::from_ref(program),
+    };
+    let mut rules: Vec<Rule> = Vec::new();
+    let mut errors = Vec::new();
+    for stmt in stmts {
+        let ExprKind::Rule { name, lhs, rhs } = &stmt.kind else {
+            continue;
+        };
+        if let Some(first) = rules.iter().find(|r| r.name == *name) {
+            errors.push(
+                Diagnostic::error(
+                    ErrorKind::InvalidRule,
+                    format!("rule `{}` is declared twice", name),
+                    stmt.span,
+                )
+                .with_secondary(first.span, "first declared here"),
+            );
+            continue;
+        }
+        match Rule::compile(name, lhs, rhs, stmt.span) {
+            Ok(rule) => rules.push(rule),
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(rules)
+    } else {
+        Err(errors)
+    }
+}
+
+/// The pass `rules`, applying `rules` until none matches.
+pub fn rule_pass(rules: &[Rule]) -> RewritePass {
+    let patterns = rules
+        .iter()
+        .map(|rule| Box::new(rule.to_rewrite()) as Box<dyn RewritePattern>)
+        .collect();
+    RewritePass::new("rules", patterns)
+}
+
+fn unsupported(expr: &Expr, side: &str) -> Diagnostic {
+    let what = match &expr.kind {
+        ExprKind::Unit => "`()`",
+        ExprKind::Buffer { .. } => "a buffer",
+        ExprKind::Assign { .. } => "an assignment",
+        ExprKind::MetaDefine { .. } => "a meta define",
+        ExprKind::Lambda { .. } => "a lambda",
+        ExprKind::Block(_) => "a block",
+        ExprKind::Unary { .. } | ExprKind::Binary { .. } => "this operator",
+        _ => "this expression",
+    };
+    Diagnostic::error(
+        ErrorKind::InvalidRule,
+        format!("{} can not appear in the {} of a rule", what, side),
+        expr.span,
+    )
+}
+
+/// Items of the args of a call.
+fn arguments(args: &Expr) -> Vec<&Expr> {
+    match &args.kind {
+        ExprKind::Unit => Vec::new(),
+        ExprKind::Tuple(items) => items.iter().collect(),
+        _ => vec![args],
+    }
+}
+
+/// Whether `callee` applied to `n` args is the builtin `map`.
+fn is_map(callee: &Expr, n: usize, bound: &dyn Fn(&str) -> bool) -> bool {
+    matches!(&callee.kind, ExprKind::Identifier { name, .. } if name == "map" && !bound(name))
+        && n == 2
+}
+
+/// Names which are constants rather than variables, as in a program.
+fn constant(name: &str) -> Option<(Literal, Type)> {
+    match name {
+        "pi" => Some((Literal::Float(std::f64::consts::PI), Type::F64)),
+        "e" => Some((Literal::Float(std::f64::consts::E), Type::F64)),
+        "true" => Some((Literal::Bool(true), Type::Bool)),
+        "false" => Some((Literal::Bool(false), Type::Bool)),
+        _ => None,
+    }
+}
+
+impl Rule {
+    fn compile(name: &str, lhs: &Expr, rhs: &Expr, span: Span) -> Result<Rule, Diagnostic> {
+        let mut checks = Vec::new();
+        let pattern = pattern(lhs, &mut checks)?;
+        if pattern
+            .root()
+            .is_none_or(|root| matches!(root, "const" | "symbol"))
+        {
+            return Err(Diagnostic::error(
+                ErrorKind::InvalidRule,
+                format!("the pattern of rule `{}` matches no operation", name),
+                lhs.span,
+            )
+            .with_help("a pattern is an operation on variables, e.g. `x + 0`"));
+        }
+        check_replacement(rhs)?;
+        Ok(Rule {
+            name: name.to_string(),
+            span,
+            pattern,
+            checks,
+            rhs: rhs.clone(),
+        })
+    }
+
+    /// The rule as a pattern of the rewrite engine.
+    pub fn to_rewrite(&self) -> DagRewrite {
+        let rhs = self.rhs.clone();
+        let mut rewrite = DagRewrite::new(&self.name, self.pattern.clone(), move |rewriter, m| {
+            Some(Builder::new(rewriter).replacement(&rhs, m))
+        });
+        for check in self.checks.clone() {
+            rewrite = rewrite.when(move |module, m| check.holds(module, m));
+        }
+        rewrite
+    }
+}
+
+/// Pattern of the left of a rule, adding the checks it needs to `checks`.
+fn pattern(expr: &Expr, checks: &mut Vec<Check>) -> Result<Pattern, Diagnostic> {
+    let all = |exprs: Vec<&Expr>, checks: &mut Vec<Check>| {
+        exprs
+            .into_iter()
+            .map(|e| pattern(e, checks))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    Ok(match &expr.kind {
+        ExprKind::Identifier { name, typ } => match constant(name) {
+            Some((literal, _)) => Pattern::Const(literal),
+            None => {
+                if *typ != Type::Unknown {
+                    checks.push(Check::Type(name.clone(), typ.clone()));
+                }
+                Pattern::value(name)
+            }
+        },
+        ExprKind::Integer { val, .. } => {
+            // `%` keeps the name apart from those of the program
+            let name = format!("%{}", checks.len());
+            checks.push(Check::Number(name.clone(), *val as f64));
+            Pattern::Value(name)
+        }
+        ExprKind::Float { val, .. } => {
+            // `%` keeps the name apart from those of the program
+            let name = format!("%{}", checks.len());
+            checks.push(Check::Number(name.clone(), *val));
+            Pattern::Value(name)
+        }
+        ExprKind::Unary { op, arg } => {
+            let op = UnOp::from_token(op).ok_or_else(|| unsupported(expr, "pattern"))?;
+            Pattern::op(op.name(), vec![pattern(arg, checks)?])
+        }
+        ExprKind::Binary { left, op, right } => {
+            let op = BinOp::from_token(op).ok_or_else(|| unsupported(expr, "pattern"))?;
+            Pattern::op(op.name(), all(vec![left, right], checks)?)
+        }
+        ExprKind::Call { callee, args } => {
+            let args = arguments(args);
+            if is_map(callee, args.len(), &|_| false) {
+                Pattern::op("map", all(args, checks)?)
+            } else if let ExprKind::Identifier { name, .. } = &callee.kind {
+                let f = Pattern::Symbol(name.clone());
+                let operands =
+                    std::iter::once(Ok(f)).chain(args.into_iter().map(|a| pattern(a, checks)));
+                Pattern::op("call", operands.collect::<Result<_, _>>()?)
+            } else {
+                return Err(unsupported(callee, "pattern"));
+            }
+        }
+        ExprKind::Tuple(items) => Pattern::op("tuple", all(items.iter().collect(), checks)?),
+        ExprKind::List(items) => Pattern::op("list", all(items.iter().collect(), checks)?),
+        ExprKind::Move { val, device } => {
+            let ExprKind::Identifier { name: device, .. } = &device.kind else {
+                unreachable!("the parser only moves to device names")
+            };
+            // `%` keeps the name apart from those of the program
+            let name = format!("%{}", checks.len());
+            checks.push(Check::Device(name.clone(), device.clone()));
+            Pattern::op("move", vec![pattern(val, checks)?]).bind(&name)
+        }
+        _ => return Err(unsupported(expr, "pattern")),
+    })
+}
+
+/// Check that the right of a rule only has expressions a `Builder` makes.
+fn check_replacement(expr: &Expr) -> Result<(), Diagnostic> {
+    match &expr.kind {
+        ExprKind::Unit
+        | ExprKind::Integer { .. }
+        | ExprKind::Float { .. }
+        | ExprKind::Identifier { .. }
+        | ExprKind::List(_)
+        | ExprKind::Tuple(_)
+        | ExprKind::Move { .. } => {}
+        ExprKind::Unary { op, .. } if UnOp::from_token(op).is_some() => {}
+        ExprKind::Binary { op, .. } if BinOp::from_token(op).is_some() => {}
+        ExprKind::Call { .. } => {}
+        ExprKind::Lambda { param, .. } => {
+            for param in lambda_params(param) {
+                if !matches!(param.kind, ExprKind::Identifier { .. }) {
+                    return Err(Diagnostic::error(
+                        ErrorKind::InvalidRule,
+                        "expect an identifier as lambda parameter",
+                        param.span,
+                    ));
+                }
+            }
+        }
+        _ => return Err(unsupported(expr, "replacement")),
+    }
+    expr.children().into_iter().try_for_each(check_replacement)
+}
+
+fn lambda_params(param: &Expr) -> Vec<&Expr> {
+    match &param.kind {
+        ExprKind::Tuple(items) => items.iter().collect(),
+        _ => vec![param],
+    }
+}
+
+/// Scalar type of the elements of `ty`.
+fn dtype(ty: &Type) -> &Type {
+    match ty {
+        Type::List(elem) => dtype(elem),
+        Type::Tensor { dtype, .. } => dtype,
+        _ => ty,
+    }
+}
+
+/// Type of `map` over `input` with a function returning `ret`.
+fn map_type(input: &Type, ret: Type) -> Type {
+    let Type::Tensor {
+        shape: TensorShapeType::Shape(dims),
+        ..
+    } = input
+    else {
+        return Type::List(Box::new(ret));
+    };
+    match ret {
+        Type::Tensor {
+            dtype,
+            shape: TensorShapeType::Shape(inner),
+        } => Type::Tensor {
+            dtype,
+            shape: TensorShapeType::Shape(dims[..1].iter().chain(&inner).cloned().collect()),
+        },
+        Type::List(_) | Type::Tensor { .. } | Type::Unknown => Type::List(Box::new(ret)),
+        dtype => Type::Tensor {
+            dtype: Box::new(dtype),
+            shape: TensorShapeType::Shape(dims[..1].to_vec()),
+        },
+    }
+}
+
+type Env = HashMap<String, ValueId>;
+
+/// Emits the replacement of a rule. Types are taken from the values the
+/// ops are built from, with the value replacing the root taking its type.
+struct Builder<'a> {
+    module: &'a mut Module,
+    root: OpId,
+    created: Vec<OpId>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(rewriter: &'a mut Rewriter) -> Self {
+        let root = rewriter.root();
+        Builder {
+            module: &mut *rewriter.module,
+            root,
+            created: Vec::new(),
+        }
+    }
+
+    fn replacement(mut self, rhs: &Expr, m: &Match) -> ValueId {
+        let result = self.module.op(self.root).results[0];
+        let ty = self.module.value(result).ty.clone();
+        let value = self.build(rhs, None, &m.values, &ty);
+        if self
+            .module
+            .def_op(value)
+            .is_some_and(|op| self.created.contains(&op))
+        {
+            self.module.value_mut(value).ty = ty;
+        }
+        value
+    }
+
+    /// Create an op before the root, or at the end of `region`.
+    fn emit(
+        &mut self,
+        region: Option<RegionId>,
+        kind: OpKind,
+        operands: Vec<ValueId>,
+        ty: Type,
+    ) -> ValueId {
+        let op = self.module.create_op(kind, operands, vec![ty]);
+        match region {
+            Some(region) => self.module.append_op(region, op),
+            None => self.module.insert_op_before(self.root, op),
+        }
+        let root = self.module.op(self.root);
+        let (span, device) = (root.span, root.device.clone());
+        let data = self.module.op_mut(op);
+        data.span = span;
+        data.device = device;
+        self.created.push(op);
+        data.results[0]
+    }
+
+    fn ty(&self, value: ValueId) -> Type {
+        self.module.value(value).ty.clone()
+    }
+
+    /// Build `expr` given the values of the names in `env`, `hint` being
+    /// the type it is expected to have, if known.
+    fn build(&mut self, expr: &Expr, region: Option<RegionId>, env: &Env, hint: &Type) -> ValueId {
+        match &expr.kind {
+            ExprKind::Unit => self.emit(region, OpKind::Const(Literal::Unit), vec![], Type::Unit),
+            ExprKind::Identifier { name, .. } => {
+                if let Some(value) = env.get(name) {
+                    return *value;
+                }
+                match constant(name) {
+                    Some((literal, ty)) => self.emit(region, OpKind::Const(literal), vec![], ty),
+                    None => self.emit(region, OpKind::Symbol(name.clone()), vec![], Type::Unknown),
+                }
+            }
+            ExprKind::Integer { val, .. } => {
+                let (literal, ty) = match dtype(hint) {
+                    ty @ (Type::F32 | Type::F64) => (Literal::Float(*val as f64), ty.clone()),
+                    ty @ (Type::I32 | Type::U32 | Type::I64 | Type::U64) => {
+                        (Literal::Int(*val as i64), ty.clone())
+                    }
+                    _ => (Literal::Int(*val as i64), Type::I64),
+                };
+                self.emit(region, OpKind::Const(literal), vec![], ty)
+            }
+            ExprKind::Float { val, .. } => {
+                let ty = match dtype(hint) {
+                    Type::F32 => Type::F32,
+                    _ => Type::F64,
+                };
+                self.emit(region, OpKind::Const(Literal::Float(*val)), vec![], ty)
+            }
+            ExprKind::Unary { op, arg } => {
+                let op = UnOp::from_token(op).expect("checked when the rule was compiled");
+                let arg = self.build(arg, region, env, hint);
+                let ty = self.ty(arg);
+                self.emit(region, OpKind::Unary(op), vec![arg], ty)
+            }
+            ExprKind::Binary { left, op, right } => {
+                let op = BinOp::from_token(op).expect("checked when the rule was compiled");
+                let left = self.build(left, region, env, hint);
+                let right = self.build(right, region, env, &self.ty(left));
+                let ty = match op {
+                    BinOp::Eq
+                    | BinOp::Ne
+                    | BinOp::Lt
+                    | BinOp::Le
+                    | BinOp::Gt
+                    | BinOp::Ge
+                    | BinOp::LogicAnd
+                    | BinOp::LogicOr => Type::Bool,
+                    // a scalar broadcasts to the shape of the other operand
+                    _ => match (self.ty(left), self.ty(right)) {
+                        (l, r @ (Type::List(_) | Type::Tensor { .. }))
+                            if !matches!(l, Type::List(_) | Type::Tensor { .. }) =>
+                        {
+                            r
+                        }
+                        (Type::Unknown, r) => r,
+                        (l, _) => l,
+                    },
+                };
+                self.emit(region, OpKind::Binary(op), vec![left, right], ty)
+            }
+            ExprKind::Call { callee, args } => {
+                let args = arguments(args);
+                if is_map(callee, args.len(), &|name| env.contains_key(name)) {
+                    let input = self.build(args[0], region, env, &Type::Unknown);
+                    let f_hint = Type::Function {
+                        params: vec![element(&self.ty(input))],
+                        ret: Box::new(Type::Unknown),
+                    };
+                    let f = self.build(args[1], region, env, &f_hint);
+                    let ret = match self.ty(f) {
+                        Type::Function { ret, .. } => *ret,
+                        _ => Type::Unknown,
+                    };
+                    let ty = map_type(&self.ty(input), ret);
+                    return self.emit(region, OpKind::Map, vec![input, f], ty);
+                }
+                let f = self.build(callee, region, env, &Type::Unknown);
+                let mut operands = vec![f];
+                for arg in args {
+                    operands.push(self.build(arg, region, env, &Type::Unknown));
+                }
+                let ty = match self.ty(f) {
+                    Type::Function { ret, .. } => *ret,
+                    _ => Type::Unknown,
+                };
+                self.emit(region, OpKind::Call, operands, ty)
+            }
+            ExprKind::Lambda { param, body } => {
+                let params = lambda_params(param);
+                let (types, ret) = match hint {
+                    Type::Function { params: types, ret } if types.len() == params.len() => {
+                        (types.clone(), (**ret).clone())
+                    }
+                    _ => (vec![Type::Unknown; params.len()], Type::Unknown),
+                };
+                let value = self.emit(region, OpKind::Lambda, vec![], Type::Unknown);
+                let op = self.module.def_op(value).unwrap();
+                let inner = self.module.add_region(op, types.clone());
+                let mut env = env.clone();
+                for (param, value) in params.iter().zip(self.module.region(inner).params.clone()) {
+                    if let ExprKind::Identifier { name, .. } = &param.kind {
+                        env.insert(name.clone(), value);
+                    }
+                }
+                let result = self.build(body, Some(inner), &env, &ret);
+                self.module.region_mut(inner).results.push(result);
+                self.module.value_mut(value).ty = Type::Function {
+                    params: types,
+                    ret: Box::new(self.ty(result)),
+                };
+                value
+            }
+            ExprKind::Tuple(items) => {
+                let items: Vec<ValueId> = items
+                    .iter()
+                    .map(|item| self.build(item, region, env, &Type::Unknown))
+                    .collect();
+                let ty = Type::Tuple(items.iter().map(|v| self.ty(*v)).collect());
+                self.emit(region, OpKind::Tuple, items, ty)
+            }
+            ExprKind::List(items) => {
+                let items: Vec<ValueId> = items
+                    .iter()
+                    .map(|item| self.build(item, region, env, &element(hint)))
+                    .collect();
+                let elem = items.first().map_or(Type::Unknown, |v| self.ty(*v));
+                self.emit(region, OpKind::List, items, Type::List(Box::new(elem)))
+            }
+            ExprKind::Move { val, device } => {
+                let ExprKind::Identifier { name, .. } = &device.kind else {
+                    unreachable!("the parser only moves to device names")
+                };
+                let val = self.build(val, region, env, hint);
+                let ty = self.ty(val);
+                let value = self.emit(region, OpKind::Move, vec![val], ty);
+                let op = self.module.def_op(value).unwrap();
+                self.module.op_mut(op).device = Some(name.clone());
+                value
+            }
+            _ => unreachable!("checked when the rule was compiled"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rewrite::apply_greedily;
+    use crate::testing::check;
+
+    fn compile(src: &str) -> (Module, Result<Vec<Rule>, Vec<Diagnostic>>) {
+        let program = check(src);
+        (ir::lower(&program), rules(&program))
+    }
+
+    fn apply(module: &mut Module, rules: &[Rule]) -> usize {
+        let patterns: Vec<Box<dyn RewritePattern>> = rules
+            .iter()
+            .map(|r| Box::new(r.to_rewrite()) as Box<dyn RewritePattern>)
+            .collect();
+        let stats = apply_greedily(module, &patterns, 10);
+        assert!(stats.converged);
+        ir::verify(module).unwrap();
+        stats.rewrites
+    }
+
+    #[test]
+    fn fuses_maps_with_a_rule() {
+        let (mut module, rules) = compile(
+            "rule fuse_map: x.map(f).map(g) => x.map(y => g(f(y)))\n\
+             xs = [1., 2., 3.]\n\
+             ys = xs.map(x => x * 2.).map(x => x + 1.).map(x => -x)",
+        );
+        let rules = rules.unwrap();
+        assert_eq!(rules[0].name, "fuse_map");
+        assert_eq!(apply(&mut module, &rules), 2);
+
+        let maps: Vec<OpId> = module
+            .ops()
+            .into_iter()
+            .filter(|op| module.op(*op).kind == OpKind::Map)
+            .collect();
+        assert_eq!(maps.len(), 1);
+        let ys = module.names.iter().find(|(n, _)| n == "ys").unwrap().1;
+        assert_eq!(module.op(maps[0]).results[0], ys);
+        assert_eq!(module.value(ys).ty.to_string(), "tensor(f64, 3)");
+        let f = module.op(maps[0]).operands[1];
+        assert_eq!(module.value(f).ty.to_string(), "(f64) => f64");
+    }
+
+    #[test]
+    fn numbers_types_and_devices_constrain_matches() {
+        let (mut module, rules) = compile(
+            "rule add_zero: x:f64 + 0 => x\n\
+             rule neg_on_cpu: -(x@cpu) => x@cpu\n\
+             a = 1. + 0\n\
+             b = 1 + 0\n\
+             c = -(2.@cpu)\n\
+             d = -(2.@xpu)",
+        );
+        assert_eq!(apply(&mut module, &rules.unwrap()), 2);
+        let text = module.to_string();
+        assert!(text.contains("name a = %0"), "{}", text);
+        assert_eq!(text.matches("add").count(), 1, "{}", text);
+        assert_eq!(text.matches("neg").count(), 1, "{}", text);
+    }
+
+    #[test]
+    fn called_names_only_match_their_function() {
+        let (mut module, rules) = compile(
+            "rule sin_zero: sin(0.) => 0.\n\
+             a = sin(0.)\n\
+             b = cos(0.)",
+        );
+        assert_eq!(apply(&mut module, &rules.unwrap()), 1);
+        let text = module.to_string();
+        assert!(!text.contains("symbol sin"), "{}", text);
+        assert!(text.contains("symbol cos"), "{}", text);
+        assert_eq!(text.matches("call").count(), 1, "{}", text);
+    }
+
+    #[test]
+    fn rejects_what_rules_can_not_express() {
+        let errors = |src| compile(src).1.unwrap_err();
+        let e = errors("rule r: x => x");
+        assert_eq!(e[0].message, "the pattern of rule `r` matches no operation");
+        let e = errors("rule r: x + 1 => { y = x; y }");
+        assert_eq!(
+            e[0].message,
+            "a block can not appear in the replacement of a rule"
+        );
+        let e = errors("rule r: (y => y)(x) => x");
+        assert_eq!(
+            e[0].message,
+            "a lambda can not appear in the pattern of a rule"
+        );
+        let e = errors("rule r: x + 0 => x\nrule r: x * 1 => x");
+        assert_eq!(e[0].message, "rule `r` is declared twice");
+    }
+}
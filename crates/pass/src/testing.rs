@@ -0,0 +1,21 @@
+//! Setup shared by the tests of the passes.
+
+use ir::Module;
+use lexer::{LasmiaoLexer, Lexer};
+use parser::TokenParser;
+use parser::expr::Expr;
+use parser::traits::Parser;
+use typeck::TypeChecker;
+
+/// Parse and type check the program `src`.
+pub fn check(src: &str) -> Expr {
+    let tokens = LasmiaoLexer::make_tokens(src).unwrap();
+    let mut program = TokenParser::new(tokens).parse_exprs().unwrap();
+    TypeChecker::new().check_program(&mut program);
+    program
+}
+
+/// Lower the program `src` once checked.
+pub fn lower(src: &str) -> Module {
+    ir::lower(&check(src))
+}
@@ -0,0 +1,63 @@
+use device::Target;
+use diagnostics::Diagnostic;
+use ir::{Module, RegionId};
+
+/// State shared by the passes of a pipeline.
+#[derive(Debug, Default)]
+pub struct PassContext {
+    /// Reported by passes; the pipeline stops after a pass reporting an error
+    pub diagnostics: Vec<Diagnostic>,
+    /// Devices the program is compiled for
+    pub target: Target,
+}
+
+impl PassContext {
+    pub fn report(&mut self, diag: Diagnostic) {
+        self.diagnostics.push(diag);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|d| d.is_error())
+    }
+}
+
+/// A transformation or analysis of the IR.
+///
+/// Function-level passes implement `run_on_function`, which is called for
+/// the body of the module and of every lambda. Module-level passes
+/// override `run_on_module` instead. Both return whether they changed
+/// anything.
+pub trait Pass {
+    /// Name in pipeline specs, e.g. `fuse`.
+    fn name(&self) -> &str;
+
+    /// One line summary, listed with the available passes.
+    fn description(&self) -> &str {
+        ""
+    }
+
+    fn run_on_function(
+        &mut self,
+        module: &mut Module,
+        function: RegionId,
+        cx: &mut PassContext,
+    ) -> bool {
+        let _ = (module, function, cx);
+        false
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, cx: &mut PassContext) -> bool {
+        let mut changed = false;
+        for function in module.functions() {
+            // skip the lambdas removed while processing the earlier ones
+            let removed = module
+                .region(function)
+                .parent
+                .is_some_and(|op| module.op(op).parent.is_none());
+            if !removed {
+                changed |= self.run_on_function(module, function, cx);
+            }
+        }
+        changed
+    }
+}
@@ -0,0 +1,151 @@
+//! Cycles taken by the ops of a module on the devices of a target.
+
+use device::{Target, size_of};
+use ir::{Module, OpId, OpKind, RegionId, ValueId};
+use parser::types::{TensorShapeType, Type};
+
+/// Estimates the cycles an op takes once its operands are ready.
+///
+/// Arithmetic runs `vector_width` elements per instruction, a map runs its
+/// lambda once per element and a `move` takes the cycles of the link it
+/// crosses. Constants, lambdas, buffers and tuples only name values and
+/// are free.
+#[derive(Debug, Clone)]
+pub struct LatencyModel {
+    /// Cycles of an arithmetic instruction
+    pub instruction: u64,
+    /// Cycles of a call to a builtin such as `sin`
+    pub call: u64,
+}
+
+impl Default for LatencyModel {
+    fn default() -> Self {
+        LatencyModel {
+            instruction: 1,
+            call: 20,
+        }
+    }
+}
+
+/// Elements of a value of type `ty`, 1 for scalars and unknown shapes.
+fn elements(ty: &Type) -> u64 {
+    match ty {
+        Type::Tensor {
+            shape: TensorShapeType::Shape(dims),
+            ..
+        } => dims
+            .iter()
+            .try_fold(1u64, |n, dim| n.checked_mul(dim.as_const()?))
+            .unwrap_or(1),
+        Type::Tuple(items) => items.iter().map(elements).sum(),
+        _ => 1,
+    }
+}
+
+impl LatencyModel {
+    pub fn cycles(&self, module: &Module, op: OpId, target: &Target) -> u64 {
+        let data = module.op(op);
+        let device = data.device.as_deref().unwrap_or(&target.host);
+        let width = target
+            .device(device)
+            .map_or(1, |d| u64::from(d.vector_width));
+        let vectors = || elements(&module.value(data.results[0]).ty).div_ceil(width);
+        match &data.kind {
+            OpKind::Const(_)
+            | OpKind::Symbol(_)
+            | OpKind::Lambda
+            | OpKind::Tuple
+            | OpKind::Extract(_)
+            | OpKind::Buffer { .. } => 0,
+            OpKind::Binary(_) | OpKind::Unary(_) | OpKind::List => vectors() * self.instruction,
+            // a buffer is allocated on the device it is moved to
+            OpKind::Move if module.value(data.results[0]).ty == Type::Buffer => 0,
+            OpKind::Move => {
+                let bytes = size_of(&module.value(data.operands[0]).ty).unwrap_or(0);
+                self.transfer(module, op, target, bytes)
+            }
+            OpKind::Call => self.apply(module, data.operands[0], target),
+            OpKind::Map => {
+                let f = *data.operands.last().unwrap();
+                vectors().saturating_mul(self.apply(module, f, target))
+            }
+        }
+    }
+
+    /// Cycles the `move` op takes to copy `bytes` of its operand, 0 when
+    /// no link connects the devices, which `schedule` reports.
+    pub fn transfer(&self, module: &Module, op: OpId, target: &Target, bytes: u64) -> u64 {
+        let data = module.op(op);
+        let to = data.device.as_deref().unwrap_or(&target.host);
+        let from = module.device_of(data.operands[0]).unwrap_or(&target.host);
+        target.transfer_cycles(from, to, bytes).unwrap_or(0)
+    }
+
+    /// Cycles of an application of the function `f`, its body for a
+    /// lambda and a builtin call otherwise.
+    fn apply(&self, module: &Module, f: ValueId, target: &Target) -> u64 {
+        match module.def_op(f) {
+            Some(op) if module.op(op).kind == OpKind::Lambda => {
+                self.region(module, module.op(op).regions[0], target)
+            }
+            _ => self.call,
+        }
+    }
+
+    fn region(&self, module: &Module, region: RegionId, target: &Target) -> u64 {
+        module
+            .region(region)
+            .ops
+            .iter()
+            .map(|op| self.cycles(module, *op, target))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectorizes_maps_and_prices_transfers() {
+        let module = ir::parse(
+            "module {
+               %x = symbol xs : tensor(f32, 64)
+               %m = move %x : tensor(f32, 64) @xpu
+               %f = lambda (%p: f32) : (f32) => f32 @xpu {
+                 %a = mul %p, %p : f32 @xpu
+                 %s = symbol sin : (f32) => f32 @xpu
+                 %b = call %s, %a : f32 @xpu
+                 yield %b
+               }
+               %y = map %m, %f : tensor(f32, 64) @xpu
+               %z = map %x, %f : tensor(f32, 64)
+               yield %y
+             }",
+        )
+        .unwrap();
+        let target = Target::parse(
+            r#"
+[devices.cpu]
+[devices.xpu]
+vector_width = 16
+
+[[links]]
+from = "cpu"
+to = "xpu"
+bandwidth = 32
+latency = 100
+"#,
+        )
+        .unwrap();
+        let model = LatencyModel::default();
+        let cycles: Vec<u64> = module
+            .region(module.body)
+            .ops
+            .iter()
+            .map(|op| model.cycles(&module, *op, &target))
+            .collect();
+        // 256 bytes, then 4 and 64 vectors of a mul and a call
+        assert_eq!(cycles, [0, 108, 0, 84, 1344]);
+    }
+}
@@ -1,14 +1,21 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+//! Orchestration of a placed program across the devices of a target.
+//!
+//! Once every op of a `Module` is placed, `schedule` decides when each
+//! runs. The compute units of every device run one op at a time, and so
+//! does the DMA engine of every link, which copies the values of `move`s
+//! while the devices compute. An op starts once the ops it depends on
+//! have finished, on any device, and its resource is free. The cycles it
+//! then takes are estimated by a `LatencyModel`. The `double-buffer` pass
+//! stages the copies feeding maps through buffers so that they run tile
+//! by tile alongside the map, and `plan` lays the tensors and buffers out
+//! in the memories of the devices.
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+pub mod latency;
+pub mod memory;
+pub mod prefetch;
+pub mod schedule;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub use latency::LatencyModel;
+pub use memory::{Allocation, MemoryPlan, plan};
+pub use prefetch::DoubleBuffer;
+pub use schedule::{Resource, Schedule, Task, schedule};
@@ -0,0 +1,409 @@
+//! Planning of the memory of the tensors and buffers of a scheduled
+//! module.
+//!
+//! A tensor lives in the main memory of its device, a buffer `$(size,
+//! space)` in the memory space it names on the device it is moved to. A
+//! tensor lives from the cycle its op starts, a buffer from its first use,
+//! until the last op using them ends, or the end of the program for its
+//! result. Values whose lifetimes do not overlap share bytes. A tensor
+//! moved through buffers, see `DoubleBuffer`, is never whole on its
+//! device, the buffers holding its tiles until its map is done.
+
+use crate::schedule::Schedule;
+use device::{Target, size_of};
+use diagnostics::{Diagnostic, ErrorKind};
+use ir::{Module, OpId, OpKind, ValueId};
+use parser::types::Type;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// Bytes every offset is a multiple of, the width of a DMA burst.
+pub const ALIGNMENT: u64 = 64;
+
+/// Bytes of a memory space holding a value while it lives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub value: ValueId,
+    pub device: String,
+    pub space: String,
+    pub offset: u64,
+    pub size: u64,
+    /// First cycle it lives
+    pub start: u64,
+    /// Cycle it dies
+    pub end: u64,
+}
+
+impl Allocation {
+    fn overlaps(&self, other: &Allocation) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// Allocations of the tensors and buffers of a module, sorted by memory
+/// space and offset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryPlan {
+    pub allocations: Vec<Allocation>,
+}
+
+impl MemoryPlan {
+    pub fn allocation(&self, value: ValueId) -> Option<&Allocation> {
+        self.allocations.iter().find(|a| a.value == value)
+    }
+
+    /// Bytes of `space` of `device` in use at once at most.
+    pub fn peak(&self, device: &str, space: &str) -> u64 {
+        self.allocations
+            .iter()
+            .filter(|a| a.device == device && a.space == space)
+            .map(|a| a.offset + a.size)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Text of the plan, each memory space with its peak followed by its
+    /// allocations, named as in the text of `module`, e.g.
+    ///
+    /// ```text
+    /// xpu.sram: 1024 of 262144 bytes
+    ///   %3            0     1024  live 16..40
+    /// ```
+    pub fn render(&self, module: &Module, target: &Target) -> String {
+        let numbers = module.value_numbers();
+        let mut out = String::new();
+        let mut space = None;
+        for a in &self.allocations {
+            if space != Some((&a.device, &a.space)) {
+                space = Some((&a.device, &a.space));
+                let capacity = target
+                    .device(&a.device)
+                    .and_then(|d| d.memory(&a.space))
+                    .map_or(0, |m| m.capacity);
+                let _ = writeln!(
+                    out,
+                    "{}.{}: {} of {} bytes",
+                    a.device,
+                    a.space,
+                    self.peak(&a.device, &a.space),
+                    capacity
+                );
+            }
+            let _ = writeln!(
+                out,
+                "  {:<8} {:>8} {:>8}  live {}..{}",
+                format!("%{}", numbers[&a.value]),
+                a.offset,
+                a.offset + a.size,
+                a.start,
+                a.end
+            );
+        }
+        out
+    }
+}
+
+/// Size and memory space of the buffer `value`, through its moves.
+fn buffer(module: &Module, value: ValueId) -> Option<(u64, &str)> {
+    let op = module.op(module.def_op(value)?);
+    match &op.kind {
+        OpKind::Buffer { size, space } => Some((*size, space)),
+        OpKind::Move => buffer(module, op.operands[0]),
+        _ => None,
+    }
+}
+
+/// Plan where the tensors and buffers computed by the body of `module`
+/// live on the devices of `target`, run as in `schedule`.
+///
+/// Offsets are found first fit, the largest values first. Fails when a
+/// buffer names a memory its device lacks, or when the values live at
+/// once in a memory space do not fit in its capacity.
+pub fn plan(
+    module: &Module,
+    target: &Target,
+    schedule: &Schedule,
+) -> Result<MemoryPlan, Vec<Diagnostic>> {
+    let mut diags = Vec::new();
+    let span = |op: OpId| module.op(op).span.unwrap_or_default();
+
+    // when the last op using each value ends, and when the first starts
+    let mut last_use: HashMap<ValueId, u64> = HashMap::new();
+    let mut first_use: HashMap<ValueId, u64> = HashMap::new();
+    for task in &schedule.tasks {
+        let nested = module
+            .op(task.op)
+            .regions
+            .iter()
+            .flat_map(|r| module.walk(*r));
+        for op in std::iter::once(task.op).chain(nested) {
+            for operand in &module.op(op).operands {
+                let end = last_use.entry(*operand).or_default();
+                *end = (*end).max(task.end);
+                let start = first_use.entry(*operand).or_insert(task.start);
+                *start = (*start).min(task.start);
+            }
+        }
+    }
+    // the buffers of a staged move hold tiles until they are used
+    for task in &schedule.tasks {
+        let data = module.op(task.op);
+        if data.kind == OpKind::Move && data.operands.len() == 3 {
+            let used = last_use.get(&data.results[0]).copied().unwrap_or(task.end);
+            for buffer in &data.operands[1..] {
+                let end = last_use.entry(*buffer).or_default();
+                *end = (*end).max(used);
+            }
+        }
+    }
+    let outputs = &module.region(module.body).results;
+
+    let mut spaces: BTreeMap<(String, String), Vec<Allocation>> = BTreeMap::new();
+    let mut ops: HashMap<ValueId, OpId> = HashMap::new();
+    for task in schedule
+        .tasks
+        .iter()
+        .filter(|t| t.tile.is_none_or(|t| t == 0))
+    {
+        let data = module.op(task.op);
+        let device_name = data.device.as_deref().unwrap_or(&target.host);
+        let Some(device) = target.device(device_name) else {
+            continue;
+        };
+        for value in &data.results {
+            let ty = &module.value(*value).ty;
+            let (size, space) = match ty {
+                Type::Buffer => {
+                    let Some((size, space)) = buffer(module, *value) else {
+                        continue;
+                    };
+                    // `$(size, space)@device` is allocated where it is moved
+                    let users = module.users(*value);
+                    if !users.is_empty()
+                        && !outputs.contains(value)
+                        && users.iter().all(|u| {
+                            let user = module.op(*u);
+                            user.kind == OpKind::Move && user.operands[0] == *value
+                        })
+                    {
+                        continue;
+                    }
+                    if device.memory(space).is_none() {
+                        let known: Vec<&str> =
+                            device.memory.iter().map(|m| m.name.as_str()).collect();
+                        diags.push(
+                            Diagnostic::error(
+                                ErrorKind::InvalidPlacement,
+                                format!(
+                                    "the buffer is on `{}`, which has no memory `{}`",
+                                    device.name, space
+                                ),
+                                span(task.op),
+                            )
+                            .with_note(format!(
+                                "the memories of `{}` are: {}",
+                                device.name,
+                                known.join(", ")
+                            ))
+                            .with_help(format!(
+                                "declare the memory `{}` of `{}` in the target, or move \
+                                 the buffer with `@` to a device having it",
+                                space, device.name
+                            )),
+                        );
+                        continue;
+                    }
+                    (size, space)
+                }
+                // only its tiles are on the device, in the buffers
+                Type::Tensor { .. } if data.kind == OpKind::Move && data.operands.len() == 3 => {
+                    continue;
+                }
+                Type::Tensor { .. } => {
+                    let (Some(size), Some(memory)) = (size_of(ty), device.main_memory()) else {
+                        continue;
+                    };
+                    (size, memory.name.as_str())
+                }
+                _ => continue,
+            };
+            // a buffer holds nothing before it is first used
+            let start = match ty {
+                Type::Buffer => first_use.get(value).copied().unwrap_or(task.start),
+                _ => task.start,
+            };
+            let end = if outputs.contains(value) {
+                schedule.makespan()
+            } else {
+                last_use.get(value).copied().unwrap_or(task.end)
+            };
+            ops.insert(*value, task.op);
+            spaces
+                .entry((device.name.clone(), space.to_string()))
+                .or_default()
+                .push(Allocation {
+                    value: *value,
+                    device: device.name.clone(),
+                    space: space.to_string(),
+                    offset: 0,
+                    size,
+                    start,
+                    // a value lives for a cycle at least
+                    end: end.max(start + 1),
+                });
+        }
+    }
+
+    let mut allocations = Vec::new();
+    for ((device, space), mut pending) in spaces {
+        pending.sort_by_key(|a| (std::cmp::Reverse(a.size), a.start));
+        let mut placed: Vec<Allocation> = Vec::new();
+        for mut a in pending {
+            let live: Vec<&Allocation> = placed.iter().filter(|b| b.overlaps(&a)).collect();
+            let mut offsets: Vec<u64> = std::iter::once(0)
+                .chain(
+                    live.iter()
+                        .map(|b| (b.offset + b.size).next_multiple_of(ALIGNMENT)),
+                )
+                .collect();
+            offsets.sort_unstable();
+            a.offset = offsets
+                .into_iter()
+                .find(|offset| {
+                    live.iter()
+                        .all(|b| offset + a.size <= b.offset || b.offset + b.size <= *offset)
+                })
+                .unwrap_or(0);
+            placed.push(a);
+        }
+
+        let capacity = target
+            .device(&device)
+            .and_then(|d| d.memory(&space))
+            .map_or(u64::MAX, |m| m.capacity);
+        if let Some(worst) = placed
+            .iter()
+            .filter(|a| a.offset + a.size > capacity)
+            .max_by_key(|a| a.offset + a.size)
+        {
+            let live: Vec<&Allocation> = placed
+                .iter()
+                .filter(|b| b.value != worst.value && b.overlaps(worst))
+                .collect();
+            let needed = worst.offset + worst.size;
+            let mut diag = Diagnostic::error(
+                ErrorKind::OutOfMemory,
+                format!(
+                    "`{}` of `{}` holds {} bytes, but {} are needed at once",
+                    space, device, capacity, needed
+                ),
+                span(ops[&worst.value]),
+            )
+            .with_label(format!(
+                "{} bytes live from cycle {} to {}",
+                worst.size, worst.start, worst.end
+            ));
+            for b in &live {
+                diag = diag.with_secondary(
+                    span(ops[&b.value]),
+                    format!("{} bytes live at the same time", b.size),
+                );
+            }
+            diags.push(diag.with_help(
+                "split the computation into smaller tiles, or place some values in another memory",
+            ));
+        }
+        placed.sort_by_key(|a| (a.offset, a.start));
+        allocations.extend(placed);
+    }
+
+    if diags.is_empty() {
+        Ok(MemoryPlan { allocations })
+    } else {
+        Err(diags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LatencyModel, schedule};
+
+    const TARGET: &str = r#"
+[devices.cpu]
+memory.dram = { kind = "dram", capacity = "1GiB" }
+
+[devices.xpu]
+memory.global = { kind = "dram", capacity = "1MiB" }
+memory.sram = { kind = "sram", capacity = 2048 }
+
+[[links]]
+from = "cpu"
+to = "xpu"
+bandwidth = 32
+latency = 100
+"#;
+
+    fn run(src: &str) -> (Module, Result<MemoryPlan, Vec<Diagnostic>>) {
+        let module = ir::parse(src).unwrap();
+        let target = Target::parse(TARGET).unwrap();
+        let schedule = schedule(&module, &target, &LatencyModel::default()).unwrap();
+        let plan = plan(&module, &target, &schedule);
+        (module, plan)
+    }
+
+    #[test]
+    fn reuses_memory_across_lifetimes() {
+        let (module, plan) = run("module {
+               %x = symbol xs : tensor(f32, 256)
+               %m = move %x : tensor(f32, 256) @xpu
+               %f = symbol fill : (buffer, tensor(f32, 256)) => f32 @xpu
+               %b0 = buffer $(1024, sram) : buffer @xpu
+               %u = call %f, %b0, %m : f32 @xpu
+               %b1 = buffer $(1500, sram) : buffer @xpu
+               %v = call %f, %b1, %m : f32 @xpu
+               %t = tuple %u, %v : (f32, f32) @xpu
+               yield %t
+             }");
+        let plan = plan.unwrap();
+        let ops = &module.region(module.body).ops;
+        let allocation = |i: usize| {
+            let a = plan.allocation(module.op(ops[i]).results[0]).unwrap();
+            (
+                a.device.as_str(),
+                a.space.as_str(),
+                a.offset,
+                a.start,
+                a.end,
+            )
+        };
+        assert_eq!(allocation(0), ("cpu", "dram", 0, 0, 132));
+        assert_eq!(allocation(1), ("xpu", "global", 0, 0, 172));
+        // the buffers are used one after the other
+        assert_eq!(allocation(3), ("xpu", "sram", 0, 132, 152));
+        assert_eq!(allocation(5), ("xpu", "sram", 0, 152, 172));
+        assert_eq!(plan.peak("xpu", "sram"), 1500);
+    }
+
+    #[test]
+    fn reports_exceeded_capacities_and_missing_memories() {
+        let (_, plan) = run("module {
+               %f = symbol fill : (buffer, buffer) => f32 @xpu
+               %b0 = buffer $(1024, sram) : buffer @xpu
+               %b1 = buffer $(1100, sram) : buffer @xpu
+               %u = call %f, %b0, %b1 : f32 @xpu
+               %b2 = buffer $(64, sram) : buffer
+               yield %u
+             }");
+        let diags = plan.unwrap_err();
+        let messages: Vec<&str> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "the buffer is on `cpu`, which has no memory `sram`",
+                "`sram` of `xpu` holds 2048 bytes, but 2176 are needed at once",
+            ]
+        );
+        assert_eq!(diags[1].kind.code(), "E0031");
+    }
+}
@@ -0,0 +1,262 @@
+//! Double buffering of the transfers feeding maps.
+//!
+//! `xs@xpu` followed by `.map(f)` on the xpu copies the whole of `xs`
+//! before the first element is computed. Staged through two buffers of a
+//! scratchpad of the xpu, the copy goes tile by tile: while the map
+//! computes on the tile in one buffer, the next tile is copied into the
+//! other. `schedule` pipelines such moves with their map.
+
+use crate::latency::LatencyModel;
+use device::{Device, MemoryKind, MemorySpace, Target, size_of};
+use ir::{Module, OpId, OpKind};
+use parser::types::{TensorShapeType, Type};
+use pass::{Pass, PassContext};
+
+/// Stages the moves of tensors from another device which only feed a map
+/// through two `$()` buffers of the scratchpad of the map's device.
+///
+/// A tile is a number of rows, the elements at an index of the outer dim,
+/// of every staged input. The buffers of a map take at most half of the
+/// scratchpad, leaving the other half to the next pipeline. Among the
+/// tilings which fit, the one whose pipeline is estimated to end first is
+/// chosen, and only when it ends before copying the inputs whole.
+pub struct DoubleBuffer {
+    pub latency: LatencyModel,
+    /// Most tiles a tensor is split into
+    pub max_tiles: u64,
+}
+
+impl Default for DoubleBuffer {
+    fn default() -> Self {
+        DoubleBuffer {
+            latency: LatencyModel::default(),
+            max_tiles: 64,
+        }
+    }
+}
+
+/// Fastest memory of `device` short of its registers.
+fn scratchpad(device: &Device) -> Option<&MemorySpace> {
+    device
+        .memory
+        .iter()
+        .rev()
+        .find(|m| m.kind == MemoryKind::Sram)
+}
+
+/// Bytes of a row of `ty` and its rows.
+fn rows_of(ty: &Type) -> Option<(u64, u64)> {
+    let Type::Tensor {
+        shape: TensorShapeType::Shape(dims),
+        ..
+    } = ty
+    else {
+        return None;
+    };
+    let n = dims.first()?.as_const().filter(|n| *n > 0)?;
+    Some((size_of(ty)? / n, n))
+}
+
+/// Moves of tensors from another device used by `map` alone, as inputs.
+fn staged_inputs(module: &Module, map: OpId) -> Vec<OpId> {
+    let data = module.op(map);
+    let outputs = &module.region(module.body).results;
+    let mut moves = Vec::new();
+    for input in &data.operands[..data.operands.len() - 1] {
+        let Some(op) = module.def_op(*input) else {
+            continue;
+        };
+        let moved = module.op(op);
+        if moved.kind == OpKind::Move
+            && moved.operands.len() == 1
+            && moved.device == data.device
+            && moved.parent == data.parent
+            && module.device_of(moved.operands[0]) != moved.device.as_deref()
+            && module.users(*input) == [map]
+            && !outputs.contains(input)
+            && !moves.contains(&op)
+        {
+            moves.push(op);
+        }
+    }
+    moves
+}
+
+impl DoubleBuffer {
+    /// Rows of the tiles of `map`, whose inputs of `rows` rows are moved
+    /// by `moves` along with the bytes of a row, if staging them pays off.
+    fn tile(
+        &self,
+        module: &Module,
+        target: &Target,
+        map: OpId,
+        moves: &[(OpId, u64)],
+        rows: u64,
+        capacity: u64,
+    ) -> Option<u64> {
+        let copy = |r: u64| -> u64 {
+            moves
+                .iter()
+                .map(|(op, row)| self.latency.transfer(module, *op, target, r * row))
+                .sum()
+        };
+        let compute = self.latency.cycles(module, map, target);
+        let whole = copy(rows) + compute;
+        let row: u64 = moves.iter().map(|(_, row)| row).sum();
+        let fit = capacity / 2 / (2 * row);
+        let mut candidates: Vec<u64> = (2..=self.max_tiles)
+            .map(|n| rows.div_ceil(n))
+            .filter(|r| *r <= fit)
+            .collect();
+        candidates.push(fit);
+        candidates.dedup();
+        // the copy of the first tile, then the slower of copying and
+        // computing for the others, then the computation of the last
+        let pipeline = |r: u64| {
+            let (tiles, copy, compute) = (rows.div_ceil(r), copy(r), (compute * r).div_ceil(rows));
+            copy + (tiles - 1) * copy.max(compute) + compute
+        };
+        candidates
+            .into_iter()
+            .filter(|r| *r > 0 && *r < rows)
+            .min_by_key(|r| (pipeline(*r), *r))
+            .filter(|r| pipeline(*r) < whole)
+    }
+}
+
+impl Pass for DoubleBuffer {
+    fn name(&self) -> &str {
+        "double-buffer"
+    }
+
+    fn description(&self) -> &str {
+        "Copy the inputs of maps tile by tile through pairs of buffers"
+    }
+
+    fn run_on_module(&mut self, module: &mut Module, cx: &mut PassContext) -> bool {
+        let target = &cx.target;
+        let mut changed = false;
+        for map in module.region(module.body).ops.clone() {
+            let data = module.op(map);
+            if data.kind != OpKind::Map {
+                continue;
+            }
+            let device = data.device.as_deref().unwrap_or(&target.host);
+            let Some(device) = target.device(device) else {
+                continue;
+            };
+            let Some(space) = scratchpad(device) else {
+                continue;
+            };
+            let mut moves = Vec::new();
+            let mut rows = 0;
+            for op in staged_inputs(module, map) {
+                let Some((row, n)) = rows_of(&module.value(module.op(op).results[0]).ty) else {
+                    continue;
+                };
+                moves.push((op, row));
+                rows = n;
+            }
+            if moves.iter().map(|(_, row)| row).sum::<u64>() == 0 {
+                continue;
+            }
+            let (device, space) = (device.name.clone(), space.clone());
+            let Some(tile) = self.tile(module, target, map, &moves, rows, space.capacity) else {
+                continue;
+            };
+            for (op, row) in moves {
+                for _ in 0..2 {
+                    let kind = OpKind::Buffer {
+                        size: tile * row,
+                        space: space.name.clone(),
+                    };
+                    let buffer = module.create_op(kind, vec![], vec![Type::Buffer]);
+                    module.op_mut(buffer).device = Some(device.clone());
+                    module.op_mut(buffer).span = module.op(op).span;
+                    module.insert_op_before(op, buffer);
+                    let value = module.op(buffer).results[0];
+                    module.op_mut(op).operands.push(value);
+                }
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_moves_feeding_maps_when_it_pays_off() {
+        let mut module = ir::parse(
+            "module {
+               %x = symbol xs : tensor(f32, 1024, 4)
+               %y = symbol ys : tensor(f32, 1024)
+               %mx = move %x : tensor(f32, 1024, 4) @xpu
+               %my = move %y : tensor(f32, 1024) @xpu
+               %f = lambda (%a: tensor(f32, 4), %b: f32) : (tensor(f32, 4), f32) => f32 @xpu {
+                 %c = extract %a[0] : f32 @xpu
+                 %s = symbol sin : (f32) => f32 @xpu
+                 %d = call %s, %c : f32 @xpu
+                 %e = add %d, %b : f32 @xpu
+                 yield %e
+               }
+               %z = map %mx, %my, %f : tensor(f32, 1024) @xpu
+               %w = move %y : tensor(f32, 1024) @xpu
+               %g = lambda (%p: f32) : (f32) => f32 @xpu {
+                 %q = mul %p, %p : f32 @xpu
+                 yield %q
+               }
+               %v = map %w, %g : tensor(f32, 1024) @xpu
+               %t = tuple %z, %v : (tensor(f32, 1024), tensor(f32, 1024))
+               yield %t
+             }",
+        )
+        .unwrap();
+        let mut cx = PassContext {
+            target: Target::parse(
+                r#"
+[devices.cpu]
+[devices.xpu]
+vector_width = 16
+memory.sram = { kind = "sram", capacity = "16KiB" }
+
+[[links]]
+from = "cpu"
+to = "xpu"
+bandwidth = 32
+latency = 100
+"#,
+            )
+            .unwrap(),
+            ..PassContext::default()
+        };
+        assert!(DoubleBuffer::default().run_on_module(&mut module, &mut cx));
+        ir::verify(&module).unwrap();
+        let text = module.to_string();
+        // 204 rows of 20 bytes fit twice in half the scratchpad, and 6
+        // tiles of 171 rows end first: 308 cycles to copy a tile, 225 to
+        // compute it
+        assert!(
+            text.contains(
+                "%2 = buffer $(2736, sram) : buffer @xpu\n  \
+                 %3 = buffer $(2736, sram) : buffer @xpu\n  \
+                 %4 = move %0, %2, %3 : tensor(f32, 1024, 4) @xpu\n  \
+                 %5 = buffer $(684, sram) : buffer @xpu\n  \
+                 %6 = buffer $(684, sram) : buffer @xpu\n  \
+                 %7 = move %1, %5, %6 : tensor(f32, 1024) @xpu"
+            ),
+            "{}",
+            text
+        );
+        // squaring is faster than a second copy
+        assert!(
+            text.contains("= move %1 : tensor(f32, 1024) @xpu"),
+            "{}",
+            text
+        );
+        assert!(!DoubleBuffer::default().run_on_module(&mut module, &mut cx));
+    }
+}
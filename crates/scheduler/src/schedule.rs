@@ -0,0 +1,572 @@
+//! List scheduling of the body of a module.
+
+use crate::latency::LatencyModel;
+use device::{Target, size_of};
+use diagnostics::{Diagnostic, ErrorKind};
+use ir::{Module, OpId, OpKind};
+use parser::types::Type;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Write};
+
+/// What runs a task: the compute units of a device, or the DMA engine of
+/// a link.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Resource {
+    Device(String),
+    Link { from: String, to: String },
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resource::Device(name) => write!(f, "{}", name),
+            Resource::Link { from, to } => write!(f, "{}->{}", from, to),
+        }
+    }
+}
+
+/// An op of the body, or a tile of it, with the cycles it runs in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Task {
+    pub op: OpId,
+    pub resource: Resource,
+    pub start: u64,
+    pub end: u64,
+    /// Ops it waits for, in program order
+    pub deps: Vec<OpId>,
+    /// Index of the tile of a pipelined op, `None` for a whole op
+    pub tile: Option<u64>,
+}
+
+/// The tasks of a module, in the order they are issued.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schedule {
+    pub tasks: Vec<Task>,
+}
+
+impl Schedule {
+    /// Cycles until the last task finishes.
+    pub fn makespan(&self) -> u64 {
+        self.tasks.iter().map(|t| t.end).max().unwrap_or(0)
+    }
+
+    /// Task of `op`, its first tile when pipelined.
+    pub fn task(&self, op: OpId) -> Option<&Task> {
+        self.tasks.iter().find(|t| t.op == op)
+    }
+
+    /// Resources running at least one task, sorted.
+    pub fn resources(&self) -> Vec<&Resource> {
+        let resources: BTreeSet<&Resource> = self.tasks.iter().map(|t| &t.resource).collect();
+        resources.into_iter().collect()
+    }
+
+    /// Ops run by `resource`, in the order it runs them.
+    pub fn order(&self, resource: &Resource) -> Vec<OpId> {
+        self.tasks
+            .iter()
+            .filter(|t| t.resource == *resource)
+            .map(|t| t.op)
+            .collect()
+    }
+
+    /// Dependencies between tasks of different resources, as pairs of a
+    /// producer and a consumer, where the producer has to signal the
+    /// consumer when it finishes.
+    pub fn syncs(&self) -> Vec<(OpId, OpId)> {
+        let resources: HashMap<OpId, &Resource> =
+            self.tasks.iter().map(|t| (t.op, &t.resource)).collect();
+        self.tasks
+            .iter()
+            .flat_map(|t| t.deps.iter().map(move |dep| (*dep, t)))
+            .filter(|(dep, t)| resources.get(dep) != Some(&&t.resource))
+            .map(|(dep, t)| (dep, t.op))
+            .collect()
+    }
+
+    /// Text of the schedule, one task per line with its resource, cycles
+    /// and the op as named in the text of `module`, e.g.
+    ///
+    /// ```text
+    /// cpu->xpu       0     228  %2 = move %0
+    /// xpu          228     292  %4 = mul %2, %2  after cpu->xpu
+    /// ```
+    pub fn render(&self, module: &Module) -> String {
+        let numbers = module.value_numbers();
+        let name = |op: OpId| {
+            let results: Vec<String> = module
+                .op(op)
+                .results
+                .iter()
+                .map(|v| format!("%{}", numbers[v]))
+                .collect();
+            results.join(", ")
+        };
+        let resources: HashMap<OpId, &Resource> =
+            self.tasks.iter().map(|t| (t.op, &t.resource)).collect();
+        let width = self
+            .resources()
+            .iter()
+            .map(|r| r.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let mut out = String::new();
+        for task in &self.tasks {
+            let data = module.op(task.op);
+            let operands: Vec<String> = data
+                .operands
+                .iter()
+                .map(|v| format!("%{}", numbers[v]))
+                .collect();
+            let _ = write!(
+                out,
+                "{:width$} {:>7} {:>7}  {} = {}",
+                task.resource.to_string(),
+                task.start,
+                task.end,
+                name(task.op),
+                data.kind.name(),
+            );
+            if !operands.is_empty() {
+                let _ = write!(out, " {}", operands.join(", "));
+            }
+            if let Some(tile) = task.tile {
+                let _ = write!(out, "  tile {}", tile);
+            }
+            let waits: BTreeSet<String> = task
+                .deps
+                .iter()
+                .filter_map(|dep| resources.get(dep))
+                .filter(|r| ***r != task.resource)
+                .map(|r| r.to_string())
+                .collect();
+            if !waits.is_empty() {
+                let waits: Vec<String> = waits.into_iter().collect();
+                let _ = write!(out, "  after {}", waits.join(", "));
+            }
+            out.push('\n');
+        }
+        let _ = writeln!(out, "// makespan: {} cycles", self.makespan());
+        out
+    }
+}
+
+/// Resource running `op`, unplaced ops running on the host. Moving a
+/// buffer allocates it without using the link.
+fn resource(module: &Module, op: OpId, target: &Target) -> Resource {
+    let data = module.op(op);
+    let to = data.device.clone().unwrap_or_else(|| target.host.clone());
+    if data.kind == OpKind::Move && module.value(data.results[0]).ty != Type::Buffer {
+        let from = module.device_of(data.operands[0]).unwrap_or(&target.host);
+        if from != to {
+            return Resource::Link {
+                from: from.to_string(),
+                to,
+            };
+        }
+    }
+    Resource::Device(to)
+}
+
+/// Tasks issued so far and the cycle each resource is free from.
+#[derive(Default)]
+struct Timeline {
+    tasks: Vec<Task>,
+    free: HashMap<Resource, u64>,
+}
+
+impl Timeline {
+    /// Issue `op` on `resource` once it is free and `after` has passed,
+    /// returning when it ends.
+    fn issue(
+        &mut self,
+        op: OpId,
+        resource: Resource,
+        after: u64,
+        cycles: u64,
+        deps: Vec<OpId>,
+        tile: Option<u64>,
+    ) -> u64 {
+        let start = after.max(self.free.get(&resource).copied().unwrap_or(0));
+        let end = start + cycles;
+        self.free.insert(resource.clone(), end);
+        self.tasks.push(Task {
+            op,
+            resource,
+            start,
+            end,
+            deps,
+            tile,
+        });
+        end
+    }
+}
+
+/// Bytes of the tiles `move` copies through its buffers.
+fn tile_bytes(module: &Module, op: OpId) -> u64 {
+    let buffer = module.def_op(module.op(op).operands[1]);
+    match buffer.map(|b| &module.op(b).kind) {
+        Some(OpKind::Buffer { size, .. }) => (*size).max(1),
+        _ => 1,
+    }
+}
+
+/// Schedule the ops of the body of `module`, each with the ops nested in
+/// it, on the devices and links of `target`.
+///
+/// Ops wait for the ops defining their operands and the values their
+/// lambdas capture, and calls with effects keep their order. Among the
+/// ops ready to run, the one with the longest path of cycles to the end
+/// of the program goes first, as soon as its resource is free.
+///
+/// A map whose inputs are moved through two buffers, see `DoubleBuffer`,
+/// runs tile by tile with those moves: tile i+1 is copied into one buffer
+/// while the map computes on tile i in the other, and tile i+2 waits for
+/// the map to be done with tile i.
+///
+/// Fails when a move copies a value between devices no link connects.
+pub fn schedule(
+    module: &Module,
+    target: &Target,
+    latency: &LatencyModel,
+) -> Result<Schedule, Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+    for op in module.ops() {
+        if let Resource::Link { from, to } = resource(module, op, target)
+            && target.link(&from, &to).is_none()
+        {
+            errors.push(
+                Diagnostic::error(
+                    ErrorKind::InvalidPlacement,
+                    format!(
+                        "no link of the target copies values from `{}` to `{}`",
+                        from, to
+                    ),
+                    module.op(op).span.unwrap_or_default(),
+                )
+                .with_help(format!(
+                    "declare a `[[links]]` from `{}` to `{}` in the target",
+                    from, to
+                )),
+            );
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let ops = &module.region(module.body).ops;
+    let index: HashMap<OpId, usize> = ops.iter().enumerate().map(|(i, op)| (*op, i)).collect();
+
+    let mut deps: Vec<Vec<usize>> = vec![Vec::new(); ops.len()];
+    let mut last_call = None;
+    for (i, op) in ops.iter().enumerate() {
+        let nested = module.op(*op).regions.iter().flat_map(|r| module.walk(*r));
+        for inner in std::iter::once(*op).chain(nested) {
+            for operand in &module.op(inner).operands {
+                if let Some(j) = module.def_op(*operand).and_then(|d| index.get(&d))
+                    && *j != i
+                    && !deps[i].contains(j)
+                {
+                    deps[i].push(*j);
+                }
+            }
+        }
+        if module.op(*op).kind == OpKind::Call && !module.is_pure(*op) {
+            if let Some(j) = last_call
+                && !deps[i].contains(&j)
+            {
+                deps[i].push(j);
+            }
+            last_call = Some(i);
+        }
+        deps[i].sort_unstable();
+    }
+
+    let cycles: Vec<u64> = ops
+        .iter()
+        .map(|op| latency.cycles(module, *op, target))
+        .collect();
+    // ops come after the ops they depend on
+    let mut rank = cycles.clone();
+    for i in (0..ops.len()).rev() {
+        for j in deps[i].clone() {
+            rank[j] = rank[j].max(cycles[j] + rank[i]);
+        }
+    }
+
+    // moves staged through buffers, by the map they feed
+    let mut staged: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        let data = module.op(*op);
+        if data.kind == OpKind::Move
+            && data.operands.len() == 3
+            && let [user] = module.users(data.results[0])[..]
+            && module.op(user).kind == OpKind::Map
+            && let Some(j) = index.get(&user)
+        {
+            staged.entry(*j).or_default().push(i);
+        }
+    }
+
+    let mut waiting: Vec<usize> = deps.iter().map(Vec::len).collect();
+    let mut users: Vec<Vec<usize>> = vec![Vec::new(); ops.len()];
+    for (i, deps) in deps.iter().enumerate() {
+        for j in deps {
+            users[*j].push(i);
+        }
+    }
+    let mut ready: Vec<usize> = (0..ops.len()).filter(|i| waiting[*i] == 0).collect();
+    let mut end = vec![0u64; ops.len()];
+    let mut timeline = Timeline::default();
+    let ids = |deps: &[usize]| deps.iter().map(|j| ops[*j]).collect::<Vec<_>>();
+    while let Some(k) =
+        (0..ready.len()).max_by_key(|k| (rank[ready[*k]], std::cmp::Reverse(ready[*k])))
+    {
+        let i = ready.swap_remove(k);
+        let unit = resource(module, ops[i], target);
+        if let Some(moves) = staged.get(&i) {
+            let tiles = moves
+                .iter()
+                .map(|m| {
+                    let ty = &module.value(module.op(ops[*m]).results[0]).ty;
+                    size_of(ty)
+                        .unwrap_or(0)
+                        .div_ceil(tile_bytes(module, ops[*m]))
+                })
+                .max()
+                .unwrap_or(1)
+                .max(1);
+            let after = deps[i]
+                .iter()
+                .filter(|j| !moves.contains(j))
+                .map(|j| end[*j])
+                .max()
+                .unwrap_or(0);
+            let mut computed: Vec<u64> = Vec::new();
+            for tile in 0..tiles {
+                let mut copied = after;
+                for m in moves {
+                    let mut wait = deps[*m].iter().map(|j| end[*j]).max().unwrap_or(0);
+                    let mut waits = ids(&deps[*m]);
+                    // the buffer of tile i+2 is the one of tile i
+                    if tile >= 2 {
+                        wait = wait.max(computed[tile as usize - 2]);
+                        waits.push(ops[i]);
+                    }
+                    let total = size_of(&module.value(module.op(ops[*m]).results[0]).ty);
+                    let size = tile_bytes(module, ops[*m]);
+                    let bytes = size.min(total.unwrap_or(0).saturating_sub(tile * size));
+                    let link = resource(module, ops[*m], target);
+                    let cycles = latency.transfer(module, ops[*m], target, bytes);
+                    end[*m] = timeline.issue(ops[*m], link, wait, cycles, waits, Some(tile));
+                    copied = copied.max(end[*m]);
+                }
+                let cycles = cycles[i].div_ceil(tiles);
+                let done = timeline.issue(
+                    ops[i],
+                    unit.clone(),
+                    copied,
+                    cycles,
+                    ids(&deps[i]),
+                    Some(tile),
+                );
+                computed.push(done);
+            }
+            end[i] = computed.last().copied().unwrap_or(after);
+        } else if !staged.values().any(|moves| moves.contains(&i)) {
+            let after = deps[i].iter().map(|j| end[*j]).max().unwrap_or(0);
+            end[i] = timeline.issue(ops[i], unit, after, cycles[i], ids(&deps[i]), None);
+        }
+        for u in &users[i] {
+            waiting[*u] -= 1;
+            if waiting[*u] == 0 {
+                ready.push(*u);
+            }
+        }
+    }
+    Ok(Schedule {
+        tasks: timeline.tasks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DoubleBuffer, plan};
+    use pass::{Pass, PassContext};
+
+    #[test]
+    fn overlaps_transfers_with_compute() {
+        let module = ir::parse(
+            "module {
+               %a = symbol a : tensor(f32, 1024)
+               %b = symbol b : tensor(f32, 1024)
+               %ma = move %a : tensor(f32, 1024) @xpu
+               %mb = move %b : tensor(f32, 1024) @xpu
+               %x = mul %ma, %ma : tensor(f32, 1024) @xpu
+               %y = mul %mb, %mb : tensor(f32, 1024) @xpu
+               %s = add %x, %y : tensor(f32, 1024) @xpu
+               %r = move %s : tensor(f32, 1024) @cpu
+               yield %r
+             }",
+        )
+        .unwrap();
+        let target = Target::parse(
+            r#"
+[devices.cpu]
+[devices.xpu]
+vector_width = 16
+
+[[links]]
+from = "cpu"
+to = "xpu"
+bandwidth = 32
+latency = 100
+
+[[links]]
+from = "xpu"
+to = "cpu"
+bandwidth = 32
+latency = 100
+"#,
+        )
+        .unwrap();
+        let schedule = schedule(&module, &target, &LatencyModel::default()).unwrap();
+        let ops = &module.region(module.body).ops;
+        let cycles = |i: usize| {
+            let task = schedule.task(ops[i]).unwrap();
+            (task.start, task.end)
+        };
+        // the copy of `b` runs while the xpu squares `a`
+        assert_eq!(
+            (2..8).map(cycles).collect::<Vec<_>>(),
+            [
+                (0, 228),
+                (228, 456),
+                (228, 292),
+                (456, 520),
+                (520, 584),
+                (584, 812)
+            ]
+        );
+        assert_eq!(schedule.makespan(), 812);
+        let xpu = Resource::Device("xpu".to_string());
+        assert_eq!(schedule.order(&xpu), [ops[4], ops[5], ops[6]]);
+        assert_eq!(
+            schedule.syncs(),
+            [
+                (ops[0], ops[2]),
+                (ops[1], ops[3]),
+                (ops[2], ops[4]),
+                (ops[3], ops[5]),
+                (ops[6], ops[7])
+            ]
+        );
+        let text = schedule.render(&module);
+        assert!(
+            text.contains("xpu          228     292  %4 = mul %2, %2  after cpu->xpu\n"),
+            "{}",
+            text
+        );
+        assert!(text.ends_with("// makespan: 812 cycles\n"));
+
+        let one_way = Target::parse(
+            "[devices.cpu]\n[devices.xpu]\n\n[[links]]\nfrom = \"cpu\"\nto = \"xpu\"\nbandwidth = 32\n",
+        )
+        .unwrap();
+        let errors = super::schedule(&module, &one_way, &LatencyModel::default()).unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "no link of the target copies values from `xpu` to `cpu`"
+        );
+    }
+
+    #[test]
+    fn pipelines_double_buffered_moves() {
+        let mut module = ir::parse(
+            "module {
+               %x = symbol xs : tensor(f32, 1024)
+               %m = move %x : tensor(f32, 1024) @xpu
+               %f = lambda (%p: f32) : (f32) => f32 @xpu {
+                 %s = symbol sin : (f32) => f32 @xpu
+                 %a = call %s, %p : f32 @xpu
+                 %b = mul %a, %a : f32 @xpu
+                 yield %b
+               }
+               %y = map %m, %f : tensor(f32, 1024) @xpu
+               yield %y
+             }",
+        )
+        .unwrap();
+        let target = Target::parse(
+            r#"
+[devices.cpu]
+memory.dram = { kind = "dram", capacity = "1GiB" }
+
+[devices.xpu]
+vector_width = 16
+memory.global = { kind = "dram", capacity = "1GiB" }
+memory.sram = { kind = "sram", capacity = "16KiB" }
+
+[[links]]
+from = "cpu"
+to = "xpu"
+bandwidth = 32
+latency = 100
+"#,
+        )
+        .unwrap();
+        let latency = LatencyModel::default();
+        // copied whole, then computed
+        assert_eq!(
+            schedule(&module, &target, &latency).unwrap().makespan(),
+            228 + 1344
+        );
+
+        let mut cx = PassContext {
+            target: target.clone(),
+            ..PassContext::default()
+        };
+        assert!(DoubleBuffer::default().run_on_module(&mut module, &mut cx));
+        let schedule = schedule(&module, &target, &latency).unwrap();
+        let tiles: Vec<(String, u64, u64)> = schedule
+            .tasks
+            .iter()
+            .filter(|t| t.tile.is_some())
+            .map(|t| (t.resource.to_string(), t.start, t.end))
+            .collect();
+        // 8 tiles of 128 rows, the third copy waiting for the first tile to
+        // be computed
+        assert_eq!(
+            tiles[..6],
+            [
+                ("cpu->xpu".to_string(), 0, 116),
+                ("xpu".to_string(), 116, 284),
+                ("cpu->xpu".to_string(), 116, 232),
+                ("xpu".to_string(), 284, 452),
+                ("cpu->xpu".to_string(), 284, 400),
+                ("xpu".to_string(), 452, 620),
+            ]
+        );
+        assert_eq!(tiles.len(), 16);
+        assert_eq!(schedule.makespan(), 116 + 8 * 168);
+
+        let plan = plan(&module, &target, &schedule).unwrap();
+        let spaces: Vec<(&str, u64, u64, u64)> = plan
+            .allocations
+            .iter()
+            .map(|a| (a.space.as_str(), a.offset, a.size, a.end))
+            .collect();
+        // the moved tensor is never whole on the xpu
+        assert_eq!(
+            spaces,
+            [
+                ("dram", 0, 4096, 1240),
+                ("global", 0, 4096, 1460),
+                ("sram", 0, 512, 1460),
+                ("sram", 512, 512, 1460),
+            ]
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use crate::unify::{Constraint, Vars};
+use parser::types::Type;
+
+fn function(params: Vec<Type>, ret: Type) -> Type {
+    Type::Function {
+        params,
+        ret: Box::new(ret),
+    }
+}
+
+/// Type of a builtin name with fresh variables, the counterpart of the
+/// builtins of the interpreter.
+pub fn lookup(name: &str, vars: &mut Vars) -> Option<Type> {
+    Some(match name {
+        "pi" | "e" => vars.fresh_constrained(Constraint::Float),
+        "true" | "false" => Type::Bool,
+        "sin" | "cos" | "tan" | "exp" | "log" | "sqrt" => {
+            let a = vars.fresh_constrained(Constraint::Float);
+            function(vec![a.clone()], a)
+        }
+        "abs" => {
+            let a = vars.fresh_constrained(Constraint::Num);
+            function(vec![a.clone()], a)
+        }
+        "min" | "max" => {
+            let a = vars.fresh_constrained(Constraint::Num);
+            function(vec![a.clone(), a.clone()], a)
+        }
+        "len" => function(vec![Type::List(Box::new(vars.fresh()))], Type::I64),
+        "sum" => {
+            let a = vars.fresh_constrained(Constraint::Num);
+            function(vec![Type::List(Box::new(a.clone()))], a)
+        }
+        "map" => {
+            let a = vars.fresh();
+            let b = vars.fresh();
+            function(
+                vec![
+                    Type::List(Box::new(a.clone())),
+                    function(vec![a], b.clone()),
+                ],
+                Type::List(Box::new(b)),
+            )
+        }
+        _ => return None,
+    })
+}
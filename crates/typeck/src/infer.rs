@@ -0,0 +1,849 @@
+use crate::builtins;
+use crate::scheme::Scheme;
+use crate::shape;
+use crate::unify::{self, Constraint, UnifyError, Vars};
+use diagnostics::{Diagnostic, ErrorKind, Span};
+use lexer::Token;
+use parser::expr::{Expr, ExprKind};
+use parser::types::{Dim, TensorShapeType, Type};
+use std::collections::{HashMap, HashSet};
+
+/// Schemes of the names in scope.
+pub type Env = HashMap<String, Scheme>;
+
+/// Hindley-Milner inference over the AST. Bindings of lambdas are
+/// generalized, other bindings stay monomorphic and their numeric
+/// literals default to `i64` or `f64` when nothing else decides.
+#[derive(Debug, Clone, Default)]
+pub struct TypeChecker {
+    vars: Vars,
+    globals: Env,
+    /// Variables quantified by some scheme, they are never defaulted
+    generic: HashSet<u32>,
+    /// Values of `MetaDefine` constants, which specialize symbolic dims
+    dims: HashMap<String, u64>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+const SYMBOLIC_HELP: &str =
+    "a symbolic dim only equals itself, give it a value with a meta define such as `N#3`";
+
+fn describe(constraint: Constraint) -> &'static str {
+    match constraint {
+        Constraint::Num => "a number",
+        Constraint::Int => "an integer",
+        Constraint::Float => "a float",
+    }
+}
+
+impl TypeChecker {
+    pub fn new() -> Self {
+        TypeChecker::default()
+    }
+
+    /// Scheme of a global binding.
+    pub fn lookup(&self, name: &str) -> Option<Scheme> {
+        self.globals.get(name).map(|s| s.zonk(&self.vars))
+    }
+
+    /// Value of a `MetaDefine` constant usable as a tensor dim.
+    pub fn dim_value(&self, name: &str) -> Option<u64> {
+        self.dims.get(name).copied()
+    }
+
+    fn specialize_dim(&self, dim: &Dim) -> Dim {
+        dim.specialize(&|name| self.dim_value(name))
+    }
+
+    /// `ty` with the symbolic dims of its tensors replaced by the values of
+    /// the `MetaDefine` constants they name.
+    fn specialize(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Tensor { dtype, shape } => Type::Tensor {
+                dtype: Box::new(self.specialize(dtype)),
+                shape: match shape {
+                    TensorShapeType::Shape(dims) => TensorShapeType::Shape(
+                        dims.iter().map(|d| self.specialize_dim(d)).collect(),
+                    ),
+                    TensorShapeType::Any => TensorShapeType::Any,
+                },
+            },
+            Type::List(elem) => Type::List(Box::new(self.specialize(elem))),
+            Type::Tuple(items) => Type::Tuple(items.iter().map(|t| self.specialize(t)).collect()),
+            Type::Function { params, ret } => Type::Function {
+                params: params.iter().map(|t| self.specialize(t)).collect(),
+                ret: Box::new(self.specialize(ret)),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    /// Infer the types of `program` and store them in the `ty` of every
+    /// node. The bindings stay visible to later programs.
+    pub fn check_program(&mut self, program: &mut Expr) -> Vec<Diagnostic> {
+        let mut env = std::mem::take(&mut self.globals);
+        if let ExprKind::Block(stmts) = &mut program.kind {
+            let mut last = Type::Unit;
+            for stmt in stmts.iter_mut() {
+                last = self.infer(stmt, &mut env);
+            }
+            program.ty = last;
+        } else {
+            self.infer(program, &mut env);
+        }
+        self.globals = env;
+        self.default_constrained();
+        self.fill(program);
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    /// Type of an expression without keeping its bindings.
+    pub fn type_of(&self, expr: &mut Expr) -> Result<Scheme, Vec<Diagnostic>> {
+        if let ExprKind::Identifier { name, typ } = &expr.kind
+            && *typ == Type::Unknown
+            && let Some(scheme) = self.lookup(name)
+        {
+            return Ok(scheme);
+        }
+        let mut checker = self.clone();
+        let mut env = checker.globals.clone();
+        let ty = checker.infer(expr, &mut env);
+        if checker.diagnostics.iter().any(|d| d.is_error()) {
+            return Err(checker.diagnostics);
+        }
+        let scheme = if matches!(expr.kind, ExprKind::Lambda { .. }) {
+            checker.generalize(&ty, &env)
+        } else {
+            checker.default_constrained();
+            Scheme::mono(ty)
+        };
+        checker.fill(expr);
+        Ok(scheme.zonk(&checker.vars))
+    }
+
+    fn generalize(&mut self, ty: &Type, env: &Env) -> Scheme {
+        let ty = self.vars.zonk(ty);
+        let mut vars = Vec::new();
+        unify::free_vars(&ty, &mut vars);
+        let mut in_env = Vec::new();
+        for scheme in env.values() {
+            let mut free = Vec::new();
+            unify::free_vars(&self.vars.zonk(&scheme.ty), &mut free);
+            in_env.extend(
+                free.into_iter()
+                    .filter(|v| !scheme.vars.iter().any(|(q, _)| q == v)),
+            );
+        }
+        vars.retain(|v| !in_env.contains(v));
+        self.generic.extend(&vars);
+        Scheme {
+            vars: vars
+                .into_iter()
+                .map(|v| (v, self.vars.constraint(v)))
+                .collect(),
+            ty,
+        }
+    }
+
+    /// Solve the constrained variables nothing else decided.
+    fn default_constrained(&mut self) {
+        for (var, constraint) in self.vars.constrained() {
+            if !self.generic.contains(&var) {
+                let _ = self.vars.unify(&Type::Var(var), &constraint.default_type());
+            }
+        }
+    }
+
+    fn fill(&self, expr: &mut Expr) {
+        expr.ty = self.vars.zonk(&expr.ty);
+        for child in expr.children_mut() {
+            self.fill(child);
+        }
+    }
+
+    /// Render types with their variables renamed in order of appearance.
+    fn names(&self, types: &[&Type]) -> Vec<String> {
+        let types: Vec<Type> = types.iter().map(|t| self.vars.zonk(t)).collect();
+        let mut order = Vec::new();
+        for ty in &types {
+            unify::free_vars(ty, &mut order);
+        }
+        let map: HashMap<u32, Type> = order
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (*v, Type::Var(i as u32)))
+            .collect();
+        types
+            .iter()
+            .map(|t| unify::substitute(t, &map).to_string())
+            .collect()
+    }
+
+    /// Unify the type `found` at `span` with `expected`.
+    fn expect(&mut self, expected: &Type, found: &Type, span: Span) -> Result<(), Diagnostic> {
+        let err = match self.vars.unify(expected, found) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let names = self.names(&[expected, found]);
+        Err(match err {
+            UnifyError::Mismatch => Diagnostic::error(
+                ErrorKind::TypeMismatch {
+                    expected: names[0].clone(),
+                    found: names[1].clone(),
+                },
+                "mismatched types",
+                span,
+            )
+            .with_label(format!("expected `{}`, found `{}`", names[0], names[1])),
+            UnifyError::Arity { expected, found } => Diagnostic::error(
+                ErrorKind::ArityMismatch { expected, found },
+                "mismatched types",
+                span,
+            )
+            .with_label(format!("expected `{}`, found `{}`", names[0], names[1])),
+            UnifyError::Unsatisfied(constraint, ty) => {
+                let ty = format!("`{}`", self.names(&[&ty]).remove(0));
+                let (expected, found) = match self.vars.resolve(found) {
+                    // e.g. a literal where a bool is expected
+                    Type::Var(_) => (ty, describe(constraint).to_string()),
+                    _ => (describe(constraint).to_string(), ty),
+                };
+                Diagnostic::error(
+                    ErrorKind::TypeMismatch {
+                        expected: expected.clone(),
+                        found: found.clone(),
+                    },
+                    "mismatched types",
+                    span,
+                )
+                .with_label(format!("expected {}, found {}", expected, found))
+            }
+            UnifyError::Shape(expected, found) => {
+                let symbolic = expected.iter().chain(&found).any(Dim::is_symbolic);
+                let (expected, found) = (
+                    TensorShapeType::Shape(expected).to_string(),
+                    TensorShapeType::Shape(found).to_string(),
+                );
+                let diag = Diagnostic::error(
+                    ErrorKind::ShapeMismatch {
+                        expected: expected.clone(),
+                        found: found.clone(),
+                    },
+                    "mismatched tensor shapes",
+                    span,
+                )
+                .with_label(format!(
+                    "expected shape ({}), found shape ({})",
+                    expected, found
+                ));
+                if symbolic {
+                    diag.with_help(SYMBOLIC_HELP)
+                } else {
+                    diag
+                }
+            }
+            UnifyError::Infinite => Diagnostic::error(
+                ErrorKind::InfiniteType,
+                format!("`{}` would have to contain `{}`", names[1], names[0]),
+                span,
+            )
+            .with_label("this would need an infinite type"),
+        })
+    }
+
+    fn report(&mut self, result: Result<(), Diagnostic>) {
+        if let Err(diag) = result {
+            self.diagnostics.push(diag);
+        }
+    }
+
+    /// Require the operand type `ty` of `op` to satisfy `constraint`.
+    fn constrain(&mut self, ty: &Type, constraint: Constraint, op: &Token, span: Span) {
+        if self.vars.satisfy(constraint, ty).is_err() {
+            let found = self.names(&[ty]).remove(0);
+            self.diagnostics.push(
+                Diagnostic::error(
+                    ErrorKind::TypeMismatch {
+                        expected: describe(constraint).to_string(),
+                        found: found.clone(),
+                    },
+                    format!("`{}` can not be applied to `{}`", op, found),
+                    span,
+                )
+                .with_label(format!("expected {}", describe(constraint))),
+            );
+        }
+    }
+
+    fn is_scalar(&self, ty: &Type) -> bool {
+        match self.vars.resolve(ty) {
+            Type::Var(v) => self.vars.constraint(v).is_some(),
+            ty => Constraint::Num.accepts(&ty),
+        }
+    }
+
+    /// Type of a list literal, a tensor when its items are numbers or
+    /// tensors of one shape.
+    fn list(&mut self, items: &[(Type, Span)]) -> Type {
+        let Some((elem, first)) = items.first().cloned() else {
+            return Type::List(Box::new(self.vars.fresh()));
+        };
+        for (ty, span) in &items[1..] {
+            let result = self
+                .expect(&elem, ty, *span)
+                .map_err(|d| d.with_secondary(first, "the element type is decided here"));
+            self.report(result);
+        }
+        let len = items.len() as u64;
+        match self.vars.resolve(&elem) {
+            Type::Tensor { dtype, shape } => Type::Tensor {
+                dtype,
+                shape: shape::stack(Dim::Const(len), &shape),
+            },
+            _ if self.is_scalar(&elem) => Type::Tensor {
+                dtype: Box::new(elem),
+                shape: TensorShapeType::Shape(vec![Dim::Const(len)]),
+            },
+            _ => Type::List(Box::new(elem)),
+        }
+    }
+
+    /// Type of an element-wise operation, where the operand with the lower
+    /// rank is broadcast to the other one.
+    fn elementwise(&mut self, l: &Type, r: &Type, left: Span, right: Span) -> Type {
+        match (self.vars.resolve(l), self.vars.resolve(r)) {
+            (
+                Type::Tensor { dtype, shape },
+                Type::Tensor {
+                    dtype: dtype2,
+                    shape: shape2,
+                },
+            ) => {
+                let result = self
+                    .expect(&dtype, &dtype2, right)
+                    .map_err(|d| d.with_secondary(left, "left operand"));
+                self.report(result);
+                let shape = shape::broadcast(&shape, &shape2).unwrap_or_else(|| {
+                    let names = self.names(&[l, r]);
+                    let mut diag = Diagnostic::error(
+                        ErrorKind::NotBroadcastable,
+                        format!("shapes ({}) and ({}) can not be broadcast", shape, shape2),
+                        left.to(right),
+                    )
+                    .with_secondary(left, format!("`{}`", names[0]))
+                    .with_secondary(right, format!("`{}`", names[1]))
+                    .with_note("dims are aligned from the last one and must be equal or 1");
+                    if [&shape, &shape2].into_iter().any(|s| {
+                        matches!(s, TensorShapeType::Shape(dims) if dims.iter().any(Dim::is_symbolic))
+                    }) {
+                        diag = diag.with_help(SYMBOLIC_HELP);
+                    }
+                    self.diagnostics.push(diag);
+                    TensorShapeType::Any
+                });
+                Type::Tensor { dtype, shape }
+            }
+            (tensor @ Type::Tensor { .. }, Type::List(_))
+            | (Type::List(_), tensor @ Type::Tensor { .. }) => {
+                let result = self
+                    .expect(l, r, right)
+                    .map_err(|d| d.with_secondary(left, "left operand"));
+                self.report(result);
+                tensor
+            }
+            (Type::Tensor { dtype, shape }, _) => {
+                let dtype = self.elementwise(&dtype, r, left, right);
+                Type::Tensor {
+                    dtype: Box::new(dtype),
+                    shape,
+                }
+            }
+            (_, Type::Tensor { dtype, shape }) => {
+                let dtype = self.elementwise(l, &dtype, left, right);
+                Type::Tensor {
+                    dtype: Box::new(dtype),
+                    shape,
+                }
+            }
+            (Type::List(a), Type::List(b)) => {
+                Type::List(Box::new(self.elementwise(&a, &b, left, right)))
+            }
+            (Type::List(a), _) => Type::List(Box::new(self.elementwise(&a, r, left, right))),
+            (_, Type::List(b)) => Type::List(Box::new(self.elementwise(l, &b, left, right))),
+            _ => {
+                let result = self
+                    .expect(l, r, right)
+                    .map_err(|d| d.with_secondary(left, "left operand"));
+                self.report(result);
+                l.clone()
+            }
+        }
+    }
+
+    /// `ty` with its elements replaced by `dtype`, e.g. for comparisons.
+    fn with_dtype(&self, ty: &Type, dtype: Type) -> Type {
+        match self.vars.resolve(ty) {
+            Type::Tensor { shape, .. } => Type::Tensor {
+                dtype: Box::new(dtype),
+                shape,
+            },
+            Type::List(elem) => Type::List(Box::new(self.with_dtype(&elem, dtype))),
+            _ => dtype,
+        }
+    }
+
+    /// Result of `map` over `input`, which keeps the outer dim of a tensor.
+    fn map_result(&mut self, input: &Type, ret: Type) -> Type {
+        let Type::Tensor {
+            shape: TensorShapeType::Shape(dims),
+            ..
+        } = self.vars.resolve(input)
+        else {
+            return ret;
+        };
+        let (Some(len), Type::List(elem)) = (dims.first(), self.vars.resolve(&ret)) else {
+            return ret;
+        };
+        match self.vars.resolve(&elem) {
+            Type::Tensor { dtype, shape } => Type::Tensor {
+                dtype,
+                shape: shape::stack(len.clone(), &shape),
+            },
+            _ if self.is_scalar(&elem) => Type::Tensor {
+                dtype: elem,
+                shape: TensorShapeType::Shape(vec![len.clone()]),
+            },
+            _ => ret,
+        }
+    }
+
+    fn literal(&mut self, constraint: Constraint, annotation: &Type, span: Span) -> Type {
+        let ty = self.vars.fresh_constrained(constraint);
+        if *annotation != Type::Unknown {
+            let result = self.expect(annotation, &ty, span);
+            self.report(result);
+        }
+        ty
+    }
+
+    fn bind_param(&mut self, param: &mut Expr, env: &mut Env) -> Type {
+        let ty = match &param.kind {
+            ExprKind::Identifier { name, typ } => {
+                let ty = if *typ == Type::Unknown {
+                    self.vars.fresh()
+                } else {
+                    self.specialize(typ)
+                };
+                env.insert(name.clone(), Scheme::mono(ty.clone()));
+                ty
+            }
+            _ => {
+                self.diagnostics.push(Diagnostic::error(
+                    ErrorKind::InvalidLambdaParam,
+                    "expect an identifier as lambda parameter",
+                    param.span,
+                ));
+                self.vars.fresh()
+            }
+        };
+        param.ty = ty.clone();
+        ty
+    }
+
+    fn infer(&mut self, expr: &mut Expr, env: &mut Env) -> Type {
+        let span = expr.span;
+        let ty = match &mut expr.kind {
+            ExprKind::Unit => Type::Unit,
+            ExprKind::Integer { typ, .. } => {
+                let typ = self.specialize(typ);
+                self.literal(Constraint::Num, &typ, span)
+            }
+            ExprKind::Float { typ, .. } => {
+                let typ = self.specialize(typ);
+                self.literal(Constraint::Float, &typ, span)
+            }
+            ExprKind::List(items) => {
+                let items: Vec<(Type, Span)> = items
+                    .iter_mut()
+                    .map(|item| (self.infer(item, env), item.span))
+                    .collect();
+                self.list(&items)
+            }
+            ExprKind::Tuple(items) => {
+                Type::Tuple(items.iter_mut().map(|item| self.infer(item, env)).collect())
+            }
+            ExprKind::Buffer { .. } => Type::Buffer,
+            ExprKind::Identifier { name, typ } => {
+                let ty = if let Some(scheme) = env.get(name) {
+                    scheme.instantiate(&mut self.vars)
+                } else if let Some(ty) = builtins::lookup(name, &mut self.vars) {
+                    ty
+                } else {
+                    self.diagnostics.push(
+                        Diagnostic::warning(
+                            ErrorKind::UnboundName { name: name.clone() },
+                            format!("`{}` is not bound, it is assumed to be an input", name),
+                            span,
+                        )
+                        .with_label("not found in this scope"),
+                    );
+                    let ty = self.vars.fresh();
+                    // later uses share the type and the warning
+                    env.insert(name.clone(), Scheme::mono(ty.clone()));
+                    ty
+                };
+                if *typ != Type::Unknown {
+                    let typ = self.specialize(typ);
+                    let result = self.expect(&typ, &ty, span);
+                    self.report(result);
+                }
+                ty
+            }
+            ExprKind::Assign { name, val } => {
+                let ty = self.infer(val, env);
+                let ExprKind::Identifier { name: id, typ } = &name.kind else {
+                    unreachable!("the parser only assigns to identifiers")
+                };
+                let (id, typ) = (id.clone(), self.specialize(typ));
+                if typ != Type::Unknown {
+                    let result = self.expect(&typ, &ty, val.span).map_err(|d| {
+                        d.with_secondary(name.span, "expected due to this annotation")
+                    });
+                    self.report(result);
+                }
+                let scheme = if matches!(val.kind, ExprKind::Lambda { .. }) {
+                    self.generalize(&ty, env)
+                } else {
+                    Scheme::mono(ty.clone())
+                };
+                name.ty = ty.clone();
+                env.insert(id, scheme);
+                ty
+            }
+            ExprKind::MetaDefine { name, val } => {
+                let ty = self.infer(val, env);
+                if let Some(Dim::Const(n)) = val.to_dim().map(|d| self.specialize_dim(&d)) {
+                    self.dims.insert(name.clone(), n);
+                }
+                env.insert(name.clone(), Scheme::mono(ty.clone()));
+                ty
+            }
+            ExprKind::Unary { op, arg } => {
+                let ty = self.infer(arg, env);
+                match op {
+                    Token::Minus => {
+                        self.constrain(&ty, Constraint::Num, op, span);
+                        ty
+                    }
+                    _ => {
+                        self.diagnostics.push(Diagnostic::error(
+                            ErrorKind::InvalidOperand,
+                            format!("unary `{}` is not supported", op),
+                            span,
+                        ));
+                        self.vars.fresh()
+                    }
+                }
+            }
+            ExprKind::Binary { left, op, right } => {
+                let l = self.infer(left, env);
+                let r = self.infer(right, env);
+                let same = |checker: &mut Self| {
+                    let result = checker
+                        .expect(&l, &r, right.span)
+                        .map_err(|d| d.with_secondary(left.span, "left operand"));
+                    checker.report(result);
+                };
+                match op {
+                    Token::Plus | Token::Minus | Token::Star | Token::Slash | Token::Mod => {
+                        let ty = self.elementwise(&l, &r, left.span, right.span);
+                        self.constrain(&ty, Constraint::Num, op, span);
+                        ty
+                    }
+                    Token::LessThan | Token::LessThanEq | Token::GreatThan | Token::GreatThanEq => {
+                        let ty = self.elementwise(&l, &r, left.span, right.span);
+                        self.constrain(&ty, Constraint::Num, op, span);
+                        self.with_dtype(&ty, Type::Bool)
+                    }
+                    Token::DoubleEqual | Token::NotEqual => {
+                        let ty = self.elementwise(&l, &r, left.span, right.span);
+                        self.with_dtype(&ty, Type::Bool)
+                    }
+                    Token::LogicAnd | Token::LogicOr => {
+                        for (ty, span) in [(&l, left.span), (&r, right.span)] {
+                            let result = self.expect(&Type::Bool, ty, span);
+                            self.report(result);
+                        }
+                        Type::Bool
+                    }
+                    _ => {
+                        // bitwise on integers, logic on bools
+                        same(self);
+                        if self.vars.resolve(&l) != Type::Bool {
+                            self.constrain(&l, Constraint::Int, op, span);
+                        }
+                        l
+                    }
+                }
+            }
+            ExprKind::Call { callee, args } => {
+                let f = self.infer(callee, env);
+                let arg_types: Vec<(Type, Span)> = match &mut args.kind {
+                    ExprKind::Unit => {
+                        args.ty = Type::Unit;
+                        Vec::new()
+                    }
+                    ExprKind::Tuple(items) => {
+                        let types: Vec<(Type, Span)> = items
+                            .iter_mut()
+                            .map(|item| (self.infer(item, env), item.span))
+                            .collect();
+                        args.ty = Type::Tuple(types.iter().map(|(t, _)| t.clone()).collect());
+                        types
+                    }
+                    _ => vec![(self.infer(args, env), args.span)],
+                };
+                // the builtin `map` keeps the outer dim of a tensor
+                let is_map = matches!(&callee.kind, ExprKind::Identifier { name, .. } if name == "map" && !env.contains_key(name));
+                match self.vars.resolve(&f) {
+                    Type::Function { params, ret } if is_map && params.len() == arg_types.len() => {
+                        for (param, (arg, span)) in params.iter().zip(&arg_types) {
+                            let result = self.expect(param, arg, *span);
+                            self.report(result);
+                        }
+                        self.map_result(&arg_types[0].0, *ret)
+                    }
+                    Type::Function { params, ret } => {
+                        // `((a, b) => ...)(x)` spreads the tuple `x` over the params
+                        let mut arg_types = arg_types;
+                        if let [(arg, span)] = &arg_types[..]
+                            && params.len() > 1
+                            && let Type::Tuple(items) = self.vars.resolve(arg)
+                            && items.len() == params.len()
+                        {
+                            let span = *span;
+                            arg_types = items.into_iter().map(|ty| (ty, span)).collect();
+                        }
+                        if params.len() != arg_types.len() {
+                            self.diagnostics.push(
+                                Diagnostic::error(
+                                    ErrorKind::ArityMismatch {
+                                        expected: params.len(),
+                                        found: arg_types.len(),
+                                    },
+                                    format!(
+                                        "the function takes {} arguments, but {} were given",
+                                        params.len(),
+                                        arg_types.len()
+                                    ),
+                                    args.span,
+                                )
+                                .with_secondary(callee.span, "function"),
+                            );
+                        } else {
+                            for (param, (arg, span)) in params.iter().zip(&arg_types) {
+                                let result = self.expect(param, arg, *span);
+                                self.report(result);
+                            }
+                        }
+                        *ret
+                    }
+                    Type::Var(v) if self.vars.constraint(v).is_none() => {
+                        let ret = self.vars.fresh();
+                        let called = Type::Function {
+                            params: arg_types.into_iter().map(|(t, _)| t).collect(),
+                            ret: Box::new(ret.clone()),
+                        };
+                        let result = self.expect(&f, &called, callee.span);
+                        self.report(result);
+                        ret
+                    }
+                    Type::Any => self.vars.fresh(),
+                    other => {
+                        let found = match other {
+                            Type::Var(v) => describe(self.vars.constraint(v).unwrap()).to_string(),
+                            _ => format!("`{}`", self.names(&[&other]).remove(0)),
+                        };
+                        self.diagnostics.push(
+                            Diagnostic::error(
+                                ErrorKind::NotCallable,
+                                format!("{} is not a function", found),
+                                callee.span,
+                            )
+                            .with_label(format!("has type {}", found)),
+                        );
+                        self.vars.fresh()
+                    }
+                }
+            }
+            ExprKind::Lambda { param, body } => {
+                let mut inner = env.clone();
+                let params = match &mut param.kind {
+                    ExprKind::Tuple(items) => {
+                        let params: Vec<Type> = items
+                            .iter_mut()
+                            .map(|item| self.bind_param(item, &mut inner))
+                            .collect();
+                        param.ty = Type::Tuple(params.clone());
+                        params
+                    }
+                    _ => vec![self.bind_param(param, &mut inner)],
+                };
+                let ret = self.infer(body, &mut inner);
+                Type::Function {
+                    params,
+                    ret: Box::new(ret),
+                }
+            }
+            // placement does not change the type
+            ExprKind::Move { val, .. } => self.infer(val, env),
+            ExprKind::Block(stmts) => {
+                let mut inner = env.clone();
+                let mut last = Type::Unit;
+                for stmt in stmts.iter_mut() {
+                    last = self.infer(stmt, &mut inner);
+                }
+                last
+            }
+            // the names of a rule are pattern variables, checked when the
+            // rule is compiled
+            ExprKind::Rule { .. } => Type::Unit,
+            // already reported by the parser
+            ExprKind::Error => self.vars.fresh(),
+        };
+        expr.ty = ty.clone();
+        ty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::{LasmiaoLexer, Lexer};
+    use parser::TokenParser;
+    use parser::traits::Parser;
+
+    fn check(checker: &mut TypeChecker, src: &str) -> (Expr, Vec<Diagnostic>) {
+        let tokens = LasmiaoLexer::make_tokens(src).unwrap();
+        let mut program = TokenParser::new(tokens).parse_exprs().unwrap();
+        let diags = checker.check_program(&mut program);
+        (program, diags)
+    }
+
+    fn type_of(checker: &TypeChecker, name: &str) -> String {
+        checker.lookup(name).unwrap().to_string()
+    }
+
+    #[test]
+    fn infers_the_readme_example() {
+        let mut checker = TypeChecker::new();
+        let src = std::fs::read_to_string("../../examples/readme.lasmiao").unwrap();
+        let (_, diags) = check(&mut checker, &src);
+        assert!(diags.iter().all(|d| !d.is_error()), "{:?}", diags);
+        assert_eq!(type_of(&checker, "a"), "f32");
+        assert_eq!(type_of(&checker, "f"), "('a) => 'a where 'a: float");
+        assert_eq!(type_of(&checker, "c"), "List<f64>");
+        assert_eq!(type_of(&checker, "list_on_cpu"), "tensor(i32, 3, 2)");
+        assert_eq!(type_of(&checker, "xpuN"), "i64");
+        assert_eq!(type_of(&checker, "buffer_on_xpu"), "buffer");
+        assert_eq!(type_of(&checker, "m3"), "my_type");
+    }
+
+    #[test]
+    fn literals_follow_their_use() {
+        let mut checker = TypeChecker::new();
+        let (program, diags) = check(
+            &mut checker,
+            "n = 2\nx:f32 = n * 1.5\nid = (y => y)\np = (id(1:u32), id(true))",
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(type_of(&checker, "n"), "f32");
+        assert_eq!(type_of(&checker, "p"), "(u32, bool)");
+        let ExprKind::Block(stmts) = &program.kind else {
+            panic!("expect a block")
+        };
+        assert_eq!(stmts[0].children()[1].ty, Type::F32);
+    }
+
+    #[test]
+    fn infers_and_broadcasts_shapes() {
+        let mut checker = TypeChecker::new();
+        let (_, diags) = check(
+            &mut checker,
+            "m = [[1., 2.], [3., 4.], [5., 6.]]\n\
+             a = m * [10., 20.] + 1.\n\
+             rows = m.map(r => r * 2.)\n\
+             sums = m.map(sum)\n\
+             big = m > 2.\n\
+             col = [[1.], [2.], [3.]] - m",
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(type_of(&checker, "a"), "tensor(f64, 3, 2)");
+        assert_eq!(type_of(&checker, "rows"), "tensor(f64, 3, 2)");
+        assert_eq!(type_of(&checker, "sums"), "tensor(f64, 3)");
+        assert_eq!(type_of(&checker, "big"), "tensor(bool, 3, 2)");
+        assert_eq!(type_of(&checker, "col"), "tensor(f64, 3, 2)");
+
+        let (_, diags) = check(
+            &mut checker,
+            "t:tensor(i32, 3, 2) = [[1, 2], [3, 4]]\nm + [1., 2., 3.]\n[[1], [2, 3]]",
+        );
+        let kinds: Vec<_> = diags
+            .iter()
+            .map(|d| (d.kind.code(), d.span().line))
+            .collect();
+        assert_eq!(kinds, [("E0024", 1), ("E0025", 2), ("E0024", 3)]);
+        assert_eq!(
+            diags[0].kind,
+            ErrorKind::ShapeMismatch {
+                expected: "3, 2".to_string(),
+                found: "2, 2".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn symbolic_dims_are_specialized_by_meta_defines() {
+        let mut checker = TypeChecker::new();
+        let (_, diags) = check(
+            &mut checker,
+            "N#3\n\
+             a:tensor(f64, N, N-1) = [[1., 2.], [3., 4.], [5., 6.]]\n\
+             scale = (x:tensor(f64, M, 2) => x * 2.)\n\
+             b:tensor(f64, M*2, 2) = a",
+        );
+        let codes: Vec<_> = diags.iter().map(|d| d.kind.code()).collect();
+        assert_eq!(codes, ["E0024"], "{:?}", diags);
+        assert!(diags[0].help[0].contains("N#3"));
+        assert_eq!(type_of(&checker, "a"), "tensor(f64, 3, 2)");
+        assert_eq!(
+            type_of(&checker, "scale"),
+            "(tensor(f64, M, 2)) => tensor(f64, M, 2)"
+        );
+    }
+
+    #[test]
+    fn reports_mismatches_with_spans() {
+        let mut checker = TypeChecker::new();
+        let (_, diags) = check(
+            &mut checker,
+            "a:bool = 1 + 2\nb = [1, true]\nn = 1\nc = n(2)",
+        );
+        let kinds: Vec<_> = diags
+            .iter()
+            .map(|d| (d.kind.code(), d.span().line))
+            .collect();
+        assert_eq!(kinds, [("E0022", 1), ("E0022", 2), ("E0018", 4)]);
+        assert_eq!(
+            diags[0].kind,
+            ErrorKind::TypeMismatch {
+                expected: "`bool`".to_string(),
+                found: "a number".to_string()
+            }
+        );
+
+        let (_, diags) = check(&mut checker, "f = (x => x(x))");
+        assert_eq!(diags[0].kind, ErrorKind::InfiniteType);
+    }
+}
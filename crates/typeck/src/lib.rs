@@ -0,0 +1,15 @@
+//! Type inference for LasMiao.
+//!
+//! Fills in the `ty` of every `Expr`, checks annotations such as
+//! `a:f32 = ...` against what is inferred and reports mismatches. Static
+//! tensor shapes are part of the types, inferred from list literals and
+//! broadcast through element-wise operations.
+
+pub mod builtins;
+pub mod infer;
+pub mod scheme;
+pub mod shape;
+pub mod unify;
+
+pub use infer::TypeChecker;
+pub use scheme::Scheme;
@@ -0,0 +1,71 @@
+use crate::unify::{self, Constraint, Vars};
+use parser::types::Type;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A type generalized over some of its variables, e.g. the type of
+/// `x => x` is `('a) => 'a` for every `'a`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheme {
+    /// Quantified variables and their constraints
+    pub vars: Vec<(u32, Option<Constraint>)>,
+    pub ty: Type,
+}
+
+impl Scheme {
+    /// A scheme without quantified variables.
+    pub fn mono(ty: Type) -> Self {
+        Scheme {
+            vars: Vec::new(),
+            ty,
+        }
+    }
+
+    /// Copy of the type with fresh variables for the quantified ones.
+    pub fn instantiate(&self, vars: &mut Vars) -> Type {
+        let map: HashMap<u32, Type> = self
+            .vars
+            .iter()
+            .map(|(v, c)| {
+                let fresh = match c {
+                    Some(c) => vars.fresh_constrained(*c),
+                    None => vars.fresh(),
+                };
+                (*v, fresh)
+            })
+            .collect();
+        unify::substitute(&vars.zonk(&self.ty), &map)
+    }
+
+    /// Apply every solution found so far.
+    pub fn zonk(&self, vars: &Vars) -> Scheme {
+        Scheme {
+            vars: self.vars.clone(),
+            ty: vars.zonk(&self.ty),
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    /// Variables are renamed `'a`, `'b`, ... in order of appearance.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut order = Vec::new();
+        unify::free_vars(&self.ty, &mut order);
+        let map: HashMap<u32, Type> = order
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (*v, Type::Var(i as u32)))
+            .collect();
+        write!(f, "{}", unify::substitute(&self.ty, &map))?;
+
+        let bounds: Vec<String> = self
+            .vars
+            .iter()
+            .filter_map(|(v, c)| Some(format!("{}: {}", map.get(v)?, c.as_ref()?)))
+            .collect();
+        if !bounds.is_empty() {
+            write!(f, " where {}", bounds.join(", "))?;
+        }
+        Ok(())
+    }
+}
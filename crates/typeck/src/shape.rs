@@ -0,0 +1,69 @@
+use parser::types::{Dim, TensorShapeType};
+
+/// Shape of an element-wise result, following NumPy: shapes are aligned
+/// at their last dims, and each pair of dims must be equal or contain a 1.
+/// `None` when the shapes can not be broadcast. A symbolic dim is only
+/// known to equal itself.
+pub fn broadcast<'a>(a: &'a TensorShapeType, b: &'a TensorShapeType) -> Option<TensorShapeType> {
+    let (TensorShapeType::Shape(a), TensorShapeType::Shape(b)) = (a, b) else {
+        return Some(TensorShapeType::Any);
+    };
+    let rank = a.len().max(b.len());
+    let one = Dim::Const(1);
+    let dim = |dims: &'a [Dim], i: usize| {
+        // missing leading dims count as 1
+        (i + dims.len())
+            .checked_sub(rank)
+            .map_or(&one, |i| &dims[i])
+    };
+    (0..rank)
+        .map(|i| match (dim(a, i), dim(b, i)) {
+            (x, y) if x == y => Some(x.clone()),
+            (Dim::Const(1), y) => Some(y.clone()),
+            (x, Dim::Const(1)) => Some(x.clone()),
+            _ => None,
+        })
+        .collect::<Option<Vec<Dim>>>()
+        .map(TensorShapeType::Shape)
+}
+
+/// Shape of `len` stacked elements of shape `inner`.
+pub fn stack(len: Dim, inner: &TensorShapeType) -> TensorShapeType {
+    match inner {
+        TensorShapeType::Shape(dims) => {
+            TensorShapeType::Shape(std::iter::once(len).chain(dims.iter().cloned()).collect())
+        }
+        TensorShapeType::Any => TensorShapeType::Any,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape(dims: &[u64]) -> TensorShapeType {
+        TensorShapeType::Shape(dims.iter().map(|d| Dim::Const(*d)).collect())
+    }
+
+    #[test]
+    fn broadcasts_like_numpy() {
+        assert_eq!(
+            broadcast(&shape(&[3, 2]), &shape(&[2])),
+            Some(shape(&[3, 2]))
+        );
+        assert_eq!(
+            broadcast(&shape(&[3, 1]), &shape(&[1, 4])),
+            Some(shape(&[3, 4]))
+        );
+        assert_eq!(broadcast(&shape(&[3]), &shape(&[])), Some(shape(&[3])));
+        assert_eq!(broadcast(&shape(&[3, 2]), &shape(&[3])), None);
+
+        let n = TensorShapeType::Shape(vec![Dim::Sym("N".to_string()), Dim::Const(2)]);
+        assert_eq!(broadcast(&n, &shape(&[1, 2])), Some(n.clone()));
+        assert_eq!(broadcast(&n, &shape(&[3, 2])), None);
+        assert_eq!(
+            broadcast(&shape(&[3]), &TensorShapeType::Any),
+            Some(TensorShapeType::Any)
+        );
+    }
+}
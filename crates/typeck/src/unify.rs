@@ -0,0 +1,275 @@
+use parser::types::{Dim, TensorShapeType, Type};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Restricts which types a variable may be solved to, e.g. the type of
+/// an integer literal must be a number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constraint {
+    Num,
+    Int,
+    Float,
+}
+
+impl Constraint {
+    pub fn accepts(&self, ty: &Type) -> bool {
+        let int = matches!(ty, Type::I32 | Type::U32 | Type::I64 | Type::U64);
+        let float = matches!(ty, Type::F32 | Type::F64);
+        match self {
+            Constraint::Num => int || float,
+            Constraint::Int => int,
+            Constraint::Float => float,
+        }
+    }
+
+    /// Constraint satisfied by the types which satisfy both.
+    fn meet(self, other: Constraint) -> Option<Constraint> {
+        match (self, other) {
+            (a, b) if a == b => Some(a),
+            (Constraint::Num, c) | (c, Constraint::Num) => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Type an unsolved variable falls back to.
+    pub fn default_type(&self) -> Type {
+        match self {
+            Constraint::Num | Constraint::Int => Type::I64,
+            Constraint::Float => Type::F64,
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constraint::Num => write!(f, "num"),
+            Constraint::Int => write!(f, "int"),
+            Constraint::Float => write!(f, "float"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnifyError {
+    Mismatch,
+    /// Functions with different numbers of parameters
+    Arity {
+        expected: usize,
+        found: usize,
+    },
+    /// The type does not satisfy the constraint of a variable
+    Unsatisfied(Constraint, Type),
+    /// Tensors with different static shapes
+    Shape(Vec<Dim>, Vec<Dim>),
+    Infinite,
+}
+
+/// Solutions and constraints of the type variables.
+#[derive(Debug, Clone, Default)]
+pub struct Vars {
+    solved: Vec<Option<Type>>,
+    constraints: Vec<Option<Constraint>>,
+}
+
+/// Type variables in `ty`, in order of appearance and without duplicates.
+pub fn free_vars(ty: &Type, out: &mut Vec<u32>) {
+    match ty {
+        Type::Var(v) if !out.contains(v) => out.push(*v),
+        Type::List(elem) => free_vars(elem, out),
+        Type::Tuple(items) => items.iter().for_each(|t| free_vars(t, out)),
+        Type::Tensor { dtype, .. } => free_vars(dtype, out),
+        Type::Function { params, ret } => {
+            params.iter().for_each(|t| free_vars(t, out));
+            free_vars(ret, out);
+        }
+        _ => {}
+    }
+}
+
+/// Replace the variables of `ty` which are in `map`.
+pub fn substitute(ty: &Type, map: &HashMap<u32, Type>) -> Type {
+    match ty {
+        Type::Var(v) => map.get(v).cloned().unwrap_or(Type::Var(*v)),
+        Type::List(elem) => Type::List(Box::new(substitute(elem, map))),
+        Type::Tuple(items) => Type::Tuple(items.iter().map(|t| substitute(t, map)).collect()),
+        Type::Tensor { dtype, shape } => Type::Tensor {
+            dtype: Box::new(substitute(dtype, map)),
+            shape: shape.clone(),
+        },
+        Type::Function { params, ret } => Type::Function {
+            params: params.iter().map(|t| substitute(t, map)).collect(),
+            ret: Box::new(substitute(ret, map)),
+        },
+        _ => ty.clone(),
+    }
+}
+
+impl Vars {
+    pub fn fresh(&mut self) -> Type {
+        self.solved.push(None);
+        self.constraints.push(None);
+        Type::Var(self.solved.len() as u32 - 1)
+    }
+
+    pub fn fresh_constrained(&mut self, constraint: Constraint) -> Type {
+        let var = self.fresh();
+        *self.constraints.last_mut().unwrap() = Some(constraint);
+        var
+    }
+
+    pub fn constraint(&self, var: u32) -> Option<Constraint> {
+        self.constraints[var as usize]
+    }
+
+    /// Unsolved variables which have a constraint.
+    pub fn constrained(&self) -> Vec<(u32, Constraint)> {
+        (0..self.solved.len())
+            .filter(|v| self.solved[*v].is_none())
+            .filter_map(|v| self.constraints[v].map(|c| (v as u32, c)))
+            .collect()
+    }
+
+    /// Follow solved variables until the outermost constructor is known.
+    pub fn resolve(&self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        while let Type::Var(v) = ty {
+            match &self.solved[v as usize] {
+                Some(solution) => ty = solution.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// Apply every solution found so far to `ty`.
+    pub fn zonk(&self, ty: &Type) -> Type {
+        match self.resolve(ty) {
+            Type::List(elem) => Type::List(Box::new(self.zonk(&elem))),
+            Type::Tuple(items) => Type::Tuple(items.iter().map(|t| self.zonk(t)).collect()),
+            Type::Tensor { dtype, shape } => Type::Tensor {
+                dtype: Box::new(self.zonk(&dtype)),
+                shape,
+            },
+            Type::Function { params, ret } => Type::Function {
+                params: params.iter().map(|t| self.zonk(t)).collect(),
+                ret: Box::new(self.zonk(&ret)),
+            },
+            ty => ty,
+        }
+    }
+
+    pub fn unify(&mut self, a: &Type, b: &Type) -> Result<(), UnifyError> {
+        match (self.resolve(a), self.resolve(b)) {
+            (Type::Any, _) | (_, Type::Any) => Ok(()),
+            (Type::Var(x), Type::Var(y)) if x == y => Ok(()),
+            (Type::Var(x), t) | (t, Type::Var(x)) => self.bind(x, t),
+            (Type::List(a), Type::List(b)) => self.unify(&a, &b),
+            (Type::Tuple(a), Type::Tuple(b)) => {
+                if a.len() != b.len() {
+                    return Err(UnifyError::Mismatch);
+                }
+                a.iter().zip(&b).try_for_each(|(a, b)| self.unify(a, b))
+            }
+            (
+                Type::Function { params, ret },
+                Type::Function {
+                    params: params2,
+                    ret: ret2,
+                },
+            ) => {
+                if params.len() != params2.len() {
+                    return Err(UnifyError::Arity {
+                        expected: params.len(),
+                        found: params2.len(),
+                    });
+                }
+                params
+                    .iter()
+                    .zip(&params2)
+                    .try_for_each(|(a, b)| self.unify(a, b))?;
+                self.unify(&ret, &ret2)
+            }
+            (
+                Type::Tensor { dtype, shape },
+                Type::Tensor {
+                    dtype: dtype2,
+                    shape: shape2,
+                },
+            ) => {
+                self.unify(&dtype, &dtype2)?;
+                match (shape, shape2) {
+                    (TensorShapeType::Shape(a), TensorShapeType::Shape(b)) if a != b => {
+                        Err(UnifyError::Shape(a, b))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            // nested lists are tensors without a fixed shape
+            (Type::List(elem), Type::Tensor { dtype, shape })
+            | (Type::Tensor { dtype, shape }, Type::List(elem)) => {
+                self.unify_list_tensor(&elem, &dtype, &shape)
+            }
+            (a, b) if a == b => Ok(()),
+            _ => Err(UnifyError::Mismatch),
+        }
+    }
+
+    fn unify_list_tensor(
+        &mut self,
+        elem: &Type,
+        dtype: &Type,
+        shape: &TensorShapeType,
+    ) -> Result<(), UnifyError> {
+        let inner = match shape {
+            TensorShapeType::Shape(dims) if dims.is_empty() => return Err(UnifyError::Mismatch),
+            TensorShapeType::Shape(dims) if dims.len() == 1 => dtype.clone(),
+            TensorShapeType::Shape(dims) => Type::Tensor {
+                dtype: Box::new(dtype.clone()),
+                shape: TensorShapeType::Shape(dims[1..].to_vec()),
+            },
+            TensorShapeType::Any => match self.resolve(elem) {
+                Type::List(_) | Type::Tensor { .. } => Type::Tensor {
+                    dtype: Box::new(dtype.clone()),
+                    shape: TensorShapeType::Any,
+                },
+                _ => dtype.clone(),
+            },
+        };
+        self.unify(elem, &inner)
+    }
+
+    fn bind(&mut self, var: u32, ty: Type) -> Result<(), UnifyError> {
+        if !matches!(ty, Type::Var(_)) {
+            let mut vars = Vec::new();
+            free_vars(&self.zonk(&ty), &mut vars);
+            if vars.contains(&var) {
+                return Err(UnifyError::Infinite);
+            }
+        }
+        if let Some(c) = self.constraint(var) {
+            self.satisfy(c, &ty)?;
+        }
+        self.solved[var as usize] = Some(ty);
+        Ok(())
+    }
+
+    /// Require `ty` to satisfy `constraint`, element by element for lists
+    /// and tensors as arithmetic applies to each element.
+    pub fn satisfy(&mut self, constraint: Constraint, ty: &Type) -> Result<(), UnifyError> {
+        match self.resolve(ty) {
+            Type::Var(v) => {
+                let merged = match self.constraint(v) {
+                    Some(c) => c.meet(constraint).ok_or(UnifyError::Mismatch)?,
+                    None => constraint,
+                };
+                self.constraints[v as usize] = Some(merged);
+                Ok(())
+            }
+            Type::List(elem) | Type::Tensor { dtype: elem, .. } => self.satisfy(constraint, &elem),
+            Type::Any => Ok(()),
+            ty if constraint.accepts(&ty) => Ok(()),
+            ty => Err(UnifyError::Unsatisfied(constraint, ty)),
+        }
+    }
+}
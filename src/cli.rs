@@ -0,0 +1,245 @@
+use crate::driver::{Emit, PassOptions};
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: laplacesmiao <COMMAND> [OPTIONS] [FILE]...
+
+Commands:
+  tokens <FILE>   Print the tokens of a file
+  parse <FILE>    Print the AST of a file
+  check <FILE>... Check files for errors without emitting anything
+  ir <FILE>       Print the IR of a file after the passes, as build --emit ir
+  build <FILE>    Compile a file and write the stages selected by --emit
+  run <FILE>      Evaluate a file and print the value of its last statement
+  repl            Start an interactive session (default)
+  help            Print this message
+
+Options:
+  --emit <STAGE>[,<STAGE>...]  Stages written by build: tokens, ast, types,
+                               ir, schedule, memory
+  --passes <PASS>[,<PASS>...]  Passes build runs on the IR, in order
+  --pass-plugin <PATH>         Load the passes of a shared library
+  --target <PATH>              Devices to compile for, described in TOML
+  --time-passes                Print the time taken by each pass
+  --dump-ir                    Print the IR after each pass
+  -o <PATH>                    Output path, `-` for stdout, where each
+                               stage follows a `// <STAGE>` header when
+                               there are several
+  -h, --help                   Print this message
+
+Exit status is 0 on success, 1 when compilation fails and 2 on bad usage.";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Tokens {
+        file: PathBuf,
+        output: Option<PathBuf>,
+    },
+    Parse {
+        file: PathBuf,
+        output: Option<PathBuf>,
+    },
+    Check {
+        files: Vec<PathBuf>,
+    },
+    Build {
+        file: PathBuf,
+        emit: Vec<Emit>,
+        output: Option<PathBuf>,
+        passes: PassOptions,
+    },
+    Run {
+        file: PathBuf,
+    },
+    Repl,
+    Help,
+}
+
+/// Parse the command line, without the program name.
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.into_iter();
+    let Some(command) = args.next() else {
+        return Ok(Command::Repl);
+    };
+
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut emit: Option<Vec<Emit>> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut passes: Option<PassOptions> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" => {
+                let path = args.next().ok_or("expect a path after `-o`")?;
+                output = Some(PathBuf::from(path));
+            }
+            "--emit" => {
+                let stages = args.next().ok_or("expect stages after `--emit`")?;
+                emit = Some(parse_emit(&stages)?);
+            }
+            _ if arg.starts_with("--emit=") => {
+                emit = Some(parse_emit(&arg["--emit=".len()..])?);
+            }
+            "--passes" => {
+                let pipeline = args.next().ok_or("expect passes after `--passes`")?;
+                passes.get_or_insert_default().pipeline = pipeline;
+            }
+            _ if arg.starts_with("--passes=") => {
+                passes.get_or_insert_default().pipeline = arg["--passes=".len()..].to_string();
+            }
+            "--pass-plugin" => {
+                let path = args.next().ok_or("expect a path after `--pass-plugin`")?;
+                passes
+                    .get_or_insert_default()
+                    .plugins
+                    .push(PathBuf::from(path));
+            }
+            "--target" => {
+                let path = args.next().ok_or("expect a path after `--target`")?;
+                passes.get_or_insert_default().target = Some(PathBuf::from(path));
+            }
+            "--time-passes" => passes.get_or_insert_default().time = true,
+            "--dump-ir" => passes.get_or_insert_default().dump = true,
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown option `{}`", arg));
+            }
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+
+    if emit.is_some() && command != "build" {
+        return Err(format!(
+            "`--emit` is only accepted by build, not {}",
+            command
+        ));
+    }
+    if passes.is_some() && command != "build" && command != "ir" {
+        return Err(format!(
+            "pass options are only accepted by build and ir, not {}",
+            command
+        ));
+    }
+
+    let single_file = |mut files: Vec<PathBuf>| -> Result<PathBuf, String> {
+        match files.len() {
+            1 => Ok(files.remove(0)),
+            0 => Err(format!("{} expects an input file", command)),
+            _ => Err(format!("{} expects a single input file", command)),
+        }
+    };
+
+    match command.as_str() {
+        "tokens" => Ok(Command::Tokens {
+            file: single_file(files)?,
+            output,
+        }),
+        "parse" => Ok(Command::Parse {
+            file: single_file(files)?,
+            output,
+        }),
+        "check" => {
+            if files.is_empty() {
+                return Err("check expects at least one input file".to_string());
+            }
+            if output.is_some() {
+                return Err("check does not write any output, `-o` is not accepted".to_string());
+            }
+            Ok(Command::Check { files })
+        }
+        "build" => Ok(Command::Build {
+            file: single_file(files)?,
+            emit: emit.unwrap_or_else(|| vec![Emit::default()]),
+            output,
+            passes: passes.unwrap_or_default(),
+        }),
+        "ir" => Ok(Command::Build {
+            file: single_file(files)?,
+            emit: vec![Emit::Ir],
+            output: Some(output.unwrap_or_else(|| PathBuf::from("-"))),
+            passes: passes.unwrap_or_default(),
+        }),
+        "run" => {
+            if output.is_some() {
+                return Err("run prints to stdout, `-o` is not accepted".to_string());
+            }
+            Ok(Command::Run {
+                file: single_file(files)?,
+            })
+        }
+        "repl" => Ok(Command::Repl),
+        "help" | "-h" | "--help" => Ok(Command::Help),
+        _ => Err(format!("unknown command `{}`", command)),
+    }
+}
+
+fn parse_emit(stages: &str) -> Result<Vec<Emit>, String> {
+    stages.split(',').map(|s| s.trim().parse()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Result<Command, String> {
+        parse_args(s.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn build_with_emit_and_output() {
+        assert_eq!(
+            args("build a.lasmiao --emit tokens,ast -o out"),
+            Ok(Command::Build {
+                file: PathBuf::from("a.lasmiao"),
+                emit: vec![Emit::Tokens, Emit::Ast],
+                output: Some(PathBuf::from("out")),
+                passes: PassOptions::default(),
+            })
+        );
+        assert_eq!(
+            args("build --emit=ast a.lasmiao"),
+            Ok(Command::Build {
+                file: PathBuf::from("a.lasmiao"),
+                emit: vec![Emit::Ast],
+                output: None,
+                passes: PassOptions::default(),
+            })
+        );
+        assert_eq!(
+            args("build a.lasmiao --passes=verify --time-passes --emit ir"),
+            Ok(Command::Build {
+                file: PathBuf::from("a.lasmiao"),
+                emit: vec![Emit::Ir],
+                output: None,
+                passes: PassOptions {
+                    pipeline: "verify".to_string(),
+                    plugins: Vec::new(),
+                    time: true,
+                    dump: false,
+                    target: None,
+                },
+            })
+        );
+        assert_eq!(
+            args("ir a.lasmiao"),
+            Ok(Command::Build {
+                file: PathBuf::from("a.lasmiao"),
+                emit: vec![Emit::Ir],
+                output: Some(PathBuf::from("-")),
+                passes: PassOptions::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_usage() {
+        assert_eq!(args(""), Ok(Command::Repl));
+        assert!(args("parse").is_err());
+        assert!(args("parse a b").is_err());
+        assert!(args("check a --emit ast").is_err());
+        assert!(args("build a --emit llvm").is_err());
+        assert!(args("run a -o out").is_err());
+        assert!(args("check a --passes verify").is_err());
+        assert!(args("ir a --emit ast").is_err());
+        assert!(args("frobnicate a").is_err());
+    }
+}
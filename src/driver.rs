@@ -0,0 +1,297 @@
+use device::Target;
+use diagnostics::{Diagnostic, Spanned, render};
+use interp::{Interpreter, Value};
+use ir::Module;
+use lexer::{LasmiaoLexer, Lexer, Token};
+use parser::TokenParser;
+use parser::expr::Expr;
+use parser::traits::Parser;
+use pass::rule::Rule;
+use pass::{PassManager, PassRegistry};
+use scheduler::{DoubleBuffer, LatencyModel, Schedule};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use typeck::TypeChecker;
+
+/// A source file loaded for compilation.
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Source {
+            name: path.display().to_string(),
+            text: fs::read_to_string(path)?,
+        })
+    }
+
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        Source {
+            name: name.into(),
+            text: text.into(),
+        }
+    }
+
+    /// Print `diags` to stderr with snippets of this source.
+    pub fn report(&self, diags: &[Diagnostic]) {
+        for diag in diags {
+            eprint!("{}", render(diag, &self.name, &self.text));
+        }
+    }
+}
+
+/// A pipeline stage stopped, its diagnostics have already been reported.
+#[derive(Debug)]
+pub struct Failed;
+
+/// Stages which `build` can write out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Emit {
+    Tokens,
+    #[default]
+    Ast,
+    /// The AST with the inferred type of every node
+    Types,
+    /// The dataflow graph in its textual form
+    Ir,
+    /// When each op of the IR runs on the devices of the target
+    Schedule,
+    /// Where the tensors and buffers of the IR live in memory
+    Memory,
+}
+
+impl Emit {
+    /// File extension used when the output path is derived from the input.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Emit::Tokens => "tokens",
+            Emit::Ast => "ast",
+            Emit::Types => "types",
+            Emit::Ir => "ir",
+            Emit::Schedule => "schedule",
+            Emit::Memory => "memory",
+        }
+    }
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokens" => Ok(Emit::Tokens),
+            "ast" => Ok(Emit::Ast),
+            "types" => Ok(Emit::Types),
+            "ir" => Ok(Emit::Ir),
+            "schedule" => Ok(Emit::Schedule),
+            "memory" => Ok(Emit::Memory),
+            _ => Err(format!(
+                "unknown emit stage `{}`, expect one of: tokens, ast, types, ir, schedule, memory",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Emit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// How `build` runs passes on the IR.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PassOptions {
+    /// Comma separated pass names, empty for none
+    pub pipeline: String,
+    /// Shared libraries whose passes can be named in the pipeline
+    pub plugins: Vec<PathBuf>,
+    /// Print the time taken by each pass
+    pub time: bool,
+    /// Print the IR after each pass
+    pub dump: bool,
+    /// Description of the devices to compile for, the host alone if none
+    pub target: Option<PathBuf>,
+}
+
+pub fn lex(source: &Source) -> Result<Vec<Spanned<Token>>, Failed> {
+    LasmiaoLexer::make_tokens(&source.text).map_err(|e| {
+        source.report(&[e]);
+        Failed
+    })
+}
+
+pub fn parse(source: &Source) -> Result<Expr, Failed> {
+    let tokens = lex(source)?;
+    let (program, errors) = TokenParser::new(tokens).parse_recovering();
+    if !errors.is_empty() {
+        source.report(&errors);
+        return Err(Failed);
+    }
+    Ok(program)
+}
+
+/// Parse and infer the types of a program. Warnings are reported but do
+/// not fail.
+pub fn typecheck(source: &Source) -> Result<Expr, Failed> {
+    let mut program = parse(source)?;
+    let diags = TypeChecker::new().check_program(&mut program);
+    source.report(&diags);
+    if diags.iter().any(|d| d.is_error()) {
+        return Err(Failed);
+    }
+    Ok(program)
+}
+
+/// Evaluate a program with the reference interpreter.
+pub fn run(source: &Source) -> Result<Value, Failed> {
+    let program = typecheck(source)?;
+    Interpreter::new().run(&program).map_err(|e| {
+        source.report(&[e]);
+        Failed
+    })
+}
+
+/// Run every stage up to the last one, without emitting anything.
+pub fn check(source: &Source) -> Result<Expr, Failed> {
+    let program = typecheck(source)?;
+    rules(source, &program)?;
+    Ok(program)
+}
+
+/// Compile the rules declared in `program`.
+fn rules(source: &Source, program: &Expr) -> Result<Vec<Rule>, Failed> {
+    pass::rule::rules(program).map_err(|diags| {
+        source.report(&diags);
+        Failed
+    })
+}
+
+/// Load the description of the target devices at `path`.
+pub fn target(path: &Path) -> Result<Target, Failed> {
+    let source = Source::load(path).map_err(|e| {
+        eprintln!("error: can not read {}: {}", path.display(), e);
+        Failed
+    })?;
+    Target::parse(&source.text).map_err(|diags| {
+        source.report(&diags);
+        Failed
+    })
+}
+
+/// Lower a checked program to the IR and run the pipeline of `options`
+/// on it for `target`. Timings and dumps go to stderr.
+pub fn compile(
+    source: &Source,
+    program: &Expr,
+    options: &PassOptions,
+    target: &Target,
+) -> Result<Module, Failed> {
+    let fail = |e: String| {
+        eprintln!("error: {}", e);
+        Failed
+    };
+    let rules = rules(source, program)?;
+    let mut registry = PassRegistry::with_builtins();
+    registry.register(
+        "rules",
+        "Apply the rules declared in the program",
+        move || Box::new(pass::rule::rule_pass(&rules)),
+    );
+    registry.register(
+        "double-buffer",
+        "Copy the inputs of maps tile by tile through pairs of buffers",
+        || Box::new(DoubleBuffer::default()),
+    );
+    for plugin in &options.plugins {
+        registry.load_plugin(plugin).map_err(fail)?;
+    }
+    let mut manager = PassManager::parse(&options.pipeline, &registry)
+        .map_err(fail)?
+        .with_dump(options.dump)
+        .with_target(target.clone());
+    let mut module = ir::lower(program);
+    let report = manager.run(&mut module).map_err(|diags| {
+        source.report(&diags);
+        Failed
+    })?;
+    source.report(&report.diagnostics);
+    if options.dump {
+        for pass in &report.passes {
+            eprint!(
+                "// IR after {}\n{}",
+                pass.name,
+                pass.ir.as_deref().unwrap_or_default()
+            );
+        }
+    }
+    if options.time {
+        eprint!("{}", report);
+    }
+    Ok(module)
+}
+
+pub fn format_tokens(tokens: &[Spanned<Token>]) -> String {
+    tokens
+        .iter()
+        .map(|t| format!("{}\t{:?}\n", t.span, t.node))
+        .collect()
+}
+
+/// Text of one stage of `program`, which was type checked from `source`
+/// and compiled to `module` for `target`.
+pub fn emit(
+    source: &Source,
+    program: &Expr,
+    module: &Module,
+    target: &Target,
+    stage: Emit,
+) -> Result<String, Failed> {
+    match stage {
+        Emit::Tokens => Ok(format_tokens(&lex(source)?)),
+        Emit::Ast => Ok(parse(source)?.to_string()),
+        Emit::Types => Ok(program.to_string()),
+        Emit::Ir => Ok(module.to_string()),
+        Emit::Schedule => Ok(schedule(source, module, target)?.render(module)),
+        Emit::Memory => {
+            let schedule = schedule(source, module, target)?;
+            let plan = scheduler::plan(module, target, &schedule).map_err(|diags| {
+                source.report(&diags);
+                Failed
+            })?;
+            Ok(plan.render(module, target))
+        }
+    }
+}
+
+/// Schedule `module` on the devices of `target`.
+fn schedule(source: &Source, module: &Module, target: &Target) -> Result<Schedule, Failed> {
+    scheduler::schedule(module, target, &LatencyModel::default()).map_err(|diags| {
+        source.report(&diags);
+        Failed
+    })
+}
+
+/// Where `build` writes `stage`: `output` as is for a single stage or
+/// stdout, or as the stem of one file per stage, otherwise next to the
+/// input.
+pub fn output_path(input: &Path, output: Option<&Path>, stage: Emit, stages: usize) -> PathBuf {
+    match output {
+        Some(path) if stages == 1 || path == Path::new("-") => path.to_path_buf(),
+        Some(path) => path.with_extension(stage.extension()),
+        None => input.with_extension(stage.extension()),
+    }
+}
+
+/// Write `text` to `path`, or to stdout for `-`.
+pub fn write_output(path: Option<&Path>, text: &str) -> io::Result<()> {
+    match path {
+        Some(path) if path != Path::new("-") => fs::write(path, text),
+        _ => io::stdout().write_all(text.as_bytes()),
+    }
+}
@@ -1,38 +1,111 @@
-use lexer::LasmiaoLexer;
-use lexer::Lexer;
-use parser::TokenParser;
-use parser::traits::Parser;
-use std::io::{self, Write};
+mod cli;
+mod driver;
+mod repl;
 
-fn main() {
-    loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
+use cli::Command;
+use device::Target;
+use driver::{Failed, Source};
+use std::path::Path;
+use std::process::ExitCode;
 
-        let mut input = String::new();
+/// Compilation failed, the diagnostics have been reported
+const EXIT_FAILURE: u8 = 1;
+/// Bad command line
+const EXIT_USAGE: u8 = 2;
 
-        input.clear();
-        io::stdin().read_line(&mut input).expect("input error");
+fn main() -> ExitCode {
+    let command = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
 
-        match input.trim() {
-            "exit" => {
-                println!("Bye");
-                break;
-            }
-            "help" => {
-                println!("exit - exit the loop");
+    match run(command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failed) => ExitCode::from(EXIT_FAILURE),
+    }
+}
+
+fn load(path: &Path) -> Result<Source, Failed> {
+    Source::load(path).map_err(|e| {
+        eprintln!("error: can not read {}: {}", path.display(), e);
+        Failed
+    })
+}
+
+fn write(path: Option<&Path>, text: &str) -> Result<(), Failed> {
+    driver::write_output(path, text).map_err(|e| {
+        eprintln!(
+            "error: can not write {}: {}",
+            path.map_or("stdout".into(), |p| p.display().to_string()),
+            e
+        );
+        Failed
+    })
+}
+
+fn run(command: Command) -> Result<(), Failed> {
+    match command {
+        Command::Tokens { file, output } => {
+            let source = load(&file)?;
+            let tokens = driver::lex(&source)?;
+            write(output.as_deref(), &driver::format_tokens(&tokens))
+        }
+        Command::Parse { file, output } => {
+            let source = load(&file)?;
+            let program = driver::parse(&source)?;
+            write(output.as_deref(), &program.to_string())
+        }
+        Command::Check { files } => {
+            let mut result = Ok(());
+            for file in files {
+                // keep going so that every file gets its diagnostics
+                if load(&file)
+                    .and_then(|source| driver::check(&source))
+                    .is_err()
+                {
+                    result = Err(Failed);
+                }
             }
-            _ => match LasmiaoLexer::make_tokens(&input) {
-                Ok(v) => {
-                    println!("Tokens: {:?}", v);
-                    let mut parser = TokenParser::new(v);
-                    match parser.parse_exprs() {
-                        Ok(expr) => println!("AST:\n {}", expr),
-                        Err(e) => println!("Parser Error:\n  {}", e),
-                    }
+            result
+        }
+        Command::Build {
+            file,
+            emit,
+            output,
+            passes,
+        } => {
+            let source = load(&file)?;
+            let program = driver::check(&source)?;
+            let target = match &passes.target {
+                Some(path) => driver::target(path)?,
+                None => Target::default(),
+            };
+            let module = driver::compile(&source, &program, &passes, &target)?;
+            for stage in &emit {
+                let mut text = driver::emit(&source, &program, &module, &target, *stage)?;
+                let path = driver::output_path(&file, output.as_deref(), *stage, emit.len());
+                if path == Path::new("-") && emit.len() > 1 {
+                    text.insert_str(0, &format!("// {}\n", stage));
                 }
-                Err(e) => println!("Lexer Error:\n  {}", e),
-            },
+                write(Some(&path), &text)?;
+            }
+            Ok(())
+        }
+        Command::Run { file } => {
+            let source = load(&file)?;
+            println!("{}", driver::run(&source)?);
+            Ok(())
+        }
+        Command::Repl => {
+            repl::run();
+            Ok(())
+        }
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
         }
     }
 }
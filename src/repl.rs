@@ -0,0 +1,291 @@
+use crate::driver::{self, Source};
+use diagnostics::ErrorKind;
+use interp::Interpreter;
+use ir::Module;
+use lexer::{LasmiaoLexer, Lexer};
+use parser::TokenParser;
+use parser::expr::{Expr, ExprKind};
+use parser::traits::Parser;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use std::path::{Path, PathBuf};
+use typeck::TypeChecker;
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = ".. ";
+
+const HELP: &str = "\
+<statements>    Evaluate the input and keep its bindings
+:type <expr>    Show the type of an expression or a binding
+:ast <input>    Show the AST of the input without keeping it
+:tokens <input> Show the tokens of the input
+:ir <expr>      Show the IR of an expression, bindings being its inputs
+:load <file>    Load the bindings of a file
+:reset          Forget every binding
+:help           Show this message
+:quit           Exit, also `exit` or Ctrl-D
+
+An input continues on the next line while a `(`, `[` or `{` is open
+or an expression is unfinished, an empty line forces it to end.";
+
+/// Bindings which persist across inputs.
+#[derive(Default)]
+struct Session {
+    types: TypeChecker,
+    interp: Interpreter,
+}
+
+fn is_binding(stmt: &Expr) -> bool {
+    matches!(
+        stmt.kind,
+        ExprKind::Assign { .. } | ExprKind::MetaDefine { .. } | ExprKind::Rule { .. }
+    )
+}
+
+impl Session {
+    /// Check `program` and evaluate its statements up to the first error,
+    /// printing the values of those which are not bindings. Only the
+    /// bindings of the statements which ran are kept.
+    fn eval(&mut self, source: &Source, mut program: Expr) {
+        let ExprKind::Block(unchecked) = &program.kind else {
+            unreachable!("a program is parsed into a block")
+        };
+        let unchecked = unchecked.clone();
+        // the bindings of a program with type errors are dropped
+        let mut types = self.types.clone();
+        let diags = types.check_program(&mut program);
+        source.report(&diags);
+        if diags.iter().any(|d| d.is_error()) {
+            return;
+        }
+
+        let ExprKind::Block(stmts) = &program.kind else {
+            unreachable!("a program is parsed into a block")
+        };
+        for (i, stmt) in stmts.iter().enumerate() {
+            match self.interp.run(stmt) {
+                Ok(val) if !is_binding(stmt) => println!("{} : {}", val, stmt.ty),
+                Ok(_) => {}
+                Err(e) => {
+                    source.report(&[e]);
+                    // a prefix of a well typed program is well typed
+                    let mut ran = Expr::new(ExprKind::Block(unchecked[..i].to_vec()), program.span);
+                    self.types.check_program(&mut ran);
+                    return;
+                }
+            }
+        }
+        self.types = types;
+    }
+
+    /// Lower `program` checked against the bindings, which become symbols
+    /// of the module. The bindings are left as they are.
+    fn lower(&self, source: &Source, mut program: Expr) -> Option<Module> {
+        let diags = self.types.clone().check_program(&mut program);
+        source.report(&diags);
+        if diags.iter().any(|d| d.is_error()) {
+            return None;
+        }
+        Some(ir::lower(&program))
+    }
+}
+
+/// Whether `input` stops in the middle of a statement, so that the next
+/// line should be appended to it.
+fn is_incomplete(input: &str) -> bool {
+    match LasmiaoLexer::make_tokens(input) {
+        Err(e) => matches!(e.kind, ErrorKind::UnclosedDelimiter { .. }),
+        Ok(tokens) => {
+            let (_, errors) = TokenParser::new(tokens).parse_recovering();
+            errors.iter().any(|e| e.kind == ErrorKind::UnexpectedEof)
+        }
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".laplacesmiao_history"))
+}
+
+pub fn run() {
+    let mut editor = DefaultEditor::new().expect("can not initialize the line editor");
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is no history on the first run
+        let _ = editor.load_history(path);
+    }
+
+    let mut session = Session::default();
+    while let Some(input) = read_input(&mut editor) {
+        let _ = editor.add_history_entry(input.trim_end());
+        let line = input.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if matches!(line, "exit" | ":quit" | ":q") {
+            break;
+        }
+        if line == "help" {
+            println!("{}", HELP);
+            continue;
+        }
+        if let Some(command) = line.strip_prefix(':') {
+            let (command, arg) = command
+                .split_once(char::is_whitespace)
+                .unwrap_or((command, ""));
+            run_command(&mut session, command, arg.trim());
+        } else {
+            eval(&mut session, &Source::new("<stdin>", input.as_str()));
+        }
+    }
+    println!("Bye");
+
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+}
+
+/// Read one input, which may span several lines. `None` on Ctrl-D.
+fn read_input(editor: &mut DefaultEditor) -> Option<String> {
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !input.is_empty() && line.trim().is_empty() {
+                    return Some(input);
+                }
+                input.push_str(&line);
+                input.push('\n');
+                if !is_incomplete(&input) {
+                    return Some(input);
+                }
+            }
+            // Ctrl-C drops the current input
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) if !input.is_empty() => return Some(input),
+            Err(ReadlineError::Eof) => return None,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                return None;
+            }
+        }
+    }
+}
+
+fn parse(source: &Source) -> Option<Expr> {
+    driver::parse(source).ok()
+}
+
+fn eval(session: &mut Session, source: &Source) {
+    if let Some(program) = parse(source) {
+        session.eval(source, program);
+    }
+}
+
+fn run_command(session: &mut Session, command: &str, arg: &str) {
+    let source = Source::new("<stdin>", arg);
+    match command {
+        "type" | "t" => {
+            let Some(mut program) = parse(&source) else {
+                return;
+            };
+            if let ExprKind::Block(stmts) = &mut program.kind {
+                for stmt in stmts {
+                    match session.types.type_of(stmt) {
+                        Ok(scheme) => println!("{}", scheme),
+                        Err(diags) => source.report(&diags),
+                    }
+                }
+            }
+        }
+        "ast" => {
+            if let Some(program) = parse(&source) {
+                print!("{}", program);
+            }
+        }
+        "ir" => {
+            if let Some(module) = parse(&source).and_then(|p| session.lower(&source, p)) {
+                print!("{}", module);
+            }
+        }
+        "tokens" => {
+            if let Ok(tokens) = driver::lex(&source) {
+                print!("{}", driver::format_tokens(&tokens));
+            }
+        }
+        "load" | "l" => match Source::load(Path::new(arg)) {
+            Ok(file) => {
+                if let Some(program) = parse(&file) {
+                    session.eval(&file, program);
+                    println!("loaded {}", arg);
+                }
+            }
+            Err(e) => eprintln!("error: can not read {}: {}", arg, e),
+        },
+        "reset" => {
+            *session = Session::default();
+            println!("all bindings are forgotten");
+        }
+        "help" | "h" | "?" => println!("{}", HELP),
+        _ => eprintln!("error: unknown command `:{}`, try :help", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_delimiters_continue_the_input() {
+        assert!(is_incomplete("f = (x =>\n"));
+        assert!(is_incomplete("a = [1,\n 2\n"));
+        assert!(is_incomplete("a = 1 +\n"));
+        assert!(!is_incomplete("a = 1 + 2\n"));
+        assert!(!is_incomplete("a = (1 2)\n"));
+    }
+
+    #[test]
+    fn evaluation_stops_at_the_first_error() {
+        let mut session = Session::default();
+        let source = Source::new("<test>", "a = 1\nb = a / 0\nc = 2\n");
+        session.eval(&source, parse(&source).unwrap());
+        assert!(session.interp.globals().get("a").is_some());
+        assert!(session.interp.globals().get("c").is_none());
+        // the types of the statements which did not run are dropped too
+        assert_eq!(session.types.lookup("a").unwrap().to_string(), "i64");
+        assert!(session.types.lookup("b").is_none());
+        assert!(session.types.lookup("c").is_none());
+    }
+
+    #[test]
+    fn bindings_persist_and_shadow() {
+        let mut session = Session::default();
+        let source = Source::new("<test>", "a:f32 = 1.\nb = 2:u64\n");
+        session.eval(&source, parse(&source).unwrap());
+        let source = Source::new("<test>", "a:i32 = 2\n");
+        session.eval(&source, parse(&source).unwrap());
+        assert_eq!(session.types.lookup("a").unwrap().to_string(), "i32");
+        assert_eq!(session.types.lookup("b").unwrap().to_string(), "u64");
+
+        // rejected inputs bind nothing
+        let source = Source::new("<test>", "d = 1\ne:bool = d\n");
+        session.eval(&source, parse(&source).unwrap());
+        assert!(session.types.lookup("d").is_none());
+    }
+
+    #[test]
+    fn lowers_expressions_over_the_bindings() {
+        let mut session = Session::default();
+        let source = Source::new("<test>", "xs = [1., 2.]\n");
+        session.eval(&source, parse(&source).unwrap());
+        let source = Source::new("<test>", "xs.map(x => x * 2.)\n");
+        let module = session.lower(&source, parse(&source).unwrap()).unwrap();
+        let text = module.to_string();
+        assert!(text.contains("%0 = symbol xs : tensor(f64, 2)"), "{}", text);
+        assert!(session.types.lookup("x").is_none());
+    }
+}
@@ -0,0 +1,407 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn laplacesmiao(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_laplacesmiao"))
+        .args(args)
+        .output()
+        .expect("failed to run laplacesmiao")
+}
+
+fn temp_file(name: &str, text: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("laplacesmiao-cli-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, text).unwrap();
+    path
+}
+
+#[test]
+fn check_accepts_the_readme_example() {
+    let out = laplacesmiao(&["check", "examples/readme.lasmiao"]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+}
+
+#[test]
+fn build_plans_the_readme_example_for_the_example_target() {
+    let out = laplacesmiao(&[
+        "build",
+        "examples/readme.lasmiao",
+        "--target",
+        "examples/xpu.toml",
+        "--passes",
+        "inline,fuse,place-devices,double-buffer,cse,dce",
+        "--emit",
+        "memory",
+        "-o",
+        "-",
+    ]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.contains("cpu.input_tensor: 1024 of 1073741824 bytes"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn check_reports_every_error_and_fails() {
+    let file = temp_file("bad.lasmiao", "a = (1 2)\nb = $(1, 2)\n");
+    let out = laplacesmiao(&["check", file.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("error[E0004]"), "{}", stderr);
+    assert!(stderr.contains("error[E0008]"), "{}", stderr);
+    assert!(stderr.contains("bad.lasmiao:2:10"), "{}", stderr);
+}
+
+#[test]
+fn build_writes_each_emit_stage() {
+    let file = temp_file("ok.lasmiao", "a = 1 + 2\n");
+    let stem = file.with_extension("");
+    let out = laplacesmiao(&[
+        "build",
+        file.to_str().unwrap(),
+        "--emit",
+        "tokens,ast",
+        "-o",
+        stem.to_str().unwrap(),
+    ]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let tokens = fs::read_to_string(stem.with_extension("tokens")).unwrap();
+    assert!(tokens.starts_with("1:1\tSymbol(\"a\")"), "{}", tokens);
+    let ast = fs::read_to_string(stem.with_extension("ast")).unwrap();
+    assert!(ast.contains("Assign(a:?)"), "{}", ast);
+
+    // on stdout each stage follows a header
+    let out = laplacesmiao(&[
+        "build",
+        file.to_str().unwrap(),
+        "--emit",
+        "tokens,ast",
+        "-o",
+        "-",
+    ]);
+    assert!(out.status.success());
+    let both = String::from_utf8_lossy(&out.stdout);
+    assert!(both.starts_with("// tokens\n1:1\t"), "{}", both);
+    assert!(both.contains("\n// ast\n"), "{}", both);
+}
+
+#[test]
+fn build_emits_inferred_types() {
+    let file = temp_file("types.lasmiao", "a:f32 = 1 + 2\n");
+    let out = laplacesmiao(&[
+        "build",
+        file.to_str().unwrap(),
+        "--emit",
+        "types",
+        "-o",
+        "-",
+    ]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("Binary(+) : f32"), "{}", stdout);
+
+    let file = temp_file("mismatch.lasmiao", "a:bool = 1 + 2\n");
+    let out = laplacesmiao(&["check", file.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("error[E0022]"));
+}
+
+#[test]
+fn build_emits_the_ir() {
+    let file = temp_file("ir.lasmiao", "xs = [1., 2.]@xpu\n");
+    let out = laplacesmiao(&["build", file.to_str().unwrap(), "--emit", "ir", "-o", "-"]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.contains("%3 = move %2 : tensor(f64, 2) @xpu"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("name xs = %3"), "{}", stdout);
+
+    let ir = laplacesmiao(&["ir", file.to_str().unwrap()]);
+    assert!(ir.status.success());
+    assert_eq!(String::from_utf8_lossy(&ir.stdout), stdout);
+
+    let out = laplacesmiao(&[
+        "build",
+        file.to_str().unwrap(),
+        "--emit",
+        "ast,ir",
+        "-o",
+        "-",
+    ]);
+    assert!(out.status.success());
+    let both = String::from_utf8_lossy(&out.stdout);
+    assert!(both.starts_with("// ast\n"), "{}", both);
+    assert!(both.ends_with(&format!("// ir\n{}", stdout)), "{}", both);
+}
+
+#[test]
+fn build_runs_and_times_passes() {
+    let file = temp_file("passes.lasmiao", "a = 1 + 2\n");
+    let path = file.to_str().unwrap();
+    let out = laplacesmiao(&[
+        "build",
+        path,
+        "--emit",
+        "ir",
+        "-o",
+        "-",
+        "--passes",
+        "verify",
+        "--time-passes",
+    ]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("verify") && stderr.contains("total"),
+        "{}",
+        stderr
+    );
+
+    let out = laplacesmiao(&["build", path, "--passes", "verify,frob"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unknown pass `frob`"));
+}
+
+#[test]
+fn build_applies_the_rules_of_the_program() {
+    let file = temp_file(
+        "rules.lasmiao",
+        "rule add_zero: x + 0 => x\na = 2 * 3 + 0\n",
+    );
+    let path = file.to_str().unwrap();
+    let out = laplacesmiao(&[
+        "build", path, "--emit", "ir", "-o", "-", "--passes", "rules",
+    ]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.contains("mul") && !stdout.contains("add"),
+        "{}",
+        stdout
+    );
+
+    let file = temp_file("bad_rule.lasmiao", "rule r: x => x\n");
+    let out = laplacesmiao(&["check", file.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("matches no operation"));
+}
+
+#[test]
+fn build_reads_the_target_description() {
+    let file = temp_file("target.lasmiao", "a = 1 + 2\n");
+    let path = file.to_str().unwrap();
+    let target = temp_file(
+        "target.toml",
+        "[devices.cpu]\nmemory.dram = { kind = \"dram\", capacity = \"1GiB\" }\n",
+    );
+    let out = laplacesmiao(&["build", path, "--target", target.to_str().unwrap()]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    let target = temp_file("bad_target.toml", "[devices.cpu]\nwidth = 4\n");
+    let out = laplacesmiao(&["build", path, "--target", target.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("unknown key `width`") && stderr.contains("bad_target.toml:2:1"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn build_emits_the_schedule() {
+    let file = temp_file("schedule.lasmiao", "xs = [1., 2.]@xpu\nxs * xs\n");
+    let path = file.to_str().unwrap();
+    let target = temp_file(
+        "schedule.toml",
+        "[devices.cpu]\n[devices.xpu]\n\n\
+         [[links]]\nfrom = \"cpu\"\nto = \"xpu\"\nbandwidth = 4\nlatency = 10\n",
+    );
+    let out = laplacesmiao(&[
+        "build",
+        path,
+        "--emit",
+        "schedule",
+        "-o",
+        "-",
+        "--target",
+        target.to_str().unwrap(),
+        "--passes",
+        "place-devices",
+    ]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.contains("cpu->xpu       2      16  %3 = move %2  after cpu"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("xpu           16      18  %4 = mul %3, %3  after cpu->xpu"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.ends_with("// makespan: 18 cycles\n"), "{}", stdout);
+}
+
+#[test]
+fn build_double_buffers_moves_feeding_maps() {
+    let file = temp_file(
+        "double_buffer.lasmiao",
+        "xs = [1., 2., 3., 4., 5., 6., 7., 8.]@xpu\nxs.map(x => sin(x) * cos(x))\n",
+    );
+    let target = temp_file(
+        "double_buffer.toml",
+        "[devices.cpu]\n[devices.xpu]\nmemory.sram = { kind = \"sram\", capacity = \"1KiB\" }\n\n\
+         [[links]]\nfrom = \"cpu\"\nto = \"xpu\"\nbandwidth = 8\nlatency = 20\n",
+    );
+    let out = laplacesmiao(&[
+        "build",
+        file.to_str().unwrap(),
+        "--emit",
+        "schedule",
+        "-o",
+        "-",
+        "--target",
+        target.to_str().unwrap(),
+        "--passes",
+        "place-devices,double-buffer",
+    ]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.contains("cpu->xpu      29      50  %11 = move %8, %9, %10  tile 1  after cpu, xpu"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.ends_with("// makespan: 357 cycles\n"), "{}", stdout);
+}
+
+#[test]
+fn build_plans_memory_within_capacities() {
+    let target = temp_file(
+        "memory.toml",
+        "[devices.cpu]\n[devices.xpu]\nmemory.sram = { kind = \"sram\", capacity = \"4KiB\" }\n",
+    );
+    let target = target.to_str().unwrap();
+    let file = temp_file(
+        "memory.lasmiao",
+        "a = $(3000, sram)@xpu\nb = $(1024, sram)@xpu\n",
+    );
+    let out = laplacesmiao(&[
+        "build",
+        file.to_str().unwrap(),
+        "--emit",
+        "memory",
+        "-o",
+        "-",
+        "--target",
+        target,
+    ]);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.starts_with("xpu.sram: 4032 of 4096 bytes\n"),
+        "{}",
+        stdout
+    );
+
+    let file = temp_file(
+        "memory_full.lasmiao",
+        "a = $(3100, sram)@xpu\nb = $(1024, sram)@xpu\n",
+    );
+    let out = laplacesmiao(&[
+        "build",
+        file.to_str().unwrap(),
+        "--emit",
+        "memory",
+        "-o",
+        "-",
+        "--target",
+        target,
+    ]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains(
+            "error[E0031]: `sram` of `xpu` holds 4096 bytes, but 4160 are needed at once"
+        ) && stderr.contains("memory_full.lasmiao:2:5"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn run_prints_the_last_value() {
+    let file = temp_file("run.lasmiao", "f = (x => x * 2)\n[1, 2].map(f)\n");
+    let out = laplacesmiao(&["run", file.to_str().unwrap()]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "[2, 4]\n");
+
+    let file = temp_file("unbound.lasmiao", "a = b + 1\n");
+    let out = laplacesmiao(&["run", file.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("error[E0017]"));
+}
+
+#[test]
+fn run_wraps_integers_to_their_inferred_type() {
+    let file = temp_file(
+        "wrap.lasmiao",
+        "a = 2147483647:i32 + 1\nb = 1:u32 - 2\n(a, b)\n",
+    );
+    let out = laplacesmiao(&["run", file.to_str().unwrap()]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "(-2147483648, 4294967295)\n"
+    );
+}
+
+#[test]
+fn bad_usage_exits_with_2() {
+    assert_eq!(laplacesmiao(&["parse"]).status.code(), Some(2));
+    assert_eq!(
+        laplacesmiao(&["build", "a", "--emit", "x"]).status.code(),
+        Some(2)
+    );
+}